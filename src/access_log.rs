@@ -0,0 +1,343 @@
+use async_trait::async_trait;
+use hyper::body::HttpBody;
+use hyper::{Body, Request, Response};
+use serde_json::json;
+use std::fs::{self, File, OpenOptions};
+use std::io::{self, Write};
+use std::net::IpAddr;
+use std::path::PathBuf;
+use std::sync::{Arc, Mutex};
+use std::time::{Duration, Instant, SystemTime, UNIX_EPOCH};
+use tracing::{error, warn};
+
+use crate::config::{AccessLogConfig, LogRotation};
+use crate::middleware::{Context, Middleware};
+use crate::syslog::Syslog;
+#[cfg(unix)]
+use crate::systemd::Journal;
+
+/// Name of the upstream that served a response, attached to the response
+/// extensions so the access log can record it.
+#[derive(Debug, Clone)]
+pub struct UpstreamUsed(pub String);
+
+/// Request details captured when the request arrives.
+#[derive(Debug, Clone)]
+struct RequestInfo {
+    pub started: Instant,
+    pub timestamp: SystemTime,
+    pub client_ip: IpAddr,
+    pub method: String,
+    pub path: String,
+    pub request_id: Option<String>,
+}
+
+/// Writes one JSON line per request to stdout or a file.
+#[derive(Clone)]
+pub struct AccessLog {
+    config: AccessLogConfig,
+    output: Arc<Mutex<Output>>,
+}
+
+enum Output {
+    Stdout,
+    File(LogFile),
+    Syslog(Syslog),
+    #[cfg(unix)]
+    Journal(Journal),
+}
+
+/// A log file rotated by size or time into `path.1`, `path.2` and so on,
+/// `path.1` being the most recent.
+struct LogFile {
+    path: PathBuf,
+    file: File,
+    /// Bytes in the current file
+    size: u64,
+    /// The hour or day the current file was started in
+    period: u64,
+    max_size: u64,
+    rotate: LogRotation,
+    max_files: usize,
+}
+
+impl LogFile {
+    fn open(path: &str, config: &AccessLogConfig) -> io::Result<LogFile> {
+        let (file, size, period) = open_append(&PathBuf::from(path), config.rotate)?;
+        Ok(LogFile {
+            path: PathBuf::from(path),
+            file,
+            size,
+            period,
+            max_size: config.max_size_mb * 1024 * 1024,
+            rotate: config.rotate,
+            max_files: config.max_files,
+        })
+    }
+
+    /// Opens the file at the path again, e.g. after logrotate has moved it away.
+    fn reopen(&mut self) -> io::Result<()> {
+        (self.file, self.size, self.period) = open_append(&self.path, self.rotate)?;
+        Ok(())
+    }
+
+    fn write_line(&mut self, line: &[u8]) -> io::Result<()> {
+        let too_big = self.max_size > 0 && self.size + line.len() as u64 > self.max_size;
+        let new_period = self.rotate != LogRotation::Never && period(self.rotate, SystemTime::now()) != self.period;
+        if self.size > 0 && (too_big || new_period) {
+            if let Err(e) = self.rotate() {
+                // Logging carries on in the current file
+                warn!("Failed to rotate access log {}: {}", self.path.display(), e);
+            }
+        }
+        self.file.write_all(line)?;
+        self.size += line.len() as u64;
+        Ok(())
+    }
+
+    fn rotate(&mut self) -> io::Result<()> {
+        let rotated = |n: usize| {
+            let mut name = self.path.clone().into_os_string();
+            name.push(format!(".{}", n));
+            PathBuf::from(name)
+        };
+        // The first number not taken
+        let mut free = 1;
+        while rotated(free).exists() {
+            free += 1;
+        }
+        if self.max_files > 0 {
+            for n in self.max_files..free {
+                fs::remove_file(rotated(n))?;
+            }
+            free = free.min(self.max_files);
+        }
+        for n in (1..free).rev() {
+            fs::rename(rotated(n), rotated(n + 1))?;
+        }
+        fs::rename(&self.path, rotated(1))?;
+        self.reopen()
+    }
+}
+
+/// Opens `path` for appending, with its size and the period it was last
+/// written in.
+fn open_append(path: &PathBuf, rotate: LogRotation) -> io::Result<(File, u64, u64)> {
+    let file = OpenOptions::new().create(true).append(true).open(path)?;
+    let metadata = file.metadata()?;
+    let written = metadata.modified().ok().filter(|_| metadata.len() > 0).unwrap_or_else(SystemTime::now);
+    Ok((file, metadata.len(), period(rotate, written)))
+}
+
+/// The hour or day, counted from the epoch in UTC, that `time` falls in.
+fn period(rotate: LogRotation, time: SystemTime) -> u64 {
+    let secs = time.duration_since(UNIX_EPOCH).unwrap_or_default().as_secs();
+    match rotate {
+        LogRotation::Never => 0,
+        LogRotation::Hourly => secs / 3600,
+        LogRotation::Daily => secs / 86_400,
+    }
+}
+
+impl AccessLog {
+    /// Opens the log at `config.path` (appending), or stdout when no path is given.
+    pub fn open(config: &AccessLogConfig) -> io::Result<AccessLog> {
+        let output = match (&config.path, &config.syslog) {
+            (Some(path), _) => Output::File(LogFile::open(path, config)?),
+            (None, Some(syslog)) => Output::Syslog(Syslog::new(syslog).map_err(io::Error::other)?),
+            #[cfg(unix)]
+            (None, None) if config.journald => Output::Journal(Journal::open()?),
+            (None, None) => Output::Stdout,
+        };
+        Ok(AccessLog { config: config.clone(), output: Arc::new(Mutex::new(output)) })
+    }
+
+    /// Whether the log was opened with these settings.
+    pub fn uses(&self, config: &AccessLogConfig) -> bool {
+        self.config == *config
+    }
+
+    /// Opens the log file again, so lines go to a new file once the old one
+    /// has been moved away. The old file is kept if the path cannot be opened.
+    pub fn reopen(&self) -> io::Result<()> {
+        match &mut *self.output.lock().unwrap() {
+            Output::File(file) => file.reopen(),
+            _ => Ok(()),
+        }
+    }
+
+    /// Writes the log line for a finished request.
+    fn log(&self, info: &RequestInfo, status: Option<u16>, upstream: Option<&str>, bytes: u64, error: Option<&str>) {
+        let line = json!({
+            "timestamp": format_rfc3339(info.timestamp),
+            "client_ip": info.client_ip.to_string(),
+            "method": info.method,
+            "path": info.path,
+            "request_id": info.request_id,
+            "status": status,
+            "upstream": upstream,
+            "bytes": bytes,
+            "latency_ms": duration_ms(info.started.elapsed()),
+            "error": error,
+        });
+
+        let result = match &mut *self.output.lock().unwrap() {
+            Output::File(file) => file.write_line(format!("{}\n", line).as_bytes()),
+            Output::Stdout => writeln!(io::stdout(), "{}", line),
+            Output::Syslog(syslog) => {
+                syslog.send(severity(status), &line.to_string());
+                Ok(())
+            }
+            #[cfg(unix)]
+            Output::Journal(journal) => {
+                journal_entry(journal, &line, status);
+                Ok(())
+            }
+        };
+        if let Err(e) = result {
+            error!("Failed to write access log: {}", e);
+        }
+    }
+}
+
+#[async_trait]
+impl Middleware for AccessLog {
+    async fn on_request(&self, req: &mut Request<Body>, ctx: &mut Context) -> Option<Response<Body>> {
+        if ctx.background {
+            return None;
+        }
+        ctx.extensions.insert(RequestInfo {
+            started: Instant::now(),
+            timestamp: SystemTime::now(),
+            client_ip: ctx.client_addr.ip(),
+            method: req.method().to_string(),
+            path: req.uri().path_and_query().map(|p| p.to_string()).unwrap_or_else(|| "/".to_string()),
+            request_id: ctx.request_id.clone(),
+        });
+        None
+    }
+
+    async fn on_response(&self, res: &mut Response<Body>, ctx: &mut Context) {
+        let info = match ctx.extensions.remove::<RequestInfo>() {
+            Some(info) => info,
+            None => return,
+        };
+
+        // Log once the body has been streamed so the byte count and latency are complete
+        let status = res.status().as_u16();
+        let upstream = res.extensions().get::<UpstreamUsed>().map(|u| u.0.clone());
+        let log = self.clone();
+        let body = std::mem::replace(res.body_mut(), Body::empty());
+        *res.body_mut() = track_body(body, move |bytes| {
+            log.log(&info, Some(status), upstream.as_deref(), bytes, None);
+        });
+    }
+
+    async fn on_error(&self, error: &(dyn std::error::Error + Send + Sync), ctx: &mut Context) {
+        if let Some(info) = ctx.extensions.remove::<RequestInfo>() {
+            self.log(&info, None, None, 0, Some(&error.to_string()));
+        }
+    }
+}
+
+/// Streams `body` through to the client, counting bytes, and calls
+/// `on_complete` with the total once the body has been fully sent.
+pub(crate) fn track_body<F>(body: Body, on_complete: F) -> Body
+where
+    F: FnOnce(u64) + Send + 'static,
+{
+    let (mut sender, tracked) = Body::channel();
+    tokio::spawn(async move {
+        let mut body = body;
+        let mut bytes = 0u64;
+        while let Some(chunk) = body.data().await {
+            match chunk {
+                Ok(chunk) => {
+                    bytes += chunk.len() as u64;
+                    if sender.send_data(chunk).await.is_err() {
+                        break;
+                    }
+                }
+                Err(_) => {
+                    sender.abort();
+                    on_complete(bytes);
+                    return;
+                }
+            }
+        }
+        // Forward trailers (e.g. gRPC status) after the data
+        if let Ok(Some(trailers)) = body.trailers().await {
+            let _ = sender.send_trailers(trailers).await;
+        }
+        on_complete(bytes);
+    });
+    tracked
+}
+
+/// The syslog severity of a request: error when it failed without a
+/// response, warning for a 5xx and informational otherwise.
+fn severity(status: Option<u16>) -> u8 {
+    match status {
+        None => 3,
+        Some(status) if status >= 500 => 4,
+        Some(_) => 6,
+    }
+}
+
+/// Sends a log line to the journal as its message, with each of its fields
+/// as a journal field too, e.g. `STATUS=200`.
+#[cfg(unix)]
+fn journal_entry(journal: &Journal, line: &serde_json::Value, status: Option<u16>) {
+    let priority = severity(status).to_string();
+    let message = line.to_string();
+    let mut values = Vec::new();
+    if let Some(fields) = line.as_object() {
+        for (name, value) in fields {
+            let value = match value {
+                serde_json::Value::Null => continue,
+                serde_json::Value::String(value) => value.clone(),
+                value => value.to_string(),
+            };
+            values.push((name.to_ascii_uppercase(), value));
+        }
+    }
+    let mut entry = vec![("PRIORITY", priority.as_str()), ("SYSLOG_IDENTIFIER", "riffy"), ("MESSAGE", message.as_str())];
+    entry.extend(values.iter().map(|(name, value)| (name.as_str(), value.as_str())));
+    journal.send(&entry);
+}
+
+fn duration_ms(elapsed: Duration) -> f64 {
+    (elapsed.as_secs_f64() * 1000.0 * 1000.0).round() / 1000.0
+}
+
+/// Formats a timestamp as RFC 3339 in UTC with millisecond precision.
+pub(crate) fn format_rfc3339(time: SystemTime) -> String {
+    let since_epoch = time.duration_since(UNIX_EPOCH).unwrap_or_default();
+    let secs = since_epoch.as_secs();
+    let (year, month, day) = civil_from_days((secs / 86_400) as i64);
+    let rem = secs % 86_400;
+    format!(
+        "{:04}-{:02}-{:02}T{:02}:{:02}:{:02}.{:03}Z",
+        year,
+        month,
+        day,
+        rem / 3600,
+        (rem % 3600) / 60,
+        rem % 60,
+        since_epoch.subsec_millis()
+    )
+}
+
+/// Converts days since 1970-01-01 to a (year, month, day) date (Howard Hinnant's algorithm).
+fn civil_from_days(days: i64) -> (i64, u32, u32) {
+    let z = days + 719_468;
+    let era = z.div_euclid(146_097);
+    let doe = z.rem_euclid(146_097);
+    let yoe = (doe - doe / 1460 + doe / 36_524 - doe / 146_096) / 365;
+    let doy = doe - (365 * yoe + yoe / 4 - yoe / 100);
+    let mp = (5 * doy + 2) / 153;
+    let day = (doy - (153 * mp + 2) / 5 + 1) as u32;
+    let month = if mp < 10 { mp + 3 } else { mp - 9 } as u32;
+    let year = yoe + era * 400 + if month <= 2 { 1 } else { 0 };
+    (year, month, day)
+}
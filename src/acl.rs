@@ -0,0 +1,263 @@
+use hyper::HeaderMap;
+use serde::{Deserialize, Deserializer};
+use std::fmt;
+use std::net::IpAddr;
+use std::str::FromStr;
+
+/// An IP address range such as `10.0.0.0/8` or `2001:db8::/32`. A bare
+/// address is a range of one.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct Cidr {
+    network: IpAddr,
+    prefix: u8,
+}
+
+impl Cidr {
+    pub fn contains(&self, ip: IpAddr) -> bool {
+        // IPv4 clients of a dual-stack socket show up as ::ffff:a.b.c.d
+        match (self.network, ip.to_canonical()) {
+            (IpAddr::V4(network), IpAddr::V4(ip)) => mask(u32::from(network) as u128, u32::from(ip) as u128, 32, self.prefix),
+            (IpAddr::V6(network), IpAddr::V6(ip)) => mask(u128::from(network), u128::from(ip), 128, self.prefix),
+            _ => false,
+        }
+    }
+}
+
+/// Whether `a` and `b`, each `bits` wide, agree in their first `prefix` bits.
+fn mask(a: u128, b: u128, bits: u8, prefix: u8) -> bool {
+    if prefix == 0 {
+        return true;
+    }
+    let shift = bits - prefix;
+    a >> shift == b >> shift
+}
+
+impl FromStr for Cidr {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        let s = s.trim();
+        let (address, prefix) = match s.split_once('/') {
+            Some((address, prefix)) => (address, Some(prefix)),
+            None => (s, None),
+        };
+        let network = address.parse::<IpAddr>().map_err(|_| format!("invalid IP address or CIDR range: {}", s))?.to_canonical();
+        let bits = if network.is_ipv4() { 32 } else { 128 };
+        let prefix = match prefix {
+            Some(prefix) => prefix.parse::<u8>().ok().filter(|&prefix| prefix <= bits).ok_or_else(|| format!("invalid CIDR prefix length: {}", s))?,
+            None => bits,
+        };
+        Ok(Cidr { network, prefix })
+    }
+}
+
+impl fmt::Display for Cidr {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(f, "{}/{}", self.network, self.prefix)
+    }
+}
+
+impl<'de> Deserialize<'de> for Cidr {
+    fn deserialize<D: Deserializer<'de>>(deserializer: D) -> Result<Self, D::Error> {
+        let s = String::deserialize(deserializer)?;
+        s.parse().map_err(serde::de::Error::custom)
+    }
+}
+
+/// Client addresses let in or refused, for the listener or a route.
+#[derive(Debug, Clone, Default, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct AccessList {
+    /// When not empty, only clients in these ranges are let in
+    pub allow: Vec<Cidr>,
+    /// Clients in these ranges are refused, even when also allowed
+    pub deny: Vec<Cidr>,
+}
+
+impl AccessList {
+    pub fn permits(&self, ip: IpAddr) -> bool {
+        !self.deny.iter().any(|range| range.contains(ip)) && (self.allow.is_empty() || self.allow.iter().any(|range| range.contains(ip)))
+    }
+}
+
+/// Parses a comma-separated list of ranges, as given in environment variables.
+pub fn parse_list(s: &str) -> Result<Vec<Cidr>, String> {
+    s.split(',').filter(|range| !range.trim().is_empty()).map(str::parse).collect()
+}
+
+/// The header trusted proxies give the client address in.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default)]
+pub enum ForwardedHeader {
+    /// `X-Forwarded-For`, appended to by most CDNs and load balancers
+    #[default]
+    XForwardedFor,
+    /// The `for=` entries of RFC 7239 `Forwarded`
+    Forwarded,
+}
+
+impl FromStr for ForwardedHeader {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s.trim().to_ascii_lowercase().as_str() {
+            "x-forwarded-for" => Ok(ForwardedHeader::XForwardedFor),
+            "forwarded" => Ok(ForwardedHeader::Forwarded),
+            other => Err(format!("unknown forwarded header, expected x-forwarded-for or forwarded: {}", other)),
+        }
+    }
+}
+
+impl<'de> Deserialize<'de> for ForwardedHeader {
+    fn deserialize<D: Deserializer<'de>>(deserializer: D) -> Result<Self, D::Error> {
+        let s = String::deserialize(deserializer)?;
+        s.parse().map_err(serde::de::Error::custom)
+    }
+}
+
+/// The address of the client behind `peer`. When `peer` is a trusted proxy,
+/// the entries of `header` are read from the right, skipping further trusted
+/// proxies, and the first address that is not one is the client's. The other
+/// header is never looked at, as proxies pass on whatever clients put in it.
+pub fn client_ip(headers: &HeaderMap, peer: IpAddr, trusted: &[Cidr], header: ForwardedHeader) -> IpAddr {
+    let is_trusted = |ip: IpAddr| trusted.iter().any(|range| range.contains(ip));
+    if !is_trusted(peer) {
+        return peer;
+    }
+    // Several headers of the same name form one list, in order
+    let values = |name| headers.get_all(name).iter().filter_map(|v| v.to_str().ok()).flat_map(|v| v.split(',')).map(str::to_string).collect::<Vec<_>>();
+    let hops = match header {
+        ForwardedHeader::XForwardedFor => values("x-forwarded-for"),
+        ForwardedHeader::Forwarded => values("forwarded").iter().filter_map(|element| forwarded_for(element)).collect(),
+    };
+
+    let mut client = peer;
+    for hop in hops.iter().rev() {
+        match parse_node(hop) {
+            Some(ip) => {
+                client = ip;
+                if !is_trusted(ip) {
+                    break;
+                }
+            }
+            // Whatever an unknown or obfuscated entry hides cannot be trusted
+            None => break,
+        }
+    }
+    client
+}
+
+/// The `for=` parameter of one `Forwarded` element.
+fn forwarded_for(element: &str) -> Option<String> {
+    element.split(';').filter_map(|pair| pair.trim().split_once('=')).find(|(key, _)| key.eq_ignore_ascii_case("for")).map(|(_, value)| value.trim().to_string())
+}
+
+/// Parses a forwarded address: `192.0.2.1`, `"[2001:db8::1]:4711"`, `192.0.2.1:80`.
+fn parse_node(node: &str) -> Option<IpAddr> {
+    let node = node.trim().trim_matches('"');
+    if let Ok(ip) = node.parse() {
+        return Some(ip);
+    }
+    match node.strip_prefix('[') {
+        Some(rest) => rest.split(']').next()?.parse().ok(),
+        None => node.rsplit_once(':')?.0.parse().ok(),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use hyper::header::HeaderValue;
+
+    fn headers(pairs: &[(&'static str, &str)]) -> HeaderMap {
+        let mut headers = HeaderMap::new();
+        for (name, value) in pairs {
+            headers.append(*name, HeaderValue::from_str(value).unwrap());
+        }
+        headers
+    }
+
+    const XFF: ForwardedHeader = ForwardedHeader::XForwardedFor;
+
+    fn ip(s: &str) -> IpAddr {
+        s.parse().unwrap()
+    }
+
+    #[test]
+    fn untrusted_peers_are_the_client() {
+        let trusted = parse_list("10.0.0.0/8").unwrap();
+        assert_eq!(client_ip(&headers(&[("x-forwarded-for", "192.0.2.7")]), ip("203.0.113.5"), &trusted, XFF), ip("203.0.113.5"));
+        assert_eq!(client_ip(&headers(&[("x-forwarded-for", "192.0.2.7")]), ip("10.0.0.1"), &[], XFF), ip("10.0.0.1"));
+    }
+
+    #[test]
+    fn walks_forwarded_for_from_the_right() {
+        let trusted = parse_list("10.0.0.0/8, 2001:db8::/32").unwrap();
+        let peer = ip("10.0.0.1");
+        // The client may put anything on the left; only what trusted proxies appended counts
+        assert_eq!(client_ip(&headers(&[("x-forwarded-for", "198.51.100.1, 192.0.2.7, 10.0.0.2")]), peer, &trusted, XFF), ip("192.0.2.7"));
+        assert_eq!(client_ip(&headers(&[("x-forwarded-for", "198.51.100.1, 192.0.2.7"), ("x-forwarded-for", "10.0.0.2")]), peer, &trusted, XFF), ip("192.0.2.7"));
+        assert_eq!(client_ip(&headers(&[("x-forwarded-for", " 192.0.2.7 ,2001:db8::5")]), peer, &trusted, XFF), ip("192.0.2.7"));
+        // With every hop trusted, the leftmost one is all there is
+        assert_eq!(client_ip(&headers(&[("x-forwarded-for", "10.0.0.3, 10.0.0.2")]), peer, &trusted, XFF), ip("10.0.0.3"));
+        assert_eq!(client_ip(&headers(&[]), peer, &trusted, XFF), peer);
+    }
+
+    #[test]
+    fn ignores_a_forged_forwarded_header() {
+        let trusted = parse_list("10.0.0.0/8").unwrap();
+        let peer = ip("10.0.0.1");
+        // The proxy only appends X-Forwarded-For and passes the client's Forwarded through
+        let map = headers(&[("forwarded", "for=198.51.100.66"), ("x-forwarded-for", "203.0.113.9")]);
+        assert_eq!(client_ip(&map, peer, &trusted, XFF), ip("203.0.113.9"));
+        assert_eq!(client_ip(&headers(&[("forwarded", "for=198.51.100.66")]), peer, &trusted, XFF), peer);
+        // And the other way round when Forwarded is the configured header
+        assert_eq!(client_ip(&map, peer, &trusted, ForwardedHeader::Forwarded), ip("198.51.100.66"));
+        assert_eq!(client_ip(&headers(&[("x-forwarded-for", "203.0.113.9")]), peer, &trusted, ForwardedHeader::Forwarded), peer);
+    }
+
+    #[test]
+    fn reads_forwarded_for_entries() {
+        let trusted = parse_list("10.0.0.0/8").unwrap();
+        let peer = ip("10.0.0.1");
+        let forwarded = [
+            ("for=192.0.2.7", "192.0.2.7"),
+            ("for=192.0.2.7:4711;proto=https", "192.0.2.7"),
+            ("for=\"[2001:db8::1]:4711\", for=10.0.0.2", "2001:db8::1"),
+            ("proto=https;For=198.51.100.3;by=10.0.0.1", "198.51.100.3"),
+        ];
+        for (value, client) in forwarded {
+            assert_eq!(client_ip(&headers(&[("forwarded", value)]), peer, &trusted, ForwardedHeader::Forwarded), ip(client), "{}", value);
+        }
+        // No for= at all leaves only the peer
+        assert_eq!(client_ip(&headers(&[("forwarded", "proto=https"), ("x-forwarded-for", "203.0.113.9")]), peer, &trusted, ForwardedHeader::Forwarded), peer);
+        assert!("Forwarded".parse::<ForwardedHeader>().is_ok());
+        assert!("x-real-ip".parse::<ForwardedHeader>().is_err());
+    }
+
+    #[test]
+    fn stops_at_entries_it_cannot_read() {
+        let trusted = parse_list("10.0.0.0/8").unwrap();
+        let peer = ip("10.0.0.1");
+        for value in ["192.0.2.7, unknown, 10.0.0.2", "192.0.2.7, _hidden", "192.0.2.7, 300.1.1.1", "192.0.2.7, "] {
+            let map = headers(&[("x-forwarded-for", value)]);
+            let client = client_ip(&map, peer, &trusted, XFF);
+            assert_ne!(client, ip("192.0.2.7"), "{}", value);
+            assert!(trusted.iter().any(|range| range.contains(client)), "{}", value);
+        }
+        assert_eq!(client_ip(&headers(&[("forwarded", "for=unknown, for=10.0.0.2")]), peer, &trusted, ForwardedHeader::Forwarded), ip("10.0.0.2"));
+    }
+
+    #[test]
+    fn parses_ranges() {
+        assert!(Cidr::from_str("10.0.0.0/8").unwrap().contains(ip("10.255.0.1")));
+        assert!(Cidr::from_str("10.0.0.0/8").unwrap().contains(ip("::ffff:10.1.2.3")));
+        assert!(!Cidr::from_str("10.0.0.0/8").unwrap().contains(ip("11.0.0.1")));
+        assert!(Cidr::from_str("0.0.0.0/0").unwrap().contains(ip("192.0.2.1")));
+        assert!(Cidr::from_str("2001:db8::1").unwrap().contains(ip("2001:db8::1")));
+        assert!(!Cidr::from_str("2001:db8::1").unwrap().contains(ip("2001:db8::2")));
+        for range in ["10.0.0.0/33", "2001:db8::/129", "10.0.0.0/", "10.0.0/8", "example.com", ""] {
+            assert!(Cidr::from_str(range).is_err(), "{}", range);
+        }
+        assert_eq!(parse_list(" 10.0.0.0/8, ,192.0.2.1").unwrap().len(), 2);
+    }
+}
@@ -0,0 +1,500 @@
+use hyper::header::{AUTHORIZATION, CONTENT_TYPE, WWW_AUTHENTICATE};
+use hyper::service::{make_service_fn, service_fn};
+use hyper::{Body, Method, Request, Response, Server, StatusCode};
+use serde::Deserialize;
+use serde_json::{json, Value};
+use std::convert::Infallible;
+use std::net::SocketAddr;
+use std::sync::Arc;
+use tracing::{error, info};
+
+use crate::balancer::{self, Upstream, MAX_WEIGHT};
+use crate::cache::{self, Purge};
+use crate::logging;
+use crate::probes;
+use crate::proxy::{Pool, ProxyState, Runtime};
+use crate::router::Route;
+
+/// Runs the admin HTTP server, which serves `/metrics` for Prometheus and the
+/// upstream management API.
+pub async fn serve(listener: std::net::TcpListener, addr: SocketAddr, runtime: Arc<Runtime>) {
+    let make_svc = make_service_fn(move |_conn| {
+        let runtime = Arc::clone(&runtime);
+        async {
+            Ok::<_, Infallible>(service_fn(move |req| {
+                let runtime = Arc::clone(&runtime);
+                async move { Ok::<_, Infallible>(handle_admin(req, &runtime).await) }
+            }))
+        }
+    });
+
+    info!("Admin server listening on http://{}", addr);
+
+    let server = match Server::from_tcp(listener) {
+        Ok(server) => server,
+        Err(e) => return error!("Admin server failed to listen on {}: {}", addr, e),
+    };
+    // After an upgrade the new process serves the port
+    if let Err(e) = server.serve(make_svc).with_graceful_shutdown(crate::handoff::handed_over()).await {
+        error!("Admin server error: {}", e);
+    }
+}
+
+async fn handle_admin(req: Request<Body>, runtime: &Runtime) -> Response<Body> {
+    if let Some(res) = probes::answer(&req, runtime, &runtime.state()) {
+        return res;
+    }
+    if req.method() == Method::GET && req.uri().path() == "/metrics" {
+        return Response::builder()
+            .header(CONTENT_TYPE, "text/plain; version=0.0.4")
+            .body(Body::from(runtime.metrics.render(&runtime.state())))
+            .unwrap();
+    }
+
+    let path: Vec<String> = req.uri().path().split('/').filter(|s| !s.is_empty()).map(String::from).collect();
+    let is_api = matches!(path.first().map(String::as_str), Some("upstreams") | Some("pools") | Some("routes") | Some("tls") | Some("cache") | Some("logging"));
+    if !is_api {
+        return error(StatusCode::NOT_FOUND, "Not Found");
+    }
+
+    match runtime.admin_token.as_deref() {
+        None => return error(StatusCode::FORBIDDEN, "admin API disabled: set admin.token (ADMIN_TOKEN)"),
+        Some(token) if !authorized(&req, token) => {
+            let mut res = error(StatusCode::UNAUTHORIZED, "missing or invalid bearer token");
+            res.headers_mut().insert(WWW_AUTHENTICATE, "Bearer".parse().unwrap());
+            return res;
+        }
+        Some(_) => {}
+    }
+
+    let method = req.method().clone();
+    let body = match hyper::body::to_bytes(req.into_body()).await {
+        Ok(body) => body,
+        Err(e) => return error(StatusCode::BAD_REQUEST, &e.to_string()),
+    };
+
+    let state = runtime.state();
+    let path: Vec<&str> = path.iter().map(String::as_str).collect();
+    match (method, path.as_slice()) {
+        (Method::GET, ["upstreams"]) => json_response(StatusCode::OK, list_pools(&state.pools)),
+        (Method::GET, ["routes"]) => {
+            let routes: Vec<Value> = state.routes().iter().enumerate().map(|(index, route)| route_json(&state, index, route)).collect();
+            json_response(StatusCode::OK, json!({ "routes": routes }))
+        }
+        (method, ["routes", id, rest @ ..]) => {
+            let (index, route) = match state.find_route(id) {
+                Some(found) => found,
+                None => return error(StatusCode::NOT_FOUND, &format!("unknown route '{}'", id)),
+            };
+            match (method, rest) {
+                (Method::GET, []) => json_response(StatusCode::OK, route_json(&state, index, route)),
+                (Method::PATCH, ["canary"]) => update_canary(&state, index, route, &body),
+                (Method::PUT, ["active"]) => set_active_pool(&state, index, route, &body),
+                (Method::PUT, ["maintenance"]) => set_maintenance(&state, index, route, &body),
+                _ => error(StatusCode::METHOD_NOT_ALLOWED, "Method Not Allowed"),
+            }
+        }
+        (Method::POST, ["cache", "purge"]) => purge_cache(&state, &body),
+        (Method::GET, ["logging"]) => match logging::level() {
+            Some(level) => json_response(StatusCode::OK, json!({ "level": level })),
+            None => error(StatusCode::CONFLICT, "logging is set up by the application embedding Riffy"),
+        },
+        (Method::PUT, ["logging"]) => set_log_level(&body),
+        (Method::POST, ["tls", "reload"]) => match runtime.reload_certificates() {
+            Ok(()) => json_response(StatusCode::OK, json!({ "reloaded": true })),
+            Err(e) => error(StatusCode::INTERNAL_SERVER_ERROR, &e),
+        },
+        (method, ["pools", pool, "upstreams", rest @ ..]) => {
+            let pool = match state.pools.iter().find(|p| p.name == *pool) {
+                Some(pool) => pool,
+                None => return error(StatusCode::NOT_FOUND, &format!("unknown pool '{}'", pool)),
+            };
+            match (method, rest) {
+                (Method::GET, []) => json_response(StatusCode::OK, list_pools(std::slice::from_ref(pool))),
+                (Method::POST, []) => add_upstream(pool, &body),
+                (Method::PATCH, [id]) => update_upstream(pool, id, &body),
+                (Method::DELETE, [id]) => remove_upstream(pool, id),
+                _ => error(StatusCode::METHOD_NOT_ALLOWED, "Method Not Allowed"),
+            }
+        }
+        _ => error(StatusCode::NOT_FOUND, "Not Found"),
+    }
+}
+
+#[derive(Deserialize)]
+#[serde(deny_unknown_fields)]
+struct NewUpstream {
+    url: String,
+    #[serde(default = "default_weight")]
+    weight: u32,
+    #[serde(default)]
+    backup: bool,
+}
+
+fn default_weight() -> u32 {
+    1
+}
+
+#[derive(Deserialize)]
+#[serde(deny_unknown_fields)]
+struct UpstreamUpdate {
+    weight: Option<u32>,
+    draining: Option<bool>,
+}
+
+fn add_upstream(pool: &Pool, body: &[u8]) -> Response<Body> {
+    let new: NewUpstream = match serde_json::from_slice(body) {
+        Ok(new) => new,
+        Err(e) => return error(StatusCode::BAD_REQUEST, &format!("invalid request body: {}", e)),
+    };
+    if new.weight == 0 || new.weight > MAX_WEIGHT {
+        return error(StatusCode::BAD_REQUEST, &format!("weight must be between 1 and {}", MAX_WEIGHT));
+    }
+    let url = match balancer::normalize_url(&new.url) {
+        Ok(url) => url,
+        Err(e) => return error(StatusCode::BAD_REQUEST, &e),
+    };
+
+    match pool.add_upstream(Upstream::new(url, new.weight).with_backup(new.backup)) {
+        Ok(upstream) => {
+            pool.mark_changed();
+            info!("Admin API added upstream {} to pool {}", upstream.url, pool.name);
+            json_response(StatusCode::CREATED, upstream_json(&upstream))
+        }
+        Err(e) => error(StatusCode::CONFLICT, &e),
+    }
+}
+
+fn update_upstream(pool: &Pool, id: &str, body: &[u8]) -> Response<Body> {
+    let update: UpstreamUpdate = match serde_json::from_slice(body) {
+        Ok(update) => update,
+        Err(e) => return error(StatusCode::BAD_REQUEST, &format!("invalid request body: {}", e)),
+    };
+    let upstream = match pool.balancer.find(id) {
+        Some(upstream) => upstream,
+        None => return error(StatusCode::NOT_FOUND, &format!("unknown upstream '{}'", id)),
+    };
+
+    if let Some(weight) = update.weight {
+        if weight == 0 || weight > MAX_WEIGHT {
+            return error(StatusCode::BAD_REQUEST, &format!("weight must be between 1 and {}", MAX_WEIGHT));
+        }
+        pool.balancer.set_weight(id, weight);
+        pool.mark_changed();
+        info!("Admin API set weight of {} to {}", upstream.label(), weight);
+    }
+    if let Some(draining) = update.draining {
+        upstream.set_draining(draining);
+        pool.mark_changed();
+        info!("Admin API {} upstream {}", if draining { "is draining" } else { "stopped draining" }, upstream.label());
+    }
+
+    json_response(StatusCode::OK, upstream_json(&upstream))
+}
+
+fn remove_upstream(pool: &Pool, id: &str) -> Response<Body> {
+    match pool.balancer.remove(id) {
+        Some(upstream) => {
+            pool.mark_changed();
+            info!("Admin API removed upstream {} from pool {}", upstream.label(), pool.name);
+            json_response(StatusCode::OK, upstream_json(&upstream))
+        }
+        None => error(StatusCode::NOT_FOUND, &format!("unknown upstream '{}'", id)),
+    }
+}
+
+#[derive(Deserialize)]
+#[serde(deny_unknown_fields)]
+struct PurgeRequest {
+    url: Option<String>,
+    prefix: Option<String>,
+    surrogate_key: Option<String>,
+    #[serde(default)]
+    all: bool,
+}
+
+fn purge_cache(state: &ProxyState, body: &[u8]) -> Response<Body> {
+    let request: PurgeRequest = match serde_json::from_slice(body) {
+        Ok(request) => request,
+        Err(e) => return error(StatusCode::BAD_REQUEST, &format!("invalid request body: {}", e)),
+    };
+    let cache = match &state.cache {
+        Some(cache) => cache,
+        None => return error(StatusCode::CONFLICT, "the cache is disabled"),
+    };
+    let purge = match (request.url, request.prefix, request.surrogate_key, request.all) {
+        (Some(url), None, None, false) => cache::url_key(&url).map(Purge::Key),
+        (None, Some(prefix), None, false) => cache::url_key(&prefix).map(Purge::Prefix),
+        (None, None, Some(key), false) => Ok(Purge::SurrogateKey(key)),
+        (None, None, None, true) => Ok(Purge::All),
+        _ => Err("give exactly one of url, prefix, surrogate_key and all".to_string()),
+    };
+    let purge = match purge {
+        Ok(purge) => purge,
+        Err(e) => return error(StatusCode::BAD_REQUEST, &e),
+    };
+
+    let purged = cache.purge(&purge);
+    info!("Admin API purged {} cached response(s)", purged);
+    json_response(StatusCode::OK, json!({ "purged": purged }))
+}
+
+#[derive(Deserialize)]
+#[serde(deny_unknown_fields)]
+struct LogLevel {
+    level: String,
+}
+
+fn set_log_level(body: &[u8]) -> Response<Body> {
+    let update: LogLevel = match serde_json::from_slice(body) {
+        Ok(update) => update,
+        Err(e) => return error(StatusCode::BAD_REQUEST, &format!("invalid request body: {}", e)),
+    };
+    match logging::set_level(&update.level) {
+        Ok(true) => {
+            info!("Admin API set the log level to {}", update.level);
+            json_response(StatusCode::OK, json!({ "level": update.level }))
+        }
+        Ok(false) => error(StatusCode::CONFLICT, "logging is set up by the application embedding Riffy"),
+        Err(e) => error(StatusCode::BAD_REQUEST, &e),
+    }
+}
+
+#[derive(Deserialize)]
+#[serde(deny_unknown_fields)]
+struct CanaryUpdate {
+    percent: f64,
+}
+
+fn update_canary(state: &ProxyState, index: usize, route: &Route, body: &[u8]) -> Response<Body> {
+    let update: CanaryUpdate = match serde_json::from_slice(body) {
+        Ok(update) => update,
+        Err(e) => return error(StatusCode::BAD_REQUEST, &format!("invalid request body: {}", e)),
+    };
+    let canary = match &route.canary {
+        Some(canary) => canary,
+        None => return error(StatusCode::CONFLICT, "route has no canary pool"),
+    };
+    if !(0.0..=100.0).contains(&update.percent) {
+        return error(StatusCode::BAD_REQUEST, "percent must be between 0 and 100");
+    }
+
+    canary.set_percent(update.percent);
+    info!("Admin API sent {}% of route {} to pool {}", canary.percent(), route_label(index, route), state.pools[canary.pool].name);
+    json_response(StatusCode::OK, route_json(state, index, route))
+}
+
+#[derive(Deserialize)]
+#[serde(deny_unknown_fields)]
+struct ActivePool {
+    pool: String,
+}
+
+fn set_active_pool(state: &ProxyState, index: usize, route: &Route, body: &[u8]) -> Response<Body> {
+    let update: ActivePool = match serde_json::from_slice(body) {
+        Ok(update) => update,
+        Err(e) => return error(StatusCode::BAD_REQUEST, &format!("invalid request body: {}", e)),
+    };
+    let pair = match &route.blue_green {
+        Some(pair) => pair,
+        None => return error(StatusCode::CONFLICT, "route has no blue-green pools"),
+    };
+    let activated = state.pools.iter().position(|pool| pool.name == update.pool).is_some_and(|pool| pair.activate(pool));
+    if !activated {
+        return error(StatusCode::BAD_REQUEST, &format!("pool '{}' is not one of the route's blue-green pools", update.pool));
+    }
+
+    info!("Admin API switched route {} to pool {}", route_label(index, route), update.pool);
+    json_response(StatusCode::OK, route_json(state, index, route))
+}
+
+#[derive(Deserialize)]
+#[serde(deny_unknown_fields)]
+struct MaintenanceUpdate {
+    enabled: bool,
+}
+
+fn set_maintenance(state: &ProxyState, index: usize, route: &Route, body: &[u8]) -> Response<Body> {
+    let update: MaintenanceUpdate = match serde_json::from_slice(body) {
+        Ok(update) => update,
+        Err(e) => return error(StatusCode::BAD_REQUEST, &format!("invalid request body: {}", e)),
+    };
+
+    route.maintenance.set_active(update.enabled);
+    if update.enabled {
+        info!("Admin API put route {} into maintenance", route_label(index, route));
+    } else {
+        info!("Admin API took route {} out of maintenance", route_label(index, route));
+    }
+    json_response(StatusCode::OK, route_json(state, index, route))
+}
+
+fn route_label(index: usize, route: &Route) -> String {
+    route.name.clone().unwrap_or_else(|| index.to_string())
+}
+
+fn route_json(state: &ProxyState, index: usize, route: &Route) -> Value {
+    let name = |pool: usize| state.pools[pool].name.as_str();
+    let blue_green = route.blue_green.as_ref().map(|pair| json!({ "active": name(pair.active()), "standby": name(pair.standby()) }));
+    let canary = route.canary.as_ref().map(|canary| json!({ "pool": name(canary.pool), "percent": canary.percent() }));
+    let mirror = route.mirror.as_ref().map(|mirror| json!({ "pool": name(mirror.pool), "percent": mirror.percent() }));
+    json!({
+        "index": index,
+        "name": route.name,
+        "hosts": route.hosts,
+        "path_prefix": route.path_prefix,
+        "listeners": route.listeners,
+        "pool": name(route.primary_pool()),
+        "blue_green": blue_green,
+        "canary": canary,
+        "mirror": mirror,
+        "maintenance": route.maintenance.active(),
+    })
+}
+
+fn list_pools(pools: &[Pool]) -> Value {
+    let pools: Vec<Value> = pools
+        .iter()
+        .map(|pool| {
+            let upstreams: Vec<Value> = pool.balancer.upstreams().iter().map(|u| upstream_json(u)).collect();
+            let stats = pool.connection_stats();
+            json!({
+                "name": pool.name,
+                "connections": { "opened": stats.opened(), "open": stats.open() },
+                "upstreams": upstreams,
+            })
+        })
+        .collect();
+    json!({ "pools": pools })
+}
+
+fn upstream_json(upstream: &Upstream) -> Value {
+    json!({
+        "id": upstream.id,
+        "url": upstream.url,
+        "address": upstream.address.map(|address| address.to_string()),
+        "weight": upstream.weight(),
+        "backup": upstream.backup,
+        "healthy": upstream.is_healthy(),
+        "ejected": upstream.is_ejected(),
+        "circuit_open": upstream.is_circuit_open(),
+        "draining": upstream.is_draining(),
+        "available": upstream.is_available(),
+        "active_requests": upstream.active_connections(),
+        "latency_ms": upstream.latency().map(|l| l.as_secs_f64() * 1000.0),
+    })
+}
+
+/// Checks the `Authorization: Bearer` header, comparing in constant time.
+fn authorized(req: &Request<Body>, token: &str) -> bool {
+    let given = match req.headers().get(AUTHORIZATION).and_then(|v| v.to_str().ok()).and_then(|v| v.strip_prefix("Bearer ")) {
+        Some(given) => given.trim().as_bytes(),
+        None => return false,
+    };
+    let expected = token.as_bytes();
+    given.len() == expected.len() && given.iter().zip(expected).fold(0, |acc, (a, b)| acc | (a ^ b)) == 0
+}
+
+fn json_response(status: StatusCode, body: Value) -> Response<Body> {
+    Response::builder()
+        .status(status)
+        .header(CONTENT_TYPE, "application/json")
+        .body(Body::from(body.to_string()))
+        .unwrap()
+}
+
+fn error(status: StatusCode, message: &str) -> Response<Body> {
+    json_response(status, json!({ "error": message }))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::config::Config;
+    use crate::proxy::ProxyBuilder;
+    use std::time::Duration;
+
+    fn runtime() -> Arc<Runtime> {
+        let config: Config = toml::from_str(
+            r#"
+            [upstreams]
+            servers = ["http://127.0.0.1:9"]
+
+            [admin]
+            token = "secret"
+            "#,
+        )
+        .unwrap();
+        Arc::clone(ProxyBuilder::new(config).build().unwrap().runtime())
+    }
+
+    fn request(method: Method, path: &str, token: Option<&str>, body: Body) -> Request<Body> {
+        let mut req = Request::builder().method(method).uri(path);
+        if let Some(token) = token {
+            req = req.header(AUTHORIZATION, token);
+        }
+        req.body(body).unwrap()
+    }
+
+    async fn json(res: Response<Body>) -> Value {
+        serde_json::from_slice(&hyper::body::to_bytes(res.into_body()).await.unwrap()).unwrap()
+    }
+
+    #[tokio::test]
+    async fn refuses_requests_without_the_token_before_reading_the_body() {
+        let runtime = runtime();
+        for token in [None, Some("Bearer wrong"), Some("Bearer secre"), Some("secret"), Some("Basic c2VjcmV0")] {
+            // A body that never ends: reading it would hang the request
+            let (_sender, body) = Body::channel();
+            let req = request(Method::POST, "/pools/default/upstreams", token, body);
+            let res = tokio::time::timeout(Duration::from_secs(5), handle_admin(req, &runtime)).await.expect("answered without the body");
+            assert_eq!(res.status(), StatusCode::UNAUTHORIZED, "{:?}", token);
+            assert_eq!(res.headers()[WWW_AUTHENTICATE], "Bearer");
+        }
+        let res = handle_admin(request(Method::GET, "/upstreams", Some("Bearer secret"), Body::empty()), &runtime).await;
+        assert_eq!(res.status(), StatusCode::OK);
+        assert_eq!(json(res).await["pools"][0]["upstreams"][0]["url"], "http://127.0.0.1:9");
+    }
+
+    #[tokio::test]
+    async fn rejects_weights_out_of_range() {
+        let runtime = runtime();
+        let id = runtime.state().pools[0].balancer.upstreams()[0].id.clone();
+        let too_heavy = format!(r#"{{"weight": {}}}"#, MAX_WEIGHT + 1);
+        let requests = [
+            (Method::PATCH, format!("/pools/default/upstreams/{}", id), too_heavy),
+            (Method::PATCH, format!("/pools/default/upstreams/{}", id), r#"{"weight": 0}"#.to_string()),
+            (Method::POST, "/pools/default/upstreams".to_string(), format!(r#"{{"url": "http://127.0.0.1:10", "weight": {}}}"#, MAX_WEIGHT + 1)),
+        ];
+        for (method, path, body) in requests {
+            let res = handle_admin(request(method, &path, Some("Bearer secret"), Body::from(body.clone())), &runtime).await;
+            assert_eq!(res.status(), StatusCode::BAD_REQUEST, "{}", body);
+        }
+        let pool = &runtime.state().pools[0];
+        assert_eq!(pool.balancer.upstreams().len(), 1);
+        assert_eq!(pool.balancer.upstreams()[0].weight(), 1);
+        // Nothing changed, so a reload has nothing to drop
+        assert!(runtime.state().pools_changed_at_runtime().is_empty());
+
+        let res = handle_admin(request(Method::PATCH, &format!("/pools/default/upstreams/{}", id), Some("Bearer secret"), Body::from(format!(r#"{{"weight": {}}}"#, MAX_WEIGHT))), &runtime).await;
+        assert_eq!(res.status(), StatusCode::OK);
+        assert_eq!(json(res).await["weight"], MAX_WEIGHT);
+    }
+
+    #[tokio::test]
+    async fn edits_are_reported_as_dropped_on_reload() {
+        let runtime = runtime();
+        let state = runtime.state();
+        let id = state.pools[0].balancer.upstreams()[0].id.clone();
+        let res = handle_admin(request(Method::PATCH, &format!("/pools/default/upstreams/{}", id), Some("Bearer secret"), Body::from(r#"{"draining": true}"#)), &runtime).await;
+        assert_eq!(res.status(), StatusCode::OK);
+        assert_eq!(state.pools_changed_at_runtime(), ["default"]);
+
+        let res = handle_admin(request(Method::DELETE, &format!("/pools/default/upstreams/{}", id), Some("Bearer secret"), Body::empty()), &runtime).await;
+        assert_eq!(res.status(), StatusCode::OK);
+        let res = handle_admin(request(Method::DELETE, &format!("/pools/default/upstreams/{}", id), Some("Bearer secret"), Body::empty()), &runtime).await;
+        assert_eq!(res.status(), StatusCode::NOT_FOUND);
+        let res = handle_admin(request(Method::GET, "/pools/other/upstreams", Some("Bearer secret"), Body::empty()), &runtime).await;
+        assert_eq!(res.status(), StatusCode::NOT_FOUND);
+    }
+}
@@ -0,0 +1,976 @@
+use rand::Rng;
+use std::borrow::Cow;
+use std::cmp::Ordering as CmpOrdering;
+use std::fmt;
+use std::net::IpAddr;
+use std::str::FromStr;
+use std::sync::atomic::{AtomicBool, AtomicU32, AtomicUsize, Ordering};
+use std::sync::{Arc, Mutex, RwLock};
+use std::time::{Duration, Instant};
+use tokio::sync::Notify;
+use tracing::warn;
+
+use crate::circuit::{CircuitBreaker, CircuitBreakerConfig, Trial};
+
+/// Strategy used to pick an upstream server for each request.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Strategy {
+    RoundRobin,
+    LeastConnections,
+    /// Consistent hashing of the client IP, so clients keep hitting the same upstream
+    IpHash,
+    /// Lowest peak-EWMA response time, weighted by in-flight requests
+    LeastLatency,
+    /// Power of two choices: the less loaded of two random upstreams
+    P2c,
+}
+
+impl FromStr for Strategy {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s.trim().to_ascii_lowercase().as_str() {
+            "round_robin" | "round-robin" => Ok(Strategy::RoundRobin),
+            "least_connections" | "least-connections" | "least_conn" => Ok(Strategy::LeastConnections),
+            "ip_hash" | "ip-hash" => Ok(Strategy::IpHash),
+            "least_latency" | "least-latency" | "ewma" | "peak_ewma" => Ok(Strategy::LeastLatency),
+            "p2c" | "power_of_two" | "power-of-two" => Ok(Strategy::P2c),
+            other => Err(format!("unknown load balancing strategy: {}", other)),
+        }
+    }
+}
+
+/// Passive health checking settings, modelled on nginx `max_fails` / `fail_timeout`.
+#[derive(Debug, Clone, Copy)]
+pub struct PassiveHealthConfig {
+    /// Failures within `fail_timeout` that eject an upstream; 0 disables ejection
+    pub max_fails: u32,
+    /// Window for counting failures and how long an ejected upstream stays out
+    pub fail_timeout: Duration,
+}
+
+impl Default for PassiveHealthConfig {
+    fn default() -> Self {
+        PassiveHealthConfig { max_fails: 0, fail_timeout: Duration::from_secs(10) }
+    }
+}
+
+/// Time constant of the response time moving average: a sample's influence
+/// falls to about a third after this long.
+const LATENCY_DECAY: Duration = Duration::from_secs(10);
+/// Response time assumed for an upstream before its first response, so its
+/// in-flight requests still count against it.
+const DEFAULT_LATENCY: Duration = Duration::from_millis(100);
+/// Least response time recorded for a failed or timed-out attempt.
+const FAILURE_LATENCY: Duration = Duration::from_secs(1);
+
+/// Peak-EWMA response time: slower samples are taken immediately, faster ones
+/// are blended in with a weight that grows with the time since the last sample.
+#[derive(Debug)]
+struct Latency {
+    /// Seconds; `None` until the first response
+    ewma: Option<f64>,
+    updated: Instant,
+}
+
+impl Latency {
+    fn observe(&mut self, rtt: Duration) {
+        let now = Instant::now();
+        let rtt = rtt.as_secs_f64();
+        self.ewma = Some(match self.ewma {
+            Some(ewma) if rtt < ewma => {
+                let decay = (-now.duration_since(self.updated).as_secs_f64() / LATENCY_DECAY.as_secs_f64()).exp();
+                ewma * decay + rtt * (1.0 - decay)
+            }
+            _ => rtt,
+        });
+        self.updated = now;
+    }
+}
+
+#[derive(Debug)]
+struct PassiveState {
+    failures: u32,
+    window_start: Instant,
+    ejected_until: Option<Instant>,
+}
+
+/// Checks an upstream URL and brings it into the form requests are built
+/// from: a lower-case scheme and host, and no trailing slash, so that
+/// `HTTP://Backend:8080/` becomes `http://backend:8080`. Socket paths of
+/// `unix:` upstreams must be absolute.
+pub fn normalize_url(url: &str) -> Result<String, String> {
+    let url = url.trim();
+    if let Some(path) = url.strip_prefix("unix:") {
+        if !path.starts_with('/') {
+            return Err(format!("unix upstreams need an absolute socket path, e.g. unix:/run/app.sock: {}", url));
+        }
+        return Ok(url.to_string());
+    }
+    if url.is_empty() {
+        return Err("empty upstream URL".to_string());
+    }
+    let uri: hyper::Uri = url.parse().map_err(|e| format!("invalid upstream URL {}: {}", url, e))?;
+    let (scheme, authority) = match (uri.scheme_str(), uri.authority()) {
+        (Some(scheme), Some(authority)) => (scheme.to_ascii_lowercase(), authority),
+        _ => return Err(format!("upstream URL {} needs a scheme and a host, e.g. http://backend:8080", url)),
+    };
+    if !matches!(scheme.as_str(), "http" | "https" | "h2c" | "tcp") {
+        return Err(format!("upstream URL {} has the unsupported scheme {}; use http, https, h2c, tcp or unix", url, scheme));
+    }
+    if authority.as_str().contains('@') {
+        return Err(format!("upstream URL {} must not contain credentials", url));
+    }
+    // The last colon of an IPv6 address in brackets is not a port's
+    let port = authority.as_str().rsplit_once(':').map(|(_, port)| port).filter(|port| !port.ends_with(']'));
+    if port.is_some_and(|port| port.parse::<u16>().is_err()) {
+        return Err(format!("upstream URL {} has an invalid port", url));
+    }
+    if uri.query().is_some() {
+        return Err(format!("upstream URL {} must not have a query", url));
+    }
+    // A path is kept as a prefix of every request's
+    let path = uri.path().trim_end_matches('/');
+    Ok(format!("{}://{}{}", scheme, authority.as_str().to_ascii_lowercase(), path))
+}
+
+/// A single upstream server, its weight, health and in-flight request count.
+#[derive(Debug)]
+pub struct Upstream {
+    pub url: String,
+    /// Opaque identifier derived from the URL, used in sticky session cookies
+    pub id: String,
+    /// Only receives traffic while no primary upstream is available
+    pub backup: bool,
+    /// The address connections go to, for one of the records a hostname resolved to
+    pub address: Option<IpAddr>,
+    weight: AtomicU32,
+    active: AtomicUsize,
+    healthy: AtomicBool,
+    draining: AtomicBool,
+    passive: Mutex<PassiveState>,
+    breaker: CircuitBreaker,
+    latency: Mutex<Latency>,
+}
+
+impl Upstream {
+    pub fn new(url: String, weight: u32) -> Self {
+        Upstream {
+            id: url_id(&url),
+            url,
+            backup: false,
+            address: None,
+            weight: AtomicU32::new(weight),
+            active: AtomicUsize::new(0),
+            healthy: AtomicBool::new(true),
+            draining: AtomicBool::new(false),
+            passive: Mutex::new(PassiveState { failures: 0, window_start: Instant::now(), ejected_until: None }),
+            breaker: CircuitBreaker::default(),
+            latency: Mutex::new(Latency { ewma: None, updated: Instant::now() }),
+        }
+    }
+
+    /// Marks the upstream as a backup, or as a primary.
+    pub fn with_backup(mut self, backup: bool) -> Self {
+        self.backup = backup;
+        self
+    }
+
+    /// Pins the upstream to one address its hostname resolved to.
+    pub fn with_address(mut self, address: IpAddr) -> Self {
+        self.id = url_id(&format!("{}#{}", self.url, address));
+        self.address = Some(address);
+        self
+    }
+
+    /// A new upstream with the same URL, weight, backup flag and address,
+    /// without any of this one's state.
+    pub fn duplicate(&self) -> Upstream {
+        let upstream = Upstream::new(self.url.clone(), self.weight()).with_backup(self.backup);
+        match self.address {
+            Some(address) => upstream.with_address(address),
+            None => upstream,
+        }
+    }
+
+    /// The URL, followed by the pinned address if there is one.
+    pub fn label(&self) -> String {
+        match self.address {
+            Some(address) => format!("{} ({})", self.url, address),
+            None => self.url.clone(),
+        }
+    }
+
+    /// Whether the upstream is given as `h2c://`, plain HTTP spoken with
+    /// HTTP/2 prior knowledge.
+    pub fn is_h2c(&self) -> bool {
+        self.url.starts_with("h2c://")
+    }
+
+    /// The socket path of an upstream given as `unix:/path/to.sock`.
+    pub fn unix_socket(&self) -> Option<&str> {
+        self.url.strip_prefix("unix:")
+    }
+
+    /// The base URL of requests to the upstream, with `h2c://` as the `http://`
+    /// it stands for. Requests to a Unix socket name `localhost`.
+    pub fn request_url(&self) -> Cow<'_, str> {
+        if self.unix_socket().is_some() {
+            return Cow::Borrowed("http://localhost");
+        }
+        match self.url.strip_prefix("h2c://") {
+            Some(rest) => Cow::Owned(format!("http://{}", rest)),
+            None => Cow::Borrowed(&self.url),
+        }
+    }
+
+    /// Parses an upstream spec such as `http://a:8080` or `http://a:8080;weight=5`.
+    pub fn parse(spec: &str) -> Result<Self, String> {
+        let mut parts = spec.split(';');
+        let url = normalize_url(parts.next().unwrap_or(""))?;
+        let mut weight = 1;
+        let mut backup = false;
+
+        for param in parts {
+            if param.trim() == "backup" {
+                backup = true;
+                continue;
+            }
+            let (key, value) = match param.split_once('=') {
+                Some((key, value)) => (key.trim(), value.trim()),
+                None => return Err(format!("invalid upstream parameter '{}' in '{}'", param, spec)),
+            };
+            match key {
+                "weight" => {
+                    weight = value.parse().map_err(|_| format!("invalid weight '{}' in '{}'", value, spec))?;
+                    if weight == 0 || weight > MAX_WEIGHT {
+                        return Err(format!("weight must be between 1 and {} in '{}'", MAX_WEIGHT, spec));
+                    }
+                }
+                other => return Err(format!("unknown upstream parameter '{}' in '{}'", other, spec)),
+            }
+        }
+
+        Ok(Upstream::new(url, weight).with_backup(backup))
+    }
+
+    pub fn weight(&self) -> u32 {
+        self.weight.load(Ordering::SeqCst)
+    }
+
+    /// Number of requests currently being proxied to this upstream.
+    pub fn active_connections(&self) -> usize {
+        self.active.load(Ordering::SeqCst)
+    }
+
+    /// Moving average of the time until response headers, once measured.
+    pub fn latency(&self) -> Option<Duration> {
+        self.latency.lock().unwrap().ewma.map(Duration::from_secs_f64)
+    }
+
+    /// Expected cost of sending one more request: the latency average scaled
+    /// by the in-flight requests and divided by the weight. Unmeasured
+    /// upstreams are taken to answer in `DEFAULT_LATENCY`.
+    fn latency_score(&self) -> f64 {
+        let ewma = self.latency.lock().unwrap().ewma.unwrap_or(DEFAULT_LATENCY.as_secs_f64());
+        ewma * (self.active_connections() + 1) as f64 / self.weight() as f64
+    }
+
+    /// Whether the active health checker considers this upstream healthy.
+    pub fn is_healthy(&self) -> bool {
+        self.healthy.load(Ordering::SeqCst)
+    }
+
+    pub fn set_healthy(&self, healthy: bool) {
+        self.healthy.store(healthy, Ordering::SeqCst);
+    }
+
+    /// Whether this upstream has been ejected after too many request failures.
+    pub fn is_ejected(&self) -> bool {
+        let state = self.passive.lock().unwrap();
+        matches!(state.ejected_until, Some(until) if Instant::now() < until)
+    }
+
+    /// Whether this upstream's circuit breaker is open.
+    pub fn is_circuit_open(&self) -> bool {
+        self.breaker.is_open()
+    }
+
+    /// Whether this upstream is being drained: in-flight requests finish, but
+    /// no new ones are sent to it.
+    pub fn is_draining(&self) -> bool {
+        self.draining.load(Ordering::SeqCst)
+    }
+
+    pub fn set_draining(&self, draining: bool) {
+        self.draining.store(draining, Ordering::SeqCst);
+    }
+
+    /// Whether this upstream may currently receive traffic.
+    pub fn is_available(&self) -> bool {
+        self.is_healthy() && !self.is_draining() && !self.is_ejected() && self.breaker.allows_request()
+    }
+
+    fn record_failure(&self, config: &PassiveHealthConfig) {
+        if config.max_fails == 0 {
+            return;
+        }
+
+        let now = Instant::now();
+        let mut state = self.passive.lock().unwrap();
+        if now.duration_since(state.window_start) > config.fail_timeout {
+            state.failures = 0;
+            state.window_start = now;
+        }
+
+        state.failures += 1;
+        if state.failures >= config.max_fails {
+            warn!("Upstream {} failed {} times, ejecting for {:?}", self.label(), state.failures, config.fail_timeout);
+            state.ejected_until = Some(now + config.fail_timeout);
+            state.failures = 0;
+            state.window_start = now;
+        }
+    }
+
+    fn record_success(&self) {
+        let mut state = self.passive.lock().unwrap();
+        state.failures = 0;
+    }
+}
+
+/// Hashes a URL so cookies don't expose backend addresses.
+fn url_id(url: &str) -> String {
+    format!("{:016x}", hash(url.as_bytes()))
+}
+
+/// 64-bit FNV-1a followed by a SplitMix64 finalizer for better bit spread.
+/// Stable across runs, so every instance maps a client the same way.
+fn hash(bytes: &[u8]) -> u64 {
+    let mut h = bytes.iter().fold(0xcbf2_9ce4_8422_2325u64, |h, byte| (h ^ *byte as u64).wrapping_mul(0x0100_0000_01b3));
+    h = (h ^ (h >> 30)).wrapping_mul(0xbf58_476d_1ce4_e5b9);
+    h = (h ^ (h >> 27)).wrapping_mul(0x94d0_49bb_1331_11eb);
+    h ^ (h >> 31)
+}
+
+/// Compares in-flight requests per unit of weight, without dividing.
+fn compare_load(a: &Upstream, b: &Upstream) -> CmpOrdering {
+    let a_load = a.active_connections() as u64 * b.weight() as u64;
+    let b_load = b.active_connections() as u64 * a.weight() as u64;
+    a_load.cmp(&b_load)
+}
+
+/// Samples two distinct eligible upstreams at random and returns the less
+/// loaded one.
+fn two_choices<'a>(members: &'a Members, allowed: &dyn Fn(&Upstream) -> bool) -> Option<&'a Arc<Upstream>> {
+    let candidates: Vec<&Arc<Upstream>> = members.upstreams.iter().filter(|u| u.is_available() && allowed(u)).collect();
+    let mut rng = rand::thread_rng();
+    match candidates.len() {
+        0 => None,
+        1 => Some(candidates[0]),
+        len => {
+            let first = rng.gen_range(0..len);
+            // Draw from the remaining len - 1 slots so the two never coincide
+            let second = (first + rng.gen_range(1..len)) % len;
+            let (a, b) = (candidates[first], candidates[second]);
+            Some(if compare_load(b, a) == CmpOrdering::Less { b } else { a })
+        }
+    }
+}
+
+/// Points each upstream gets on the hash ring per unit of weight.
+const RING_POINTS_PER_WEIGHT: u32 = 100;
+/// Most points an upstream gets on the hash ring; weights too large for it
+/// are scaled down, keeping their ratios.
+const MAX_RING_POINTS: u32 = 1000;
+/// Highest weight an upstream can have.
+pub const MAX_WEIGHT: u32 = 10_000;
+
+/// Keeps an upstream's in-flight count raised until dropped.
+pub struct ConnectionGuard {
+    upstream: Arc<Upstream>,
+    /// Wakes a queued request when this one finishes
+    released: Option<Arc<Notify>>,
+    /// The circuit breaker trial this request takes up, if any
+    trial: Option<Trial>,
+}
+
+impl ConnectionGuard {
+    fn new(upstream: Arc<Upstream>) -> Self {
+        upstream.active.fetch_add(1, Ordering::SeqCst);
+        ConnectionGuard { upstream, released: None, trial: None }
+    }
+
+    /// Counts a request in flight unless the upstream already has `max`.
+    fn within(upstream: Arc<Upstream>, max: usize, released: &Arc<Notify>) -> Option<Self> {
+        upstream.active.fetch_update(Ordering::SeqCst, Ordering::SeqCst, |active| (active < max).then(|| active + 1)).ok()?;
+        Some(ConnectionGuard { upstream, released: Some(Arc::clone(released)), trial: None })
+    }
+
+    pub fn upstream(&self) -> &Arc<Upstream> {
+        &self.upstream
+    }
+}
+
+impl Drop for ConnectionGuard {
+    fn drop(&mut self) {
+        self.upstream.active.fetch_sub(1, Ordering::SeqCst);
+        // A trial whose result was recorded has already moved the circuit on
+        if let Some(trial) = self.trial {
+            self.upstream.breaker.abandon(trial);
+        }
+        if let Some(released) = &self.released {
+            released.notify_one();
+        }
+    }
+}
+
+/// A cap on the requests in flight to each upstream of a pool, with a
+/// bounded queue for requests that arrive while every upstream is at it.
+#[derive(Debug, Clone, Copy)]
+pub struct ConcurrencyLimit {
+    pub max_requests: usize,
+    pub queue_size: usize,
+    pub queue_timeout: Duration,
+}
+
+/// Why no upstream could be acquired for a request.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum AcquireError {
+    /// No upstream is in rotation
+    Unavailable,
+    /// Every upstream is at its limit and the queue is full
+    QueueFull,
+    /// Every upstream stayed at its limit for the whole queue timeout
+    QueueTimeout,
+}
+
+impl fmt::Display for AcquireError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        f.write_str(match self {
+            AcquireError::Unavailable => "no healthy upstream servers available",
+            AcquireError::QueueFull => "upstream queue is full",
+            AcquireError::QueueTimeout => "timed out waiting in the upstream queue",
+        })
+    }
+}
+
+impl std::error::Error for AcquireError {}
+
+/// A place in a pool's queue, given up when dropped.
+struct QueueSlot<'a>(&'a AtomicUsize);
+
+impl<'a> QueueSlot<'a> {
+    fn take(queued: &'a AtomicUsize, size: usize) -> Option<Self> {
+        queued.fetch_update(Ordering::SeqCst, Ordering::SeqCst, |n| (n < size).then(|| n + 1)).ok()?;
+        Some(QueueSlot(queued))
+    }
+}
+
+impl Drop for QueueSlot<'_> {
+    fn drop(&mut self) {
+        self.0.fetch_sub(1, Ordering::SeqCst);
+    }
+}
+
+/// The upstream set and its per-strategy state, rebuilt whenever upstreams
+/// are added, removed or reweighted at runtime.
+struct Members {
+    upstreams: Vec<Arc<Upstream>>,
+    // Current weights for smooth weighted round-robin, one per upstream
+    current_weights: Mutex<Vec<i64>>,
+    // Consistent hash ring of (point, upstream index), sorted by point
+    ring: Vec<(u64, usize)>,
+}
+
+impl Members {
+    fn new(upstreams: Vec<Arc<Upstream>>, strategy: Strategy) -> Self {
+        let current_weights = Mutex::new(vec![0; upstreams.len()]);
+        let ring = if strategy == Strategy::IpHash { build_ring(&upstreams) } else { Vec::new() };
+        Members { upstreams, current_weights, ring }
+    }
+}
+
+/// Picks upstream servers according to the configured strategy.
+pub struct Balancer {
+    members: RwLock<Members>,
+    strategy: Strategy,
+    counter: AtomicUsize,
+    passive: PassiveHealthConfig,
+    breaker: Option<CircuitBreakerConfig>,
+    limit: Option<ConcurrencyLimit>,
+    /// Requests waiting for an upstream below its limit
+    queued: AtomicUsize,
+    released: Arc<Notify>,
+}
+
+impl Balancer {
+    pub fn new(upstreams: Vec<Upstream>, strategy: Strategy, passive: PassiveHealthConfig, breaker: Option<CircuitBreakerConfig>) -> Self {
+        let members = Members::new(upstreams.into_iter().map(Arc::new).collect(), strategy);
+        Balancer {
+            members: RwLock::new(members),
+            strategy,
+            counter: AtomicUsize::new(0),
+            passive,
+            breaker,
+            limit: None,
+            queued: AtomicUsize::new(0),
+            released: Arc::new(Notify::new()),
+        }
+    }
+
+    /// Caps the requests in flight to each upstream.
+    pub fn with_limit(mut self, limit: Option<ConcurrencyLimit>) -> Self {
+        self.limit = limit;
+        self
+    }
+
+    /// Requests currently waiting in the queue.
+    pub fn queued(&self) -> usize {
+        self.queued.load(Ordering::SeqCst)
+    }
+
+    /// Records the outcome of a proxied request for passive health checking
+    /// and the circuit breaker.
+    pub fn record_result(&self, upstream: &Upstream, success: bool) {
+        if success {
+            upstream.record_success();
+        } else {
+            upstream.record_failure(&self.passive);
+        }
+        if let Some(config) = &self.breaker {
+            upstream.breaker.record(success, config, &upstream.label());
+        }
+    }
+
+    /// Records the time an upstream took to send response headers.
+    pub fn record_latency(&self, upstream: &Upstream, rtt: Duration) {
+        upstream.latency.lock().unwrap().observe(rtt);
+    }
+
+    /// Records an attempt that failed or timed out after `elapsed`, as a
+    /// response time of at least `FAILURE_LATENCY`, so an upstream that
+    /// never answers stops looking fast.
+    pub fn record_failed_latency(&self, upstream: &Upstream, elapsed: Duration) {
+        self.record_latency(upstream, elapsed.max(FAILURE_LATENCY));
+    }
+
+    /// All upstreams in this pool, including ones currently out of rotation.
+    pub fn upstreams(&self) -> Vec<Arc<Upstream>> {
+        self.members.read().unwrap().upstreams.clone()
+    }
+
+    /// Looks up an upstream by its id.
+    pub fn find(&self, id: &str) -> Option<Arc<Upstream>> {
+        self.members.read().unwrap().upstreams.iter().find(|u| u.id == id).cloned()
+    }
+
+    /// Adds an upstream to the pool at runtime.
+    pub fn add(&self, upstream: Upstream) -> Result<Arc<Upstream>, String> {
+        let mut members = self.members.write().unwrap();
+        if members.upstreams.iter().any(|u| u.id == upstream.id) {
+            return Err(format!("upstream {} already exists", upstream.label()));
+        }
+        let upstream = Arc::new(upstream);
+        let mut upstreams = members.upstreams.clone();
+        upstreams.push(Arc::clone(&upstream));
+        *members = Members::new(upstreams, self.strategy);
+        Ok(upstream)
+    }
+
+    /// Removes an upstream at runtime. Requests already in flight to it finish normally.
+    pub fn remove(&self, id: &str) -> Option<Arc<Upstream>> {
+        let mut members = self.members.write().unwrap();
+        let index = members.upstreams.iter().position(|u| u.id == id)?;
+        let mut upstreams = members.upstreams.clone();
+        let removed = upstreams.remove(index);
+        *members = Members::new(upstreams, self.strategy);
+        Some(removed)
+    }
+
+    /// Changes an upstream's weight at runtime.
+    pub fn set_weight(&self, id: &str, weight: u32) -> bool {
+        let mut members = self.members.write().unwrap();
+        match members.upstreams.iter().find(|u| u.id == id) {
+            Some(upstream) => {
+                upstream.weight.store(weight, Ordering::SeqCst);
+                *members = Members::new(members.upstreams.clone(), self.strategy);
+                true
+            }
+            None => false,
+        }
+    }
+
+    /// Selects an available upstream for a request from `client` and marks a
+    /// request as in flight on it. The upstream a sticky session is pinned to
+    /// is preferred; upstreams already tried for this request are avoided
+    /// unless no other upstream is available. Backup upstreams are only
+    /// chosen while no primary upstream is available.
+    /// An upstream at its concurrency limit is skipped like an unavailable one.
+    pub fn select(&self, client: IpAddr, sticky_id: Option<&str>, tried: &[Arc<Upstream>]) -> Option<ConnectionGuard> {
+        let is_tried = |u: &Upstream| tried.iter().any(|t| std::ptr::eq(t.as_ref(), u));
+        let members = self.members.read().unwrap();
+        let use_backups = !members.upstreams.iter().any(|u| !u.backup && u.is_available());
+        let in_tier = |u: &Upstream| u.backup == use_backups && self.has_capacity(u);
+
+        let pinned = sticky_id.and_then(|id| members.upstreams.iter().find(|u| u.id == id));
+        if let Some(upstream) = pinned {
+            if upstream.is_available() && in_tier(upstream) && !is_tried(upstream) {
+                if let Some(guard) = self.dispatch(upstream) {
+                    return Some(guard);
+                }
+            }
+        }
+
+        self.pick(&members, client, &|u| in_tier(u) && !is_tried(u)).or_else(|| self.pick(&members, client, &in_tier))
+    }
+
+    /// Like `select`, but while the upstreams in rotation are all at their
+    /// concurrency limit the request waits in the pool's queue for one of
+    /// them to finish a request.
+    pub async fn acquire(&self, client: IpAddr, sticky_id: Option<&str>, tried: &[Arc<Upstream>]) -> Result<ConnectionGuard, AcquireError> {
+        let limit = match self.limit {
+            Some(limit) => limit,
+            None => return self.select(client, sticky_id, tried).ok_or(AcquireError::Unavailable),
+        };
+        let deadline = tokio::time::Instant::now() + limit.queue_timeout;
+        let mut slot = None;
+        loop {
+            // Registered before selecting, so a request finishing in between is not missed
+            let released = self.released.notified();
+            if let Some(guard) = self.select(client, sticky_id, tried) {
+                return Ok(guard);
+            }
+            if !self.at_capacity() {
+                return Err(AcquireError::Unavailable);
+            }
+            if slot.is_none() {
+                slot = Some(QueueSlot::take(&self.queued, limit.queue_size).ok_or(AcquireError::QueueFull)?);
+            }
+            if tokio::time::timeout_at(deadline, released).await.is_err() {
+                return Err(AcquireError::QueueTimeout);
+            }
+        }
+    }
+
+    fn has_capacity(&self, upstream: &Upstream) -> bool {
+        self.limit.is_none_or(|limit| upstream.active_connections() < limit.max_requests)
+    }
+
+    /// Whether some upstream in rotation is only held back by its concurrency limit.
+    fn at_capacity(&self) -> bool {
+        self.limit.is_some() && self.members.read().unwrap().upstreams.iter().any(|u| u.is_available() && !self.has_capacity(u))
+    }
+
+    fn pick(&self, members: &Members, client: IpAddr, allowed: &dyn Fn(&Upstream) -> bool) -> Option<ConnectionGuard> {
+        // Start scanning at a rotating offset so ties are spread evenly
+        let rotated = || {
+            let offset = self.counter.fetch_add(1, Ordering::SeqCst);
+            let len = members.upstreams.len();
+            (0..len).map(move |i| &members.upstreams[(offset + i) % len]).filter(|u| u.is_available() && allowed(u))
+        };
+        let upstream = match self.strategy {
+            Strategy::RoundRobin => next_weighted(members, allowed)?,
+            Strategy::IpHash => ring_lookup(members, client, allowed)?,
+            Strategy::LeastConnections => rotated().min_by(|a, b| compare_load(a, b))?,
+            Strategy::LeastLatency => rotated().min_by(|a, b| a.latency_score().total_cmp(&b.latency_score()))?,
+            Strategy::P2c => two_choices(members, allowed)?,
+        };
+
+        self.dispatch(upstream)
+    }
+
+    /// Counts a request in flight, or returns `None` if another request took
+    /// the upstream's last free slot first.
+    fn dispatch(&self, upstream: &Arc<Upstream>) -> Option<ConnectionGuard> {
+        let mut guard = match &self.limit {
+            Some(limit) => ConnectionGuard::within(Arc::clone(upstream), limit.max_requests, &self.released)?,
+            None => ConnectionGuard::new(Arc::clone(upstream)),
+        };
+        if let Some(config) = &self.breaker {
+            guard.trial = upstream.breaker.on_dispatch(config);
+        }
+        Some(guard)
+    }
+}
+
+fn build_ring(upstreams: &[Arc<Upstream>]) -> Vec<(u64, usize)> {
+    let heaviest = upstreams.iter().map(|upstream| upstream.weight().min(MAX_WEIGHT) as u64).max().unwrap_or(1);
+    let per_weight = (RING_POINTS_PER_WEIGHT as u64).min(MAX_RING_POINTS as u64 / heaviest);
+    let points = |weight: u32| match per_weight {
+        0 => (weight.min(MAX_WEIGHT) as u64 * MAX_RING_POINTS as u64 / heaviest).max(1),
+        per_weight => weight.min(MAX_WEIGHT) as u64 * per_weight,
+    };
+    let mut ring: Vec<(u64, usize)> = upstreams
+        .iter()
+        .enumerate()
+        .flat_map(|(index, upstream)| (0..points(upstream.weight())).map(move |i| (hash(format!("{}#{}", upstream.label(), i).as_bytes()), index)))
+        .collect();
+    ring.sort_unstable();
+    ring
+}
+
+/// Smooth weighted round-robin (as in nginx): every pick raises each
+/// available upstream's current weight by its configured weight, chooses
+/// the highest and lowers the winner by the total, interleaving picks
+/// proportionally.
+fn next_weighted<'a>(members: &'a Members, allowed: &dyn Fn(&Upstream) -> bool) -> Option<&'a Arc<Upstream>> {
+    let mut current = members.current_weights.lock().unwrap();
+    let mut total = 0;
+    let mut best: Option<usize> = None;
+
+    for (i, upstream) in members.upstreams.iter().enumerate() {
+        if !upstream.is_available() || !allowed(upstream) {
+            continue;
+        }
+        current[i] += upstream.weight() as i64;
+        total += upstream.weight() as i64;
+        if best.is_none_or(|b| current[i] > current[b]) {
+            best = Some(i);
+        }
+    }
+
+    let best = best?;
+    current[best] -= total;
+    Some(&members.upstreams[best])
+}
+
+/// Walks the hash ring clockwise from the client's point to the first
+/// usable upstream, so an unavailable upstream only moves its own clients.
+fn ring_lookup<'a>(members: &'a Members, client: IpAddr, allowed: &dyn Fn(&Upstream) -> bool) -> Option<&'a Arc<Upstream>> {
+    let point = match client {
+        IpAddr::V4(ip) => hash(&ip.octets()),
+        IpAddr::V6(ip) => hash(&ip.octets()),
+    };
+    let start = members.ring.partition_point(|(p, _)| *p < point);
+    (0..members.ring.len())
+        .map(|i| &members.upstreams[members.ring[(start + i) % members.ring.len()].1])
+        .find(|u| u.is_available() && allowed(u))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn weights_are_bounded() {
+        assert_eq!(Upstream::parse("http://a:8080;weight=10000").unwrap().weight(), MAX_WEIGHT);
+        for spec in ["http://a:8080;weight=0", "http://a:8080;weight=10001", "http://a:8080;weight=4294967295", "http://a:8080;weight=-1"] {
+            assert!(Upstream::parse(spec).is_err(), "{}", spec);
+        }
+        // Even an upstream made with a larger weight gets no more ring points
+        let ring = build_ring(&[Arc::new(Upstream::new("http://a:8080".to_string(), u32::MAX))]);
+        assert_eq!(ring.len(), MAX_RING_POINTS as usize);
+    }
+
+    #[test]
+    fn ring_points_follow_weights_within_a_budget() {
+        let points = |weights: &[u32]| {
+            let upstreams: Vec<Arc<Upstream>> = weights.iter().enumerate().map(|(i, &weight)| Arc::new(Upstream::new(format!("http://u{}:8080", i), weight))).collect();
+            let ring = build_ring(&upstreams);
+            (0..weights.len()).map(|i| ring.iter().filter(|(_, index)| *index == i).count()).collect::<Vec<_>>()
+        };
+        assert_eq!(points(&[1, 2, 10]), [100, 200, 1000]);
+        // Heavier pools are scaled down, no upstream dropping below one point
+        assert_eq!(points(&[20, 5]), [1000, 250]);
+        assert_eq!(points(&[MAX_WEIGHT, MAX_WEIGHT / 2, 1]), [1000, 500, 1]);
+    }
+
+    const CLIENT: IpAddr = IpAddr::V4(std::net::Ipv4Addr::new(192, 0, 2, 1));
+    const EJECT_ON_FAILURE: PassiveHealthConfig = PassiveHealthConfig { max_fails: 1, fail_timeout: Duration::from_secs(60) };
+
+    fn balancer(specs: &[&str], strategy: Strategy) -> Balancer {
+        Balancer::new(specs.iter().map(|spec| Upstream::parse(spec).unwrap()).collect(), strategy, EJECT_ON_FAILURE, None)
+    }
+
+    /// The host of the upstream the next request goes to.
+    fn pick(balancer: &Balancer, client: IpAddr) -> String {
+        let guard = balancer.select(client, None, &[]).expect("an upstream is available");
+        guard.upstream().url.trim_start_matches("http://").to_string()
+    }
+
+    fn picks(balancer: &Balancer, n: usize) -> Vec<String> {
+        (0..n).map(|_| pick(balancer, CLIENT)).collect()
+    }
+
+    #[test]
+    fn least_connections_weighs_requests_in_flight() {
+        let balancer = balancer(&["http://a;weight=2", "http://b"], Strategy::LeastConnections);
+        let [a, b] = [0, 1].map(|i| Arc::clone(&balancer.upstreams()[i]));
+        let mut held = vec![ConnectionGuard::new(Arc::clone(&a))];
+        // Half of a's weight in use against an idle b
+        assert_eq!(pick(&balancer, CLIENT), "b");
+        held.push(ConnectionGuard::new(Arc::clone(&b)));
+        assert_eq!(pick(&balancer, CLIENT), "a");
+        held.push(ConnectionGuard::new(Arc::clone(&b)));
+        held.push(ConnectionGuard::new(Arc::clone(&a)));
+        assert_eq!(picks(&balancer, 3), ["a", "a", "a"]);
+        // Picks only count while their requests are in flight
+        assert_eq!((a.active_connections(), b.active_connections()), (2, 2));
+        drop(held);
+        assert_eq!((a.active_connections(), b.active_connections()), (0, 0));
+    }
+
+    #[test]
+    fn round_robin_interleaves_by_weight() {
+        let balancer = balancer(&["http://a;weight=5", "http://b", "http://c"], Strategy::RoundRobin);
+        assert_eq!(picks(&balancer, 14), ["a", "a", "b", "a", "c", "a", "a"].repeat(2));
+        let even = self::balancer(&["http://a", "http://b"], Strategy::RoundRobin);
+        assert_eq!(picks(&even, 4), ["a", "b", "a", "b"]);
+    }
+
+    #[test]
+    fn a_trial_dropped_without_a_result_lets_the_next_one_through() {
+        let breaker = CircuitBreakerConfig {
+            consecutive_failures: 1,
+            error_rate: None,
+            min_requests: 0,
+            window: Duration::from_secs(60),
+            open_duration: Duration::ZERO,
+            half_open_requests: 1,
+        };
+        let passive = PassiveHealthConfig { max_fails: 0, fail_timeout: Duration::from_secs(60) };
+        let balancer = Balancer::new(vec![Upstream::parse("http://a").unwrap()], Strategy::RoundRobin, passive, Some(breaker));
+        balancer.record_result(&balancer.upstreams()[0], false);
+        // E.g. the client disconnecting or the request timing out mid-trial
+        let trial = balancer.select(CLIENT, None, &[]).expect("a trial");
+        assert!(balancer.select(CLIENT, None, &[]).is_none());
+        drop(trial);
+        let trial = balancer.select(CLIENT, None, &[]).expect("the slot was freed");
+        balancer.record_result(trial.upstream(), true);
+        drop(trial);
+        assert_eq!(picks(&balancer, 3), ["a", "a", "a"]);
+    }
+
+    /// Takes `upstream` out of rotation in each way it can leave it.
+    fn take_out(balancer: &Balancer, upstream: &Upstream, how: &str) {
+        match how {
+            "unhealthy" => upstream.set_healthy(false),
+            "draining" => upstream.set_draining(true),
+            "ejected" => balancer.record_result(upstream, false),
+            _ => unreachable!(),
+        }
+    }
+
+    #[test]
+    fn every_strategy_skips_upstreams_out_of_rotation() {
+        let strategies = [Strategy::RoundRobin, Strategy::LeastConnections, Strategy::IpHash, Strategy::LeastLatency, Strategy::P2c];
+        for strategy in strategies {
+            for how in ["unhealthy", "draining", "ejected"] {
+                let balancer = balancer(&["http://a;weight=10", "http://b"], strategy);
+                take_out(&balancer, &balancer.upstreams()[0], how);
+                assert_eq!(picks(&balancer, 20), vec!["b"; 20], "{:?} {}", strategy, how);
+                take_out(&balancer, &balancer.upstreams()[1], how);
+                assert!(balancer.select(CLIENT, None, &[]).is_none(), "{:?} {}", strategy, how);
+            }
+        }
+    }
+
+    #[test]
+    fn retries_avoid_tried_upstreams() {
+        let balancer = balancer(&["http://a", "http://b"], Strategy::RoundRobin);
+        let a = Arc::clone(&balancer.upstreams()[0]);
+        for _ in 0..4 {
+            assert_eq!(balancer.select(CLIENT, None, &[Arc::clone(&a)]).unwrap().upstream().url, "http://b");
+        }
+        // Unless there is nothing else
+        let all = balancer.upstreams();
+        assert!(balancer.select(CLIENT, None, &all).is_some());
+    }
+
+    #[test]
+    fn sticky_sessions_keep_their_upstream_while_available() {
+        let balancer = balancer(&["http://a", "http://b"], Strategy::RoundRobin);
+        let b_id = balancer.upstreams()[1].id.clone();
+        for _ in 0..4 {
+            assert_eq!(balancer.select(CLIENT, Some(&b_id), &[]).unwrap().upstream().url, "http://b");
+        }
+        balancer.upstreams()[1].set_draining(true);
+        assert_eq!(balancer.select(CLIENT, Some(&b_id), &[]).unwrap().upstream().url, "http://a");
+    }
+
+    #[test]
+    fn ip_hash_keeps_clients_on_their_upstream() {
+        let balancer = balancer(&["http://a;weight=3", "http://b", "http://c"], Strategy::IpHash);
+        let clients: Vec<IpAddr> = (0..1000u32).map(|i| IpAddr::V4((0x0a00_0000 + i).into())).collect();
+        let before: Vec<String> = clients.iter().map(|&client| pick(&balancer, client)).collect();
+        assert_eq!(clients.iter().map(|&client| pick(&balancer, client)).collect::<Vec<_>>(), before);
+        // Weight 3 of 5 gets about 60% of the clients
+        let on_a = before.iter().filter(|host| *host == "a").count();
+        assert!((500..700).contains(&on_a), "{}", on_a);
+
+        // Only the clients of an upstream that leaves move, and not back to it
+        let b = balancer.find(&Upstream::parse("http://b").unwrap().id).unwrap();
+        b.set_healthy(false);
+        for (&client, host) in clients.iter().zip(&before) {
+            let now = pick(&balancer, client);
+            if host == "b" {
+                assert_ne!(now, "b");
+            } else {
+                assert_eq!(&now, host);
+            }
+        }
+    }
+
+    #[test]
+    fn least_latency_prefers_fast_upstreams() {
+        let balancer = balancer(&["http://a", "http://b", "http://c;weight=4"], Strategy::LeastLatency);
+        let [a, b, c] = [0, 1, 2].map(|i| Arc::clone(&balancer.upstreams()[i]));
+        balancer.record_latency(&a, Duration::from_millis(100));
+        balancer.record_latency(&b, Duration::from_millis(50));
+        // Unmeasured, c is taken to need 100ms, over weight 4
+        assert_eq!(pick(&balancer, CLIENT), "c");
+        balancer.record_latency(&c, Duration::from_millis(600));
+        assert_eq!(picks(&balancer, 3), ["b", "b", "b"]);
+        // A slower sample is taken at once
+        balancer.record_latency(&b, Duration::from_millis(200));
+        assert_eq!(pick(&balancer, CLIENT), "a");
+        // In-flight requests raise the cost: with 2 of them a's goes from 100ms to 300ms, past c's 150ms
+        let _held: Vec<ConnectionGuard> = (0..2).map(|_| ConnectionGuard::new(Arc::clone(&a))).collect();
+        assert_eq!(pick(&balancer, CLIENT), "c");
+    }
+
+    #[test]
+    fn least_latency_stops_picking_a_hung_upstream() {
+        let balancer = balancer(&["http://a", "http://b"], Strategy::LeastLatency);
+        let [a, b] = [0, 1].map(|i| Arc::clone(&balancer.upstreams()[i]));
+        balancer.record_latency(&b, Duration::from_millis(150));
+        // a accepts requests but never answers them: unmeasured, its in-flight ones still count
+        assert_eq!(pick(&balancer, CLIENT), "a");
+        let held: Vec<ConnectionGuard> = (0..2).map(|_| ConnectionGuard::new(Arc::clone(&a))).collect();
+        assert_eq!(picks(&balancer, 3), ["b", "b", "b"]);
+        // Once they time out, the failures are what it is measured by
+        drop(held);
+        balancer.record_failed_latency(&a, Duration::from_millis(20));
+        assert!(a.latency().unwrap() >= FAILURE_LATENCY);
+        assert_eq!(picks(&balancer, 3), ["b", "b", "b"]);
+    }
+
+    #[test]
+    fn p2c_never_picks_the_busier_of_two() {
+        let balancer = balancer(&["http://a", "http://b", "http://c"], Strategy::P2c);
+        let busy = Arc::clone(&balancer.upstreams()[0]);
+        let _held = (ConnectionGuard::new(Arc::clone(&busy)), ConnectionGuard::new(busy));
+        let picked = picks(&balancer, 100);
+        assert!(!picked.contains(&"a".to_string()));
+        assert!(picked.contains(&"b".to_string()) && picked.contains(&"c".to_string()));
+        // With one candidate there is no choice to make
+        let single = self::balancer(&["http://a"], Strategy::P2c);
+        assert_eq!(picks(&single, 3), ["a", "a", "a"]);
+    }
+
+    #[test]
+    fn backups_take_over_once_every_primary_is_out() {
+        let strategies = [Strategy::RoundRobin, Strategy::LeastConnections, Strategy::IpHash, Strategy::LeastLatency, Strategy::P2c];
+        for strategy in strategies {
+            for how in ["unhealthy", "draining", "ejected"] {
+                let balancer = balancer(&["http://a;weight=10", "http://b", "http://c;backup"], strategy);
+                take_out(&balancer, &balancer.upstreams()[0], how);
+                assert_eq!(picks(&balancer, 20), vec!["b"; 20], "{:?} {}", strategy, how);
+                take_out(&balancer, &balancer.upstreams()[1], how);
+                assert_eq!(picks(&balancer, 20), vec!["c"; 20], "{:?} {}", strategy, how);
+                take_out(&balancer, &balancer.upstreams()[2], how);
+                assert!(balancer.select(CLIENT, None, &[]).is_none(), "{:?} {}", strategy, how);
+            }
+        }
+    }
+}
@@ -0,0 +1,64 @@
+use futures_util::stream::{self, StreamExt};
+use hyper::body::Bytes;
+use hyper::Body;
+use std::net::IpAddr;
+use std::sync::Arc;
+use std::time::Duration;
+
+use crate::config::BandwidthConfig;
+use crate::token_bucket::TokenBuckets;
+
+/// Largest piece of a response sent at once, so a big chunk, such as a
+/// cached body, is paced rather than sent in one go after a long wait.
+const MAX_PIECE: usize = 16 * 1024;
+
+/// Token-bucket limit on the response bytes sent to each client IP. A client's
+/// responses share its bucket, so opening more connections gains it nothing.
+#[derive(Debug)]
+pub struct Bandwidth {
+    enabled: bool,
+    /// Measured in bytes
+    buckets: TokenBuckets,
+}
+
+impl Bandwidth {
+    pub fn new(config: &BandwidthConfig) -> Self {
+        Bandwidth {
+            enabled: config.enabled,
+            buckets: TokenBuckets::new(config.bytes_per_second as f64, config.burst as f64),
+        }
+    }
+
+    /// Paces `body` to the bandwidth left to `client`, or passes it through
+    /// when the limit is disabled.
+    pub fn throttle(self: &Arc<Self>, body: Body, client: IpAddr) -> Body {
+        if !self.enabled {
+            return body;
+        }
+        let bandwidth = Arc::clone(self);
+        let pieces = body.flat_map(|chunk| {
+            stream::iter(match chunk {
+                Ok(bytes) => split(bytes).into_iter().map(Ok).collect(),
+                Err(e) => vec![Err(e)],
+            })
+        });
+        Body::wrap_stream(pieces.then(move |piece| {
+            let wait = piece.as_ref().map_or(Duration::ZERO, |bytes| bandwidth.buckets.take_ahead(client, bytes.len() as f64));
+            async move {
+                if !wait.is_zero() {
+                    tokio::time::sleep(wait).await;
+                }
+                piece
+            }
+        }))
+    }
+}
+
+fn split(mut bytes: Bytes) -> Vec<Bytes> {
+    let mut pieces = Vec::with_capacity(bytes.len() / MAX_PIECE + 1);
+    while bytes.len() > MAX_PIECE {
+        pieces.push(bytes.split_to(MAX_PIECE));
+    }
+    pieces.push(bytes);
+    pieces
+}
@@ -0,0 +1,203 @@
+use base64::Engine;
+use hyper::header::{AUTHORIZATION, WWW_AUTHENTICATE};
+use hyper::{Body, Request, Response, StatusCode};
+use ring::digest;
+use std::collections::HashMap;
+use std::sync::Mutex;
+
+use crate::config::BasicAuthConfig;
+
+/// A password hash from an htpasswd file.
+#[derive(Debug)]
+enum Hash {
+    /// `$2y$`, `$2a$` or `$2b$`, as written by `htpasswd -B`
+    Bcrypt(String),
+    /// `{SHA}` followed by the base64 SHA-1 digest, as written by `htpasswd -s`
+    Sha1(Vec<u8>),
+}
+
+/// HTTP Basic authentication of a route against the users of an htpasswd file.
+#[derive(Debug)]
+pub struct BasicAuth {
+    realm: String,
+    users: HashMap<String, Hash>,
+    /// A bcrypt hash of the file, checked for unknown users so that the time
+    /// a login takes does not tell which users exist
+    decoy: Option<String>,
+    /// SHA-256 of the last password accepted per user, so bcrypt's deliberately
+    /// slow check runs once per user rather than on every request
+    verified: Mutex<HashMap<String, digest::Digest>>,
+}
+
+impl BasicAuth {
+    /// Reads the users of `config.htpasswd`; the file is read again on reload.
+    pub fn load(config: &BasicAuthConfig) -> Result<BasicAuth, String> {
+        let contents = std::fs::read_to_string(&config.htpasswd).map_err(|e| format!("failed to read htpasswd file {}: {}", config.htpasswd, e))?;
+        Self::parse(config, &contents)
+    }
+
+    /// Parses `contents`, the htpasswd file named by `config`.
+    fn parse(config: &BasicAuthConfig, contents: &str) -> Result<BasicAuth, String> {
+        let mut users = HashMap::new();
+        for (number, line) in contents.lines().enumerate() {
+            let line = line.trim();
+            if line.is_empty() || line.starts_with('#') {
+                continue;
+            }
+            let invalid = |reason: &str| format!("{} line {}: {}", config.htpasswd, number + 1, reason);
+            let (user, hash) = line.split_once(':').ok_or_else(|| invalid("expected user:hash"))?;
+            let hash = if hash.starts_with("$2y$") || hash.starts_with("$2a$") || hash.starts_with("$2b$") {
+                Hash::Bcrypt(hash.to_string())
+            } else if let Some(encoded) = hash.strip_prefix("{SHA}") {
+                Hash::Sha1(base64::engine::general_purpose::STANDARD.decode(encoded).map_err(|_| invalid("invalid {SHA} hash"))?)
+            } else {
+                return Err(invalid("unsupported hash, use bcrypt (htpasswd -B) or SHA-1 (htpasswd -s)"));
+            };
+            users.insert(user.to_string(), hash);
+        }
+        let decoy = users.values().find_map(|hash| match hash {
+            Hash::Bcrypt(hash) => Some(hash.clone()),
+            Hash::Sha1(_) => None,
+        });
+        Ok(BasicAuth { realm: config.realm.clone(), users, decoy, verified: Mutex::new(HashMap::new()) })
+    }
+
+    /// Checks the request's credentials, returning a 401 asking for them when
+    /// they are missing or wrong.
+    pub async fn check(&self, req: &Request<Body>) -> Option<Response<Body>> {
+        let credentials = req
+            .headers()
+            .get(AUTHORIZATION)
+            .and_then(|v| v.to_str().ok())
+            .and_then(|v| v.split_once(' '))
+            .filter(|(scheme, _)| scheme.eq_ignore_ascii_case("basic"))
+            .and_then(|(_, encoded)| base64::engine::general_purpose::STANDARD.decode(encoded.trim()).ok())
+            .and_then(|decoded| String::from_utf8(decoded).ok());
+        let authorized = match credentials.as_deref().and_then(|c| c.split_once(':')) {
+            Some((user, password)) => self.verify(user, password).await,
+            None => false,
+        };
+        if authorized {
+            return None;
+        }
+        let res = Response::builder()
+            .status(StatusCode::UNAUTHORIZED)
+            .header(WWW_AUTHENTICATE, format!("Basic realm=\"{}\"", self.realm.replace('"', "")))
+            .body(Body::from("Unauthorized"))
+            .unwrap();
+        Some(res)
+    }
+
+    async fn verify(&self, user: &str, password: &str) -> bool {
+        let fingerprint = digest::digest(&digest::SHA256, password.as_bytes());
+        if self.verified.lock().unwrap().get(user).is_some_and(|known| constant_time_eq(known.as_ref(), fingerprint.as_ref())) {
+            return true;
+        }
+        let valid = match self.users.get(user) {
+            Some(Hash::Bcrypt(hash)) => bcrypt_verify(hash, password).await,
+            Some(Hash::Sha1(expected)) => constant_time_eq(digest::digest(&digest::SHA1_FOR_LEGACY_USE_ONLY, password.as_bytes()).as_ref(), expected),
+            None => {
+                if let Some(hash) = &self.decoy {
+                    bcrypt_verify(hash, password).await;
+                }
+                false
+            }
+        };
+        if valid {
+            self.verified.lock().unwrap().insert(user.to_string(), fingerprint);
+        }
+        valid
+    }
+}
+
+/// Checks `password` against a bcrypt `hash` off the async workers, as
+/// bcrypt takes tens of milliseconds.
+async fn bcrypt_verify(hash: &str, password: &str) -> bool {
+    let (hash, password) = (hash.to_string(), password.to_string());
+    tokio::task::spawn_blocking(move || bcrypt::verify(password, &hash).unwrap_or(false)).await.unwrap_or(false)
+}
+
+/// Compares digests in constant time, as the admin API does its token.
+fn constant_time_eq(a: &[u8], b: &[u8]) -> bool {
+    a.len() == b.len() && a.iter().zip(b).fold(0, |acc, (a, b)| acc | (a ^ b)) == 0
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn parse(contents: &str) -> Result<BasicAuth, String> {
+        BasicAuth::parse(&BasicAuthConfig { htpasswd: "htpasswd".to_string(), realm: "Staging".to_string() }, contents)
+    }
+
+    fn request(authorization: Option<&str>) -> Request<Body> {
+        let mut req = Request::builder().uri("/");
+        if let Some(authorization) = authorization {
+            req = req.header(AUTHORIZATION, authorization);
+        }
+        req.body(Body::empty()).unwrap()
+    }
+
+    fn basic(credentials: &str) -> String {
+        format!("Basic {}", base64::engine::general_purpose::STANDARD.encode(credentials))
+    }
+
+    #[test]
+    fn parses_htpasswd_files() {
+        let bcrypt = bcrypt::hash("secret", 4).unwrap();
+        let auth = parse(&format!("# staging users\n\nalice:{{SHA}}5en6G6MezRroT3XKqkdPOmY/BfQ=\n  bob:{}  \r\n", bcrypt)).unwrap();
+        assert!(matches!(auth.users.get("alice"), Some(Hash::Sha1(digest)) if digest.len() == 20));
+        assert!(matches!(auth.users.get("bob"), Some(Hash::Bcrypt(hash)) if *hash == bcrypt));
+        assert_eq!(auth.users.len(), 2);
+        assert!(parse("").unwrap().users.is_empty());
+    }
+
+    #[test]
+    fn rejects_malformed_lines() {
+        for (contents, reason) in [
+            ("alice", "line 1: expected user:hash"),
+            ("# users\nalice:{SHA}not base64!", "line 2: invalid {SHA} hash"),
+            ("alice:$apr1$salt$hash", "line 1: unsupported hash"),
+            ("alice:plaintext", "line 1: unsupported hash"),
+            ("alice:", "line 1: unsupported hash"),
+        ] {
+            let error = parse(contents).unwrap_err();
+            assert!(error.starts_with(&format!("htpasswd {}", reason)), "{}: {}", contents, error);
+        }
+    }
+
+    #[tokio::test]
+    async fn verifies_sha1_passwords() {
+        let auth = parse("alice:{SHA}5en6G6MezRroT3XKqkdPOmY/BfQ=\ncarol:{SHA}").unwrap();
+        assert!(auth.check(&request(Some(&basic("alice:secret")))).await.is_none());
+        assert!(auth.check(&request(Some("basic YWxpY2U6c2VjcmV0"))).await.is_none());
+        let long = "secret".repeat(1000);
+        for (user, password) in [("alice", "wrong"), ("alice", "secret2"), ("alice", ""), ("alice", long.as_str()), ("mallory", "secret"), ("carol", "")] {
+            assert!(!auth.verify(user, password).await, "{}:{}", user, password);
+        }
+    }
+
+    #[tokio::test]
+    async fn verifies_bcrypt_passwords() {
+        let auth = parse(&format!("bob:{}", bcrypt::hash("hunter2", 4).unwrap())).unwrap();
+        assert!(!auth.verify("bob", "hunter3").await);
+        // Unknown users are checked against a decoy, which never lets them in
+        assert!(auth.decoy.is_some());
+        assert!(!auth.verify("mallory", "hunter2").await);
+        assert!(auth.verify("bob", "hunter2").await);
+        // Remembered, but only for the password that was accepted
+        assert!(auth.verified.lock().unwrap().contains_key("bob"));
+        assert!(auth.verify("bob", "hunter2").await);
+        assert!(!auth.verify("bob", "hunter3").await);
+    }
+
+    #[tokio::test]
+    async fn asks_for_missing_or_unreadable_credentials() {
+        let auth = parse("alice:{SHA}5en6G6MezRroT3XKqkdPOmY/BfQ=").unwrap();
+        for authorization in [None, Some("Bearer YWxpY2U6c2VjcmV0"), Some("Basic"), Some("Basic !!!"), Some("Basic YWxpY2U="), Some(basic("alice:wrong").as_str())] {
+            let res = auth.check(&request(authorization)).await.expect("asks for credentials");
+            assert_eq!(res.status(), StatusCode::UNAUTHORIZED, "{:?}", authorization);
+            assert_eq!(res.headers()[WWW_AUTHENTICATE], "Basic realm=\"Staging\"");
+        }
+    }
+}
@@ -0,0 +1,953 @@
+use async_trait::async_trait;
+use hyper::body::{Bytes, HttpBody};
+use hyper::header::{HeaderMap, HeaderName, HeaderValue, AGE, AUTHORIZATION, CACHE_CONTROL, CONTENT_TYPE, COOKIE, DATE, EXPIRES, HOST, IF_MODIFIED_SINCE, IF_NONE_MATCH, SET_COOKIE, VARY};
+use hyper::{Body, Method, Request, Response, StatusCode, Uri};
+use serde_json::json;
+use std::collections::{BTreeMap, BTreeSet, HashMap};
+use std::sync::atomic::{AtomicU64, Ordering};
+use std::sync::{Arc, Mutex};
+use std::time::{Duration, Instant, SystemTime};
+use tokio::sync::watch;
+use tracing::{debug, info, warn};
+
+use crate::acl::Cidr;
+use crate::config::CacheKeyConfig;
+use crate::disk_cache::{DiskCache, DiskFile};
+use crate::geoip::GeoInfo;
+use crate::headers::EventStream;
+use crate::middleware::{Context, Middleware};
+use crate::router::{Route, Router};
+
+/// Size limits for the response cache.
+#[derive(Debug, Clone, Copy)]
+pub struct CacheConfig {
+    /// Total bytes of response bodies kept before the least recently used are evicted
+    pub max_size: usize,
+    /// Larger responses are passed through without being cached
+    pub max_object_size: usize,
+    /// How long past their lifetime responses are served while being
+    /// refreshed, unless they say with `stale-while-revalidate`
+    pub stale_while_revalidate: Duration,
+    /// How long past their lifetime responses stand in for upstream errors,
+    /// unless they say with `stale-if-error`
+    pub stale_if_error: Duration,
+    /// Whether concurrent misses for a response wait for the first to fetch it
+    pub coalesce: bool,
+}
+
+/// Response header tagging a cached response with keys it can be purged by,
+/// separated by spaces.
+const SURROGATE_KEY: &str = "surrogate-key";
+/// How long a refresh of a stale response may take before another request
+/// starts one.
+const REVALIDATE_TIMEOUT: Duration = Duration::from_secs(30);
+/// How long a miss waits for a concurrent request to fetch the same response
+/// before fetching it itself.
+const COALESCE_TIMEOUT: Duration = Duration::from_secs(10);
+/// The longest a response is kept for, whatever its headers ask for.
+const MAX_AGE: Duration = Duration::from_secs(365 * 24 * 60 * 60);
+
+/// Request header values a response varies on.
+type VaryValues = Vec<(HeaderName, Option<HeaderValue>)>;
+
+#[derive(Debug, Clone)]
+pub(crate) struct Entry {
+    pub(crate) status: StatusCode,
+    pub(crate) headers: HeaderMap,
+    pub(crate) body: Bytes,
+    pub(crate) vary: VaryValues,
+    /// From the `Surrogate-Key` header
+    pub(crate) surrogate_keys: Vec<String>,
+    pub(crate) stored_at: Instant,
+    pub(crate) fresh_until: Instant,
+    pub(crate) stale_while_revalidate: Duration,
+    pub(crate) stale_if_error: Duration,
+    /// When a request started refreshing the entry, if one is under way
+    pub(crate) revalidating: Option<Instant>,
+    /// Position in the LRU order
+    pub(crate) tick: u64,
+}
+
+impl Entry {
+    /// Until when the entry may be served in some case, after which it is dropped.
+    pub(crate) fn usable_until(&self) -> Instant {
+        later(self.fresh_until, self.stale_while_revalidate.max(self.stale_if_error))
+    }
+
+    pub(crate) fn varies_from(&self, request_headers: &HeaderMap) -> bool {
+        !self.vary.iter().all(|(name, value)| request_headers.get(name) == value.as_ref())
+    }
+
+    /// How the entry may answer a request at `now`, or `None` when it may
+    /// only stand in for an error. Marks it as being refreshed when the
+    /// request should refresh it.
+    fn freshness(&mut self, now: Instant) -> Option<Freshness> {
+        if now < self.fresh_until {
+            Some(Freshness::Fresh)
+        } else if now < later(self.fresh_until, self.stale_while_revalidate) {
+            // One request at a time refreshes an entry
+            let revalidate = self.revalidating.is_none_or(|since| now.duration_since(since) >= REVALIDATE_TIMEOUT);
+            if revalidate {
+                self.revalidating = Some(now);
+            }
+            Some(Freshness::Stale { revalidate })
+        } else {
+            None
+        }
+    }
+
+    fn stands_in_for_errors(&self, now: Instant) -> bool {
+        now < later(self.fresh_until, self.stale_if_error)
+    }
+
+    fn response(&self) -> Response<Body> {
+        let mut res = Response::new(Body::from(self.body.clone()));
+        *res.status_mut() = self.status;
+        *res.headers_mut() = self.headers.clone();
+        if let Ok(age) = HeaderValue::from_str(&self.stored_at.elapsed().as_secs().to_string()) {
+            res.headers_mut().insert(AGE, age);
+        }
+        res
+    }
+}
+
+#[derive(Debug, Default)]
+struct Store {
+    entries: HashMap<String, Entry>,
+    // Least recently used first: tick -> key
+    lru: BTreeMap<u64, String>,
+    size: usize,
+    next_tick: u64,
+    /// Counts purges, so a response that was being fetched during one is not stored
+    generation: u64,
+}
+
+impl Store {
+    fn touch(&mut self, key: &str) {
+        self.next_tick += 1;
+        let tick = self.next_tick;
+        if let Some(entry) = self.entries.get_mut(key) {
+            self.lru.remove(&entry.tick);
+            entry.tick = tick;
+            self.lru.insert(tick, key.to_string());
+        }
+    }
+
+    fn remove(&mut self, key: &str) {
+        if let Some(entry) = self.entries.remove(key) {
+            self.lru.remove(&entry.tick);
+            self.size -= entry.body.len();
+        }
+    }
+
+    fn insert(&mut self, key: String, mut entry: Entry, max_size: usize) {
+        self.remove(&key);
+        while self.size + entry.body.len() > max_size {
+            let oldest = match self.lru.values().next() {
+                Some(oldest) => oldest.clone(),
+                None => break,
+            };
+            self.remove(&oldest);
+        }
+        self.next_tick += 1;
+        entry.tick = self.next_tick;
+        self.size += entry.body.len();
+        self.lru.insert(entry.tick, key.clone());
+        self.entries.insert(key, entry);
+    }
+}
+
+/// Entries removed by [`Cache::purge`].
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum Purge {
+    /// The response to one URL, keyed as `host/path?query`
+    Key(String),
+    /// Every response whose key starts with this
+    Prefix(String),
+    /// Responses tagged with this surrogate key
+    SurrogateKey(String),
+    All,
+}
+
+impl Purge {
+    pub(crate) fn matches(&self, key: &str, entry: &Entry) -> bool {
+        // URLs are purged on every listener
+        let key = key.split_once('\n').map_or(key, |(_, url)| url);
+        match self {
+            // The key of a route varying on headers or cookies continues past the URL
+            Purge::Key(wanted) => key.strip_prefix(wanted.as_str()).is_some_and(|rest| rest.is_empty() || rest.starts_with('\n')),
+            Purge::Prefix(prefix) => key.starts_with(prefix.as_str()),
+            Purge::SurrogateKey(wanted) => entry.surrogate_keys.iter().any(|key| key == wanted),
+            Purge::All => true,
+        }
+    }
+}
+
+/// What a route's cached responses are keyed by: the host and path, the
+/// query parameters chosen, and the values of chosen headers and cookies.
+#[derive(Debug, Clone)]
+pub struct CacheKey {
+    query_params: Vec<String>,
+    ignore_query_params: Vec<String>,
+    headers: Vec<HeaderName>,
+    cookies: Vec<String>,
+}
+
+impl CacheKey {
+    pub fn new(config: &CacheKeyConfig) -> Self {
+        CacheKey {
+            query_params: config.query_params.clone(),
+            ignore_query_params: config.ignore_query_params.clone(),
+            // Header names were checked with the config
+            headers: config.headers.iter().filter_map(|name| HeaderName::from_bytes(name.as_bytes()).ok()).collect(),
+            cookies: config.cookies.clone(),
+        }
+    }
+
+    /// Whether a query parameter is part of the key.
+    fn keeps(&self, param: &str) -> bool {
+        let name = param.split('=').next().unwrap_or(param);
+        let matches = |pattern: &String| match pattern.strip_suffix('*') {
+            Some(prefix) => name.starts_with(prefix),
+            None => name == pattern,
+        };
+        (self.query_params.is_empty() || self.query_params.iter().any(matches)) && !self.ignore_query_params.iter().any(matches)
+    }
+
+    /// Appends the chosen header and cookie values of a request to `key`.
+    fn append_varying(&self, key: &mut String, headers: &HeaderMap) {
+        for name in &self.headers {
+            let values: Vec<&str> = headers.get_all(name).iter().filter_map(|v| v.to_str().ok()).collect();
+            key.push_str(&format!("\n{}: {}", name, values.join(", ")));
+        }
+        for name in &self.cookies {
+            let value = headers
+                .get_all(COOKIE)
+                .iter()
+                .filter_map(|v| v.to_str().ok())
+                .flat_map(|v| v.split(';'))
+                .filter_map(|cookie| cookie.trim().split_once('='))
+                .find(|(cookie, _)| cookie == name)
+                .map_or("", |(_, value)| value);
+            key.push_str(&format!("\ncookie {}={}", name, value));
+        }
+    }
+}
+
+/// In-memory LRU cache for GET responses with explicit freshness
+/// (`Cache-Control: max-age`/`s-maxage` or `Expires`).
+pub struct Cache {
+    config: CacheConfig,
+    /// Clients allowed to send PURGE requests; PURGE is proxied like other methods when empty
+    purge_from: Vec<Cidr>,
+    /// Finds the route of a request, for its cache key
+    router: Router,
+    store: Arc<Mutex<Store>>,
+    /// Tier below the memory one, when enabled
+    disk: Option<Arc<DiskCache>>,
+    /// Keys being fetched by a request that missed, for others to wait on
+    fetching: Arc<Mutex<HashMap<String, watch::Receiver<()>>>>,
+    hits: AtomicU64,
+    misses: AtomicU64,
+    coalesced: AtomicU64,
+}
+
+/// Set in the context by earlier middleware for requests whose responses
+/// must neither be served from nor stored in the cache.
+pub struct Uncacheable;
+
+/// Carried from `on_request` to `on_response` for requests that missed.
+struct Lookup {
+    key: String,
+    request_headers: HeaderMap,
+    /// Held until the response is stored or found not to be cacheable
+    fetch: Option<Fetch>,
+}
+
+/// Marks a key as being fetched; requests waiting on it are woken when it is
+/// dropped.
+struct Fetch {
+    key: String,
+    fetching: Arc<Mutex<HashMap<String, watch::Receiver<()>>>>,
+    _done: watch::Sender<()>,
+}
+
+impl Drop for Fetch {
+    fn drop(&mut self) {
+        self.fetching.lock().unwrap().remove(&self.key);
+    }
+}
+
+/// Left in the context by a stale hit that should be refreshed, for the proxy
+/// to send a request of its own.
+pub struct Revalidate {
+    uri: Uri,
+    headers: HeaderMap,
+}
+
+impl Revalidate {
+    /// The request refreshing the entry, which bypasses the cache lookup.
+    pub fn into_request(self) -> Request<Body> {
+        let mut req = Request::builder().uri(self.uri).body(Body::empty()).unwrap();
+        *req.headers_mut() = self.headers;
+        req.extensions_mut().insert(Revalidation);
+        req
+    }
+}
+
+/// Marks a request refreshing a stale entry.
+#[derive(Clone, Copy)]
+pub struct Revalidation;
+
+/// How a cached response found for a request may be used.
+enum Freshness {
+    Fresh,
+    /// Past its lifetime, but served while a request refreshes it; `revalidate`
+    /// tells whether this request should start that refresh
+    Stale { revalidate: bool },
+}
+
+impl Cache {
+    pub fn new(config: CacheConfig) -> Self {
+        Cache {
+            config,
+            purge_from: Vec::new(),
+            router: Router::default(),
+            store: Arc::new(Mutex::new(Store::default())),
+            disk: None,
+            fetching: Arc::new(Mutex::new(HashMap::new())),
+            hits: AtomicU64::new(0),
+            misses: AtomicU64::new(0),
+            coalesced: AtomicU64::new(0),
+        }
+    }
+
+    /// Answers PURGE requests from clients in `ranges`.
+    pub fn with_purge_from(mut self, ranges: Vec<Cidr>) -> Self {
+        self.purge_from = ranges;
+        self
+    }
+
+    /// Keeps responses in `disk` too, and those too large for memory there alone.
+    pub fn with_disk(mut self, disk: Arc<DiskCache>) -> Self {
+        self.disk = Some(disk);
+        self
+    }
+
+    pub fn disk(&self) -> Option<&Arc<DiskCache>> {
+        self.disk.as_ref()
+    }
+
+    /// Keys requests by the cache keys of their routes in `router`.
+    pub fn with_router(mut self, router: Router) -> Self {
+        self.router = router;
+        self
+    }
+
+    /// The URL part of a request's cache key, with only the query parameters
+    /// its route keeps, and the request's route.
+    fn url_key(&self, req: &Request<Body>, ctx: &Context) -> (String, Option<&Route>) {
+        let route = self.router.route(req, &ctx.listener);
+        let rules = route.and_then(|route| route.cache_key.as_deref());
+        let host = req.headers().get(HOST).and_then(|h| h.to_str().ok()).or_else(|| req.uri().host()).unwrap_or("");
+        let mut key = format!("{}{}", host.to_ascii_lowercase(), req.uri().path());
+        if let Some(query) = req.uri().query() {
+            let kept: Vec<&str> = query.split('&').filter(|param| rules.is_none_or(|rules| rules.keeps(param))).collect();
+            if !kept.is_empty() {
+                key.push('?');
+                key.push_str(&kept.join("&"));
+            }
+        }
+        (key, route)
+    }
+
+    /// Responses are keyed by listener, host, path and query, adjusted by the
+    /// route's key rules; Vary is handled per entry.
+    fn cache_key(&self, req: &Request<Body>, ctx: &Context) -> String {
+        let (url, route) = self.url_key(req, ctx);
+        // Listeners serve routes of their own, which may send the same URL to different pools
+        let mut key = format!("{}\n{}", ctx.listener, url);
+        // The same URL may be served by another pool to clients from elsewhere
+        if route.is_some_and(|route| !route.countries.is_empty()) {
+            let country = req.extensions().get::<GeoInfo>().and_then(|info| info.country.as_deref()).unwrap_or("");
+            key.push_str(&format!("\ncountry {}", country));
+        }
+        if let Some(rules) = route.and_then(|route| route.cache_key.as_deref()) {
+            rules.append_varying(&mut key, req.headers());
+        }
+        key
+    }
+
+    pub fn hits(&self) -> u64 {
+        self.hits.load(Ordering::Relaxed)
+    }
+
+    pub fn misses(&self) -> u64 {
+        self.misses.load(Ordering::Relaxed)
+    }
+
+    /// Requests answered with a response fetched for a concurrent request.
+    pub fn coalesced(&self) -> u64 {
+        self.coalesced.load(Ordering::Relaxed)
+    }
+
+    /// Number of cached responses and their total body size in bytes.
+    pub fn usage(&self) -> (usize, usize) {
+        let store = self.store.lock().unwrap();
+        (store.entries.len(), store.size)
+    }
+
+    /// Removes the entries `purge` selects, returning how many there were.
+    pub fn purge(&self, purge: &Purge) -> usize {
+        let mut store = self.store.lock().unwrap();
+        store.generation += 1;
+        let mut keys: BTreeSet<String> = store.entries.iter().filter(|(key, entry)| purge.matches(key, entry)).map(|(key, _)| key.clone()).collect();
+        for key in &keys {
+            store.remove(key);
+        }
+        // With the memory lock held, so a response being stored cannot slip past the purge
+        if let Some(disk) = &self.disk {
+            keys.extend(disk.purge(purge));
+        }
+        keys.len()
+    }
+
+    /// Answers a PURGE request: the URL it names is purged, every URL under it
+    /// when it ends in `*`, or the responses tagged with the surrogate keys in
+    /// its `Surrogate-Key` header.
+    fn answer_purge(&self, req: &Request<Body>, ctx: &Context) -> Response<Body> {
+        if !self.purge_from.iter().any(|range| range.contains(ctx.client_addr.ip())) {
+            return Response::builder().status(StatusCode::FORBIDDEN).body(Body::from("Forbidden")).unwrap();
+        }
+        let surrogate_keys: Vec<String> = req.headers().get_all(SURROGATE_KEY).iter().filter_map(|v| v.to_str().ok()).flat_map(str::split_whitespace).map(String::from).collect();
+        let purges = if surrogate_keys.is_empty() {
+            let (key, _) = self.url_key(req, ctx);
+            vec![match key.strip_suffix('*') {
+                Some(prefix) => Purge::Prefix(prefix.to_string()),
+                None => Purge::Key(key),
+            }]
+        } else {
+            surrogate_keys.into_iter().map(Purge::SurrogateKey).collect()
+        };
+        let purged: usize = purges.iter().map(|purge| self.purge(purge)).sum();
+        info!("Purged {} cached response(s) on request from {}", purged, ctx.client_addr.ip());
+        Response::builder().header(CONTENT_TYPE, "application/json").body(Body::from(json!({ "purged": purged }).to_string())).unwrap()
+    }
+
+    fn lookup(&self, key: &str, request_headers: &HeaderMap) -> Option<(Response<Body>, Freshness)> {
+        let now = Instant::now();
+        let mut store = self.store.lock().unwrap();
+        let entry = store.entries.get_mut(key)?;
+        if now >= entry.usable_until() {
+            store.remove(key);
+            return None;
+        }
+        if entry.varies_from(request_headers) {
+            return None;
+        }
+        let freshness = entry.freshness(now)?;
+        let res = entry.response();
+        store.touch(key);
+        Some((res, freshness))
+    }
+
+    /// The response to a request found on disk, where the entry may be used
+    /// as `usable` decides. Small enough responses are moved up into memory.
+    async fn disk_lookup<T>(&self, key: &str, request_headers: &HeaderMap, usable: impl FnOnce(&mut Entry, Instant) -> Option<T>) -> Option<(Response<Body>, T)> {
+        let disk = self.disk.as_ref()?;
+        let generation = self.store.lock().unwrap().generation;
+        let (entry, file, found) = disk.find(key, request_headers, usable)?;
+        Some((self.disk_response(disk, key, entry, &file, generation).await?, found))
+    }
+
+    async fn disk_response(&self, disk: &DiskCache, key: &str, entry: Entry, file: &DiskFile, generation: u64) -> Option<Response<Body>> {
+        if file.len > self.config.max_object_size as u64 {
+            let mut res = entry.response();
+            *res.body_mut() = disk.stream(key, file).await?;
+            return Some(res);
+        }
+        let entry = Entry { body: disk.read(key, file).await?, ..entry };
+        let res = entry.response();
+        let mut store = self.store.lock().unwrap();
+        if store.generation == generation {
+            store.insert(key.to_string(), entry, self.config.max_size);
+        }
+        Some(res)
+    }
+
+    /// Answers a request from the cache, if a usable response is stored.
+    async fn hit(&self, key: &str, req: &Request<Body>, ctx: &mut Context) -> Option<Response<Body>> {
+        let (mut res, freshness) = match self.lookup(key, req.headers()) {
+            Some(found) => found,
+            None => self.disk_lookup(key, req.headers(), |entry, now| entry.freshness(now)).await?,
+        };
+        self.hits.fetch_add(1, Ordering::Relaxed);
+        let status = match freshness {
+            Freshness::Fresh => "HIT",
+            Freshness::Stale { revalidate } => {
+                if revalidate {
+                    ctx.extensions.insert(Revalidate { uri: req.uri().clone(), headers: req.headers().clone() });
+                }
+                "STALE"
+            }
+        };
+        debug!("Cache {} for {:?}", status, key);
+        res.headers_mut().insert("x-cache", HeaderValue::from_static(status));
+        Some(res)
+    }
+
+    /// Marks `key` as being fetched by the caller, or returns a receiver that
+    /// is closed once the request already fetching it is done.
+    fn fetch(&self, key: &str) -> Result<Fetch, watch::Receiver<()>> {
+        let mut fetching = self.fetching.lock().unwrap();
+        if let Some(done) = fetching.get(key) {
+            return Err(done.clone());
+        }
+        let (done, receiver) = watch::channel(());
+        fetching.insert(key.to_string(), receiver);
+        Ok(Fetch { key: key.to_string(), fetching: Arc::clone(&self.fetching), _done: done })
+    }
+
+    /// The response stored for `key`, when it may stand in for an upstream error.
+    async fn stale_if_error(&self, key: &str, request_headers: &HeaderMap) -> Option<Response<Body>> {
+        let in_memory = {
+            let store = self.store.lock().unwrap();
+            store.entries.get(key).filter(|entry| entry.stands_in_for_errors(Instant::now()) && !entry.varies_from(request_headers)).map(Entry::response)
+        };
+        match in_memory {
+            Some(res) => Some(res),
+            None => self.disk_lookup(key, request_headers, |entry, now| entry.stands_in_for_errors(now).then_some(())).await.map(|(res, ())| res),
+        }
+    }
+
+    /// Largest response body stored in either tier.
+    fn max_object_size(&self) -> u64 {
+        let memory = self.config.max_object_size as u64;
+        self.disk.as_ref().map_or(memory, |disk| disk.max_object_size().max(memory))
+    }
+}
+
+#[async_trait]
+impl Middleware for Cache {
+    async fn on_request(&self, req: &mut Request<Body>, ctx: &mut Context) -> Option<Response<Body>> {
+        if req.method().as_str() == "PURGE" && !self.purge_from.is_empty() {
+            return Some(self.answer_purge(req, ctx));
+        }
+        if req.method() != Method::GET || directives(req.headers()).iter().any(|d| d == "no-store") || ctx.extensions.get::<Uncacheable>().is_some() {
+            return None;
+        }
+        let key = self.cache_key(req, ctx);
+        // Conditions are checked against the full response once it is back, so the
+        // upstream is asked for one that can be stored
+        req.headers_mut().remove(IF_NONE_MATCH);
+        req.headers_mut().remove(IF_MODIFIED_SINCE);
+
+        // Refreshing a stale entry fetches it anew, and is not counted as a miss
+        if req.extensions().get::<Revalidation>().is_some() {
+            ctx.extensions.insert(Lookup { key, request_headers: req.headers().clone(), fetch: None });
+            return None;
+        }
+
+        // `Cache-Control: no-cache` from the client forces a fresh fetch, which may still be stored
+        let bypass = directives(req.headers()).iter().any(|d| d == "no-cache");
+        if bypass {
+            self.misses.fetch_add(1, Ordering::Relaxed);
+            ctx.extensions.insert(Lookup { key, request_headers: req.headers().clone(), fetch: None });
+            return None;
+        }
+        if let Some(res) = self.hit(&key, req, ctx).await {
+            return Some(res);
+        }
+
+        // Only the first of concurrent misses goes upstream; the others are answered from what it stores
+        let mut fetch = None;
+        if self.config.coalesce {
+            match self.fetch(&key) {
+                Ok(leader) => fetch = Some(leader),
+                Err(mut done) => {
+                    let _ = tokio::time::timeout(COALESCE_TIMEOUT, done.changed()).await;
+                    if let Some(res) = self.hit(&key, req, ctx).await {
+                        self.coalesced.fetch_add(1, Ordering::Relaxed);
+                        return Some(res);
+                    }
+                    // Not stored, so likely not cacheable: fetch it without holding up others in turn
+                }
+            }
+        }
+
+        self.misses.fetch_add(1, Ordering::Relaxed);
+        ctx.extensions.insert(Lookup { key, request_headers: req.headers().clone(), fetch });
+        None
+    }
+
+    async fn on_response(&self, res: &mut Response<Body>, ctx: &mut Context) {
+        let lookup = match ctx.extensions.remove::<Lookup>() {
+            Some(lookup) => lookup,
+            None => return,
+        };
+        // An upstream failing is hidden behind a recent enough copy
+        if matches!(res.status().as_u16(), 500 | 502 | 503 | 504) {
+            if let Some(mut stale) = self.stale_if_error(&lookup.key, &lookup.request_headers).await {
+                stale.headers_mut().insert("x-cache", HeaderValue::from_static("STALE"));
+                *res = stale;
+                return;
+            }
+        }
+        res.headers_mut().insert("x-cache", HeaderValue::from_static("MISS"));
+
+        let (ttl, vary) = match cacheability(res, &lookup.request_headers) {
+            Some(cacheable) => cacheable,
+            None => return,
+        };
+        let too_large = res.body().size_hint().upper().is_some_and(|len| len > self.max_object_size());
+        if too_large {
+            return;
+        }
+
+        let mut headers = res.headers().clone();
+        headers.remove("x-cache");
+        // The configured allowances only apply to responses that do not forbid serving them stale
+        let directives = directives(&headers);
+        let revalidate = directives.iter().any(|d| d == "must-revalidate" || d == "proxy-revalidate");
+        let allowance = |name: &str, default: Duration| seconds(&directives, name).map_or(if revalidate { Duration::ZERO } else { default }, |secs| Duration::from_secs(secs).min(MAX_AGE));
+        let stale_while_revalidate = allowance("stale-while-revalidate=", self.config.stale_while_revalidate);
+        let stale_if_error = allowance("stale-if-error=", self.config.stale_if_error);
+        let surrogate_keys = headers.get_all(SURROGATE_KEY).iter().filter_map(|v| v.to_str().ok()).flat_map(str::split_whitespace).map(String::from).collect();
+        let entry = Entry {
+            status: res.status(),
+            headers,
+            body: Bytes::new(),
+            vary,
+            surrogate_keys,
+            stored_at: Instant::now(),
+            fresh_until: later(Instant::now(), ttl),
+            stale_while_revalidate,
+            stale_if_error,
+            revalidating: None,
+            tick: 0,
+        };
+
+        // Store the body once it has been streamed to the client
+        let body = std::mem::replace(res.body_mut(), Body::empty());
+        let (mut sender, tee) = Body::channel();
+        *res.body_mut() = tee;
+        let store = Arc::clone(&self.store);
+        let disk = self.disk.clone();
+        let config = self.config;
+        let generation = store.lock().unwrap().generation;
+        tokio::spawn(async move {
+            let mut body = body;
+            let mut collected = Vec::new();
+            let mut complete = true;
+            let mut sending = true;
+            // Dropped, deleting the partial file, once the body turns out too large or cannot be written
+            let mut writer = match &disk {
+                Some(disk) => disk.create(&lookup.key, &entry).await.map_err(|e| warn!("Failed to write to the disk cache: {}", e)).ok(),
+                None => None,
+            };
+            while let Some(chunk) = body.data().await {
+                let chunk = match chunk {
+                    Ok(chunk) => chunk,
+                    Err(_) => {
+                        sender.abort();
+                        return;
+                    }
+                };
+                if complete && collected.len() + chunk.len() <= config.max_object_size {
+                    collected.extend_from_slice(&chunk);
+                } else {
+                    complete = false;
+                    collected = Vec::new();
+                }
+                if let (Some(disk), Some(file)) = (&disk, writer.as_mut()) {
+                    let written = if file.len() + chunk.len() as u64 > disk.max_object_size() {
+                        false
+                    } else {
+                        file.write(&chunk).await.map_err(|e| warn!("Failed to write to the disk cache: {}", e)).is_ok()
+                    };
+                    if !written {
+                        writer = None;
+                    }
+                }
+                // A client that left, or was answered with 304, does not stop a response that can still be stored
+                if sending && sender.send_data(chunk).await.is_err() {
+                    sending = false;
+                }
+                if !sending && !complete && writer.is_none() {
+                    return;
+                }
+            }
+            if let Some(file) = writer.as_mut() {
+                if let Err(e) = file.finish().await {
+                    warn!("Failed to write to the disk cache: {}", e);
+                    writer = None;
+                }
+            }
+            let mut store = store.lock().unwrap();
+            if store.generation == generation {
+                if let (Some(disk), Some(file)) = (&disk, writer) {
+                    if let Err(e) = disk.commit(lookup.key.clone(), entry.clone(), file) {
+                        warn!("Failed to write to the disk cache: {}", e);
+                    }
+                }
+                if complete {
+                    let entry = Entry { body: Bytes::from(collected), ..entry };
+                    store.insert(lookup.key, entry, config.max_size);
+                }
+            }
+            // Requests waiting on this one look the response up once it is stored
+            drop(store);
+            drop(lookup.fetch);
+        });
+    }
+}
+
+/// The cache key of the response to `url`, an absolute URL; the scheme is
+/// not part of it.
+pub fn url_key(url: &str) -> Result<String, String> {
+    let uri: Uri = url.parse().map_err(|e| format!("invalid URL {}: {}", url, e))?;
+    let authority = uri.authority().ok_or_else(|| format!("URL needs a scheme and host: {}", url))?;
+    let path = uri.path_and_query().map(|p| p.as_str()).unwrap_or("/");
+    Ok(format!("{}{}", authority.as_str().to_ascii_lowercase(), path))
+}
+
+/// Returns the freshness lifetime and Vary values if the response may be stored.
+fn cacheability(res: &Response<Body>, request_headers: &HeaderMap) -> Option<(Duration, VaryValues)> {
+    if !matches!(res.status().as_u16(), 200 | 203 | 204 | 300 | 301 | 404 | 410) || res.headers().contains_key(SET_COOKIE) {
+        return None;
+    }
+    // An event stream is only ever meant for the client that opened it
+    if res.extensions().get::<EventStream>().is_some() {
+        return None;
+    }
+
+    let directives = directives(res.headers());
+    let has = |name: &str| directives.iter().any(|d| d == name);
+    if has("no-store") || has("no-cache") || has("private") {
+        return None;
+    }
+    // Responses to authenticated requests are only shared when explicitly allowed
+    if request_headers.contains_key(AUTHORIZATION) && !has("public") && !directives.iter().any(|d| d.starts_with("s-maxage=")) {
+        return None;
+    }
+
+    let ttl = match seconds(&directives, "s-maxage=").or_else(|| seconds(&directives, "max-age=")) {
+        Some(secs) => Duration::from_secs(secs),
+        None => {
+            let expires = header_date(res.headers(), EXPIRES)?;
+            let date = header_date(res.headers(), DATE).unwrap_or_else(SystemTime::now);
+            expires.duration_since(date).ok()?
+        }
+    };
+    let ttl = ttl.min(MAX_AGE);
+    if ttl.is_zero() {
+        return None;
+    }
+
+    let mut vary = Vec::new();
+    for value in res.headers().get_all(VARY) {
+        for name in value.to_str().ok()?.split(',') {
+            let name = name.trim();
+            if name == "*" {
+                return None;
+            }
+            let name = HeaderName::from_bytes(name.as_bytes()).ok()?;
+            let value = request_headers.get(&name).cloned();
+            vary.push((name, value));
+        }
+    }
+
+    Some((ttl, vary))
+}
+
+/// Lowercased Cache-Control directives.
+fn directives(headers: &HeaderMap) -> Vec<String> {
+    headers
+        .get_all(CACHE_CONTROL)
+        .iter()
+        .filter_map(|v| v.to_str().ok())
+        .flat_map(|v| v.split(','))
+        .map(|d| d.trim().to_ascii_lowercase())
+        .filter(|d| !d.is_empty())
+        .collect()
+}
+
+/// The seconds given by a directive such as `max-age=`.
+fn seconds(directives: &[String], name: &str) -> Option<u64> {
+    directives.iter().find_map(|d| d.strip_prefix(name)?.trim_matches('"').parse::<u64>().ok())
+}
+
+/// `instant` plus `by`, or as far on as an `Instant` goes.
+fn later(instant: Instant, by: Duration) -> Instant {
+    instant.checked_add(by).or_else(|| instant.checked_add(MAX_AGE)).unwrap_or(instant)
+}
+
+fn header_date(headers: &HeaderMap, name: HeaderName) -> Option<SystemTime> {
+    httpdate::parse_http_date(headers.get(name)?.to_str().ok()?).ok()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::config::CacheSettings;
+
+    fn response(headers: &[(&'static str, &'static str)]) -> Response<Body> {
+        let mut res = Response::builder();
+        for (name, value) in headers {
+            res = res.header(*name, *value);
+        }
+        res.body(Body::empty()).unwrap()
+    }
+
+    fn request_headers(headers: &[(&'static str, &'static str)]) -> HeaderMap {
+        headers.iter().map(|(name, value)| (HeaderName::from_static(name), HeaderValue::from_static(value))).collect()
+    }
+
+    fn ttl(res: &Response<Body>, request: &[(&'static str, &'static str)]) -> Option<Duration> {
+        cacheability(res, &request_headers(request)).map(|(ttl, _)| ttl)
+    }
+
+    #[test]
+    fn stores_responses_with_a_lifetime() {
+        assert_eq!(ttl(&response(&[("cache-control", "max-age=60")]), &[]), Some(Duration::from_secs(60)));
+        assert_eq!(ttl(&response(&[("cache-control", "Public, Max-Age=\"60\"")]), &[]), Some(Duration::from_secs(60)));
+        // s-maxage is for shared caches such as this one
+        assert_eq!(ttl(&response(&[("cache-control", "max-age=60, s-maxage=300")]), &[]), Some(Duration::from_secs(300)));
+        assert_eq!(ttl(&response(&[("date", "Sun, 06 Nov 1994 08:49:37 GMT"), ("expires", "Sun, 06 Nov 1994 08:50:37 GMT")]), &[]), Some(Duration::from_secs(60)));
+        // Nothing is kept for more than a year
+        assert_eq!(ttl(&response(&[("cache-control", "max-age=18446744073709551615")]), &[]), Some(MAX_AGE));
+        assert_eq!(ttl(&response(&[("date", "Sun, 06 Nov 1994 08:49:37 GMT"), ("expires", "Fri, 31 Dec 9999 23:59:59 GMT")]), &[]), Some(MAX_AGE));
+        for headers in [
+            &[][..],
+            &[("cache-control", "max-age=0")],
+            &[("cache-control", "max-age=soon")],
+            &[("date", "Sun, 06 Nov 1994 08:49:37 GMT"), ("expires", "Sun, 06 Nov 1994 08:48:37 GMT")],
+            &[("expires", "0")],
+        ] {
+            assert_eq!(ttl(&response(headers), &[]), None, "{:?}", headers);
+        }
+        let mut partial = response(&[("cache-control", "max-age=60")]);
+        *partial.status_mut() = StatusCode::PARTIAL_CONTENT;
+        assert_eq!(ttl(&partial, &[]), None);
+    }
+
+    #[test]
+    fn never_stores_private_responses() {
+        for cache_control in ["private", "no-store", "no-cache", "max-age=60, private", "public, max-age=60, no-store", "PRIVATE, max-age=60"] {
+            assert_eq!(ttl(&response(&[("cache-control", cache_control)]), &[]), None, "{}", cache_control);
+        }
+        let mut split = response(&[("cache-control", "max-age=60")]);
+        split.headers_mut().append(CACHE_CONTROL, HeaderValue::from_static("private"));
+        assert_eq!(ttl(&split, &[]), None);
+        assert_eq!(ttl(&response(&[("cache-control", "public, max-age=60"), ("set-cookie", "session=1")]), &[]), None);
+    }
+
+    #[test]
+    fn shares_authenticated_responses_only_when_allowed() {
+        let authorized = [("authorization", "Bearer token")];
+        assert_eq!(ttl(&response(&[("cache-control", "max-age=60")]), &authorized), None);
+        assert_eq!(ttl(&response(&[("cache-control", "public, max-age=60")]), &authorized), Some(Duration::from_secs(60)));
+        assert_eq!(ttl(&response(&[("cache-control", "s-maxage=60")]), &authorized), Some(Duration::from_secs(60)));
+        assert_eq!(ttl(&response(&[("cache-control", "public, private, max-age=60")]), &authorized), None);
+    }
+
+    #[test]
+    fn matches_vary_values() {
+        let res = response(&[("cache-control", "max-age=60"), ("vary", "Accept-Encoding, accept-language")]);
+        let (_, vary) = cacheability(&res, &request_headers(&[("accept-encoding", "gzip"), ("user-agent", "curl")])).unwrap();
+        assert_eq!(vary, [(HeaderName::from_static("accept-encoding"), Some(HeaderValue::from_static("gzip"))), (HeaderName::from_static("accept-language"), None)]);
+
+        let now = Instant::now();
+        let entry = Entry {
+            status: StatusCode::OK,
+            headers: HeaderMap::new(),
+            body: Bytes::new(),
+            vary,
+            surrogate_keys: Vec::new(),
+            stored_at: now,
+            fresh_until: now,
+            stale_while_revalidate: Duration::ZERO,
+            stale_if_error: Duration::ZERO,
+            revalidating: None,
+            tick: 0,
+        };
+        assert!(!entry.varies_from(&request_headers(&[("accept-encoding", "gzip")])));
+        assert!(!entry.varies_from(&request_headers(&[("accept-encoding", "gzip"), ("user-agent", "wget")])));
+        assert!(entry.varies_from(&request_headers(&[("accept-encoding", "br")])));
+        assert!(entry.varies_from(&request_headers(&[])));
+        assert!(entry.varies_from(&request_headers(&[("accept-encoding", "gzip"), ("accept-language", "de")])));
+
+        assert!(cacheability(&response(&[("cache-control", "max-age=60"), ("vary", "*")]), &HeaderMap::new()).is_none());
+        assert!(cacheability(&response(&[("cache-control", "max-age=60"), ("vary", "accept, bad header")]), &HeaderMap::new()).is_none());
+    }
+
+    #[test]
+    fn endless_allowances_do_not_overflow() {
+        let now = Instant::now();
+        let mut entry = Entry {
+            status: StatusCode::OK,
+            headers: HeaderMap::new(),
+            body: Bytes::new(),
+            vary: Vec::new(),
+            surrogate_keys: Vec::new(),
+            stored_at: now,
+            fresh_until: now,
+            stale_while_revalidate: Duration::MAX,
+            stale_if_error: Duration::MAX,
+            revalidating: None,
+            tick: 0,
+        };
+        assert!(entry.usable_until() > now);
+        assert!(matches!(entry.freshness(now), Some(Freshness::Stale { revalidate: true })));
+        assert!(entry.stands_in_for_errors(now));
+    }
+
+    #[test]
+    fn country_routes_are_cached_per_country() {
+        let route = Route { countries: vec!["DE".to_string()], ..crate::router::tests::route("/shop") };
+        let cache = Cache::new(CacheSettings { enabled: true, ..CacheSettings::default() }.cache().unwrap()).with_router(Router::new(vec![route]));
+        let ctx = Context::new(([192, 0, 2, 1], 4000).into(), false);
+        let key = |path: &str, country: Option<&str>| {
+            let mut req = Request::get(path).header(HOST, "example.com").body(Body::empty()).unwrap();
+            req.extensions_mut().insert(GeoInfo { country: country.map(str::to_string), asn: None });
+            cache.cache_key(&req, &ctx)
+        };
+        assert_eq!(key("/shop/cart", Some("DE")), "default\nexample.com/shop/cart\ncountry DE");
+        // Everyone else falls through to another route
+        assert_eq!(key("/shop/cart", Some("US")), "default\nexample.com/shop/cart");
+        assert_eq!(key("/shop/cart", None), "default\nexample.com/shop/cart");
+        assert_eq!(key("/about", Some("DE")), "default\nexample.com/about");
+    }
+
+    #[test]
+    fn listeners_are_cached_apart_and_purged_together() {
+        let cache = Cache::new(CacheSettings { enabled: true, ..CacheSettings::default() }.cache().unwrap());
+        let req = Request::get("/page?id=1").header(HOST, "example.com").body(Body::empty()).unwrap();
+        let mut ctx = Context::new(([192, 0, 2, 1], 4000).into(), false);
+        let default = cache.cache_key(&req, &ctx);
+        ctx.listener = Arc::from("internal");
+        let internal = cache.cache_key(&req, &ctx);
+        assert_eq!((default.as_str(), internal.as_str()), ("default\nexample.com/page?id=1", "internal\nexample.com/page?id=1"));
+
+        let now = Instant::now();
+        let entry = Entry {
+            status: StatusCode::OK,
+            headers: HeaderMap::new(),
+            body: Bytes::new(),
+            vary: Vec::new(),
+            surrogate_keys: Vec::new(),
+            stored_at: now,
+            fresh_until: now,
+            stale_while_revalidate: Duration::ZERO,
+            stale_if_error: Duration::ZERO,
+            revalidating: None,
+            tick: 0,
+        };
+        for key in [&default, &internal] {
+            assert!(Purge::Key("example.com/page?id=1".to_string()).matches(key, &entry), "{}", key);
+            assert!(Purge::Prefix("example.com/pa".to_string()).matches(key, &entry), "{}", key);
+            assert!(!Purge::Key("example.com/page".to_string()).matches(key, &entry), "{}", key);
+            assert!(!Purge::Prefix("internal".to_string()).matches(key, &entry), "{}", key);
+        }
+    }
+}
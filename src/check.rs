@@ -0,0 +1,85 @@
+use futures_util::future::join_all;
+use hyper::Uri;
+use std::collections::BTreeSet;
+use std::time::Duration;
+
+use crate::config::{Config, Overrides, UpstreamsConfig, DEFAULT_POOL};
+use crate::error_pages::ErrorPages;
+use crate::geoip::GeoIp;
+use crate::tls;
+
+/// How long resolving an upstream's hostname may take.
+const RESOLVE_TIMEOUT: Duration = Duration::from_secs(5);
+
+/// Checks a configuration as far as possible without binding any port:
+/// that it loads and validates, that certificates, keys and other files it
+/// names can be read, that upstream URLs parse and that their hostnames
+/// resolve. Returns every problem found rather than stopping at the first.
+pub async fn check(config_path: Option<&str>, overrides: &Overrides) -> Vec<String> {
+    let config = match Config::read_with(config_path, overrides) {
+        Ok(config) => config,
+        Err(e) => return vec![e],
+    };
+    let mut problems = Vec::new();
+    if let Err(e) = config.validate() {
+        problems.push(e);
+    }
+
+    if config.serves_tls() {
+        if let Err(e) = tls::load_acceptor(&config.tls) {
+            problems.push(format!("TLS: {}", e));
+        }
+    }
+    if let Err(e) = ErrorPages::load(&config.error_pages) {
+        problems.push(e);
+    }
+    if config.geoip.enabled {
+        if let Err(e) = GeoIp::open(&config.geoip) {
+            problems.push(e);
+        }
+    }
+
+    let mut hosts = BTreeSet::new();
+    let pools = std::iter::once((DEFAULT_POOL, &config.upstreams)).chain(config.pools.iter().map(|(name, pool)| (name.as_str(), pool)));
+    for (name, pool) in pools {
+        check_pool(name, pool, &mut hosts, &mut problems);
+    }
+    let lookups = hosts.into_iter().map(|(host, port)| async move {
+        match tokio::time::timeout(RESOLVE_TIMEOUT, tokio::net::lookup_host((host.as_str(), port))).await.map(|resolved| resolved.map(|mut addrs| addrs.next())) {
+            Ok(Ok(Some(_))) => None,
+            Ok(Ok(None)) => Some(format!("upstream host {} resolves to no addresses", host)),
+            Ok(Err(e)) => Some(format!("upstream host {} does not resolve: {}", host, e)),
+            Err(_) => Some(format!("upstream host {} did not resolve within {} seconds", host, RESOLVE_TIMEOUT.as_secs())),
+        }
+    });
+    problems.extend(join_all(lookups).await.into_iter().flatten());
+    problems
+}
+
+/// Checks the static upstreams of a pool and its upstream TLS settings,
+/// collecting the hostnames to resolve.
+fn check_pool(name: &str, pool: &UpstreamsConfig, hosts: &mut BTreeSet<(String, u16)>, problems: &mut Vec<String>) {
+    if let Err(e) = tls::upstream_connector(pool, None) {
+        problems.push(format!("pool {}: {}", name, e));
+    }
+    // Discovered upstreams are not known until Riffy runs
+    if pool.discovers_upstreams() {
+        return;
+    }
+    let upstreams = match pool.build_upstreams() {
+        Ok(upstreams) => upstreams,
+        Err(e) => return problems.push(format!("pool {}: {}", name, e)),
+    };
+    // Their URLs were checked as they were built
+    for uri in upstreams.iter().filter(|upstream| upstream.unix_socket().is_none()).filter_map(|upstream| upstream.request_url().parse::<Uri>().ok()) {
+        match uri.host() {
+            // IP addresses need no resolving
+            Some(host) if host.starts_with('[') || host.parse::<std::net::IpAddr>().is_ok() => {}
+            Some(host) => {
+                let port = uri.port_u16().unwrap_or(if uri.scheme_str() == Some("https") { 443 } else { 80 });
+                hosts.insert((host.to_string(), port));
+            }
+            None => {}
+        }
+    }
+}
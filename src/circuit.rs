@@ -0,0 +1,311 @@
+use std::sync::Mutex;
+use std::time::{Duration, Instant};
+use tracing::{info, warn};
+
+/// Thresholds for opening and closing a per-upstream circuit breaker.
+#[derive(Debug, Clone, Copy)]
+pub struct CircuitBreakerConfig {
+    /// Consecutive failures that open the circuit
+    pub consecutive_failures: u32,
+    /// Failure ratio within `window` that opens the circuit, once `min_requests` were seen
+    pub error_rate: Option<f64>,
+    pub min_requests: u32,
+    pub window: Duration,
+    /// How long the circuit stays open before trial requests are let through
+    pub open_duration: Duration,
+    /// Concurrent trial requests allowed while half-open
+    pub half_open_requests: u32,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq)]
+enum State {
+    Closed,
+    Open { until: Instant },
+    HalfOpen { in_flight: u32, limit: u32 },
+}
+
+/// A trial request let through a half-open circuit, to be given back with
+/// [`CircuitBreaker::abandon`] if it ends without a recorded result.
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct Trial {
+    /// The half-open period it belongs to
+    period: u64,
+}
+
+#[derive(Debug)]
+struct Inner {
+    state: State,
+    /// Counts the times the circuit turned half-open
+    half_open_periods: u64,
+    consecutive_failures: u32,
+    window_start: Instant,
+    window_requests: u32,
+    window_failures: u32,
+}
+
+/// Closed -> open on too many failures, open -> half-open after a cool-down,
+/// and half-open -> closed (or back to open) depending on the trial requests.
+#[derive(Debug)]
+pub struct CircuitBreaker {
+    inner: Mutex<Inner>,
+}
+
+impl Default for CircuitBreaker {
+    fn default() -> Self {
+        CircuitBreaker {
+            inner: Mutex::new(Inner {
+                state: State::Closed,
+                half_open_periods: 0,
+                consecutive_failures: 0,
+                window_start: Instant::now(),
+                window_requests: 0,
+                window_failures: 0,
+            }),
+        }
+    }
+}
+
+impl CircuitBreaker {
+    /// Whether a new request may be sent through this circuit.
+    pub fn allows_request(&self) -> bool {
+        match self.inner.lock().unwrap().state {
+            State::Closed => true,
+            State::Open { until } => Instant::now() >= until,
+            State::HalfOpen { in_flight, limit } => in_flight < limit,
+        }
+    }
+
+    /// Whether the circuit is currently rejecting traffic.
+    pub fn is_open(&self) -> bool {
+        matches!(self.inner.lock().unwrap().state, State::Open { until } if Instant::now() < until)
+    }
+
+    /// Registers a request being sent, turning an expired open circuit
+    /// half-open. Returns the trial the request takes up while half-open.
+    pub fn on_dispatch(&self, config: &CircuitBreakerConfig) -> Option<Trial> {
+        let mut inner = self.inner.lock().unwrap();
+        match inner.state {
+            State::Open { until } if Instant::now() >= until => {
+                inner.state = State::HalfOpen { in_flight: 1, limit: config.half_open_requests.max(1) };
+                inner.half_open_periods += 1;
+            }
+            State::HalfOpen { in_flight, limit } => {
+                inner.state = State::HalfOpen { in_flight: in_flight + 1, limit };
+            }
+            _ => return None,
+        }
+        Some(Trial { period: inner.half_open_periods })
+    }
+
+    /// Gives back the slot of a trial that ended without a result, e.g. as
+    /// the client went away, so it does not hold the circuit half-open for
+    /// good. Does nothing once a result moved the circuit on.
+    pub fn abandon(&self, trial: Trial) {
+        let mut inner = self.inner.lock().unwrap();
+        if let State::HalfOpen { in_flight, limit } = inner.state {
+            if trial.period == inner.half_open_periods {
+                inner.state = State::HalfOpen { in_flight: in_flight.saturating_sub(1), limit };
+            }
+        }
+    }
+
+    /// Records the outcome of a request and updates the circuit state.
+    pub fn record(&self, success: bool, config: &CircuitBreakerConfig, upstream: &str) {
+        let now = Instant::now();
+        let mut inner = self.inner.lock().unwrap();
+
+        match inner.state {
+            State::HalfOpen { .. } => {
+                // The first trial result decides; other trials still in flight are ignored
+                if success {
+                    info!("Circuit for {} closed after successful trial request", upstream);
+                    inner.reset(State::Closed, now);
+                } else {
+                    warn!("Circuit for {} re-opened after failed trial request", upstream);
+                    inner.reset(State::Open { until: now + config.open_duration }, now);
+                }
+            }
+            State::Open { .. } => {}
+            State::Closed => {
+                if now.duration_since(inner.window_start) > config.window {
+                    inner.window_start = now;
+                    inner.window_requests = 0;
+                    inner.window_failures = 0;
+                }
+                inner.window_requests += 1;
+
+                if success {
+                    inner.consecutive_failures = 0;
+                    return;
+                }
+
+                inner.window_failures += 1;
+                inner.consecutive_failures += 1;
+
+                let too_many_consecutive = config.consecutive_failures > 0 && inner.consecutive_failures >= config.consecutive_failures;
+                let error_rate_exceeded = match config.error_rate {
+                    Some(rate) => inner.window_requests >= config.min_requests
+                        && inner.window_failures as f64 / inner.window_requests as f64 >= rate,
+                    None => false,
+                };
+
+                if too_many_consecutive || error_rate_exceeded {
+                    warn!("Circuit for {} opened for {:?}", upstream, config.open_duration);
+                    inner.reset(State::Open { until: now + config.open_duration }, now);
+                }
+            }
+        }
+    }
+}
+
+impl Inner {
+    fn reset(&mut self, state: State, now: Instant) {
+        self.state = state;
+        self.consecutive_failures = 0;
+        self.window_start = now;
+        self.window_requests = 0;
+        self.window_failures = 0;
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const CONFIG: CircuitBreakerConfig = CircuitBreakerConfig {
+        consecutive_failures: 3,
+        error_rate: None,
+        min_requests: 0,
+        window: Duration::from_secs(60),
+        open_duration: Duration::from_secs(30),
+        half_open_requests: 2,
+    };
+
+    fn state(breaker: &CircuitBreaker) -> State {
+        breaker.inner.lock().unwrap().state
+    }
+
+    /// Lets an open circuit's cool-down run out.
+    fn cool_down(breaker: &CircuitBreaker) {
+        let mut inner = breaker.inner.lock().unwrap();
+        assert!(matches!(inner.state, State::Open { .. }));
+        inner.state = State::Open { until: Instant::now() };
+    }
+
+    #[test]
+    fn opens_after_consecutive_failures() {
+        let breaker = CircuitBreaker::default();
+        for success in [false, false, true, false, false] {
+            breaker.record(success, &CONFIG, "a");
+        }
+        assert_eq!(state(&breaker), State::Closed);
+        assert!(breaker.allows_request());
+        breaker.record(false, &CONFIG, "a");
+        assert!(breaker.is_open());
+        assert!(!breaker.allows_request());
+        // Results of requests sent before it opened change nothing
+        breaker.record(true, &CONFIG, "a");
+        assert!(breaker.is_open());
+    }
+
+    #[test]
+    fn opens_on_the_error_rate_once_enough_requests_were_seen() {
+        let config = CircuitBreakerConfig { consecutive_failures: 0, error_rate: Some(0.5), min_requests: 4, ..CONFIG };
+        let breaker = CircuitBreaker::default();
+        for success in [false, true, false] {
+            breaker.record(success, &config, "a");
+        }
+        assert_eq!(state(&breaker), State::Closed);
+        breaker.record(true, &config, "a");
+        assert_eq!(state(&breaker), State::Closed);
+        breaker.record(false, &config, "a");
+        assert!(breaker.is_open());
+    }
+
+    #[test]
+    fn forgets_failures_from_past_windows() {
+        let config = CircuitBreakerConfig { consecutive_failures: 0, error_rate: Some(0.5), min_requests: 2, ..CONFIG };
+        let breaker = CircuitBreaker::default();
+        breaker.record(false, &config, "a");
+        breaker.inner.lock().unwrap().window_start -= Duration::from_secs(61);
+        breaker.record(true, &config, "a");
+        breaker.record(true, &config, "a");
+        breaker.record(false, &config, "a");
+        assert_eq!(state(&breaker), State::Closed);
+    }
+
+    #[test]
+    fn half_open_lets_a_few_trials_through() {
+        let breaker = CircuitBreaker::default();
+        (0..3).for_each(|_| breaker.record(false, &CONFIG, "a"));
+        cool_down(&breaker);
+        assert!(!breaker.is_open());
+        assert!(breaker.allows_request());
+        breaker.on_dispatch(&CONFIG);
+        assert_eq!(state(&breaker), State::HalfOpen { in_flight: 1, limit: 2 });
+        assert!(breaker.allows_request());
+        breaker.on_dispatch(&CONFIG);
+        assert!(!breaker.allows_request());
+    }
+
+    #[test]
+    fn a_successful_trial_closes_the_circuit() {
+        let breaker = CircuitBreaker::default();
+        (0..3).for_each(|_| breaker.record(false, &CONFIG, "a"));
+        cool_down(&breaker);
+        breaker.on_dispatch(&CONFIG);
+        breaker.record(true, &CONFIG, "a");
+        assert_eq!(state(&breaker), State::Closed);
+        // Starting the count again
+        (0..2).for_each(|_| breaker.record(false, &CONFIG, "a"));
+        assert_eq!(state(&breaker), State::Closed);
+    }
+
+    #[test]
+    fn a_failed_trial_opens_it_again() {
+        let breaker = CircuitBreaker::default();
+        (0..3).for_each(|_| breaker.record(false, &CONFIG, "a"));
+        cool_down(&breaker);
+        breaker.on_dispatch(&CONFIG);
+        breaker.on_dispatch(&CONFIG);
+        breaker.record(false, &CONFIG, "a");
+        assert!(breaker.is_open());
+        // The other trial finishing later does not close it
+        breaker.record(true, &CONFIG, "a");
+        assert!(breaker.is_open());
+    }
+
+    #[test]
+    fn an_abandoned_trial_frees_its_slot() {
+        let config = CircuitBreakerConfig { half_open_requests: 1, ..CONFIG };
+        let breaker = CircuitBreaker::default();
+        (0..3).for_each(|_| breaker.record(false, &config, "a"));
+        cool_down(&breaker);
+        let trial = breaker.on_dispatch(&config).expect("a trial");
+        assert!(!breaker.allows_request());
+        breaker.abandon(trial);
+        assert!(breaker.allows_request());
+        let next = breaker.on_dispatch(&config).expect("a trial");
+        assert_eq!(state(&breaker), State::HalfOpen { in_flight: 1, limit: 1 });
+
+        // Once a result moved the circuit on, trials from before change nothing
+        breaker.record(false, &config, "a");
+        cool_down(&breaker);
+        breaker.on_dispatch(&config);
+        breaker.abandon(next);
+        breaker.abandon(trial);
+        assert_eq!(state(&breaker), State::HalfOpen { in_flight: 1, limit: 1 });
+        breaker.record(true, &config, "a");
+        assert_eq!(breaker.on_dispatch(&config), None);
+    }
+
+    #[test]
+    fn half_open_allows_at_least_one_trial() {
+        let config = CircuitBreakerConfig { half_open_requests: 0, ..CONFIG };
+        let breaker = CircuitBreaker::default();
+        (0..3).for_each(|_| breaker.record(false, &config, "a"));
+        cool_down(&breaker);
+        breaker.on_dispatch(&config);
+        assert_eq!(state(&breaker), State::HalfOpen { in_flight: 1, limit: 1 });
+    }
+}
@@ -0,0 +1,203 @@
+use async_compression::tokio::bufread::{BrotliEncoder, GzipEncoder};
+use async_trait::async_trait;
+use futures_util::TryStreamExt;
+use hyper::body::HttpBody;
+use hyper::header::{HeaderMap, HeaderValue, ACCEPT_ENCODING, ACCEPT_RANGES, CACHE_CONTROL, CONTENT_ENCODING, CONTENT_LENGTH, CONTENT_RANGE, CONTENT_TYPE, ETAG, VARY};
+use hyper::{Body, Method, Request, Response, StatusCode};
+use std::io;
+use tokio_util::io::{ReaderStream, StreamReader};
+
+use crate::headers::EventStream;
+use crate::middleware::{Context, Middleware};
+
+#[derive(Debug, Clone, Copy, PartialEq)]
+enum Encoding {
+    Brotli,
+    Gzip,
+}
+
+impl Encoding {
+    fn name(self) -> &'static str {
+        match self {
+            Encoding::Brotli => "br",
+            Encoding::Gzip => "gzip",
+        }
+    }
+}
+
+/// The encoding chosen for the client, carried from `on_request` to `on_response`.
+struct Accepted(Option<Encoding>);
+
+/// Compresses responses with Brotli or gzip according to the client's
+/// `Accept-Encoding`.
+#[derive(Debug)]
+pub struct Compression {
+    min_size: u64,
+    /// Content-type prefixes eligible for compression, lowercased
+    content_types: Vec<String>,
+}
+
+impl Compression {
+    pub fn new(min_size: u64, content_types: &[String]) -> Self {
+        Compression { min_size, content_types: content_types.iter().map(|t| t.trim().to_ascii_lowercase()).collect() }
+    }
+
+    /// Whether the response could be compressed for some client.
+    fn eligible(&self, res: &Response<Body>) -> bool {
+        let status = res.status();
+        if status.is_informational() || status == StatusCode::NO_CONTENT || status == StatusCode::NOT_MODIFIED || status == StatusCode::SWITCHING_PROTOCOLS {
+            return false;
+        }
+        // The encoders hold output back until they have a block's worth, delaying events
+        if res.extensions().get::<EventStream>().is_some() {
+            return false;
+        }
+        // A compressed slice would no longer match its Content-Range
+        if status == StatusCode::PARTIAL_CONTENT || res.headers().contains_key(CONTENT_RANGE) {
+            return false;
+        }
+        let headers = res.headers();
+        if headers.contains_key(CONTENT_ENCODING) || has_directive(headers, "no-transform") {
+            return false;
+        }
+        let content_type = match headers.get(CONTENT_TYPE).and_then(|v| v.to_str().ok()) {
+            Some(content_type) => content_type.to_ascii_lowercase(),
+            None => return false,
+        };
+        if !self.content_types.iter().any(|t| content_type.starts_with(t.as_str())) {
+            return false;
+        }
+        // Bodies of unknown length, e.g. chunked, are compressed
+        res.body().size_hint().exact().is_none_or(|len| len >= self.min_size)
+    }
+}
+
+#[async_trait]
+impl Middleware for Compression {
+    async fn on_request(&self, req: &mut Request<Body>, ctx: &mut Context) -> Option<Response<Body>> {
+        // Nobody reads the body of a background request
+        if ctx.background {
+            return None;
+        }
+        let encoding = if req.method() == Method::HEAD { None } else { negotiate(req.headers()) };
+        ctx.extensions.insert(Accepted(encoding));
+        None
+    }
+
+    async fn on_response(&self, res: &mut Response<Body>, ctx: &mut Context) {
+        let encoding = match ctx.extensions.remove::<Accepted>() {
+            Some(Accepted(encoding)) => encoding,
+            None => return,
+        };
+        if !self.eligible(res) {
+            return;
+        }
+        // The representation depends on Accept-Encoding whether or not this client gets it compressed
+        add_vary(res.headers_mut());
+        let encoding = match encoding {
+            Some(encoding) => encoding,
+            None => return,
+        };
+
+        let headers = res.headers_mut();
+        headers.remove(CONTENT_LENGTH);
+        headers.remove(ACCEPT_RANGES);
+        headers.insert(CONTENT_ENCODING, HeaderValue::from_static(encoding.name()));
+        // The compressed bytes differ, so a strong validator no longer applies
+        if let Some(etag) = headers.get(ETAG).and_then(|v| v.to_str().ok()).filter(|e| !e.starts_with("W/")) {
+            if let Ok(weak) = HeaderValue::from_str(&format!("W/{}", etag)) {
+                headers.insert(ETAG, weak);
+            }
+        }
+
+        let body = std::mem::replace(res.body_mut(), Body::empty());
+        let reader = StreamReader::new(TryStreamExt::map_err(body, io::Error::other));
+        *res.body_mut() = match encoding {
+            Encoding::Brotli => Body::wrap_stream(ReaderStream::new(BrotliEncoder::new(reader))),
+            Encoding::Gzip => Body::wrap_stream(ReaderStream::new(GzipEncoder::new(reader))),
+        };
+    }
+}
+
+/// Picks the supported encoding with the highest q-value, preferring Brotli on ties.
+fn negotiate(headers: &HeaderMap) -> Option<Encoding> {
+    let mut best: Option<(Encoding, f32)> = None;
+    let mut consider = |encoding: Encoding, q: f32| {
+        if q > 0.0 && best.is_none_or(|(current, best_q)| q > best_q || (q == best_q && encoding == Encoding::Brotli && current != Encoding::Brotli)) {
+            best = Some((encoding, q));
+        }
+    };
+
+    let mut explicit = Vec::new();
+    let mut wildcard = None;
+    for item in headers.get_all(ACCEPT_ENCODING).iter().filter_map(|v| v.to_str().ok()).flat_map(|v| v.split(',')) {
+        let mut parts = item.split(';');
+        let name = parts.next().unwrap_or("").trim().to_ascii_lowercase();
+        let q = parts
+            .filter_map(|p| p.trim().strip_prefix("q="))
+            .find_map(|q| q.trim().parse::<f32>().ok())
+            .unwrap_or(1.0);
+        match name.as_str() {
+            "br" => explicit.push((Encoding::Brotli, q)),
+            "gzip" | "x-gzip" => explicit.push((Encoding::Gzip, q)),
+            "*" => wildcard = Some(q),
+            _ => {}
+        }
+    }
+
+    for &(encoding, q) in &explicit {
+        consider(encoding, q);
+    }
+    // `*` applies to encodings not listed explicitly
+    if let Some(q) = wildcard {
+        for encoding in [Encoding::Brotli, Encoding::Gzip] {
+            if !explicit.iter().any(|(e, _)| *e == encoding) {
+                consider(encoding, q);
+            }
+        }
+    }
+    best.map(|(encoding, _)| encoding)
+}
+
+fn add_vary(headers: &mut HeaderMap) {
+    let present = headers
+        .get_all(VARY)
+        .iter()
+        .filter_map(|v| v.to_str().ok())
+        .flat_map(|v| v.split(','))
+        .any(|name| name.trim() == "*" || name.trim().eq_ignore_ascii_case("accept-encoding"));
+    if !present {
+        headers.append(VARY, HeaderValue::from_static("Accept-Encoding"));
+    }
+}
+
+fn has_directive(headers: &HeaderMap, directive: &str) -> bool {
+    headers
+        .get_all(CACHE_CONTROL)
+        .iter()
+        .filter_map(|v| v.to_str().ok())
+        .flat_map(|v| v.split(','))
+        .any(|d| d.trim().eq_ignore_ascii_case(directive))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn response(status: StatusCode, headers: &[(&'static str, &'static str)]) -> Response<Body> {
+        let mut res = Response::builder().status(status).header(CONTENT_TYPE, "text/html");
+        for (name, value) in headers {
+            res = res.header(*name, *value);
+        }
+        res.body(Body::from("x".repeat(2048))).unwrap()
+    }
+
+    #[test]
+    fn ranges_are_not_compressed() {
+        let compression = Compression::new(1024, &["text/".to_string()]);
+        assert!(compression.eligible(&response(StatusCode::OK, &[])));
+        assert!(!compression.eligible(&response(StatusCode::PARTIAL_CONTENT, &[("content-range", "bytes 0-2047/4096")])));
+        assert!(!compression.eligible(&response(StatusCode::PARTIAL_CONTENT, &[])));
+        assert!(!compression.eligible(&response(StatusCode::RANGE_NOT_SATISFIABLE, &[("content-range", "bytes */4096")])));
+    }
+}
@@ -0,0 +1,229 @@
+use async_trait::async_trait;
+use hyper::header::{HeaderMap, CONTENT_ENCODING, CONTENT_LENGTH, CONTENT_TYPE, ETAG, IF_MODIFIED_SINCE, IF_NONE_MATCH, LAST_MODIFIED, TRANSFER_ENCODING};
+use hyper::{Body, Method, Request, Response, StatusCode};
+use std::time::SystemTime;
+
+use crate::middleware::{Context, Middleware};
+
+/// The conditions of a GET or HEAD request, checked against its response.
+struct Conditions {
+    if_none_match: Option<IfNoneMatch>,
+    if_modified_since: Option<SystemTime>,
+}
+
+enum IfNoneMatch {
+    /// `*`, met by any response
+    Any,
+    /// Entity tags without any `W/`
+    Tags(Vec<String>),
+}
+
+impl Conditions {
+    fn of(req: &Request<Body>) -> Option<Self> {
+        if req.method() != Method::GET && req.method() != Method::HEAD {
+            return None;
+        }
+        let headers = req.headers();
+        let if_none_match = headers.contains_key(IF_NONE_MATCH).then(|| {
+            let values: Vec<&str> = headers.get_all(IF_NONE_MATCH).iter().filter_map(|v| v.to_str().ok()).collect();
+            if values.iter().any(|v| v.trim() == "*") {
+                IfNoneMatch::Any
+            } else {
+                IfNoneMatch::Tags(values.iter().flat_map(|v| entity_tags(v)).collect())
+            }
+        });
+        let if_modified_since = headers.get(IF_MODIFIED_SINCE).and_then(|v| httpdate::parse_http_date(v.to_str().ok()?).ok());
+        (if_none_match.is_some() || if_modified_since.is_some()).then_some(Conditions { if_none_match, if_modified_since })
+    }
+
+    /// Whether the client already has the response, by RFC 9110: `If-None-Match`
+    /// is compared weakly with the ETag, and `If-Modified-Since` only counts
+    /// without it.
+    fn not_modified(&self, res: &Response<Body>) -> bool {
+        if res.status() != StatusCode::OK {
+            return false;
+        }
+        if let Some(if_none_match) = &self.if_none_match {
+            let tags = match if_none_match {
+                IfNoneMatch::Any => return true,
+                IfNoneMatch::Tags(tags) => tags,
+            };
+            let etag = res.headers().get(ETAG).and_then(|v| v.to_str().ok()).and_then(|v| entity_tags(v).into_iter().next());
+            return etag.is_some_and(|etag| tags.contains(&etag));
+        }
+        match (self.if_modified_since, last_modified(res.headers())) {
+            (Some(since), Some(modified)) => modified <= since,
+            _ => false,
+        }
+    }
+}
+
+/// Middleware answering conditional GET and HEAD requests with `304 Not
+/// Modified` when the client's copy is current, for cached and proxied
+/// responses alike. It runs just before the cache, which leaves the
+/// conditions out of the requests it sends upstream so that full responses
+/// come back to be stored.
+pub struct ConditionalRequests;
+
+#[async_trait]
+impl Middleware for ConditionalRequests {
+    async fn on_request(&self, req: &mut Request<Body>, ctx: &mut Context) -> Option<Response<Body>> {
+        if let Some(conditions) = Conditions::of(req) {
+            ctx.extensions.insert(conditions);
+        }
+        None
+    }
+
+    async fn on_response(&self, res: &mut Response<Body>, ctx: &mut Context) {
+        let conditions = match ctx.extensions.remove::<Conditions>() {
+            Some(conditions) => conditions,
+            None => return,
+        };
+        if !conditions.not_modified(res) {
+            return;
+        }
+        *res.status_mut() = StatusCode::NOT_MODIFIED;
+        *res.body_mut() = Body::empty();
+        for name in [CONTENT_LENGTH, CONTENT_TYPE, CONTENT_ENCODING, TRANSFER_ENCODING] {
+            res.headers_mut().remove(name);
+        }
+    }
+}
+
+/// The entity tags in a header value, quoted and without any `W/`.
+fn entity_tags(value: &str) -> Vec<String> {
+    let mut tags = Vec::new();
+    let mut rest = value;
+    loop {
+        rest = rest.trim_start_matches(|c: char| c == ',' || c.is_whitespace());
+        rest = rest.strip_prefix("W/").unwrap_or(rest);
+        let end = match rest.strip_prefix('"').and_then(|tag| tag.find('"')) {
+            Some(end) => end + 2,
+            None => return tags,
+        };
+        tags.push(rest[..end].to_string());
+        rest = &rest[end..];
+    }
+}
+
+fn last_modified(headers: &HeaderMap) -> Option<SystemTime> {
+    httpdate::parse_http_date(headers.get(LAST_MODIFIED)?.to_str().ok()?).ok()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::time::Duration;
+
+    const MODIFIED: &str = "Wed, 21 Oct 2015 07:28:00 GMT";
+    const EARLIER: &str = "Tue, 20 Oct 2015 07:28:00 GMT";
+
+    fn request(method: Method, headers: &[(&str, &str)]) -> Request<Body> {
+        let mut req = Request::builder().method(method).uri("/page");
+        for (name, value) in headers {
+            req = req.header(*name, *value);
+        }
+        req.body(Body::empty()).unwrap()
+    }
+
+    fn response(headers: &[(&str, &str)]) -> Response<Body> {
+        let mut res = Response::builder().header(CONTENT_TYPE, "text/html").header(CONTENT_LENGTH, "5");
+        for (name, value) in headers {
+            res = res.header(*name, *value);
+        }
+        res.body(Body::from("hello")).unwrap()
+    }
+
+    /// Runs `req` and `res` through the middleware, returning the response.
+    async fn answer(mut req: Request<Body>, mut res: Response<Body>) -> Response<Body> {
+        let mut ctx = Context::new(([192, 0, 2, 1], 4000).into(), false);
+        assert!(ConditionalRequests.on_request(&mut req, &mut ctx).await.is_none());
+        ConditionalRequests.on_response(&mut res, &mut ctx).await;
+        res
+    }
+
+    async fn status(req: &[(&str, &str)], res: &[(&str, &str)]) -> StatusCode {
+        answer(request(Method::GET, req), response(res)).await.status()
+    }
+
+    #[test]
+    fn parses_entity_tags() {
+        assert_eq!(entity_tags(r#""abc""#), [r#""abc""#]);
+        assert_eq!(entity_tags(r#"W/"abc""#), [r#""abc""#]);
+        assert_eq!(entity_tags(r#" "a", W/"b" ,"c,d""#), [r#""a""#, r#""b""#, r#""c,d""#]);
+        assert_eq!(entity_tags(r#""""#), [r#""""#]);
+        // Unquoted tags are not entity tags; what comes before them still counts
+        assert!(entity_tags("abc").is_empty());
+        assert!(entity_tags("*").is_empty());
+        assert_eq!(entity_tags(r#""a", b, "c""#), [r#""a""#]);
+        assert!(entity_tags(r#""unterminated"#).is_empty());
+    }
+
+    #[tokio::test]
+    async fn matches_entity_tags_weakly() {
+        let etag = [(ETAG.as_str(), r#""v1""#)];
+        assert_eq!(status(&[("if-none-match", r#""v1""#)], &etag).await, StatusCode::NOT_MODIFIED);
+        assert_eq!(status(&[("if-none-match", r#""v0", W/"v1""#)], &etag).await, StatusCode::NOT_MODIFIED);
+        assert_eq!(status(&[("if-none-match", r#""v0""#), ("if-none-match", r#""v1""#)], &etag).await, StatusCode::NOT_MODIFIED);
+        assert_eq!(status(&[("if-none-match", r#""v0""#)], &etag).await, StatusCode::OK);
+        assert_eq!(status(&[("if-none-match", "*")], &etag).await, StatusCode::NOT_MODIFIED);
+        // `*` is met by any response, with an ETag or not
+        assert_eq!(status(&[("if-none-match", "*")], &[]).await, StatusCode::NOT_MODIFIED);
+        assert_eq!(status(&[("if-none-match", r#""v1""#)], &[]).await, StatusCode::OK);
+    }
+
+    #[tokio::test]
+    async fn a_weakened_etag_from_compression_still_matches() {
+        // Compression turns "v1" into W/"v1", which clients then send back either way
+        let weak = [(ETAG.as_str(), r#"W/"v1""#), (CONTENT_ENCODING.as_str(), "gzip")];
+        assert_eq!(status(&[("if-none-match", r#"W/"v1""#)], &weak).await, StatusCode::NOT_MODIFIED);
+        assert_eq!(status(&[("if-none-match", r#""v1""#)], &weak).await, StatusCode::NOT_MODIFIED);
+        assert_eq!(status(&[("if-none-match", r#"W/"v2""#)], &weak).await, StatusCode::OK);
+    }
+
+    #[tokio::test]
+    async fn if_none_match_takes_precedence_over_if_modified_since() {
+        let res = [(ETAG.as_str(), r#""v2""#), (LAST_MODIFIED.as_str(), EARLIER)];
+        // The date alone would say not modified, but the tag says otherwise
+        assert_eq!(status(&[("if-none-match", r#""v1""#), ("if-modified-since", MODIFIED)], &res).await, StatusCode::OK);
+        // And the other way round
+        assert_eq!(status(&[("if-none-match", r#""v2""#), ("if-modified-since", "Mon, 01 Jan 2001 00:00:00 GMT")], &res).await, StatusCode::NOT_MODIFIED);
+    }
+
+    #[tokio::test]
+    async fn compares_modification_dates() {
+        let res = [(LAST_MODIFIED.as_str(), MODIFIED)];
+        assert_eq!(status(&[("if-modified-since", MODIFIED)], &res).await, StatusCode::NOT_MODIFIED);
+        let later = httpdate::fmt_http_date(httpdate::parse_http_date(MODIFIED).unwrap() + Duration::from_secs(1));
+        assert_eq!(status(&[("if-modified-since", &later)], &res).await, StatusCode::NOT_MODIFIED);
+        assert_eq!(status(&[("if-modified-since", EARLIER)], &res).await, StatusCode::OK);
+        assert_eq!(status(&[("if-modified-since", "yesterday")], &res).await, StatusCode::OK);
+        assert_eq!(status(&[("if-modified-since", MODIFIED)], &[]).await, StatusCode::OK);
+    }
+
+    #[tokio::test]
+    async fn leaves_other_requests_and_responses_alone() {
+        let conditions = [("if-none-match", "*")];
+        let res = answer(request(Method::POST, &conditions), response(&[])).await;
+        assert_eq!(res.status(), StatusCode::OK);
+        let mut not_found = response(&[]);
+        *not_found.status_mut() = StatusCode::NOT_FOUND;
+        assert_eq!(answer(request(Method::GET, &conditions), not_found).await.status(), StatusCode::NOT_FOUND);
+        assert_eq!(answer(request(Method::HEAD, &conditions), response(&[])).await.status(), StatusCode::NOT_MODIFIED);
+    }
+
+    #[tokio::test]
+    async fn not_modified_drops_the_body_and_entity_headers() {
+        let headers = [(ETAG.as_str(), r#""v1""#), (CONTENT_ENCODING.as_str(), "br"), ("cache-control", "max-age=60"), (LAST_MODIFIED.as_str(), MODIFIED)];
+        let res = answer(request(Method::GET, &[("if-none-match", r#""v1""#)]), response(&headers)).await;
+        assert_eq!(res.status(), StatusCode::NOT_MODIFIED);
+        for name in [CONTENT_LENGTH, CONTENT_TYPE, CONTENT_ENCODING, TRANSFER_ENCODING] {
+            assert!(!res.headers().contains_key(&name), "{}", name);
+        }
+        // What identifies and describes the current version stays
+        assert_eq!(res.headers()[ETAG], r#""v1""#);
+        assert_eq!(res.headers()["cache-control"], "max-age=60");
+        assert_eq!(res.headers()[LAST_MODIFIED], MODIFIED);
+        assert!(hyper::body::to_bytes(res.into_body()).await.unwrap().is_empty());
+    }
+}
@@ -0,0 +1,2466 @@
+use regex::Regex;
+use serde::{Deserialize, Deserializer};
+use std::collections::BTreeMap;
+use std::env;
+use std::fs;
+use std::net::{IpAddr, Ipv4Addr, SocketAddr};
+use std::path::{Component, Path};
+use std::str::FromStr;
+use std::time::Duration;
+
+use crate::acl::{self, AccessList, Cidr, ForwardedHeader};
+use crate::balancer::{self, ConcurrencyLimit, PassiveHealthConfig, Strategy, Upstream};
+use crate::cache::CacheConfig;
+use crate::circuit::CircuitBreakerConfig;
+use crate::headers::HeaderRuleSet;
+use crate::health::HealthCheckConfig;
+use crate::redirect::RedirectRule;
+use crate::retry::RetryPolicy;
+
+/// Top-level configuration, loaded from an optional TOML file and then
+/// overridden by environment variables.
+#[derive(Debug, Clone, Default, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct Config {
+    pub listener: ListenerConfig,
+    /// Further HTTP listeners, each serving the routes that name it
+    pub listeners: Vec<AdditionalListenerConfig>,
+    pub upstreams: UpstreamsConfig,
+    pub tls: TlsConfig,
+    pub timeouts: TimeoutsConfig,
+    pub admin: AdminConfig,
+    pub logging: LoggingConfig,
+    pub access_log: AccessLogConfig,
+    pub slow_requests: SlowRequestsConfig,
+    pub request_id: RequestIdConfig,
+    pub telemetry: TelemetryConfig,
+    pub rate_limit: RateLimitConfig,
+    pub bandwidth: BandwidthConfig,
+    pub load_shedding: LoadSheddingConfig,
+    pub jwt: JwtConfig,
+    pub cors: CorsConfig,
+    pub geoip: GeoIpConfig,
+    pub cache: CacheSettings,
+    pub compression: CompressionConfig,
+    pub redirect: RedirectConfig,
+    pub http3: Http3Config,
+    /// Redirects answered before requests are routed, the first matching one winning
+    pub redirects: Vec<RedirectRuleConfig>,
+    pub probes: ProbesConfig,
+    pub limits: LimitsConfig,
+    pub runtime: RuntimeConfig,
+    pub upgrade: UpgradeConfig,
+    pub process: ProcessConfig,
+    /// Header changes for every request and response, before any route's own
+    pub headers: HeadersConfig,
+    /// HSTS, CSP and similar headers added to responses
+    pub security_headers: SecurityHeadersConfig,
+    /// Rewrites of upstream redirects and cookies that name the upstream rather than the public site
+    pub response_rewrite: ResponseRewriteConfig,
+    /// Templates for the error responses Riffy generates, keyed by status code
+    pub error_pages: BTreeMap<String, ErrorPageConfig>,
+    /// Additional named upstream pools, selected by `routes`
+    pub pools: BTreeMap<String, UpstreamsConfig>,
+    /// Rules mapping requests to pools; unmatched requests go to `upstreams`
+    pub routes: Vec<RouteConfig>,
+}
+
+/// Settings taking precedence over both the config file and environment
+/// variables, such as command line flags. They are applied again on reload.
+#[derive(Debug, Clone, Default)]
+pub struct Overrides {
+    pub listen_address: Option<BindAddress>,
+    pub listen_port: Option<u16>,
+    pub admin_port: Option<u16>,
+    /// Replaces the servers of `[upstreams]` when not empty
+    pub upstreams: Vec<String>,
+    pub worker_threads: Option<usize>,
+}
+
+impl Overrides {
+    fn apply(&self, config: &mut Config) {
+        if self.listen_address.is_some() {
+            config.listener.address = self.listen_address;
+        }
+        if self.listen_port.is_some() {
+            config.listener.port = self.listen_port;
+        }
+        if self.admin_port.is_some() {
+            config.admin.port = self.admin_port;
+        }
+        if !self.upstreams.is_empty() {
+            config.upstreams.servers = self.upstreams.iter().map(|server| UpstreamEntry::Spec(server.clone())).collect();
+        }
+        if self.worker_threads.is_some() {
+            config.runtime.worker_threads = self.worker_threads;
+        }
+    }
+}
+
+/// Name under which `[upstreams]` can be referenced from routes.
+pub const DEFAULT_POOL: &str = "default";
+/// Name under which `[listener]` can be referenced from routes.
+pub const DEFAULT_LISTENER: &str = "default";
+
+#[derive(Debug, Clone, Default, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct ListenerConfig {
+    /// Address to listen on, and that of the redirect listener; every IPv4 address when unset
+    pub address: Option<BindAddress>,
+    /// Port to listen on; defaults to 443 with TLS enabled and 80 without
+    pub port: Option<u16>,
+    /// Unix socket path to listen on in place of the port
+    pub socket: Option<String>,
+    /// Octal permissions of the socket file, e.g. `"660"`
+    pub socket_mode: Option<String>,
+    /// Sockets bound to the port with SO_REUSEPORT, each accepting in a task
+    /// of its own so the kernel spreads new connections across them; one when unset
+    pub acceptors: Option<usize>,
+    /// Expect a PROXY protocol v1/v2 header on every connection, e.g. behind HAProxy or an AWS NLB
+    pub proxy_protocol: bool,
+    pub mode: ListenerMode,
+    /// Proxies whose `trusted_proxies_header` gives the client address
+    pub trusted_proxies: Vec<Cidr>,
+    /// `"x-forwarded-for"` or `"forwarded"`, whichever the trusted proxies append to
+    pub trusted_proxies_header: ForwardedHeader,
+    /// Clients allowed to connect, before any route's own list
+    pub access: AccessList,
+}
+
+impl ListenerConfig {
+    /// The permission bits given by `socket_mode`, if any.
+    pub fn socket_mode(&self) -> Result<Option<u32>, String> {
+        parse_socket_mode(self.socket_mode.as_deref(), "listener.socket_mode (LISTEN_SOCKET_MODE)")
+    }
+
+    /// The number of sockets accepting on the port.
+    pub fn acceptors(&self) -> usize {
+        self.acceptors.unwrap_or(1)
+    }
+}
+
+/// An HTTP listener in `[[listeners]]`, next to the one in `[listener]`.
+#[derive(Debug, Clone, PartialEq, Deserialize)]
+#[serde(deny_unknown_fields)]
+pub struct AdditionalListenerConfig {
+    /// Referenced by the `listeners` of routes
+    pub name: String,
+    /// Address to bind; every IPv4 address when unset
+    pub address: Option<BindAddress>,
+    pub port: Option<u16>,
+    /// Unix socket path to listen on in place of a port
+    pub socket: Option<String>,
+    /// Octal permissions of the socket file, e.g. `"660"`
+    pub socket_mode: Option<String>,
+    /// Terminate TLS with the `[tls]` certificates
+    #[serde(default)]
+    pub tls: bool,
+    /// Expect a PROXY protocol header on every connection
+    #[serde(default)]
+    pub proxy_protocol: bool,
+}
+
+impl AdditionalListenerConfig {
+    /// The permission bits given by `socket_mode`, if any.
+    pub fn socket_mode(&self) -> Result<Option<u32>, String> {
+        parse_socket_mode(self.socket_mode.as_deref(), &format!("listeners.{}.socket_mode", self.name))
+    }
+
+    /// The address to bind when listening on a port.
+    pub fn addr(&self) -> Option<SocketAddr> {
+        self.port.map(|port| SocketAddr::new(self.address.unwrap_or_default().0, port))
+    }
+}
+
+/// An IP address to listen on, such as `127.0.0.1`, `::1` or `[::]`, the
+/// last accepting IPv6 and IPv4 clients alike.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct BindAddress(pub IpAddr);
+
+impl Default for BindAddress {
+    fn default() -> Self {
+        BindAddress(IpAddr::V4(Ipv4Addr::UNSPECIFIED))
+    }
+}
+
+impl FromStr for BindAddress {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        let s = s.trim();
+        // IPv6 addresses may be written in brackets, as in URLs
+        let address = match s.strip_prefix('[') {
+            Some(rest) => rest.strip_suffix(']').filter(|address| address.contains(':')),
+            None => Some(s),
+        };
+        address.and_then(|address| address.parse().ok()).map(BindAddress).ok_or_else(|| format!("invalid bind address, expected an IP address such as 0.0.0.0 or [::]: {}", s))
+    }
+}
+
+impl<'de> Deserialize<'de> for BindAddress {
+    fn deserialize<D: Deserializer<'de>>(deserializer: D) -> Result<Self, D::Error> {
+        let s = String::deserialize(deserializer)?;
+        s.parse().map_err(serde::de::Error::custom)
+    }
+}
+
+/// Octal permission bits such as `660`; `setting` names them in errors.
+fn parse_socket_mode(mode: Option<&str>, setting: &str) -> Result<Option<u32>, String> {
+    mode.map(|mode| u32::from_str_radix(mode.trim().trim_start_matches("0o"), 8).ok().filter(|bits| *bits <= 0o777).ok_or_else(|| format!("invalid {}, expected octal permissions such as 660: {}", setting, mode)))
+        .transpose()
+}
+
+/// What the listener proxies.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default)]
+pub enum ListenerMode {
+    /// HTTP(S) reverse proxying
+    #[default]
+    Http,
+    /// Raw TCP streams forwarded to `[upstreams]`
+    Tcp,
+    /// TLS streams forwarded without decryption, routed by their SNI hostname
+    TlsPassthrough,
+}
+
+impl FromStr for ListenerMode {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s.trim().to_ascii_lowercase().as_str() {
+            "http" => Ok(ListenerMode::Http),
+            "tcp" => Ok(ListenerMode::Tcp),
+            "tls_passthrough" | "tls-passthrough" => Ok(ListenerMode::TlsPassthrough),
+            other => Err(format!("unknown listener mode: {}", other)),
+        }
+    }
+}
+
+#[derive(Debug, Clone, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct UpstreamsConfig {
+    pub servers: Vec<UpstreamEntry>,
+    pub strategy: Strategy,
+    /// Failures within `fail_timeout` seconds that eject an upstream; 0 disables
+    pub max_fails: u32,
+    pub fail_timeout: u64,
+    pub health_check: HealthCheckSettings,
+    pub tls: UpstreamTlsSettings,
+    pub retry: RetrySettings,
+    pub circuit_breaker: CircuitBreakerSettings,
+    pub sticky: StickySettings,
+    pub connections: ConnectionSettings,
+    pub concurrency: ConcurrencySettings,
+    /// Timeouts for this pool, replacing the global `[timeouts]`
+    pub timeouts: TimeoutsConfig,
+    /// Send a PROXY protocol v2 header with the client address on each upstream connection
+    pub proxy_protocol: bool,
+    /// Speak HTTP/2 to the upstreams, negotiated with ALPN over TLS and with prior knowledge over plain HTTP
+    pub http2: bool,
+    pub host_header: HostHeader,
+    pub dns: DnsSettings,
+    pub kubernetes: KubernetesSettings,
+    pub consul: ConsulSettings,
+    pub srv: SrvSettings,
+    /// Start without servers, answering 503 until some are added, rather than refusing to start
+    pub allow_empty: bool,
+}
+
+/// The Host header sent to a pool's upstreams.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default)]
+pub enum HostHeader {
+    /// The Host the client sent
+    #[default]
+    Preserve,
+    /// The upstream's own host and port, with the client's in `X-Forwarded-Host`
+    Upstream,
+}
+
+impl FromStr for HostHeader {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s.trim().to_ascii_lowercase().as_str() {
+            "preserve" => Ok(HostHeader::Preserve),
+            "upstream" => Ok(HostHeader::Upstream),
+            other => Err(format!("unknown host_header mode: {}", other)),
+        }
+    }
+}
+
+impl Default for UpstreamsConfig {
+    fn default() -> Self {
+        UpstreamsConfig {
+            servers: vec![UpstreamEntry::Spec("http://localhost:8080".to_string())],
+            strategy: Strategy::RoundRobin,
+            max_fails: 0,
+            fail_timeout: 10,
+            health_check: HealthCheckSettings::default(),
+            tls: UpstreamTlsSettings::default(),
+            retry: RetrySettings::default(),
+            circuit_breaker: CircuitBreakerSettings::default(),
+            sticky: StickySettings::default(),
+            connections: ConnectionSettings::default(),
+            concurrency: ConcurrencySettings::default(),
+            timeouts: TimeoutsConfig::default(),
+            proxy_protocol: false,
+            http2: false,
+            host_header: HostHeader::Preserve,
+            dns: DnsSettings::default(),
+            kubernetes: KubernetesSettings::default(),
+            consul: ConsulSettings::default(),
+            srv: SrvSettings::default(),
+            allow_empty: false,
+        }
+    }
+}
+
+/// An upstream given either as a spec string (`http://a:8080;weight=5`) or a table.
+#[derive(Debug, Clone, Deserialize)]
+#[serde(untagged)]
+pub enum UpstreamEntry {
+    Spec(String),
+    Server {
+        url: String,
+        #[serde(default = "default_weight")]
+        weight: u32,
+        /// Only send traffic here while no other upstream is available
+        #[serde(default)]
+        backup: bool,
+    },
+}
+
+fn default_weight() -> u32 {
+    1
+}
+
+#[derive(Debug, Clone, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct HealthCheckSettings {
+    pub enabled: bool,
+    pub path: String,
+    /// Seconds between probes
+    pub interval: u64,
+    /// Seconds before a probe counts as failed
+    pub timeout: u64,
+    pub healthy_threshold: u32,
+    pub unhealthy_threshold: u32,
+    /// Probe with the gRPC health checking protocol instead of requesting `path`
+    pub grpc: bool,
+    /// Service asked about by gRPC probes; empty for the server as a whole
+    pub grpc_service: String,
+}
+
+impl Default for HealthCheckSettings {
+    fn default() -> Self {
+        HealthCheckSettings {
+            enabled: false,
+            path: "/".to_string(),
+            interval: 10,
+            timeout: 2,
+            healthy_threshold: 2,
+            unhealthy_threshold: 3,
+            grpc: false,
+            grpc_service: String::new(),
+        }
+    }
+}
+
+/// Retries of failed idempotent requests against other upstreams.
+#[derive(Debug, Clone, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct RetrySettings {
+    /// Extra attempts after the first one; 0 disables retries
+    pub retries: u32,
+    /// Seconds allowed for each attempt
+    pub per_try_timeout: Option<u64>,
+    /// Milliseconds before the first retry, doubled for each further retry
+    pub backoff_ms: u64,
+    pub max_backoff_ms: u64,
+}
+
+impl Default for RetrySettings {
+    fn default() -> Self {
+        RetrySettings { retries: 0, per_try_timeout: None, backoff_ms: 50, max_backoff_ms: 1000 }
+    }
+}
+
+/// Per-upstream circuit breaker; durations are in seconds.
+#[derive(Debug, Clone, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct CircuitBreakerSettings {
+    pub enabled: bool,
+    pub consecutive_failures: u32,
+    /// Failure ratio (0.0 - 1.0) within `window` that opens the circuit
+    pub error_rate: Option<f64>,
+    pub min_requests: u32,
+    pub window: u64,
+    pub open_duration: u64,
+    pub half_open_requests: u32,
+}
+
+impl Default for CircuitBreakerSettings {
+    fn default() -> Self {
+        CircuitBreakerSettings {
+            enabled: false,
+            consecutive_failures: 5,
+            error_rate: None,
+            min_requests: 20,
+            window: 10,
+            open_duration: 30,
+            half_open_requests: 1,
+        }
+    }
+}
+
+/// Cookie-based session affinity.
+#[derive(Debug, Clone, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct StickySettings {
+    pub enabled: bool,
+    /// Name of the cookie identifying the pinned upstream
+    pub cookie: String,
+}
+
+impl Default for StickySettings {
+    fn default() -> Self {
+        StickySettings { enabled: false, cookie: "riffy_srv".to_string() }
+    }
+}
+
+/// Reuse of upstream connections; durations are in seconds.
+#[derive(Debug, Clone, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct ConnectionSettings {
+    /// Idle connections kept open per upstream; 0 disables connection reuse
+    pub max_idle_per_host: usize,
+    /// Idle connections are closed after this long
+    pub idle_timeout: u64,
+    /// Interval of TCP keep-alive probes on upstream connections; 0 disables them
+    pub tcp_keepalive: u64,
+}
+
+impl Default for ConnectionSettings {
+    fn default() -> Self {
+        ConnectionSettings { max_idle_per_host: 32, idle_timeout: 90, tcp_keepalive: 60 }
+    }
+}
+
+/// Cap on the requests in flight to each upstream, with a queue for the excess.
+#[derive(Debug, Clone, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct ConcurrencySettings {
+    /// Requests in flight per upstream; 0 means no limit
+    pub max_requests: usize,
+    /// Requests that may wait for a free upstream; further requests get a 503
+    pub queue_size: usize,
+    /// Milliseconds a request may wait before it gets a 503
+    pub queue_timeout_ms: u64,
+}
+
+impl Default for ConcurrencySettings {
+    fn default() -> Self {
+        ConcurrencySettings { max_requests: 0, queue_size: 100, queue_timeout_ms: 1000 }
+    }
+}
+
+/// Resolution of upstream hostnames to one upstream per address; durations are in seconds.
+#[derive(Debug, Clone, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct DnsSettings {
+    pub enabled: bool,
+    /// Shortest wait before resolving again, even when the records' TTL is lower
+    pub min_ttl: u64,
+    /// Longest wait before resolving again, even when the records' TTL is higher
+    pub max_ttl: u64,
+}
+
+impl Default for DnsSettings {
+    fn default() -> Self {
+        DnsSettings { enabled: false, min_ttl: 5, max_ttl: 300 }
+    }
+}
+
+/// Discovery of a pool's upstreams from DNS SRV records, replacing `servers`.
+/// Lookups are repeated within the bounds of `[upstreams.dns]`.
+#[derive(Debug, Clone, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct SrvSettings {
+    pub enabled: bool,
+    /// Record name, such as `_http._tcp.api.internal`
+    pub name: String,
+    /// Scheme of the upstream URLs: "http", "https" or "tcp"
+    pub scheme: String,
+}
+
+impl Default for SrvSettings {
+    fn default() -> Self {
+        SrvSettings { enabled: false, name: String::new(), scheme: "http".to_string() }
+    }
+}
+
+/// Discovery of a pool's upstreams from the EndpointSlices of a Kubernetes
+/// Service, replacing `servers`.
+#[derive(Debug, Clone, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct KubernetesSettings {
+    pub enabled: bool,
+    pub service: String,
+    /// Namespace of the Service; Riffy's own namespace when unset
+    pub namespace: Option<String>,
+    /// Name or number of the endpoint port to use; the first port when unset
+    pub port: Option<String>,
+    /// Scheme of the upstream URLs: "http", "https" or "tcp"
+    pub scheme: String,
+    /// API server URL; the in-cluster address when unset
+    pub api_server: Option<String>,
+}
+
+impl Default for KubernetesSettings {
+    fn default() -> Self {
+        KubernetesSettings { enabled: false, service: String::new(), namespace: None, port: None, scheme: "http".to_string(), api_server: None }
+    }
+}
+
+/// Discovery of a pool's upstreams from the passing instances of a Consul
+/// service, replacing `servers`.
+#[derive(Debug, Clone, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct ConsulSettings {
+    pub enabled: bool,
+    /// HTTP address of the Consul agent
+    pub address: String,
+    pub service: String,
+    /// Only instances carrying every one of these tags
+    pub tags: Vec<String>,
+    /// Datacenter to query; the agent's own when unset
+    pub datacenter: Option<String>,
+    /// ACL token sent with each query
+    pub token: Option<String>,
+    /// Scheme of the upstream URLs: "http", "https" or "tcp"
+    pub scheme: String,
+}
+
+impl Default for ConsulSettings {
+    fn default() -> Self {
+        ConsulSettings {
+            enabled: false,
+            address: "http://127.0.0.1:8500".to_string(),
+            service: String::new(),
+            tags: Vec::new(),
+            datacenter: None,
+            token: None,
+            scheme: "http".to_string(),
+        }
+    }
+}
+
+/// TLS settings for connecting to `https://` upstreams.
+#[derive(Debug, Clone, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct UpstreamTlsSettings {
+    /// PEM bundle of CAs to trust instead of the system roots
+    pub ca_bundle: Option<String>,
+    /// Set to false to accept certificates for other hostnames (development only)
+    pub verify_hostname: bool,
+    /// Client certificate chain presented to upstreams that require mutual TLS
+    pub cert_path: Option<String>,
+    pub key_path: Option<String>,
+}
+
+impl Default for UpstreamTlsSettings {
+    fn default() -> Self {
+        UpstreamTlsSettings { ca_bundle: None, verify_hostname: true, cert_path: None, key_path: None }
+    }
+}
+
+#[derive(Debug, Clone, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct TlsConfig {
+    pub enabled: bool,
+    /// Offer HTTP/2 to clients via ALPN
+    pub http2: bool,
+    /// Default certificate, used when no SNI-specific certificate matches
+    pub cert_path: Option<String>,
+    pub key_path: Option<String>,
+    /// Certificates selected by the SNI hostname sent by the client
+    pub certificates: Vec<SniCertificate>,
+    pub client_auth: ClientAuthConfig,
+    pub min_version: TlsVersion,
+    pub max_version: TlsVersion,
+    /// Cipher suites to offer, e.g. `TLS13_AES_256_GCM_SHA384`; all supported suites when empty
+    pub cipher_suites: Vec<String>,
+    /// Seconds between checks of the certificate files for changes; not watched when unset
+    pub watch_interval: Option<u64>,
+}
+
+impl Default for TlsConfig {
+    fn default() -> Self {
+        TlsConfig {
+            enabled: false,
+            http2: true,
+            cert_path: None,
+            key_path: None,
+            certificates: Vec::new(),
+            client_auth: ClientAuthConfig::default(),
+            min_version: TlsVersion::Tls12,
+            max_version: TlsVersion::Tls13,
+            cipher_suites: Vec::new(),
+            watch_interval: None,
+        }
+    }
+}
+
+/// TLS protocol versions supported by the listener.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, PartialOrd, Ord)]
+pub enum TlsVersion {
+    Tls12,
+    Tls13,
+}
+
+impl FromStr for TlsVersion {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s.trim().to_ascii_lowercase().trim_start_matches("tlsv").trim_start_matches("tls") {
+            "1.2" => Ok(TlsVersion::Tls12),
+            "1.3" => Ok(TlsVersion::Tls13),
+            "1.0" | "1.1" => Err(format!("TLS version {} is not supported; use 1.2 or 1.3", s.trim())),
+            _ => Err(format!("unknown TLS version: {}", s.trim())),
+        }
+    }
+}
+
+/// Whether clients must present a certificate.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default)]
+pub enum ClientAuthMode {
+    #[default]
+    None,
+    /// Verify certificates that clients present, but allow anonymous clients
+    Optional,
+    Required,
+}
+
+impl FromStr for ClientAuthMode {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s.trim().to_ascii_lowercase().as_str() {
+            "none" => Ok(ClientAuthMode::None),
+            "optional" => Ok(ClientAuthMode::Optional),
+            "required" => Ok(ClientAuthMode::Required),
+            other => Err(format!("unknown client auth mode: {}", other)),
+        }
+    }
+}
+
+/// Client certificate (mutual TLS) authentication.
+#[derive(Debug, Clone, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct ClientAuthConfig {
+    pub mode: ClientAuthMode,
+    /// PEM bundle of CAs that issue client certificates
+    pub ca_bundle: Option<String>,
+    /// Certificate revocation list (PEM or DER) checked against client certificates
+    pub crl_path: Option<String>,
+    /// Request header carrying the verified client certificate subject to upstreams; empty disables it
+    pub subject_header: String,
+}
+
+impl Default for ClientAuthConfig {
+    fn default() -> Self {
+        ClientAuthConfig { mode: ClientAuthMode::None, ca_bundle: None, crl_path: None, subject_header: "X-Client-Cert-Subject".to_string() }
+    }
+}
+
+#[derive(Debug, Clone, Deserialize)]
+#[serde(deny_unknown_fields)]
+pub struct SniCertificate {
+    /// Hostnames served with this certificate; `*.example.com` matches one label
+    pub hostnames: Vec<String>,
+    pub cert_path: String,
+    pub key_path: String,
+}
+
+#[derive(Debug, Clone, Default, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct TimeoutsConfig {
+    /// Seconds allowed for establishing a TCP connection to an upstream
+    pub connect: Option<u64>,
+    /// Seconds to wait for an upstream's response headers once the request was sent
+    pub response_header: Option<u64>,
+    /// Seconds allowed for the whole request, including retries and the response body
+    pub request: Option<u64>,
+}
+
+impl TimeoutsConfig {
+    fn validate(&self, section: &str) -> Result<(), String> {
+        let timeouts = [("connect", self.connect), ("response_header", self.response_header), ("request", self.request)];
+        for (name, timeout) in timeouts {
+            if timeout == Some(0) {
+                return Err(format!("{}.{} must be at least 1 second", section, name));
+            }
+        }
+        Ok(())
+    }
+}
+
+#[derive(Debug, Clone, Default, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct AdminConfig {
+    /// Address for the admin server; every IPv4 address when unset
+    pub address: Option<BindAddress>,
+    /// Port for the admin server (metrics and API); disabled when unset
+    pub port: Option<u16>,
+    /// Bearer token for the upstream management API; the API is disabled when unset
+    pub token: Option<String>,
+}
+
+/// Per-client-IP token bucket limits.
+#[derive(Debug, Clone, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct RateLimitConfig {
+    pub enabled: bool,
+    /// Sustained requests per second allowed for each client IP
+    pub requests_per_second: f64,
+    /// Requests a client may send in a burst above the sustained rate
+    pub burst: u32,
+}
+
+impl Default for RateLimitConfig {
+    fn default() -> Self {
+        RateLimitConfig { enabled: false, requests_per_second: 10.0, burst: 20 }
+    }
+}
+
+/// Per-client-IP token bucket limits on response bandwidth.
+#[derive(Debug, Clone, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct BandwidthConfig {
+    pub enabled: bool,
+    /// Sustained response bytes per second sent to each client IP, across all its responses
+    pub bytes_per_second: u64,
+    /// Bytes a client may receive in a burst above the sustained rate
+    pub burst: u64,
+}
+
+impl Default for BandwidthConfig {
+    fn default() -> Self {
+        BandwidthConfig { enabled: false, bytes_per_second: 1024 * 1024, burst: 4 * 1024 * 1024 }
+    }
+}
+
+impl BandwidthConfig {
+    fn validate(&self, section: &str) -> Result<(), String> {
+        if self.enabled && (self.bytes_per_second == 0 || self.burst == 0) {
+            return Err(format!("{}.bytes_per_second and burst must be at least 1", section));
+        }
+        Ok(())
+    }
+}
+
+/// Adaptive limit on the requests Riffy handles at once, lowered when
+/// latency rises above its baseline.
+#[derive(Debug, Clone, PartialEq, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct LoadSheddingConfig {
+    pub enabled: bool,
+    /// The limit never drops below this many requests in flight, and starts here
+    pub min_limit: usize,
+    pub max_limit: usize,
+    /// How far latency may rise above its baseline, as a factor, before the limit shrinks
+    pub tolerance: f64,
+}
+
+impl Default for LoadSheddingConfig {
+    fn default() -> Self {
+        LoadSheddingConfig { enabled: false, min_limit: 20, max_limit: 1000, tolerance: 2.0 }
+    }
+}
+
+/// Validation of bearer JWTs against the keys of a JWKS endpoint.
+#[derive(Debug, Clone, PartialEq, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct JwtConfig {
+    pub enabled: bool,
+    /// URL of the identity provider's JSON Web Key Set
+    pub jwks_url: String,
+    /// Required `iss` claim
+    pub issuer: Option<String>,
+    /// Required `aud` claim, or one of its values
+    pub audience: Option<String>,
+    /// Claims passed to upstreams, mapped to the header that carries each
+    pub forward_claims: BTreeMap<String, String>,
+    /// Seconds the key set is used before it is fetched again
+    pub jwks_cache_ttl: u64,
+    /// Seconds of clock skew allowed when checking `exp` and `nbf`
+    pub leeway: u64,
+}
+
+impl Default for JwtConfig {
+    fn default() -> Self {
+        JwtConfig {
+            enabled: false,
+            jwks_url: String::new(),
+            issuer: None,
+            audience: None,
+            forward_claims: BTreeMap::new(),
+            jwks_cache_ttl: 300,
+            leeway: 60,
+        }
+    }
+}
+
+/// CORS answered at the proxy instead of by each upstream.
+#[derive(Debug, Clone, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct CorsConfig {
+    pub enabled: bool,
+    /// Origins such as `https://app.example.com` allowed to make requests; `*` allows any
+    pub allowed_origins: Vec<String>,
+    pub allowed_methods: Vec<String>,
+    /// Request headers allowed in preflights; the requested ones when empty
+    pub allowed_headers: Vec<String>,
+    /// Response headers readable by scripts besides the CORS-safelisted ones
+    pub exposed_headers: Vec<String>,
+    /// Allow cookies and HTTP authentication
+    pub allow_credentials: bool,
+    /// Seconds browsers may cache a preflight answer
+    pub max_age: Option<u64>,
+}
+
+impl Default for CorsConfig {
+    fn default() -> Self {
+        CorsConfig {
+            enabled: false,
+            allowed_origins: Vec::new(),
+            allowed_methods: ["GET", "HEAD", "POST", "PUT", "PATCH", "DELETE"].iter().map(|m| m.to_string()).collect(),
+            allowed_headers: Vec::new(),
+            exposed_headers: Vec::new(),
+            allow_credentials: false,
+            max_age: None,
+        }
+    }
+}
+
+/// Country and network (ASN) lookups of client addresses in MaxMind databases.
+#[derive(Debug, Clone, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct GeoIpConfig {
+    pub enabled: bool,
+    /// GeoLite2-Country or GeoLite2-City database, or their GeoIP2 versions
+    pub country_database: Option<String>,
+    /// GeoLite2-ASN database
+    pub asn_database: Option<String>,
+    /// Header telling upstreams the client's ISO country code; empty to not send it
+    pub country_header: String,
+    /// Header telling upstreams the client's autonomous system number; empty to not send it
+    pub asn_header: String,
+    /// ISO country codes of clients refused with 403
+    pub deny_countries: Vec<String>,
+    /// When not empty, clients from other or unknown countries are refused
+    pub allow_countries: Vec<String>,
+}
+
+impl Default for GeoIpConfig {
+    fn default() -> Self {
+        GeoIpConfig {
+            enabled: false,
+            country_database: None,
+            asn_database: None,
+            country_header: "X-Country-Code".to_string(),
+            asn_header: "X-ASN".to_string(),
+            deny_countries: Vec::new(),
+            allow_countries: Vec::new(),
+        }
+    }
+}
+
+/// Checks that `countries` are two-letter ISO 3166 codes.
+fn validate_countries(countries: &[String]) -> Result<(), String> {
+    match countries.iter().find(|code| code.len() != 2 || !code.bytes().all(|b| b.is_ascii_alphabetic())) {
+        Some(code) => Err(format!("invalid country code {}, expected two letters such as DE", code)),
+        None => Ok(()),
+    }
+}
+
+/// In-memory response cache.
+#[derive(Debug, Clone, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct CacheSettings {
+    pub enabled: bool,
+    /// Total size of cached response bodies, in megabytes
+    pub max_size_mb: usize,
+    /// Largest response body that is cached, in kilobytes
+    pub max_object_kb: usize,
+    /// Client ranges allowed to send PURGE requests; PURGE is proxied like any other method when empty
+    pub purge_from: Vec<Cidr>,
+    /// Seconds a response is served past its lifetime while it is refreshed, unless it says otherwise
+    pub stale_while_revalidate: u64,
+    /// Seconds a response stands in for upstream errors past its lifetime, unless it says otherwise
+    pub stale_if_error: u64,
+    /// Concurrent misses for the same response wait for one upstream fetch
+    pub coalesce: bool,
+    /// Tier of files below the memory cache
+    pub disk: DiskCacheConfig,
+}
+
+impl Default for CacheSettings {
+    fn default() -> Self {
+        CacheSettings { enabled: false, max_size_mb: 64, max_object_kb: 1024, purge_from: Vec::new(), stale_while_revalidate: 0, stale_if_error: 0, coalesce: true, disk: DiskCacheConfig::default() }
+    }
+}
+
+impl CacheSettings {
+    /// Cache limits, or `None` when caching is disabled.
+    pub fn cache(&self) -> Option<CacheConfig> {
+        if !self.enabled {
+            return None;
+        }
+        Some(CacheConfig {
+            max_size: self.max_size_mb * 1024 * 1024,
+            max_object_size: self.max_object_kb * 1024,
+            stale_while_revalidate: Duration::from_secs(self.stale_while_revalidate),
+            stale_if_error: Duration::from_secs(self.stale_if_error),
+            coalesce: self.coalesce,
+        })
+    }
+}
+
+/// Persistent tier of the response cache, below the memory one.
+#[derive(Debug, Clone, PartialEq, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct DiskCacheConfig {
+    /// Directory holding the cached responses; no disk tier when unset
+    pub path: Option<String>,
+    /// Total size of the files, in megabytes
+    pub max_size_mb: u64,
+    /// Largest response body stored on disk, in megabytes
+    pub max_object_mb: u64,
+}
+
+impl Default for DiskCacheConfig {
+    fn default() -> Self {
+        DiskCacheConfig { path: None, max_size_mb: 1024, max_object_mb: 100 }
+    }
+}
+
+/// What a route's cached responses are keyed by, besides host and path.
+/// Query parameter names may end in `*` to match a prefix, as in `utm_*`.
+#[derive(Debug, Clone, Default, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct CacheKeyConfig {
+    /// Query parameters kept in the key, dropping the others; every one when empty
+    pub query_params: Vec<String>,
+    /// Query parameters dropped from the key, such as tracking parameters
+    pub ignore_query_params: Vec<String>,
+    /// Request headers whose values are part of the key
+    pub headers: Vec<String>,
+    /// Request cookies whose values are part of the key
+    pub cookies: Vec<String>,
+}
+
+impl CacheKeyConfig {
+    fn validate(&self, section: &str) -> Result<(), String> {
+        if !self.query_params.is_empty() && !self.ignore_query_params.is_empty() {
+            return Err(format!("{} sets both query_params and ignore_query_params", section));
+        }
+        if let Some(name) = self.headers.iter().find(|name| hyper::header::HeaderName::from_bytes(name.as_bytes()).is_err()) {
+            return Err(format!("{}: invalid header name {}", section, name));
+        }
+        Ok(())
+    }
+}
+
+/// On-the-fly compression of upstream responses.
+#[derive(Debug, Clone, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct CompressionConfig {
+    pub enabled: bool,
+    /// Responses with a smaller Content-Length are sent as-is, in bytes
+    pub min_size: u64,
+    /// Content-type prefixes to compress, such as `text/` or `application/json`
+    pub content_types: Vec<String>,
+}
+
+impl Default for CompressionConfig {
+    fn default() -> Self {
+        let content_types = ["text/", "application/json", "application/javascript", "application/xml", "image/svg+xml"];
+        CompressionConfig { enabled: false, min_size: 1024, content_types: content_types.iter().map(|t| t.to_string()).collect() }
+    }
+}
+
+/// Plain HTTP listener that redirects clients to HTTPS.
+#[derive(Debug, Clone, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct RedirectConfig {
+    pub enabled: bool,
+    pub port: u16,
+    /// Directory of ACME HTTP-01 tokens served under `/.well-known/acme-challenge/`
+    pub acme_challenge_dir: Option<String>,
+}
+
+impl Default for RedirectConfig {
+    fn default() -> Self {
+        RedirectConfig { enabled: false, port: 80, acme_challenge_dir: None }
+    }
+}
+
+/// Experimental HTTP/3 (QUIC) listener next to the TLS one, needing Riffy
+/// built with the `http3` feature.
+#[derive(Debug, Clone, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct Http3Config {
+    pub enabled: bool,
+    /// UDP port; the TLS listener's port when unset
+    pub port: Option<u16>,
+    /// Seconds clients may remember the `Alt-Svc` advertisement
+    pub alt_svc_max_age: u64,
+}
+
+impl Default for Http3Config {
+    fn default() -> Self {
+        Http3Config { enabled: false, port: None, alt_svc_max_age: 86400 }
+    }
+}
+
+impl Http3Config {
+    /// The UDP port for a TLS listener on `listen_port`.
+    pub fn port(&self, listen_port: u16) -> u16 {
+        self.port.unwrap_or(listen_port)
+    }
+}
+
+/// A redirect for requests whose path equals `path`, starts with `prefix`
+/// or matches `regex`; exactly one of them is set.
+#[derive(Debug, Clone, Deserialize)]
+#[serde(deny_unknown_fields)]
+pub struct RedirectRuleConfig {
+    /// Hostnames the rule applies to; any host when empty
+    #[serde(default)]
+    pub hosts: Vec<String>,
+    pub path: Option<String>,
+    /// Matched on whole segments; the rest of the path is appended to `to`
+    pub prefix: Option<String>,
+    /// Matched against the path; `to` can refer to capture groups as `$1`
+    pub regex: Option<String>,
+    /// Path or absolute URL to redirect to
+    pub to: String,
+    /// 301, 302, 303, 307 or 308
+    #[serde(default = "default_redirect_status")]
+    pub status: u16,
+    /// Append the request's query string to `to`
+    #[serde(default = "default_preserve_query")]
+    pub preserve_query: bool,
+}
+
+fn default_redirect_status() -> u16 {
+    301
+}
+
+fn default_preserve_query() -> bool {
+    true
+}
+
+/// Header rules for requests sent upstream and responses sent to clients.
+#[derive(Debug, Clone, Default, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct HeadersConfig {
+    pub request: HeaderRulesConfig,
+    pub response: HeaderRulesConfig,
+}
+
+/// Security headers added to responses that do not already carry them.
+#[derive(Debug, Clone, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct SecurityHeadersConfig {
+    pub enabled: bool,
+    /// `max-age` of `Strict-Transport-Security`, sent on TLS connections only; 0 leaves it out
+    pub hsts_max_age: u64,
+    pub hsts_include_subdomains: bool,
+    pub hsts_preload: bool,
+    /// Send `X-Content-Type-Options: nosniff`
+    pub content_type_options: bool,
+    /// `X-Frame-Options` value, `DENY` or `SAMEORIGIN`; empty leaves it out
+    pub frame_options: String,
+    /// `Content-Security-Policy` value
+    pub content_security_policy: Option<String>,
+}
+
+impl Default for SecurityHeadersConfig {
+    fn default() -> Self {
+        SecurityHeadersConfig {
+            enabled: false,
+            hsts_max_age: 31_536_000,
+            hsts_include_subdomains: false,
+            hsts_preload: false,
+            content_type_options: true,
+            frame_options: "SAMEORIGIN".to_string(),
+            content_security_policy: None,
+        }
+    }
+}
+
+impl SecurityHeadersConfig {
+    fn validate(&self) -> Result<(), String> {
+        if !self.frame_options.is_empty() && !self.frame_options.eq_ignore_ascii_case("DENY") && !self.frame_options.eq_ignore_ascii_case("SAMEORIGIN") {
+            return Err(format!("security_headers.frame_options must be DENY or SAMEORIGIN: {}", self.frame_options));
+        }
+        if let Some(policy) = &self.content_security_policy {
+            hyper::header::HeaderValue::from_str(policy).map_err(|_| format!("invalid security_headers.content_security_policy: {}", policy))?;
+        }
+        if self.hsts_preload && (self.hsts_max_age < 31_536_000 || !self.hsts_include_subdomains) {
+            return Err("security_headers.hsts_preload needs hsts_include_subdomains and an hsts_max_age of at least 31536000".to_string());
+        }
+        Ok(())
+    }
+}
+
+/// Changes to upstream response headers so they point at the public site.
+#[derive(Debug, Clone, Default, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct ResponseRewriteConfig {
+    /// Point `Location` and `Content-Location` URLs on the upstream at the host the client used
+    pub location: bool,
+    /// Do the same for the URL in a `Refresh` header
+    pub refresh: bool,
+    /// `Domain` attributes of `Set-Cookie` replaced, such as `backend.internal = "example.com"`; an empty value removes the attribute
+    pub cookie_domains: BTreeMap<String, String>,
+    /// `Path` prefixes of `Set-Cookie` replaced, such as `"/" = "/app/"`
+    pub cookie_paths: BTreeMap<String, String>,
+}
+
+/// Template files for one status code, with `{{status}}`, `{{reason}}` and
+/// `{{request_id}}` filled in.
+#[derive(Debug, Clone, Default, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct ErrorPageConfig {
+    /// Sent unless the client prefers JSON
+    pub html: Option<String>,
+    /// Sent to clients preferring `application/json`
+    pub json: Option<String>,
+}
+
+/// Header changes, applied in the order remove, rewrite, set, append.
+#[derive(Debug, Clone, Default, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct HeaderRulesConfig {
+    /// Header names to remove; a trailing `*` matches any suffix, e.g. `X-Debug-*`
+    pub remove: Vec<String>,
+    pub rewrite: Vec<HeaderRewrite>,
+    /// Headers to replace, or add when missing
+    pub set: BTreeMap<String, String>,
+    /// Headers to add alongside existing values
+    pub append: BTreeMap<String, String>,
+}
+
+/// Replaces matches of a regular expression in every value of a header.
+#[derive(Debug, Clone, Deserialize)]
+#[serde(deny_unknown_fields)]
+pub struct HeaderRewrite {
+    pub name: String,
+    pub pattern: String,
+    /// May refer to capture groups as `$1` or `${name}`
+    pub replacement: String,
+}
+
+/// Limits on what clients may send.
+#[derive(Debug, Clone, Default, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct LimitsConfig {
+    /// Largest request body in bytes; 0 means no limit
+    pub max_body_size: u64,
+    /// Largest upstream response body in bytes passed on to a client; 0 means no limit
+    pub max_response_size: u64,
+    /// Connections a single client IP may have open at once; 0 means no limit
+    pub max_connections_per_ip: usize,
+    /// Seconds a client has to complete the TLS handshake and to send each
+    /// request's headers before the connection is closed
+    pub header_timeout: Option<u64>,
+}
+
+/// Riffy's own log messages, as opposed to the access log.
+#[derive(Debug, Clone, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct LoggingConfig {
+    /// Filter directives such as `info` or `warn,riffy::health=debug`
+    pub level: String,
+}
+
+impl Default for LoggingConfig {
+    fn default() -> Self {
+        LoggingConfig { level: "info".to_string() }
+    }
+}
+
+/// Settings of the Tokio runtime serving connections.
+#[derive(Debug, Clone, Default, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct RuntimeConfig {
+    /// Threads running connections and requests; one per CPU core when unset
+    pub worker_threads: Option<usize>,
+}
+
+/// The account Riffy switches to once its ports are bound, e.g. to bind
+/// 80 and 443 as root and serve as an unprivileged user.
+#[derive(Debug, Clone, Default, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct ProcessConfig {
+    /// User name or id; its primary group is taken unless `group` is set
+    pub user: Option<String>,
+    /// Group name or id
+    pub group: Option<String>,
+}
+
+/// Zero-downtime upgrades, handing the listening sockets to a new process.
+#[derive(Debug, Clone, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct UpgradeConfig {
+    /// Control socket a new process connects to for the sockets of the running one
+    pub socket: Option<String>,
+    /// Seconds the old process waits for open connections to finish before exiting
+    pub drain_timeout: u64,
+}
+
+impl Default for UpgradeConfig {
+    fn default() -> Self {
+        UpgradeConfig { socket: None, drain_timeout: 30 }
+    }
+}
+
+/// Liveness and readiness endpoints answered by Riffy itself.
+#[derive(Debug, Clone, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct ProbesConfig {
+    /// Also answer the probe paths on the main listener instead of proxying them;
+    /// the admin port always serves them
+    pub enabled: bool,
+    pub liveness_path: String,
+    pub readiness_path: String,
+}
+
+impl Default for ProbesConfig {
+    fn default() -> Self {
+        ProbesConfig { enabled: false, liveness_path: "/healthz".to_string(), readiness_path: "/readyz".to_string() }
+    }
+}
+
+/// Sends requests matching the given hostnames and path prefix to a pool.
+#[derive(Debug, Clone, Deserialize)]
+#[serde(deny_unknown_fields)]
+pub struct RouteConfig {
+    /// Identifies the route in the admin API
+    pub name: Option<String>,
+    /// Hostnames matched against the Host header; `*.example.com` matches one label
+    #[serde(default)]
+    pub hosts: Vec<String>,
+    /// Path prefix such as `/api`, matched on whole segments
+    pub path_prefix: Option<String>,
+    /// ISO country codes of the clients matched, looked up with `[geoip]`
+    #[serde(default)]
+    pub countries: Vec<String>,
+    /// Remove the matched prefix from the path sent upstream
+    #[serde(default)]
+    pub strip_prefix: bool,
+    /// Further changes to the path sent upstream, after `strip_prefix`
+    #[serde(default)]
+    pub rewrite: RewriteConfig,
+    /// The default pool when unset, as for routes serving static files
+    #[serde(default = "default_route_pool")]
+    pub pool: String,
+    /// Makes `pool` one half of a blue-green pair
+    pub blue_green: Option<BlueGreenConfig>,
+    /// Sends a percentage of the requests to another pool
+    pub canary: Option<CanaryConfig>,
+    /// Copies a percentage of the requests to a shadow pool
+    pub mirror: Option<MirrorConfig>,
+    /// Response header and request timeouts replacing the pool's and global ones
+    #[serde(default)]
+    pub timeouts: TimeoutsConfig,
+    /// Names of the listeners serving the route; every listener when empty
+    #[serde(default)]
+    pub listeners: Vec<String>,
+    /// Marks a Server-Sent Events endpoint: responses are streamed as they
+    /// arrive, and only the route's own timeouts apply
+    #[serde(default)]
+    pub sse: bool,
+    /// Replaces `limits.max_body_size` for this route; 0 means no limit
+    pub max_body_size: Option<u64>,
+    /// Replaces `limits.max_response_size` for this route; 0 means no limit
+    pub max_response_size: Option<u64>,
+    /// Replaces the global `[bandwidth]` for this route, with buckets of its own
+    pub bandwidth: Option<BandwidthConfig>,
+    /// Query parameters, headers and cookies keying the route's cached responses
+    pub cache_key: Option<CacheKeyConfig>,
+    /// Header changes applied after the global `[headers]` rules
+    #[serde(default)]
+    pub headers: HeadersConfig,
+    /// Replaces the global `[security_headers]` for this route
+    pub security_headers: Option<SecurityHeadersConfig>,
+    /// Replaces the global `[response_rewrite]` for this route
+    pub response_rewrite: Option<ResponseRewriteConfig>,
+    /// Clients allowed to use this route, checked after `listener.access`
+    #[serde(default)]
+    pub access: AccessList,
+    /// External service deciding whether each request may pass
+    pub forward_auth: Option<ForwardAuthConfig>,
+    /// Users asked for a password before the route can be used
+    pub basic_auth: Option<BasicAuthConfig>,
+    /// Page served instead of proxying while the route is in maintenance
+    #[serde(default)]
+    pub maintenance: MaintenanceConfig,
+    /// Serve files from a local directory instead of proxying to the pool
+    pub static_files: Option<StaticFilesConfig>,
+}
+
+fn default_route_pool() -> String {
+    DEFAULT_POOL.to_string()
+}
+
+#[derive(Debug, Clone, PartialEq, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct AccessLogConfig {
+    /// Write one JSON line per request
+    pub enabled: bool,
+    /// File to append to; stdout when unset
+    pub path: Option<String>,
+    /// Rotate the file once it reaches this many megabytes; 0 never rotates by size
+    pub max_size_mb: u64,
+    /// Also rotate the file when the hour or day changes (UTC)
+    pub rotate: LogRotation,
+    /// Rotated files kept, the oldest deleted first; 0 keeps them all
+    pub max_files: usize,
+    /// Send lines to a syslog server or socket instead of stdout or a file
+    pub syslog: Option<SyslogConfig>,
+    /// Send lines to the systemd journal instead, with their fields as journal fields
+    pub journald: bool,
+}
+
+impl Default for AccessLogConfig {
+    fn default() -> Self {
+        AccessLogConfig { enabled: false, path: None, max_size_mb: 0, rotate: LogRotation::Never, max_files: 7, syslog: None, journald: false }
+    }
+}
+
+#[derive(Debug, Clone, PartialEq, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct SyslogConfig {
+    /// `udp://host:port`, `tcp://host:port` or `unix:///path`
+    pub address: String,
+    /// Facility name such as `daemon` or `local0`
+    pub facility: String,
+    /// APP-NAME of the messages
+    pub app_name: String,
+}
+
+impl Default for SyslogConfig {
+    fn default() -> Self {
+        SyslogConfig { address: "unix:///dev/log".to_string(), facility: "local0".to_string(), app_name: "riffy".to_string() }
+    }
+}
+
+/// When a log file is rotated regardless of its size.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default)]
+pub enum LogRotation {
+    #[default]
+    Never,
+    Hourly,
+    Daily,
+}
+
+impl FromStr for LogRotation {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s.trim().to_ascii_lowercase().as_str() {
+            "never" => Ok(LogRotation::Never),
+            "hourly" => Ok(LogRotation::Hourly),
+            "daily" => Ok(LogRotation::Daily),
+            other => Err(format!("unknown log rotation: {}", other)),
+        }
+    }
+}
+
+/// Logging of requests that take longer than `threshold_ms`, with a
+/// breakdown of where the time went.
+#[derive(Debug, Clone, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct SlowRequestsConfig {
+    pub enabled: bool,
+    /// From the request's arrival until the last byte of the response was sent
+    pub threshold_ms: u64,
+}
+
+impl Default for SlowRequestsConfig {
+    fn default() -> Self {
+        SlowRequestsConfig { enabled: false, threshold_ms: 2000 }
+    }
+}
+
+/// Rewrites of the path sent upstream; the query string is kept as it is.
+#[derive(Debug, Clone, Default, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct RewriteConfig {
+    /// Regular expression replaced, at its first match in the path, by `replacement`
+    pub regex: Option<String>,
+    /// May refer to capture groups as `$1`
+    pub replacement: String,
+    /// Prefix added in front of the path
+    pub add_prefix: Option<String>,
+}
+
+/// Blue-green deployment: the route's `pool` starts active and `standby` idle,
+/// and the admin API switches between them.
+#[derive(Debug, Clone, Deserialize)]
+#[serde(deny_unknown_fields)]
+pub struct BlueGreenConfig {
+    pub standby: String,
+}
+
+/// Second pool of a route for gradual rollouts.
+#[derive(Debug, Clone, Deserialize)]
+#[serde(deny_unknown_fields)]
+pub struct CanaryConfig {
+    pub pool: String,
+    /// Share of the route's requests, from 0 to 100
+    pub percent: f64,
+}
+
+/// Shadow pool of a route, receiving copies of requests whose responses are discarded.
+#[derive(Debug, Clone, Deserialize)]
+#[serde(deny_unknown_fields)]
+pub struct MirrorConfig {
+    pub pool: String,
+    /// Share of the route's requests to copy, from 0 to 100
+    #[serde(default = "default_mirror_percent")]
+    pub percent: f64,
+}
+
+fn default_mirror_percent() -> f64 {
+    100.0
+}
+
+/// Authorization service asked about every request of a route.
+#[derive(Debug, Clone, Deserialize)]
+#[serde(deny_unknown_fields)]
+pub struct ForwardAuthConfig {
+    /// URL requested for each request; a 2xx answer lets the request through
+    pub address: String,
+    /// Request headers passed to the service; all of them when empty
+    #[serde(default)]
+    pub request_headers: Vec<String>,
+    /// Headers of a 2xx answer copied into the request sent upstream
+    #[serde(default)]
+    pub response_headers: Vec<String>,
+    /// Seconds allowed for the service to answer
+    #[serde(default = "default_forward_auth_timeout")]
+    pub timeout: u64,
+    /// Let the cache store the route's responses, for when they are the same for every approved client
+    #[serde(default)]
+    pub cache: bool,
+}
+
+fn default_forward_auth_timeout() -> u64 {
+    5
+}
+
+/// Maintenance mode of a route, switched on and off through the admin API.
+#[derive(Debug, Clone, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct MaintenanceConfig {
+    /// Start in maintenance
+    pub enabled: bool,
+    /// HTML file served with the 503; the `503` error page or a plain message when unset
+    pub page: Option<String>,
+    /// Seconds sent in `Retry-After`
+    pub retry_after: u64,
+}
+
+impl Default for MaintenanceConfig {
+    fn default() -> Self {
+        MaintenanceConfig { enabled: false, page: None, retry_after: 300 }
+    }
+}
+
+/// HTTP Basic authentication of a route.
+#[derive(Debug, Clone, Deserialize)]
+#[serde(deny_unknown_fields)]
+pub struct BasicAuthConfig {
+    /// htpasswd file with bcrypt (`htpasswd -B`) or SHA-1 (`htpasswd -s`) hashes
+    pub htpasswd: String,
+    /// Shown by browsers when asking for the password
+    #[serde(default = "default_basic_auth_realm")]
+    pub realm: String,
+}
+
+fn default_basic_auth_realm() -> String {
+    "Restricted".to_string()
+}
+
+/// Files served by a route from a local directory.
+#[derive(Debug, Clone, Deserialize)]
+#[serde(deny_unknown_fields)]
+pub struct StaticFilesConfig {
+    /// Directory the request paths are looked up in, after the route's prefix stripping and rewrites
+    pub root: String,
+    /// Files served for a directory, in order of preference
+    #[serde(default = "default_static_index")]
+    pub index: Vec<String>,
+    /// List the files of directories without an index file
+    #[serde(default)]
+    pub listing: bool,
+    /// File under `root` served for paths that are not found, as single-page apps need
+    pub fallback: Option<String>,
+    /// `Cache-Control` header sent with the files
+    pub cache_control: Option<String>,
+}
+
+fn default_static_index() -> Vec<String> {
+    vec!["index.html".to_string()]
+}
+
+impl StaticFilesConfig {
+    fn validate(&self, section: &str) -> Result<(), String> {
+        if self.root.trim().is_empty() {
+            return Err(format!("{}.root must be set", section));
+        }
+        if let Some(name) = self.index.iter().find(|name| name.is_empty() || name.contains('/')) {
+            return Err(format!("{}.index must list file names, not {:?}", section, name));
+        }
+        if self.fallback.as_deref().is_some_and(|fallback| Path::new(fallback.trim_start_matches('/')).components().any(|part| matches!(part, Component::ParentDir))) {
+            return Err(format!("{}.fallback must be a file under root", section));
+        }
+        if let Some(value) = &self.cache_control {
+            hyper::header::HeaderValue::from_str(value).map_err(|_| format!("{}: invalid cache_control {}", section, value))?;
+        }
+        Ok(())
+    }
+}
+
+/// Tags every request with an ID that is sent upstream, echoed to the client
+/// and written to the access log.
+#[derive(Debug, Clone, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct RequestIdConfig {
+    pub enabled: bool,
+    /// Header carrying the ID; a valid ID sent by the client is kept
+    pub header: String,
+}
+
+impl Default for RequestIdConfig {
+    fn default() -> Self {
+        RequestIdConfig { enabled: false, header: "X-Request-Id".to_string() }
+    }
+}
+
+/// OpenTelemetry tracing: a span per request, exported over OTLP/HTTP.
+#[derive(Debug, Clone, PartialEq, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct TelemetryConfig {
+    pub enabled: bool,
+    /// OTLP/HTTP traces endpoint of a collector, Jaeger or Tempo
+    pub endpoint: String,
+    pub service_name: String,
+    /// Fraction of new traces to record; incoming traces keep the caller's decision
+    pub sample_ratio: f64,
+    /// Seconds between span exports
+    pub export_interval: u64,
+}
+
+impl Default for TelemetryConfig {
+    fn default() -> Self {
+        TelemetryConfig {
+            enabled: false,
+            endpoint: "http://localhost:4318/v1/traces".to_string(),
+            service_name: "riffy".to_string(),
+            sample_ratio: 1.0,
+            export_interval: 5,
+        }
+    }
+}
+
+impl<'de> Deserialize<'de> for Strategy {
+    fn deserialize<D: Deserializer<'de>>(deserializer: D) -> Result<Self, D::Error> {
+        let s = String::deserialize(deserializer)?;
+        s.parse().map_err(serde::de::Error::custom)
+    }
+}
+
+impl<'de> Deserialize<'de> for ListenerMode {
+    fn deserialize<D: Deserializer<'de>>(deserializer: D) -> Result<Self, D::Error> {
+        let s = String::deserialize(deserializer)?;
+        s.parse().map_err(serde::de::Error::custom)
+    }
+}
+
+impl<'de> Deserialize<'de> for LogRotation {
+    fn deserialize<D: Deserializer<'de>>(deserializer: D) -> Result<Self, D::Error> {
+        let s = String::deserialize(deserializer)?;
+        s.parse().map_err(serde::de::Error::custom)
+    }
+}
+
+impl<'de> Deserialize<'de> for HostHeader {
+    fn deserialize<D: Deserializer<'de>>(deserializer: D) -> Result<Self, D::Error> {
+        let s = String::deserialize(deserializer)?;
+        s.parse().map_err(serde::de::Error::custom)
+    }
+}
+
+impl<'de> Deserialize<'de> for TlsVersion {
+    fn deserialize<D: Deserializer<'de>>(deserializer: D) -> Result<Self, D::Error> {
+        let s = String::deserialize(deserializer)?;
+        s.parse().map_err(serde::de::Error::custom)
+    }
+}
+
+impl<'de> Deserialize<'de> for ClientAuthMode {
+    fn deserialize<D: Deserializer<'de>>(deserializer: D) -> Result<Self, D::Error> {
+        let s = String::deserialize(deserializer)?;
+        s.parse().map_err(serde::de::Error::custom)
+    }
+}
+
+impl Config {
+    /// Loads the config file (if any), applies environment overrides and validates the result.
+    pub fn load(path: Option<&str>) -> Result<Config, String> {
+        Config::load_with(path, &Overrides::default())
+    }
+
+    /// Like [`Config::load`], with settings given on the command line applied
+    /// over the file and environment variables before validating.
+    pub fn load_with(path: Option<&str>, overrides: &Overrides) -> Result<Config, String> {
+        let config = Config::read_with(path, overrides)?;
+        config.validate()?;
+        Ok(config)
+    }
+
+    /// Reads the config file and applies environment variables and
+    /// `overrides`, without validating the result.
+    pub fn read_with(path: Option<&str>, overrides: &Overrides) -> Result<Config, String> {
+        let mut config = match path {
+            Some(path) => {
+                let contents = fs::read_to_string(path).map_err(|e| format!("failed to read {}: {}", path, e))?;
+                // YAML by extension, TOML otherwise
+                if path.ends_with(".yaml") || path.ends_with(".yml") {
+                    serde_yaml::from_str(&contents).map_err(|e| format!("failed to parse {}: {}", path, e))?
+                } else {
+                    toml::from_str(&contents).map_err(|e| format!("failed to parse {}: {}", path, e))?
+                }
+            }
+            None => Config::default(),
+        };
+
+        config.apply_env()?;
+        overrides.apply(&mut config);
+        Ok(config)
+    }
+
+    /// Overrides settings with any environment variables that are set.
+    fn apply_env(&mut self) -> Result<(), String> {
+        if let Ok(servers) = env::var("UPSTREAM_SERVERS") {
+            // Blank entries, as from a trailing comma or an empty variable, are skipped
+            self.upstreams.servers = servers.split(',').map(str::trim).filter(|s| !s.is_empty()).map(|s| UpstreamEntry::Spec(s.to_string())).collect();
+        }
+        env_override("UPSTREAM_ALLOW_EMPTY", &mut self.upstreams.allow_empty)?;
+        env_override("LB_STRATEGY", &mut self.upstreams.strategy)?;
+        env_override("MAX_FAILS", &mut self.upstreams.max_fails)?;
+        env_override("FAIL_TIMEOUT", &mut self.upstreams.fail_timeout)?;
+
+        let health = &mut self.upstreams.health_check;
+        env_override("HEALTH_CHECK_ENABLED", &mut health.enabled)?;
+        env_override("HEALTH_CHECK_PATH", &mut health.path)?;
+        env_override("HEALTH_CHECK_INTERVAL", &mut health.interval)?;
+        env_override("HEALTH_CHECK_TIMEOUT", &mut health.timeout)?;
+        env_override("HEALTH_CHECK_HEALTHY_THRESHOLD", &mut health.healthy_threshold)?;
+        env_override("HEALTH_CHECK_UNHEALTHY_THRESHOLD", &mut health.unhealthy_threshold)?;
+        env_override("HEALTH_CHECK_GRPC", &mut health.grpc)?;
+
+        env_override("RETRY_ATTEMPTS", &mut self.upstreams.retry.retries)?;
+        env_override_opt("RETRY_PER_TRY_TIMEOUT", &mut self.upstreams.retry.per_try_timeout)?;
+        env_override("RETRY_BACKOFF_MS", &mut self.upstreams.retry.backoff_ms)?;
+        env_override("RETRY_MAX_BACKOFF_MS", &mut self.upstreams.retry.max_backoff_ms)?;
+
+        let breaker = &mut self.upstreams.circuit_breaker;
+        env_override("CIRCUIT_BREAKER_ENABLED", &mut breaker.enabled)?;
+        env_override("CIRCUIT_BREAKER_CONSECUTIVE_FAILURES", &mut breaker.consecutive_failures)?;
+        env_override_opt("CIRCUIT_BREAKER_ERROR_RATE", &mut breaker.error_rate)?;
+        env_override("CIRCUIT_BREAKER_OPEN_DURATION", &mut breaker.open_duration)?;
+
+        env_override("STICKY_SESSIONS_ENABLED", &mut self.upstreams.sticky.enabled)?;
+        env_override("STICKY_COOKIE", &mut self.upstreams.sticky.cookie)?;
+        env_override("UPSTREAM_PROXY_PROTOCOL", &mut self.upstreams.proxy_protocol)?;
+        env_override("UPSTREAM_HTTP2", &mut self.upstreams.http2)?;
+        env_override("UPSTREAM_HOST_HEADER", &mut self.upstreams.host_header)?;
+        env_override("UPSTREAM_MAX_IDLE_CONNECTIONS", &mut self.upstreams.connections.max_idle_per_host)?;
+        env_override("UPSTREAM_IDLE_TIMEOUT", &mut self.upstreams.connections.idle_timeout)?;
+        env_override("UPSTREAM_TCP_KEEPALIVE", &mut self.upstreams.connections.tcp_keepalive)?;
+        env_override("UPSTREAM_MAX_REQUESTS", &mut self.upstreams.concurrency.max_requests)?;
+        env_override("UPSTREAM_QUEUE_SIZE", &mut self.upstreams.concurrency.queue_size)?;
+        env_override("UPSTREAM_QUEUE_TIMEOUT_MS", &mut self.upstreams.concurrency.queue_timeout_ms)?;
+        env_override("UPSTREAM_DNS_ENABLED", &mut self.upstreams.dns.enabled)?;
+        env_override("UPSTREAM_DNS_MIN_TTL", &mut self.upstreams.dns.min_ttl)?;
+        env_override("UPSTREAM_DNS_MAX_TTL", &mut self.upstreams.dns.max_ttl)?;
+        env_override("SRV_DISCOVERY_ENABLED", &mut self.upstreams.srv.enabled)?;
+        env_override("SRV_DISCOVERY_NAME", &mut self.upstreams.srv.name)?;
+        env_override("SRV_DISCOVERY_SCHEME", &mut self.upstreams.srv.scheme)?;
+
+        let kubernetes = &mut self.upstreams.kubernetes;
+        env_override("K8S_DISCOVERY_ENABLED", &mut kubernetes.enabled)?;
+        env_override("K8S_DISCOVERY_SERVICE", &mut kubernetes.service)?;
+        env_override_opt("K8S_DISCOVERY_NAMESPACE", &mut kubernetes.namespace)?;
+        env_override_opt("K8S_DISCOVERY_PORT", &mut kubernetes.port)?;
+        env_override("K8S_DISCOVERY_SCHEME", &mut kubernetes.scheme)?;
+
+        let consul = &mut self.upstreams.consul;
+        env_override("CONSUL_DISCOVERY_ENABLED", &mut consul.enabled)?;
+        env_override("CONSUL_HTTP_ADDR", &mut consul.address)?;
+        env_override("CONSUL_DISCOVERY_SERVICE", &mut consul.service)?;
+        if let Ok(tags) = env::var("CONSUL_DISCOVERY_TAGS") {
+            consul.tags = tags.split(',').map(|tag| tag.trim().to_string()).filter(|tag| !tag.is_empty()).collect();
+        }
+        env_override_opt("CONSUL_DATACENTER", &mut consul.datacenter)?;
+        env_override_opt("CONSUL_HTTP_TOKEN", &mut consul.token)?;
+        env_override("CONSUL_DISCOVERY_SCHEME", &mut consul.scheme)?;
+
+        env_override_opt("UPSTREAM_CA_BUNDLE", &mut self.upstreams.tls.ca_bundle)?;
+        env_override("UPSTREAM_TLS_VERIFY_HOSTNAME", &mut self.upstreams.tls.verify_hostname)?;
+        env_override_opt("UPSTREAM_TLS_CERT", &mut self.upstreams.tls.cert_path)?;
+        env_override_opt("UPSTREAM_TLS_KEY", &mut self.upstreams.tls.key_path)?;
+
+        env_override("SSL_ENABLED", &mut self.tls.enabled)?;
+        env_override_opt("SSL_CERT_PATH", &mut self.tls.cert_path)?;
+        env_override_opt("SSL_KEY_PATH", &mut self.tls.key_path)?;
+        env_override("HTTP2_ENABLED", &mut self.tls.http2)?;
+        env_override("TLS_MIN_VERSION", &mut self.tls.min_version)?;
+        env_override("TLS_MAX_VERSION", &mut self.tls.max_version)?;
+        if let Ok(suites) = env::var("TLS_CIPHER_SUITES") {
+            self.tls.cipher_suites = suites.split(',').map(|s| s.trim().to_string()).filter(|s| !s.is_empty()).collect();
+        }
+        env_override_opt("TLS_WATCH_INTERVAL", &mut self.tls.watch_interval)?;
+        env_override("TLS_CLIENT_AUTH", &mut self.tls.client_auth.mode)?;
+        env_override_opt("TLS_CLIENT_CA_BUNDLE", &mut self.tls.client_auth.ca_bundle)?;
+        env_override_opt("TLS_CLIENT_CRL", &mut self.tls.client_auth.crl_path)?;
+
+        env_override_opt("LISTEN_ADDR", &mut self.listener.address)?;
+        env_override_opt("LISTEN_PORT", &mut self.listener.port)?;
+        env_override_opt("LISTEN_SOCKET", &mut self.listener.socket)?;
+        env_override_opt("LISTEN_SOCKET_MODE", &mut self.listener.socket_mode)?;
+        env_override_opt("LISTEN_ACCEPTORS", &mut self.listener.acceptors)?;
+        env_override_opt("WORKER_THREADS", &mut self.runtime.worker_threads)?;
+        env_override_opt("UPGRADE_SOCKET", &mut self.upgrade.socket)?;
+        env_override_opt("PROCESS_USER", &mut self.process.user)?;
+        env_override_opt("PROCESS_GROUP", &mut self.process.group)?;
+        env_override("UPGRADE_DRAIN_TIMEOUT", &mut self.upgrade.drain_timeout)?;
+        env_override("PROXY_PROTOCOL_ENABLED", &mut self.listener.proxy_protocol)?;
+        env_override("LISTENER_MODE", &mut self.listener.mode)?;
+        if let Ok(ranges) = env::var("TRUSTED_PROXIES") {
+            self.listener.trusted_proxies = acl::parse_list(&ranges).map_err(|e| format!("invalid value for TRUSTED_PROXIES: {}", e))?;
+        }
+        env_override("TRUSTED_PROXIES_HEADER", &mut self.listener.trusted_proxies_header)?;
+        if let Ok(ranges) = env::var("ACCESS_ALLOW") {
+            self.listener.access.allow = acl::parse_list(&ranges).map_err(|e| format!("invalid value for ACCESS_ALLOW: {}", e))?;
+        }
+        if let Ok(ranges) = env::var("ACCESS_DENY") {
+            self.listener.access.deny = acl::parse_list(&ranges).map_err(|e| format!("invalid value for ACCESS_DENY: {}", e))?;
+        }
+        env_override_opt("CONNECT_TIMEOUT", &mut self.timeouts.connect)?;
+        env_override_opt("RESPONSE_HEADER_TIMEOUT", &mut self.timeouts.response_header)?;
+        env_override_opt("REQUEST_TIMEOUT", &mut self.timeouts.request)?;
+        env_override_opt("ADMIN_PORT", &mut self.admin.port)?;
+        env_override_opt("ADMIN_ADDR", &mut self.admin.address)?;
+        env_override_opt("ADMIN_TOKEN", &mut self.admin.token)?;
+        env_override("RATE_LIMIT_ENABLED", &mut self.rate_limit.enabled)?;
+        env_override("RATE_LIMIT_RPS", &mut self.rate_limit.requests_per_second)?;
+        env_override("RATE_LIMIT_BURST", &mut self.rate_limit.burst)?;
+        env_override("BANDWIDTH_ENABLED", &mut self.bandwidth.enabled)?;
+        env_override("BANDWIDTH_BYTES_PER_SECOND", &mut self.bandwidth.bytes_per_second)?;
+        env_override("BANDWIDTH_BURST", &mut self.bandwidth.burst)?;
+        env_override("LOAD_SHEDDING_ENABLED", &mut self.load_shedding.enabled)?;
+        env_override("LOAD_SHEDDING_MIN_LIMIT", &mut self.load_shedding.min_limit)?;
+        env_override("LOAD_SHEDDING_MAX_LIMIT", &mut self.load_shedding.max_limit)?;
+        env_override("LOAD_SHEDDING_TOLERANCE", &mut self.load_shedding.tolerance)?;
+        env_override("JWT_ENABLED", &mut self.jwt.enabled)?;
+        env_override("JWT_JWKS_URL", &mut self.jwt.jwks_url)?;
+        env_override_opt("JWT_ISSUER", &mut self.jwt.issuer)?;
+        env_override_opt("JWT_AUDIENCE", &mut self.jwt.audience)?;
+        env_override("SECURITY_HEADERS_ENABLED", &mut self.security_headers.enabled)?;
+        env_override("HSTS_MAX_AGE", &mut self.security_headers.hsts_max_age)?;
+        env_override("FRAME_OPTIONS", &mut self.security_headers.frame_options)?;
+        env_override_opt("CONTENT_SECURITY_POLICY", &mut self.security_headers.content_security_policy)?;
+        env_override("GEOIP_ENABLED", &mut self.geoip.enabled)?;
+        env_override_opt("GEOIP_COUNTRY_DATABASE", &mut self.geoip.country_database)?;
+        env_override_opt("GEOIP_ASN_DATABASE", &mut self.geoip.asn_database)?;
+        let country_lists = [("GEOIP_DENY_COUNTRIES", &mut self.geoip.deny_countries), ("GEOIP_ALLOW_COUNTRIES", &mut self.geoip.allow_countries)];
+        for (name, list) in country_lists {
+            if let Ok(value) = env::var(name) {
+                *list = value.split(',').map(|code| code.trim().to_string()).filter(|code| !code.is_empty()).collect();
+            }
+        }
+        env_override("CORS_ENABLED", &mut self.cors.enabled)?;
+        let cors_lists = [
+            ("CORS_ALLOWED_ORIGINS", &mut self.cors.allowed_origins),
+            ("CORS_ALLOWED_METHODS", &mut self.cors.allowed_methods),
+            ("CORS_ALLOWED_HEADERS", &mut self.cors.allowed_headers),
+        ];
+        for (name, list) in cors_lists {
+            if let Ok(value) = env::var(name) {
+                *list = value.split(',').map(|item| item.trim().to_string()).filter(|item| !item.is_empty()).collect();
+            }
+        }
+        env_override("CORS_ALLOW_CREDENTIALS", &mut self.cors.allow_credentials)?;
+        env_override_opt("CORS_MAX_AGE", &mut self.cors.max_age)?;
+        env_override("CACHE_ENABLED", &mut self.cache.enabled)?;
+        env_override("CACHE_MAX_SIZE_MB", &mut self.cache.max_size_mb)?;
+        env_override("CACHE_MAX_OBJECT_KB", &mut self.cache.max_object_kb)?;
+        env_override("CACHE_STALE_WHILE_REVALIDATE", &mut self.cache.stale_while_revalidate)?;
+        env_override("CACHE_STALE_IF_ERROR", &mut self.cache.stale_if_error)?;
+        env_override("CACHE_COALESCE", &mut self.cache.coalesce)?;
+        env_override_opt("CACHE_DISK_PATH", &mut self.cache.disk.path)?;
+        env_override("CACHE_DISK_MAX_SIZE_MB", &mut self.cache.disk.max_size_mb)?;
+        env_override("CACHE_DISK_MAX_OBJECT_MB", &mut self.cache.disk.max_object_mb)?;
+        if let Ok(ranges) = env::var("CACHE_PURGE_FROM") {
+            self.cache.purge_from = acl::parse_list(&ranges).map_err(|e| format!("invalid value for CACHE_PURGE_FROM: {}", e))?;
+        }
+        env_override("COMPRESSION_ENABLED", &mut self.compression.enabled)?;
+        env_override("COMPRESSION_MIN_SIZE", &mut self.compression.min_size)?;
+        env_override("MAX_BODY_SIZE", &mut self.limits.max_body_size)?;
+        env_override("MAX_RESPONSE_SIZE", &mut self.limits.max_response_size)?;
+        env_override("MAX_CONNECTIONS_PER_IP", &mut self.limits.max_connections_per_ip)?;
+        env_override_opt("CLIENT_HEADER_TIMEOUT", &mut self.limits.header_timeout)?;
+        env_override("PROBES_ENABLED", &mut self.probes.enabled)?;
+        env_override("LIVENESS_PATH", &mut self.probes.liveness_path)?;
+        env_override("READINESS_PATH", &mut self.probes.readiness_path)?;
+        env_override("HTTP_REDIRECT_ENABLED", &mut self.redirect.enabled)?;
+        env_override("HTTP_REDIRECT_PORT", &mut self.redirect.port)?;
+        env_override_opt("ACME_CHALLENGE_DIR", &mut self.redirect.acme_challenge_dir)?;
+        env_override("HTTP3_ENABLED", &mut self.http3.enabled)?;
+        env_override_opt("HTTP3_PORT", &mut self.http3.port)?;
+        // The name tracing_subscriber's EnvFilter is usually read from, taking precedence
+        env_override("LOG_LEVEL", &mut self.logging.level)?;
+        env_override("RUST_LOG", &mut self.logging.level)?;
+        env_override("ACCESS_LOG_ENABLED", &mut self.access_log.enabled)?;
+        env_override_opt("ACCESS_LOG_PATH", &mut self.access_log.path)?;
+        env_override("ACCESS_LOG_MAX_SIZE_MB", &mut self.access_log.max_size_mb)?;
+        env_override("ACCESS_LOG_ROTATE", &mut self.access_log.rotate)?;
+        env_override("ACCESS_LOG_MAX_FILES", &mut self.access_log.max_files)?;
+        if let Ok(address) = env::var("ACCESS_LOG_SYSLOG_ADDRESS") {
+            self.access_log.syslog.get_or_insert_with(SyslogConfig::default).address = address.trim().to_string();
+        }
+        env_override("ACCESS_LOG_JOURNALD", &mut self.access_log.journald)?;
+        env_override("SLOW_REQUESTS_ENABLED", &mut self.slow_requests.enabled)?;
+        env_override("SLOW_REQUEST_THRESHOLD_MS", &mut self.slow_requests.threshold_ms)?;
+        env_override("REQUEST_ID_ENABLED", &mut self.request_id.enabled)?;
+        env_override("REQUEST_ID_HEADER", &mut self.request_id.header)?;
+        env_override("TELEMETRY_ENABLED", &mut self.telemetry.enabled)?;
+        env_override("OTEL_EXPORTER_OTLP_TRACES_ENDPOINT", &mut self.telemetry.endpoint)?;
+        env_override("OTEL_SERVICE_NAME", &mut self.telemetry.service_name)?;
+        env_override("TELEMETRY_SAMPLE_RATIO", &mut self.telemetry.sample_ratio)?;
+        Ok(())
+    }
+
+    /// Checks the settings for consistency so problems surface at startup.
+    pub fn validate(&self) -> Result<(), String> {
+        self.upstreams.validate("upstreams")?;
+        for (name, pool) in &self.pools {
+            if name == DEFAULT_POOL {
+                return Err(format!("pool name '{}' is reserved for [upstreams]", DEFAULT_POOL));
+            }
+            pool.validate(&format!("pools.{}", name))?;
+        }
+        for route in &self.routes {
+            route.timeouts.validate(&format!("route to pool '{}': timeouts", route.pool))?;
+            HeaderRuleSet::new(&route.headers).map_err(|e| format!("route to pool '{}': headers: {}", route.pool, e))?;
+            if route.timeouts.connect.is_some() {
+                return Err(format!("route to pool '{}' sets a connect timeout; set it on the pool instead", route.pool));
+            }
+            if route.hosts.is_empty() && route.path_prefix.is_none() && route.countries.is_empty() && route.listeners.is_empty() {
+                return Err(format!("route to pool '{}' needs hosts, a path_prefix, countries or listeners", route.pool));
+            }
+            if let Some(name) = route.listeners.iter().find(|name| name.as_str() != DEFAULT_LISTENER && !self.listeners.iter().any(|listener| &listener.name == *name)) {
+                return Err(format!("route to pool '{}' names unknown listener '{}'", route.pool, name));
+            }
+            let countries_known = self.geoip.enabled && self.geoip.country_database.is_some();
+            if !route.countries.is_empty() && !countries_known {
+                return Err(format!("route to pool '{}' matches countries, which needs geoip.enabled and geoip.country_database", route.pool));
+            }
+            validate_countries(&route.countries).map_err(|e| format!("route to pool '{}': {}", route.pool, e))?;
+            if let Some(prefix) = &route.path_prefix {
+                if !prefix.starts_with('/') {
+                    return Err(format!("route path_prefix must start with '/': {}", prefix));
+                }
+            }
+            if route.strip_prefix && route.path_prefix.is_none() {
+                return Err(format!("route to pool '{}' sets strip_prefix without a path_prefix", route.pool));
+            }
+            if let Some(pattern) = &route.rewrite.regex {
+                Regex::new(pattern).map_err(|e| format!("route to pool '{}': invalid rewrite.regex {}: {}", route.pool, pattern, e))?;
+            }
+            if route.rewrite.add_prefix.as_deref().is_some_and(|prefix| !prefix.starts_with('/')) {
+                return Err(format!("route to pool '{}': rewrite.add_prefix must start with '/'", route.pool));
+            }
+            if route.pool != DEFAULT_POOL && !self.pools.contains_key(&route.pool) {
+                return Err(format!("route refers to unknown pool '{}'", route.pool));
+            }
+            if let Some(security) = &route.security_headers {
+                security.validate().map_err(|e| format!("route to pool '{}': {}", route.pool, e))?;
+            }
+            if let Some(bandwidth) = &route.bandwidth {
+                bandwidth.validate(&format!("route to pool '{}': bandwidth", route.pool))?;
+            }
+            if let Some(cache_key) = &route.cache_key {
+                cache_key.validate(&format!("route to pool '{}': cache_key", route.pool))?;
+            }
+            if let Some(files) = &route.static_files {
+                files.validate(&format!("route to pool '{}': static_files", route.pool))?;
+                if route.blue_green.is_some() || route.canary.is_some() || route.mirror.is_some() {
+                    return Err(format!("route to pool '{}' serves static_files, so cannot set blue_green, canary or mirror", route.pool));
+                }
+            }
+            if let Some(auth) = &route.forward_auth {
+                let address: hyper::Uri = auth.address.parse().map_err(|e| format!("route to pool '{}': invalid forward_auth.address {}: {}", route.pool, auth.address, e))?;
+                if !matches!(address.scheme_str(), Some("http") | Some("https")) {
+                    return Err(format!("route to pool '{}': forward_auth.address must be an http or https URL: {}", route.pool, auth.address));
+                }
+                for name in auth.request_headers.iter().chain(&auth.response_headers) {
+                    if hyper::header::HeaderName::from_bytes(name.as_bytes()).is_err() {
+                        return Err(format!("route to pool '{}': invalid forward_auth header {}", route.pool, name));
+                    }
+                }
+                if auth.timeout == 0 {
+                    return Err(format!("route to pool '{}': forward_auth.timeout must be at least 1 second", route.pool));
+                }
+            }
+            if let Some(pair) = &route.blue_green {
+                if pair.standby != DEFAULT_POOL && !self.pools.contains_key(&pair.standby) {
+                    return Err(format!("route to pool '{}' has a blue-green standby in unknown pool '{}'", route.pool, pair.standby));
+                }
+                if pair.standby == route.pool {
+                    return Err(format!("route to pool '{}' has a blue-green standby in the same pool", route.pool));
+                }
+            }
+            let shares = [("canary", route.canary.as_ref().map(|c| (&c.pool, c.percent))), ("mirror", route.mirror.as_ref().map(|m| (&m.pool, m.percent)))];
+            for (kind, share) in shares {
+                let (pool, percent) = match share {
+                    Some(share) => share,
+                    None => continue,
+                };
+                if pool != DEFAULT_POOL && !self.pools.contains_key(pool) {
+                    return Err(format!("route to pool '{}' has a {} in unknown pool '{}'", route.pool, kind, pool));
+                }
+                if *pool == route.pool {
+                    return Err(format!("route to pool '{}' has a {} in the same pool", route.pool, kind));
+                }
+                if !(0.0..=100.0).contains(&percent) {
+                    return Err(format!("route to pool '{}': {}.percent must be between 0 and 100", route.pool, kind));
+                }
+            }
+            if let Some(name) = &route.name {
+                if name.is_empty() || name.parse::<usize>().is_ok() {
+                    return Err(format!("route name '{}' must be non-empty and not a number", name));
+                }
+                if self.routes.iter().filter(|other| other.name.as_ref() == Some(name)).count() > 1 {
+                    return Err(format!("route name '{}' is used more than once", name));
+                }
+            }
+        }
+
+        match self.listener.mode {
+            ListenerMode::Http => {}
+            ListenerMode::Tcp if !self.routes.is_empty() || !self.pools.is_empty() => {
+                return Err("routes and pools are not available in tcp listener mode; use [upstreams]".to_string());
+            }
+            ListenerMode::Tcp => {}
+            ListenerMode::TlsPassthrough => {
+                if let Some(route) = self.routes.iter().find(|route| route.path_prefix.is_some() || route.hosts.is_empty()) {
+                    return Err(format!("route to pool '{}' must match on hosts only in tls_passthrough listener mode", route.pool));
+                }
+            }
+        }
+        if self.listener.mode != ListenerMode::Http {
+            if self.tls.enabled {
+                return Err("TLS termination is not available in tcp or tls_passthrough listener mode".to_string());
+            }
+            for settings in std::iter::once(&self.upstreams).chain(self.pools.values()) {
+                for upstream in settings.build_upstreams()?.iter().filter(|upstream| upstream.unix_socket().is_none()) {
+                    let uri: hyper::Uri = upstream.url.parse().map_err(|e| format!("invalid upstream {}: {}", upstream.url, e))?;
+                    if uri.scheme().is_none() || uri.port_u16().is_none() {
+                        return Err(format!("upstream {} must be written as tcp://host:port or unix:/path in tcp and tls_passthrough listener modes", upstream.url));
+                    }
+                }
+            }
+        }
+
+        if self.serves_tls() {
+            if self.tls.cert_path.is_some() != self.tls.key_path.is_some() {
+                return Err("tls.cert_path (SSL_CERT_PATH) and tls.key_path (SSL_KEY_PATH) must be set together".to_string());
+            }
+            if self.tls.cert_path.is_none() && self.tls.certificates.is_empty() {
+                return Err("tls.cert_path (SSL_CERT_PATH) or tls.certificates must be set when TLS is enabled".to_string());
+            }
+            for entry in &self.tls.certificates {
+                if entry.hostnames.is_empty() {
+                    return Err(format!("tls.certificates entry {} has no hostnames", entry.cert_path));
+                }
+            }
+            if self.tls.min_version > self.tls.max_version {
+                return Err("tls.min_version must not be greater than tls.max_version".to_string());
+            }
+            if self.tls.watch_interval == Some(0) {
+                return Err("tls.watch_interval must be at least 1 second".to_string());
+            }
+            let client_auth = &self.tls.client_auth;
+            if client_auth.mode != ClientAuthMode::None {
+                if client_auth.ca_bundle.is_none() {
+                    return Err("tls.client_auth.ca_bundle (TLS_CLIENT_CA_BUNDLE) must be set when client authentication is enabled".to_string());
+                }
+                if !client_auth.subject_header.is_empty() && hyper::header::HeaderName::from_bytes(client_auth.subject_header.as_bytes()).is_err() {
+                    return Err(format!("invalid tls.client_auth.subject_header: {}", client_auth.subject_header));
+                }
+            }
+        }
+
+        self.timeouts.validate("timeouts")?;
+        if self.request_id.enabled && hyper::header::HeaderName::from_bytes(self.request_id.header.as_bytes()).is_err() {
+            return Err(format!("invalid request_id.header: {}", self.request_id.header));
+        }
+        HeaderRuleSet::new(&self.headers).map_err(|e| format!("headers: {}", e))?;
+        crate::logging::parse(&self.logging.level).map_err(|e| format!("logging.level: {}", e))?;
+        if self.access_log.path.is_none() && (self.access_log.max_size_mb > 0 || self.access_log.rotate != LogRotation::Never) {
+            return Err("access_log rotation needs access_log.path; stdout cannot be rotated".to_string());
+        }
+        if [self.access_log.path.is_some(), self.access_log.syslog.is_some(), self.access_log.journald].iter().filter(|set| **set).count() > 1 {
+            return Err("access_log can only go to one of path, syslog or journald".to_string());
+        }
+        if let Some(syslog) = &self.access_log.syslog {
+            crate::syslog::parse_address(&syslog.address)?;
+            if crate::syslog::facility(&syslog.facility).is_none() {
+                return Err(format!("unknown access_log.syslog.facility: {}", syslog.facility));
+            }
+            if syslog.app_name.is_empty() || syslog.app_name.len() > 48 || !syslog.app_name.bytes().all(|b| b.is_ascii_graphic()) {
+                return Err(format!("access_log.syslog.app_name must be 1 to 48 printable characters without spaces: {}", syslog.app_name));
+            }
+        }
+        if cfg!(not(unix)) && self.access_log.journald {
+            return Err("access_log.journald is only supported on Unix".to_string());
+        }
+
+        if self.telemetry.enabled {
+            let telemetry = &self.telemetry;
+            let endpoint: hyper::Uri = telemetry.endpoint.parse().map_err(|e| format!("invalid telemetry.endpoint {}: {}", telemetry.endpoint, e))?;
+            if !matches!(endpoint.scheme_str(), Some("http") | Some("https")) {
+                return Err(format!("telemetry.endpoint must be an http or https URL: {}", telemetry.endpoint));
+            }
+            if !(0.0..=1.0).contains(&telemetry.sample_ratio) {
+                return Err(format!("telemetry.sample_ratio must be between 0 and 1: {}", telemetry.sample_ratio));
+            }
+            if telemetry.export_interval == 0 {
+                return Err("telemetry.export_interval must be at least 1 second".to_string());
+            }
+        }
+
+        if self.rate_limit.enabled {
+            let rate = self.rate_limit.requests_per_second;
+            if !rate.is_finite() || rate <= 0.0 {
+                return Err(format!("rate_limit.requests_per_second must be positive: {}", self.rate_limit.requests_per_second));
+            }
+            if self.rate_limit.burst == 0 {
+                return Err("rate_limit.burst must be at least 1".to_string());
+            }
+        }
+        self.bandwidth.validate("bandwidth")?;
+
+        if self.limits.header_timeout == Some(0) {
+            return Err("limits.header_timeout must be at least 1 second".to_string());
+        }
+
+        if self.load_shedding.enabled {
+            let shedding = &self.load_shedding;
+            if shedding.min_limit == 0 || shedding.min_limit > shedding.max_limit {
+                return Err(format!("load_shedding.min_limit must be between 1 and max_limit ({}): {}", shedding.max_limit, shedding.min_limit));
+            }
+            if !shedding.tolerance.is_finite() || shedding.tolerance < 1.0 {
+                return Err(format!("load_shedding.tolerance must be at least 1: {}", shedding.tolerance));
+            }
+        }
+
+        self.security_headers.validate()?;
+        for (status, page) in &self.error_pages {
+            if !status.parse::<u16>().is_ok_and(|status| (400..600).contains(&status)) {
+                return Err(format!("error_pages keys must be status codes from 400 to 599: {}", status));
+            }
+            if page.html.is_none() && page.json.is_none() {
+                return Err(format!("error_pages.{} needs an html or json template", status));
+            }
+        }
+        if self.geoip.enabled {
+            let geoip = &self.geoip;
+            if geoip.country_database.is_none() && geoip.asn_database.is_none() {
+                return Err("geoip.enabled needs geoip.country_database or geoip.asn_database".to_string());
+            }
+            if geoip.country_database.is_none() && !(geoip.deny_countries.is_empty() && geoip.allow_countries.is_empty()) {
+                return Err("geoip.deny_countries and geoip.allow_countries need geoip.country_database".to_string());
+            }
+            validate_countries(&geoip.deny_countries).and_then(|_| validate_countries(&geoip.allow_countries)).map_err(|e| format!("geoip: {}", e))?;
+            for name in [&geoip.country_header, &geoip.asn_header] {
+                if !name.is_empty() && hyper::header::HeaderName::from_bytes(name.as_bytes()).is_err() {
+                    return Err(format!("invalid geoip header: {}", name));
+                }
+            }
+        }
+        if self.cors.enabled {
+            let cors = &self.cors;
+            if cors.allowed_origins.is_empty() {
+                return Err("cors.allowed_origins must not be empty".to_string());
+            }
+            for origin in &cors.allowed_origins {
+                if origin != "*" && !(origin.starts_with("http://") || origin.starts_with("https://")) {
+                    return Err(format!("cors.allowed_origins entries must be * or start with http:// or https://: {}", origin));
+                }
+            }
+            // Reflecting every origin with credentials would let any site read users' responses
+            if cors.allow_credentials && cors.allowed_origins.iter().any(|origin| origin == "*") {
+                return Err("cors.allow_credentials cannot be combined with allowed_origins = [\"*\"]; list the origins allowed to send credentials".to_string());
+            }
+            for method in &cors.allowed_methods {
+                hyper::Method::from_bytes(method.as_bytes()).map_err(|_| format!("invalid method in cors.allowed_methods: {}", method))?;
+            }
+            for name in cors.allowed_headers.iter().chain(&cors.exposed_headers) {
+                if name != "*" && hyper::header::HeaderName::from_bytes(name.as_bytes()).is_err() {
+                    return Err(format!("invalid header in cors settings: {}", name));
+                }
+            }
+        }
+        if self.jwt.enabled {
+            let jwt = &self.jwt;
+            let url: hyper::Uri = jwt.jwks_url.parse().map_err(|e| format!("invalid jwt.jwks_url {}: {}", jwt.jwks_url, e))?;
+            if !matches!(url.scheme_str(), Some("http") | Some("https")) {
+                return Err(format!("jwt.jwks_url must be an http or https URL: {}", jwt.jwks_url));
+            }
+            if jwt.jwks_cache_ttl == 0 {
+                return Err("jwt.jwks_cache_ttl must be at least 1 second".to_string());
+            }
+            for (claim, header) in &jwt.forward_claims {
+                hyper::header::HeaderName::from_bytes(header.as_bytes()).map_err(|_| format!("invalid header for jwt.forward_claims.{}: {}", claim, header))?;
+            }
+        }
+
+        if self.cache.enabled && (self.cache.max_size_mb == 0 || self.cache.max_object_kb == 0) {
+            return Err("cache.max_size_mb and cache.max_object_kb must be at least 1".to_string());
+        }
+        if self.cache.enabled && self.cache.disk.path.is_some() && (self.cache.disk.max_size_mb == 0 || self.cache.disk.max_object_mb == 0) {
+            return Err("cache.disk.max_size_mb and cache.disk.max_object_mb must be at least 1".to_string());
+        }
+
+        if self.compression.enabled && self.compression.content_types.iter().all(|t| t.trim().is_empty()) {
+            return Err("compression.content_types must list at least one content type".to_string());
+        }
+
+        if self.admin.port == Some(self.listen_port()) {
+            return Err("admin.port must differ from the listener port".to_string());
+        }
+
+        for path in [&self.probes.liveness_path, &self.probes.readiness_path] {
+            if !path.starts_with('/') {
+                return Err(format!("probe paths must start with '/': {}", path));
+            }
+        }
+        if self.probes.liveness_path == self.probes.readiness_path {
+            return Err("probes.liveness_path and probes.readiness_path must differ".to_string());
+        }
+
+        for rule in &self.redirects {
+            RedirectRule::new(rule).map_err(|e| format!("redirect to {}: {}", rule.to, e))?;
+        }
+        if self.redirect.enabled {
+            if !self.tls.enabled {
+                return Err("redirect.enabled (HTTP_REDIRECT_ENABLED) requires TLS to be enabled".to_string());
+            }
+            if self.redirect.port == self.listen_port() || self.admin.port == Some(self.redirect.port) {
+                return Err("redirect.port must differ from the listener and admin ports".to_string());
+            }
+        }
+        self.listener.socket_mode()?;
+        match &self.listener.socket {
+            Some(socket) if socket.is_empty() => return Err("listener.socket (LISTEN_SOCKET) must not be empty".to_string()),
+            // Both advertise the listener's port to clients, which a socket does not have
+            Some(_) if self.redirect.enabled || self.http3.enabled => return Err("redirect and http3 need a TCP listener, not listener.socket".to_string()),
+            None if self.listener.socket_mode.is_some() => return Err("listener.socket_mode (LISTEN_SOCKET_MODE) needs listener.socket".to_string()),
+            _ => {}
+        }
+        match self.listener.acceptors {
+            Some(0) => return Err("listener.acceptors (LISTEN_ACCEPTORS) must be at least 1".to_string()),
+            Some(n) if n > 1 && !cfg!(unix) => return Err("listener.acceptors (LISTEN_ACCEPTORS) above 1 needs SO_REUSEPORT, which this platform lacks".to_string()),
+            Some(n) if n > 1 && self.listener.socket.is_some() => return Err("listener.acceptors (LISTEN_ACCEPTORS) needs a TCP listener, not listener.socket".to_string()),
+            _ => {}
+        }
+        if self.runtime.worker_threads == Some(0) {
+            return Err("runtime.worker_threads (WORKER_THREADS) must be at least 1".to_string());
+        }
+        match &self.upgrade.socket {
+            Some(socket) if socket.is_empty() => return Err("upgrade.socket (UPGRADE_SOCKET) must not be empty".to_string()),
+            Some(_) if !cfg!(unix) => return Err("upgrade.socket (UPGRADE_SOCKET) needs Unix domain sockets, which this platform lacks".to_string()),
+            // QUIC connections live in the UDP socket's process, so they cannot be handed over
+            Some(_) if self.http3.enabled => return Err("http3 does not support upgrade.socket yet".to_string()),
+            _ => {}
+        }
+        for (name, value) in [("process.user (PROCESS_USER)", &self.process.user), ("process.group (PROCESS_GROUP)", &self.process.group)] {
+            match value {
+                Some(value) if value.is_empty() => return Err(format!("{} must not be empty", name)),
+                Some(_) if !cfg!(unix) => return Err(format!("{} is only supported on Unix-like systems", name)),
+                _ => {}
+            }
+        }
+        self.validate_listeners()?;
+        if self.http3.enabled {
+            if !cfg!(feature = "http3") {
+                return Err("http3.enabled (HTTP3_ENABLED) needs Riffy built with the http3 feature: cargo build --release --features http3".to_string());
+            }
+            if !self.tls.enabled || self.listener.mode != ListenerMode::Http {
+                return Err("http3.enabled (HTTP3_ENABLED) requires TLS to be enabled in http listener mode".to_string());
+            }
+            // Requests over QUIC would skip the client certificate check
+            if self.tls.client_auth.mode != ClientAuthMode::None {
+                return Err("http3 does not support tls.client_auth yet".to_string());
+            }
+        }
+
+        Ok(())
+    }
+
+    fn validate_listeners(&self) -> Result<(), String> {
+        if self.listeners.is_empty() {
+            return Ok(());
+        }
+        if self.listener.mode != ListenerMode::Http {
+            return Err("[[listeners]] are only available in http listener mode".to_string());
+        }
+        let mut ports: Vec<u16> = [self.listener.socket.is_none().then(|| self.listen_port()), self.admin.port, self.redirect.enabled.then_some(self.redirect.port)].iter().flatten().copied().collect();
+        for (i, listener) in self.listeners.iter().enumerate() {
+            let name = &listener.name;
+            if name.is_empty() || name == DEFAULT_LISTENER {
+                return Err(format!("listener name '{}' is reserved for [listener]; give each of [[listeners]] a name of its own", name));
+            }
+            if self.listeners[..i].iter().any(|other| &other.name == name) {
+                return Err(format!("listener name '{}' is used more than once", name));
+            }
+            listener.socket_mode()?;
+            match (listener.port, &listener.socket) {
+                (Some(port), None) if ports.contains(&port) => return Err(format!("listeners.{}.port {} is already in use by another listener or the admin server", name, port)),
+                (Some(port), None) => ports.push(port),
+                (None, Some(socket)) if !socket.is_empty() => {}
+                _ => return Err(format!("listeners.{} needs either a port or a socket", name)),
+            }
+            if listener.socket_mode.is_some() && listener.socket.is_none() {
+                return Err(format!("listeners.{}.socket_mode needs a socket", name));
+            }
+        }
+        Ok(())
+    }
+
+    /// The port to listen on, falling back to the scheme default.
+    pub fn listen_port(&self) -> u16 {
+        self.listener.port.unwrap_or(if self.tls.enabled { 443 } else { 80 })
+    }
+
+    /// The address and port to listen on.
+    pub fn listen_addr(&self) -> SocketAddr {
+        SocketAddr::new(self.listener.address.unwrap_or_default().0, self.listen_port())
+    }
+
+    /// Whether any listener terminates TLS, needing the `[tls]` certificates.
+    pub fn serves_tls(&self) -> bool {
+        self.tls.enabled || self.listeners.iter().any(|listener| listener.tls)
+    }
+}
+
+impl UpstreamsConfig {
+    /// Checks a pool's settings; `section` names it in error messages.
+    fn validate(&self, section: &str) -> Result<(), String> {
+        if self.servers.is_empty() && !self.discovers_upstreams() && !self.allow_empty {
+            return Err(format!("{} has no upstream servers; list some in servers (or UPSTREAM_SERVERS), or set allow_empty to answer 503 without them", section));
+        }
+        self.build_upstreams().map_err(|e| format!("{}: {}", section, e))?;
+        if self.tls.cert_path.is_some() != self.tls.key_path.is_some() {
+            return Err(format!("{}.tls.cert_path and key_path must be set together", section));
+        }
+
+        let health = &self.health_check;
+        if health.enabled {
+            if !health.path.starts_with('/') {
+                return Err(format!("{}.health_check.path must start with '/': {}", section, health.path));
+            }
+            if health.interval == 0 {
+                return Err(format!("{}.health_check.interval must be at least 1 second", section));
+            }
+            // A probe that always times out takes every upstream out of rotation
+            if health.timeout == 0 {
+                return Err(format!("{}.health_check.timeout must be at least 1 second", section));
+            }
+            if health.timeout >= health.interval {
+                return Err(format!("{}.health_check.timeout must be shorter than interval ({}s)", section, health.interval));
+            }
+            if health.healthy_threshold == 0 || health.unhealthy_threshold == 0 {
+                return Err(format!("{}.health_check thresholds must be at least 1", section));
+            }
+            let discovers_h2c = [(self.kubernetes.enabled, &self.kubernetes.scheme), (self.consul.enabled, &self.consul.scheme), (self.srv.enabled, &self.srv.scheme)]
+                .iter()
+                .any(|(enabled, scheme)| *enabled && scheme.as_str() == "h2c");
+            if health.grpc && !self.http2 && !discovers_h2c && !self.build_upstreams()?.iter().all(Upstream::is_h2c) {
+                return Err(format!("{}.health_check.grpc needs http2 = true or h2c:// upstreams", section));
+            }
+        }
+
+        let breaker = &self.circuit_breaker;
+        if breaker.enabled {
+            if let Some(rate) = breaker.error_rate {
+                if !(0.0..=1.0).contains(&rate) || rate == 0.0 {
+                    return Err(format!("{}.circuit_breaker.error_rate must be in (0, 1]: {}", section, rate));
+                }
+            }
+            if breaker.consecutive_failures == 0 && breaker.error_rate.is_none() {
+                return Err(format!("{}.circuit_breaker needs consecutive_failures or error_rate", section));
+            }
+        }
+
+        self.timeouts.validate(&format!("{}.timeouts", section))?;
+        if self.connections.idle_timeout == 0 {
+            return Err(format!("{}.connections.idle_timeout must be at least 1 second", section));
+        }
+        if (self.dns.enabled || self.srv.enabled) && (self.dns.min_ttl == 0 || self.dns.max_ttl < self.dns.min_ttl) {
+            return Err(format!("{}.dns needs 1 <= min_ttl <= max_ttl", section));
+        }
+        let kubernetes = &self.kubernetes;
+        let consul = &self.consul;
+        let srv = &self.srv;
+        if [kubernetes.enabled, consul.enabled, srv.enabled].iter().filter(|&&enabled| enabled).count() > 1 {
+            return Err(format!("{} can only discover upstreams from one of kubernetes, consul and srv", section));
+        }
+        let discovery = [
+            ("kubernetes", kubernetes.enabled, "service", &kubernetes.service, &kubernetes.scheme),
+            ("consul", consul.enabled, "service", &consul.service, &consul.scheme),
+            ("srv", srv.enabled, "name", &srv.name, &srv.scheme),
+        ];
+        for (name, enabled, field, service, scheme) in discovery {
+            if !enabled {
+                continue;
+            }
+            if service.is_empty() {
+                return Err(format!("{}.{}.{} is required", section, name, field));
+            }
+            if !["http", "https", "h2c", "tcp"].contains(&scheme.as_str()) {
+                return Err(format!("{}.{}.scheme must be http, https, h2c or tcp: {}", section, name, scheme));
+            }
+        }
+        if let Some(url) = kubernetes.api_server.as_ref().filter(|_| kubernetes.enabled) {
+            url.parse::<hyper::Uri>().map_err(|e| format!("invalid {}.kubernetes.api_server {}: {}", section, url, e))?;
+        }
+        if consul.enabled {
+            consul.address.parse::<hyper::Uri>().map_err(|e| format!("invalid {}.consul.address {}: {}", section, consul.address, e))?;
+        }
+
+        let cookie = &self.sticky.cookie;
+        if self.sticky.enabled && (cookie.is_empty() || !cookie.chars().all(|c| c.is_ascii_alphanumeric() || "-_.".contains(c))) {
+            return Err(format!("{}.sticky.cookie is not a valid cookie name: {}", section, self.sticky.cookie));
+        }
+
+        Ok(())
+    }
+
+    /// Whether the upstreams come from a service registry rather than `servers`.
+    pub fn discovers_upstreams(&self) -> bool {
+        self.kubernetes.enabled || self.consul.enabled || self.srv.enabled
+    }
+
+    /// The sticky session cookie name, or `None` when sticky sessions are disabled.
+    pub fn sticky_cookie(&self) -> Option<String> {
+        if self.sticky.enabled {
+            Some(self.sticky.cookie.clone())
+        } else {
+            None
+        }
+    }
+
+    pub fn build_upstreams(&self) -> Result<Vec<Upstream>, String> {
+        self.servers
+            .iter()
+            .map(|entry| match entry {
+                UpstreamEntry::Spec(spec) => Upstream::parse(spec),
+                UpstreamEntry::Server { url, weight, backup } if (1..=balancer::MAX_WEIGHT).contains(weight) => Ok(Upstream::new(balancer::normalize_url(url)?, *weight).with_backup(*backup)),
+                UpstreamEntry::Server { url, .. } => Err(format!("weight must be between 1 and {} for {}", balancer::MAX_WEIGHT, url)),
+            })
+            .collect()
+    }
+
+    pub fn retry_policy(&self) -> RetryPolicy {
+        RetryPolicy {
+            retries: self.retry.retries,
+            per_try_timeout: self.retry.per_try_timeout.map(Duration::from_secs),
+            backoff: Duration::from_millis(self.retry.backoff_ms),
+            max_backoff: Duration::from_millis(self.retry.max_backoff_ms),
+        }
+    }
+
+    /// The per-upstream concurrency limit, or `None` when unlimited.
+    pub fn concurrency_limit(&self) -> Option<ConcurrencyLimit> {
+        let concurrency = &self.concurrency;
+        if concurrency.max_requests == 0 {
+            return None;
+        }
+        Some(ConcurrencyLimit {
+            max_requests: concurrency.max_requests,
+            queue_size: concurrency.queue_size,
+            queue_timeout: Duration::from_millis(concurrency.queue_timeout_ms),
+        })
+    }
+
+    /// Circuit breaker thresholds, or `None` when disabled.
+    pub fn circuit_breaker(&self) -> Option<CircuitBreakerConfig> {
+        let breaker = &self.circuit_breaker;
+        if !breaker.enabled {
+            return None;
+        }
+        Some(CircuitBreakerConfig {
+            consecutive_failures: breaker.consecutive_failures,
+            error_rate: breaker.error_rate,
+            min_requests: breaker.min_requests,
+            window: Duration::from_secs(breaker.window),
+            open_duration: Duration::from_secs(breaker.open_duration),
+            half_open_requests: breaker.half_open_requests,
+        })
+    }
+
+    pub fn passive_health(&self) -> PassiveHealthConfig {
+        PassiveHealthConfig { max_fails: self.max_fails, fail_timeout: Duration::from_secs(self.fail_timeout) }
+    }
+
+    /// Active health check settings, or `None` when disabled.
+    pub fn health_check(&self) -> Option<HealthCheckConfig> {
+        let health = &self.health_check;
+        if !health.enabled {
+            return None;
+        }
+        Some(HealthCheckConfig {
+            path: health.path.clone(),
+            interval: Duration::from_secs(health.interval),
+            timeout: Duration::from_secs(health.timeout),
+            healthy_threshold: health.healthy_threshold,
+            unhealthy_threshold: health.unhealthy_threshold,
+            grpc: health.grpc.then(|| health.grpc_service.clone()),
+            tcp: false,
+        })
+    }
+}
+
+fn env_override<T: FromStr>(name: &str, target: &mut T) -> Result<(), String> {
+    if let Ok(value) = env::var(name) {
+        *target = value.trim().parse().map_err(|_| format!("invalid value for {}: {}", name, value))?;
+    }
+    Ok(())
+}
+
+fn env_override_opt<T: FromStr>(name: &str, target: &mut Option<T>) -> Result<(), String> {
+    if let Ok(value) = env::var(name) {
+        *target = Some(value.trim().parse().map_err(|_| format!("invalid value for {}: {}", name, value))?);
+    }
+    Ok(())
+}
@@ -0,0 +1,47 @@
+use std::collections::HashMap;
+use std::net::IpAddr;
+use std::sync::{Arc, Mutex};
+
+/// Open client connections per IP address. Counts are kept even without a
+/// limit, so one that is set by a reload applies to connections already open.
+#[derive(Debug, Default)]
+pub struct ConnectionLimiter {
+    open: Arc<Mutex<HashMap<IpAddr, usize>>>,
+}
+
+/// An open connection, counted against its address until dropped.
+#[derive(Debug)]
+pub struct ClientConnection {
+    open: Arc<Mutex<HashMap<IpAddr, usize>>>,
+    ip: IpAddr,
+}
+
+impl ConnectionLimiter {
+    pub fn new() -> Self {
+        ConnectionLimiter::default()
+    }
+
+    /// Counts a new connection from `ip`, or returns `None` when it already
+    /// has `max` open; 0 means no limit.
+    pub fn open(&self, ip: IpAddr, max: usize) -> Option<ClientConnection> {
+        let mut open = self.open.lock().unwrap();
+        let count = open.entry(ip).or_insert(0);
+        if max > 0 && *count >= max {
+            return None;
+        }
+        *count += 1;
+        Some(ClientConnection { open: Arc::clone(&self.open), ip })
+    }
+}
+
+impl Drop for ClientConnection {
+    fn drop(&mut self) {
+        let mut open = self.open.lock().unwrap();
+        if let Some(count) = open.get_mut(&self.ip) {
+            *count -= 1;
+            if *count == 0 {
+                open.remove(&self.ip);
+            }
+        }
+    }
+}
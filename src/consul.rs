@@ -0,0 +1,136 @@
+use hyper::client::Client;
+use hyper::{Body, Request};
+use serde_json::Value;
+use std::net::IpAddr;
+use std::sync::Arc;
+use std::time::Duration;
+use tracing::warn;
+
+use crate::balancer::{Balancer, Upstream, MAX_WEIGHT};
+use crate::config::{ConsulSettings, UpstreamsConfig};
+use crate::discovery::Membership;
+use crate::health::HealthCheckConfig;
+use crate::tls::{self, ClientConnector, UpstreamConnector};
+
+/// How long Consul holds a blocking query open while nothing changes.
+const BLOCKING_WAIT: &str = "5m";
+/// Wait before retrying after a failed query.
+const RETRY_DELAY: Duration = Duration::from_secs(5);
+
+/// Keeps a pool in line with the passing instances of a Consul service.
+struct Watcher {
+    client: Client<ClientConnector>,
+    /// Health endpoint of the service, with the tag and datacenter filters
+    url: String,
+    token: Option<String>,
+    tags: Vec<String>,
+    scheme: String,
+    membership: Membership,
+}
+
+/// Starts watching the configured service with blocking queries. The watch
+/// stops once the pool has been dropped, e.g. after a config reload.
+pub fn spawn(balancer: &Arc<Balancer>, settings: &ConsulSettings, health: Option<(HealthCheckConfig, UpstreamConnector)>) -> Result<(), String> {
+    let mut url = format!("{}/v1/health/service/{}?passing=true", settings.address.trim_end_matches('/'), escape(&settings.service));
+    for tag in &settings.tags {
+        url.push_str(&format!("&tag={}", escape(tag)));
+    }
+    if let Some(datacenter) = &settings.datacenter {
+        url.push_str(&format!("&dc={}", escape(datacenter)));
+    }
+
+    let connector = tls::upstream_connector(&UpstreamsConfig::default(), Some(Duration::from_secs(10)))?;
+    let watcher = Watcher {
+        client: connector.client(None),
+        url,
+        token: settings.token.clone(),
+        tags: settings.tags.clone(),
+        scheme: settings.scheme.clone(),
+        membership: Membership::new(balancer, health, format!("Consul service {}", settings.service)),
+    };
+    tokio::spawn(watcher.run());
+    Ok(())
+}
+
+impl Watcher {
+    async fn run(self) {
+        let mut index = 0;
+        while self.membership.is_active() {
+            match self.query(index).await {
+                Ok((next, instances)) => {
+                    let upstreams = instances.into_iter().map(|(url, weight)| Upstream::new(url, weight)).collect();
+                    if !self.membership.sync(upstreams) {
+                        return;
+                    }
+                    // A lower index means Consul's state was reset; start over
+                    index = if next < index { 0 } else { next };
+                    if index == 0 {
+                        tokio::time::sleep(RETRY_DELAY).await;
+                    }
+                }
+                // The last known instances stay in use until Consul answers again
+                Err(e) => {
+                    warn!("Consul discovery of {} failed: {}", self.url, e);
+                    index = 0;
+                    tokio::time::sleep(RETRY_DELAY).await;
+                }
+            }
+        }
+    }
+
+    /// Waits for the instances to change from `index`, returning the new
+    /// index and the upstream URLs with their weights.
+    async fn query(&self, index: u64) -> Result<(u64, Vec<(String, u32)>), String> {
+        let mut req = Request::get(format!("{}&index={}&wait={}", self.url, index, BLOCKING_WAIT));
+        if let Some(token) = &self.token {
+            req = req.header("X-Consul-Token", token.as_str());
+        }
+        let req = req.body(Body::empty()).map_err(|e| e.to_string())?;
+        // Consul adds up to 1/16 of the wait as jitter
+        let exchange = async {
+            let res = self.client.request(req).await.map_err(|e| e.to_string())?;
+            if !res.status().is_success() {
+                return Err(format!("Consul returned {}", res.status()));
+            }
+            let next = res.headers().get("X-Consul-Index").and_then(|v| v.to_str().ok()).and_then(|v| v.parse().ok()).unwrap_or(0);
+            let bytes = hyper::body::to_bytes(res.into_body()).await.map_err(|e| e.to_string())?;
+            Ok((next, bytes))
+        };
+        let (next, bytes) = tokio::time::timeout(Duration::from_secs(6 * 60), exchange).await.map_err(|_| "query timed out".to_string())??;
+        let entries: Value = serde_json::from_slice(&bytes).map_err(|e| format!("invalid health response: {}", e))?;
+
+        let mut instances: Vec<(String, u32)> = entries.as_array().into_iter().flatten().filter_map(|entry| self.instance(entry)).collect();
+        instances.sort();
+        instances.dedup_by(|a, b| a.0 == b.0);
+        Ok((next, instances))
+    }
+
+    /// The upstream URL and weight of one health entry.
+    fn instance(&self, entry: &Value) -> Option<(String, u32)> {
+        let service = &entry["Service"];
+        let tags: Vec<&str> = service["Tags"].as_array().into_iter().flatten().filter_map(Value::as_str).collect();
+        if !self.tags.iter().all(|tag| tags.contains(&tag.as_str())) {
+            return None;
+        }
+        // Instances registered without an address use their node's
+        let address = service["Address"].as_str().filter(|a| !a.is_empty()).or_else(|| entry["Node"]["Address"].as_str())?;
+        let port = service["Port"].as_u64().filter(|&port| port > 0 && port <= u16::MAX as u64)?;
+        let weight = service["Weights"]["Passing"].as_u64().unwrap_or(1).clamp(1, MAX_WEIGHT as u64) as u32;
+        let host = match address.parse::<IpAddr>() {
+            Ok(IpAddr::V6(ip)) => format!("[{}]", ip),
+            _ => address.to_string(),
+        };
+        Some((format!("{}://{}:{}", self.scheme, host, port), weight))
+    }
+}
+
+/// Percent-encodes a query parameter value.
+fn escape(value: &str) -> String {
+    value
+        .bytes()
+        .map(|b| match b {
+            b'A'..=b'Z' | b'a'..=b'z' | b'0'..=b'9' | b'-' | b'_' | b'.' | b'~' => (b as char).to_string(),
+            _ => format!("%{:02X}", b),
+        })
+        .collect()
+}
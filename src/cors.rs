@@ -0,0 +1,224 @@
+use async_trait::async_trait;
+use hyper::header::{
+    HeaderValue, ACCESS_CONTROL_ALLOW_CREDENTIALS, ACCESS_CONTROL_ALLOW_HEADERS, ACCESS_CONTROL_ALLOW_METHODS, ACCESS_CONTROL_ALLOW_ORIGIN, ACCESS_CONTROL_EXPOSE_HEADERS, ACCESS_CONTROL_MAX_AGE,
+    ACCESS_CONTROL_REQUEST_HEADERS, ACCESS_CONTROL_REQUEST_METHOD, ORIGIN, VARY,
+};
+use hyper::{Body, HeaderMap, Method, Request, Response, StatusCode};
+
+use crate::config::CorsConfig;
+use crate::middleware::{Context, Middleware};
+
+/// Middleware answering CORS preflights itself and setting the CORS headers
+/// of every response to a cross-origin request, replacing any the upstream
+/// sent, so backends need no CORS handling of their own.
+pub struct Cors {
+    any_origin: bool,
+    origins: Vec<String>,
+    methods: HeaderValue,
+    /// `None` when the headers a preflight asks for are allowed
+    headers: Option<HeaderValue>,
+    exposed: Option<HeaderValue>,
+    credentials: bool,
+    max_age: Option<HeaderValue>,
+}
+
+/// The `Origin` of a request, and whether it is allowed, for its response.
+struct RequestOrigin {
+    origin: HeaderValue,
+    allowed: bool,
+}
+
+impl Cors {
+    pub fn new(config: &CorsConfig) -> Result<Self, String> {
+        let join = |items: &[String]| HeaderValue::from_str(&items.join(", ")).map_err(|e| format!("invalid cors setting {:?}: {}", items, e));
+        // With credentials browsers take `*` literally, so requested headers are echoed instead
+        let any_header = config.allowed_headers.is_empty() || (config.allow_credentials && config.allowed_headers.iter().any(|h| h == "*"));
+        Ok(Cors {
+            any_origin: config.allowed_origins.iter().any(|origin| origin == "*"),
+            origins: config.allowed_origins.iter().map(|origin| origin.trim_end_matches('/').to_ascii_lowercase()).collect(),
+            methods: join(&config.allowed_methods)?,
+            headers: if any_header { None } else { Some(join(&config.allowed_headers)?) },
+            exposed: if config.exposed_headers.is_empty() { None } else { Some(join(&config.exposed_headers)?) },
+            credentials: config.allow_credentials,
+            max_age: config.max_age.map(HeaderValue::from),
+        })
+    }
+
+    fn allows(&self, origin: &HeaderValue) -> bool {
+        self.any_origin || origin.to_str().is_ok_and(|origin| self.origins.iter().any(|allowed| allowed.eq_ignore_ascii_case(origin)))
+    }
+
+    /// Sets the headers common to preflights and other responses.
+    fn allow_origin(&self, headers: &mut HeaderMap, origin: &HeaderValue) {
+        if self.any_origin {
+            headers.insert(ACCESS_CONTROL_ALLOW_ORIGIN, HeaderValue::from_static("*"));
+        } else {
+            headers.insert(ACCESS_CONTROL_ALLOW_ORIGIN, origin.clone());
+        }
+        if self.credentials {
+            headers.insert(ACCESS_CONTROL_ALLOW_CREDENTIALS, HeaderValue::from_static("true"));
+        }
+    }
+
+    fn preflight(&self, req: &Request<Body>, origin: &HeaderValue) -> Response<Body> {
+        let mut res = Response::builder().status(StatusCode::NO_CONTENT).body(Body::empty()).unwrap();
+        let headers = res.headers_mut();
+        headers.insert(VARY, HeaderValue::from_static("Origin, Access-Control-Request-Method, Access-Control-Request-Headers"));
+        // A refused origin gets no CORS headers, which makes the browser fail the request
+        if !self.allows(origin) {
+            return res;
+        }
+        self.allow_origin(headers, origin);
+        headers.insert(ACCESS_CONTROL_ALLOW_METHODS, self.methods.clone());
+        let requested = req.headers().get(ACCESS_CONTROL_REQUEST_HEADERS);
+        if let Some(allowed) = self.headers.as_ref().or(requested) {
+            headers.insert(ACCESS_CONTROL_ALLOW_HEADERS, allowed.clone());
+        }
+        if let Some(max_age) = &self.max_age {
+            headers.insert(ACCESS_CONTROL_MAX_AGE, max_age.clone());
+        }
+        res
+    }
+}
+
+#[async_trait]
+impl Middleware for Cors {
+    async fn on_request(&self, req: &mut Request<Body>, ctx: &mut Context) -> Option<Response<Body>> {
+        let origin = req.headers().get(ORIGIN)?.clone();
+        if req.method() == Method::OPTIONS && req.headers().contains_key(ACCESS_CONTROL_REQUEST_METHOD) {
+            return Some(self.preflight(req, &origin));
+        }
+        ctx.extensions.insert(RequestOrigin { allowed: self.allows(&origin), origin });
+        None
+    }
+
+    async fn on_response(&self, res: &mut Response<Body>, ctx: &mut Context) {
+        let RequestOrigin { origin, allowed } = match ctx.extensions.remove::<RequestOrigin>() {
+            Some(request) => request,
+            None => return,
+        };
+        let headers = res.headers_mut();
+        let upstream: Vec<_> = headers.keys().filter(|name| name.as_str().starts_with("access-control-")).cloned().collect();
+        for name in upstream {
+            headers.remove(name);
+        }
+        if !self.any_origin {
+            headers.append(VARY, HeaderValue::from_static("Origin"));
+        }
+        if allowed {
+            self.allow_origin(headers, &origin);
+            if let Some(exposed) = &self.exposed {
+                headers.insert(ACCESS_CONTROL_EXPOSE_HEADERS, exposed.clone());
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::config::Config;
+    use hyper::header::HeaderName;
+
+    fn cors(origins: &[&str], configure: impl FnOnce(&mut CorsConfig)) -> Cors {
+        let mut config = CorsConfig { enabled: true, allowed_origins: origins.iter().map(|origin| origin.to_string()).collect(), ..CorsConfig::default() };
+        configure(&mut config);
+        Cors::new(&config).unwrap()
+    }
+
+    fn context() -> Context {
+        Context::new(([192, 0, 2, 1], 4000).into(), false)
+    }
+
+    fn preflight(origin: &str, requested_headers: Option<&str>) -> Request<Body> {
+        let mut req = Request::builder().method(Method::OPTIONS).uri("/api").header(ORIGIN, origin).header(ACCESS_CONTROL_REQUEST_METHOD, "PUT");
+        if let Some(headers) = requested_headers {
+            req = req.header(ACCESS_CONTROL_REQUEST_HEADERS, headers);
+        }
+        req.body(Body::empty()).unwrap()
+    }
+
+    /// The headers of the response to a GET from `origin`, given the upstream's `headers`.
+    async fn response(cors: &Cors, origin: Option<&str>, headers: &[(&str, &str)]) -> HeaderMap {
+        let mut req = Request::get("/api");
+        if let Some(origin) = origin {
+            req = req.header(ORIGIN, origin);
+        }
+        let mut ctx = context();
+        assert!(cors.on_request(&mut req.body(Body::empty()).unwrap(), &mut ctx).await.is_none());
+        let mut res = Response::new(Body::empty());
+        for (name, value) in headers {
+            res.headers_mut().append(HeaderName::from_bytes(name.as_bytes()).unwrap(), HeaderValue::from_str(value).unwrap());
+        }
+        cors.on_response(&mut res, &mut ctx).await;
+        res.headers().clone()
+    }
+
+    #[tokio::test]
+    async fn answers_preflights_for_allowed_origins() {
+        let cors = cors(&["https://App.example.com/"], |config| config.max_age = Some(600));
+        let res = cors.on_request(&mut preflight("https://app.example.com", Some("x-token")), &mut context()).await.expect("answered here");
+        assert_eq!(res.status(), StatusCode::NO_CONTENT);
+        let headers = res.headers();
+        assert_eq!(headers[ACCESS_CONTROL_ALLOW_ORIGIN], "https://app.example.com");
+        assert_eq!(headers[ACCESS_CONTROL_ALLOW_METHODS], "GET, HEAD, POST, PUT, PATCH, DELETE");
+        // Without a list, the requested headers are allowed
+        assert_eq!(headers[ACCESS_CONTROL_ALLOW_HEADERS], "x-token");
+        assert_eq!(headers[ACCESS_CONTROL_MAX_AGE], "600");
+        assert!(!headers.contains_key(ACCESS_CONTROL_ALLOW_CREDENTIALS));
+        assert!(headers[VARY].to_str().unwrap().starts_with("Origin"));
+
+        let res = cors.on_request(&mut preflight("https://evil.example.com", None), &mut context()).await.expect("answered here");
+        assert_eq!(res.status(), StatusCode::NO_CONTENT);
+        assert!(!res.headers().keys().any(|name| name.as_str().starts_with("access-control-")));
+    }
+
+    #[tokio::test]
+    async fn lists_the_allowed_headers_when_configured() {
+        let cors = cors(&["https://app.example.com"], |config| config.allowed_headers = vec!["Content-Type".to_string(), "X-Token".to_string()]);
+        let res = cors.on_request(&mut preflight("https://app.example.com", Some("x-other")), &mut context()).await.unwrap();
+        assert_eq!(res.headers()[ACCESS_CONTROL_ALLOW_HEADERS], "Content-Type, X-Token");
+        // With credentials a `*` would be taken literally, so the requested headers are echoed
+        let cors = self::cors(&["https://app.example.com"], |config| {
+            config.allowed_headers = vec!["*".to_string()];
+            config.allow_credentials = true;
+        });
+        let res = cors.on_request(&mut preflight("https://app.example.com", Some("x-other")), &mut context()).await.unwrap();
+        assert_eq!(res.headers()[ACCESS_CONTROL_ALLOW_HEADERS], "x-other");
+        assert_eq!(res.headers()[ACCESS_CONTROL_ALLOW_CREDENTIALS], "true");
+    }
+
+    #[tokio::test]
+    async fn replaces_the_upstreams_cors_headers() {
+        let cors = cors(&["https://app.example.com"], |config| config.exposed_headers = vec!["X-Total".to_string()]);
+        let upstream = [("access-control-allow-origin", "*"), ("access-control-allow-credentials", "true"), ("vary", "Accept-Encoding")];
+        let headers = response(&cors, Some("https://app.example.com"), &upstream).await;
+        assert_eq!(headers[ACCESS_CONTROL_ALLOW_ORIGIN], "https://app.example.com");
+        assert_eq!(headers[ACCESS_CONTROL_EXPOSE_HEADERS], "X-Total");
+        assert!(!headers.contains_key(ACCESS_CONTROL_ALLOW_CREDENTIALS));
+        assert_eq!(headers.get_all(VARY).iter().collect::<Vec<_>>(), ["Accept-Encoding", "Origin"]);
+
+        // Other origins get none, the upstream's included
+        let headers = response(&cors, Some("https://evil.example.com"), &upstream).await;
+        assert!(!headers.keys().any(|name| name.as_str().starts_with("access-control-")));
+        // Same-origin requests are left alone
+        let headers = response(&cors, None, &upstream).await;
+        assert_eq!(headers[ACCESS_CONTROL_ALLOW_ORIGIN], "*");
+    }
+
+    #[tokio::test]
+    async fn any_origin_is_never_named() {
+        let cors = cors(&["*"], |_| ());
+        let headers = response(&cors, Some("https://anywhere.example"), &[]).await;
+        assert_eq!(headers[ACCESS_CONTROL_ALLOW_ORIGIN], "*");
+        assert!(!headers.contains_key(VARY));
+
+        // Credentials need the origins listed, or any site could read users' responses
+        let cors = CorsConfig { enabled: true, allowed_origins: vec!["*".to_string()], allow_credentials: true, ..CorsConfig::default() };
+        let mut config = Config { cors, ..Config::default() };
+        let error = config.validate().unwrap_err();
+        assert!(error.contains("allow_credentials") && error.contains("list the origins"), "{}", error);
+        config.cors.allowed_origins = vec!["https://app.example.com".to_string()];
+        config.validate().unwrap();
+    }
+}
@@ -0,0 +1,63 @@
+use std::sync::{Arc, Weak};
+use tracing::info;
+
+use crate::balancer::{Balancer, Upstream};
+use crate::health::{self, HealthCheckConfig};
+use crate::tls::UpstreamConnector;
+
+/// A pool whose upstreams come from a service registry. It is only held
+/// weakly, so watchers stop once the pool has been replaced by a reload.
+#[derive(Clone)]
+pub struct Membership {
+    balancer: Weak<Balancer>,
+    /// Health checks for newly discovered upstreams, when enabled
+    health: Option<(HealthCheckConfig, UpstreamConnector)>,
+    /// Names the registry and service in log messages
+    source: String,
+}
+
+impl Membership {
+    pub fn new(balancer: &Arc<Balancer>, health: Option<(HealthCheckConfig, UpstreamConnector)>, source: String) -> Self {
+        Membership { balancer: Arc::downgrade(balancer), health, source }
+    }
+
+    /// Whether the pool is still in use.
+    pub fn is_active(&self) -> bool {
+        self.balancer.strong_count() > 0
+    }
+
+    /// Makes the pool's upstreams match `wanted`: upstreams that are no
+    /// longer listed are removed, once their requests in flight finish, new
+    /// ones are added and changed weights applied. Returns `false` once the
+    /// pool is gone.
+    pub fn sync(&self, wanted: Vec<Upstream>) -> bool {
+        let balancer = match self.balancer.upgrade() {
+            Some(balancer) => balancer,
+            None => return false,
+        };
+        let current = balancer.upstreams();
+        for upstream in &current {
+            if !wanted.iter().any(|u| u.id == upstream.id && u.backup == upstream.backup) {
+                balancer.remove(&upstream.id);
+                info!("Upstream {} left {}, removed", upstream.label(), self.source);
+            }
+        }
+        for upstream in wanted {
+            match current.iter().find(|u| u.id == upstream.id && u.backup == upstream.backup) {
+                Some(known) if known.weight() != upstream.weight() => {
+                    balancer.set_weight(&known.id, upstream.weight());
+                }
+                Some(_) => {}
+                None => {
+                    if let Ok(upstream) = balancer.add(upstream) {
+                        info!("Upstream {} found in {}, added", upstream.label(), self.source);
+                        if let Some((config, connector)) = &self.health {
+                            health::spawn_one(&upstream, config.clone(), connector.clone());
+                        }
+                    }
+                }
+            }
+        }
+        true
+    }
+}
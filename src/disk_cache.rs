@@ -0,0 +1,593 @@
+use hyper::body::Bytes;
+use hyper::header::{HeaderMap, HeaderName, HeaderValue};
+use hyper::{Body, StatusCode};
+use ring::digest::{digest, SHA256};
+use serde::{Deserialize, Serialize};
+use std::collections::{BTreeMap, HashMap};
+use std::convert::TryInto;
+use std::fs;
+use std::io::{self, Read, SeekFrom};
+use std::path::{Path, PathBuf};
+use std::sync::Mutex;
+use std::time::{Duration, Instant, SystemTime, UNIX_EPOCH};
+use tokio::io::{AsyncReadExt, AsyncSeekExt, AsyncWriteExt};
+use tokio_util::io::ReaderStream;
+use tracing::info;
+
+use crate::cache::{Entry, Purge};
+use crate::config::DiskCacheConfig;
+
+/// Start of every cache file, so files written by another version are skipped.
+const MAGIC: &[u8; 8] = b"RIFFYC1\n";
+/// The magic, then the lengths of the metadata (4 bytes) and the body (8 bytes).
+const HEADER_LEN: u64 = 20;
+/// Larger metadata is taken for a corrupt file rather than read into memory.
+const MAX_META_LEN: u64 = 1024 * 1024;
+/// Cache files are named by the SHA-256 of their key, in hex.
+const NAME_LEN: usize = 64;
+
+/// Everything about a cached response but its body, as written to its file.
+#[derive(Serialize, Deserialize)]
+struct Meta {
+    key: String,
+    status: u16,
+    headers: Vec<(String, Vec<u8>)>,
+    vary: Vec<(String, Option<Vec<u8>>)>,
+    surrogate_keys: Vec<String>,
+    /// Milliseconds since the Unix epoch
+    stored_at: u64,
+    fresh_until: u64,
+    /// Milliseconds
+    stale_while_revalidate: u64,
+    stale_if_error: u64,
+}
+
+impl Meta {
+    fn new(key: &str, entry: &Entry) -> Self {
+        Meta {
+            key: key.to_string(),
+            status: entry.status.as_u16(),
+            headers: entry.headers.iter().map(|(name, value)| (name.to_string(), value.as_bytes().to_vec())).collect(),
+            vary: entry.vary.iter().map(|(name, value)| (name.to_string(), value.as_ref().map(|v| v.as_bytes().to_vec()))).collect(),
+            surrogate_keys: entry.surrogate_keys.clone(),
+            stored_at: unix_millis(entry.stored_at),
+            fresh_until: unix_millis(entry.fresh_until),
+            stale_while_revalidate: entry.stale_while_revalidate.as_millis() as u64,
+            stale_if_error: entry.stale_if_error.as_millis() as u64,
+        }
+    }
+
+    /// The entry, without its body, or `None` if the file holds something invalid.
+    fn entry(self) -> Option<(String, Entry)> {
+        let mut headers = HeaderMap::new();
+        for (name, value) in self.headers {
+            headers.append(HeaderName::from_bytes(name.as_bytes()).ok()?, HeaderValue::from_bytes(&value).ok()?);
+        }
+        let vary = self
+            .vary
+            .into_iter()
+            .map(|(name, value)| Some((HeaderName::from_bytes(name.as_bytes()).ok()?, value.map(|v| HeaderValue::from_bytes(&v)).transpose().ok()?)))
+            .collect::<Option<_>>()?;
+        let entry = Entry {
+            status: StatusCode::from_u16(self.status).ok()?,
+            headers,
+            body: Bytes::new(),
+            vary,
+            surrogate_keys: self.surrogate_keys,
+            stored_at: instant_of(self.stored_at),
+            fresh_until: instant_of(self.fresh_until),
+            stale_while_revalidate: Duration::from_millis(self.stale_while_revalidate),
+            stale_if_error: Duration::from_millis(self.stale_if_error),
+            revalidating: None,
+            tick: 0,
+        };
+        Some((self.key, entry))
+    }
+}
+
+/// Where a cached response is stored on disk.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub(crate) struct DiskFile {
+    path: PathBuf,
+    /// Where the body starts
+    offset: u64,
+    pub(crate) len: u64,
+}
+
+impl DiskFile {
+    fn size(&self) -> u64 {
+        self.offset + self.len
+    }
+}
+
+struct Stored {
+    /// The entry without its body
+    entry: Entry,
+    file: DiskFile,
+}
+
+#[derive(Default)]
+struct Index {
+    entries: HashMap<String, Stored>,
+    // Least recently used first: tick -> key
+    lru: BTreeMap<u64, String>,
+    /// Total size of the files
+    size: u64,
+    next_tick: u64,
+}
+
+impl Index {
+    fn touch(&mut self, key: &str) {
+        self.next_tick += 1;
+        let tick = self.next_tick;
+        if let Some(stored) = self.entries.get_mut(key) {
+            self.lru.remove(&stored.entry.tick);
+            stored.entry.tick = tick;
+            self.lru.insert(tick, key.to_string());
+        }
+    }
+
+    /// Forgets an entry, returning its file.
+    fn remove(&mut self, key: &str) -> Option<DiskFile> {
+        let stored = self.entries.remove(key)?;
+        self.lru.remove(&stored.entry.tick);
+        self.size -= stored.file.size();
+        Some(stored.file)
+    }
+
+    fn insert(&mut self, key: String, mut stored: Stored) {
+        self.next_tick += 1;
+        stored.entry.tick = self.next_tick;
+        self.size += stored.file.size();
+        self.lru.insert(stored.entry.tick, key.clone());
+        self.entries.insert(key, stored);
+    }
+
+    /// Drops the least recently used entries, deleting their files, until
+    /// `extra` more bytes fit in `max_size`.
+    fn make_room(&mut self, extra: u64, max_size: u64) {
+        while self.size + extra > max_size {
+            let oldest = match self.lru.values().next() {
+                Some(oldest) => oldest.clone(),
+                None => break,
+            };
+            if let Some(file) = self.remove(&oldest) {
+                let _ = fs::remove_file(&file.path);
+            }
+        }
+    }
+}
+
+/// Tier of the response cache below the memory one, keeping responses in
+/// files under a directory so they survive restarts. The files are indexed in
+/// memory, rebuilt from the directory at startup, and the least recently used
+/// are deleted when the tier is full.
+pub struct DiskCache {
+    config: DiskCacheConfig,
+    dir: PathBuf,
+    max_size: u64,
+    max_object_size: u64,
+    // Files are renamed into place and deleted with the lock held, so the index matches the directory
+    index: Mutex<Index>,
+}
+
+impl DiskCache {
+    /// Opens the directory at `config.path`, creating it if needed, and
+    /// indexes the responses already in it, deleting expired ones.
+    pub fn open(config: &DiskCacheConfig) -> Result<Self, String> {
+        let dir = PathBuf::from(config.path.as_deref().ok_or("cache.disk.path is not set")?);
+        fs::create_dir_all(&dir).map_err(|e| format!("failed to create cache directory {}: {}", dir.display(), e))?;
+        let listing = fs::read_dir(&dir).map_err(|e| format!("failed to read cache directory {}: {}", dir.display(), e))?;
+
+        let now = Instant::now();
+        let mut loaded = Vec::new();
+        for file in listing.flatten() {
+            let path = file.path();
+            let name = file.file_name().to_string_lossy().into_owned();
+            // Other files in the directory are left alone
+            if name.ends_with(".tmp") && name.get(..NAME_LEN).is_some_and(is_cache_name) {
+                // Left by a response being written when Riffy stopped
+                let _ = fs::remove_file(&path);
+                continue;
+            }
+            if !is_cache_name(&name) {
+                continue;
+            }
+            match read_header(&path) {
+                Some((key, entry, file)) if now < entry.usable_until() && path == cache_path(&dir, &key) => loaded.push((key, entry, file)),
+                _ => {
+                    let _ = fs::remove_file(&path);
+                }
+            }
+        }
+
+        // The oldest responses are evicted first
+        loaded.sort_by_key(|(_, entry, _)| entry.stored_at);
+        let max_size = config.max_size_mb * 1024 * 1024;
+        let mut index = Index::default();
+        for (key, entry, file) in loaded {
+            index.make_room(file.size(), max_size);
+            index.insert(key, Stored { entry, file });
+        }
+        info!("Disk cache at {} holds {} response(s), {} bytes", dir.display(), index.entries.len(), index.size);
+
+        Ok(DiskCache { config: config.clone(), dir, max_size, max_object_size: config.max_object_mb * 1024 * 1024, index: Mutex::new(index) })
+    }
+
+    /// Whether the tier was opened with these settings.
+    pub fn uses(&self, config: &DiskCacheConfig) -> bool {
+        self.config == *config
+    }
+
+    /// Largest response body stored.
+    pub(crate) fn max_object_size(&self) -> u64 {
+        self.max_object_size
+    }
+
+    /// Number of responses stored and the total size of their files in bytes.
+    pub fn usage(&self) -> (usize, u64) {
+        let index = self.index.lock().unwrap();
+        (index.entries.len(), index.size)
+    }
+
+    /// The entry stored for `key`, without its body, and its file, when
+    /// `usable` finds a use for it; expired entries are deleted.
+    pub(crate) fn find<T>(&self, key: &str, request_headers: &HeaderMap, usable: impl FnOnce(&mut Entry, Instant) -> Option<T>) -> Option<(Entry, DiskFile, T)> {
+        let now = Instant::now();
+        let mut index = self.index.lock().unwrap();
+        let stored = index.entries.get_mut(key)?;
+        if now >= stored.entry.usable_until() {
+            if let Some(file) = index.remove(key) {
+                let _ = fs::remove_file(&file.path);
+            }
+            return None;
+        }
+        if stored.entry.varies_from(request_headers) {
+            return None;
+        }
+        let found = usable(&mut stored.entry, now)?;
+        let (entry, file) = (stored.entry.clone(), stored.file.clone());
+        index.touch(key);
+        Some((entry, file, found))
+    }
+
+    /// Reads the body of a response found with [`DiskCache::find`].
+    pub(crate) async fn read(&self, key: &str, file: &DiskFile) -> Option<Bytes> {
+        let mut opened = self.open_body(key, file).await?;
+        let mut body = Vec::with_capacity(file.len as usize);
+        match opened.read_to_end(&mut body).await {
+            Ok(_) if body.len() as u64 == file.len => Some(Bytes::from(body)),
+            _ => None,
+        }
+    }
+
+    /// Streams the body of a response found with [`DiskCache::find`].
+    pub(crate) async fn stream(&self, key: &str, file: &DiskFile) -> Option<Body> {
+        let opened = self.open_body(key, file).await?;
+        Some(Body::wrap_stream(ReaderStream::new(opened.take(file.len))))
+    }
+
+    /// Opens a response's file at the start of its body, checking that it was
+    /// not replaced since it was found.
+    async fn open_body(&self, key: &str, file: &DiskFile) -> Option<tokio::fs::File> {
+        let mut opened = match tokio::fs::File::open(&file.path).await {
+            Ok(opened) => opened,
+            Err(e) => {
+                // Deleted by hand; other errors may pass
+                if e.kind() == io::ErrorKind::NotFound {
+                    self.forget(key, file);
+                }
+                return None;
+            }
+        };
+        let mut header = [0; HEADER_LEN as usize];
+        opened.read_exact(&mut header).await.ok()?;
+        let (meta_len, body_len) = parse_header(&header)?;
+        if HEADER_LEN + meta_len != file.offset || body_len != file.len {
+            return None;
+        }
+        opened.seek(SeekFrom::Start(file.offset)).await.ok()?;
+        Some(opened)
+    }
+
+    /// Drops `key` from the index if it still refers to `file`.
+    fn forget(&self, key: &str, file: &DiskFile) {
+        let mut index = self.index.lock().unwrap();
+        if index.entries.get(key).is_some_and(|stored| stored.file == *file) {
+            index.remove(key);
+        }
+    }
+
+    /// Starts writing a response to a temporary file, moved into place by
+    /// [`DiskCache::commit`] once the body is complete.
+    pub(crate) async fn create(&self, key: &str, entry: &Entry) -> io::Result<DiskWriter> {
+        let path = cache_path(&self.dir, key);
+        let tmp = path.with_extension(format!("{:016x}.tmp", rand::random::<u64>()));
+        let meta = serde_json::to_vec(&Meta::new(key, entry))?;
+        let file = tokio::fs::File::create(&tmp).await?;
+        let mut writer = DiskWriter { file, tmp, path, offset: HEADER_LEN + meta.len() as u64, len: 0 };
+        writer.file.write_all(MAGIC).await?;
+        writer.file.write_all(&(meta.len() as u32).to_be_bytes()).await?;
+        // The body length is filled in by `finish`
+        writer.file.write_all(&0u64.to_be_bytes()).await?;
+        writer.file.write_all(&meta).await?;
+        Ok(writer)
+    }
+
+    /// Moves a finished response into place, evicting others to make room.
+    pub(crate) fn commit(&self, key: String, entry: Entry, writer: DiskWriter) -> io::Result<()> {
+        let file = DiskFile { path: writer.path.clone(), offset: writer.offset, len: writer.len };
+        let mut index = self.index.lock().unwrap();
+        // Renamed first, so a failed rename leaves the previous response in place and indexed
+        fs::rename(&writer.tmp, &file.path)?;
+        // The previous file for the key, if any, was replaced by the rename
+        index.remove(&key);
+        index.make_room(file.size(), self.max_size);
+        index.insert(key, Stored { entry, file });
+        Ok(())
+    }
+
+    /// Deletes the responses `purge` selects, returning their keys.
+    pub(crate) fn purge(&self, purge: &Purge) -> Vec<String> {
+        let mut index = self.index.lock().unwrap();
+        let keys: Vec<String> = index.entries.iter().filter(|(key, stored)| purge.matches(key, &stored.entry)).map(|(key, _)| key.clone()).collect();
+        for key in &keys {
+            if let Some(file) = index.remove(key) {
+                let _ = fs::remove_file(&file.path);
+            }
+        }
+        keys
+    }
+}
+
+/// A response being written to a temporary file, deleted if it is dropped
+/// before being committed.
+pub(crate) struct DiskWriter {
+    file: tokio::fs::File,
+    tmp: PathBuf,
+    path: PathBuf,
+    offset: u64,
+    len: u64,
+}
+
+impl DiskWriter {
+    /// Bytes of body written so far.
+    pub(crate) fn len(&self) -> u64 {
+        self.len
+    }
+
+    pub(crate) async fn write(&mut self, chunk: &[u8]) -> io::Result<()> {
+        self.file.write_all(chunk).await?;
+        self.len += chunk.len() as u64;
+        Ok(())
+    }
+
+    /// Records the body length in the file once the body is complete.
+    pub(crate) async fn finish(&mut self) -> io::Result<()> {
+        self.file.seek(SeekFrom::Start(HEADER_LEN - 8)).await?;
+        self.file.write_all(&self.len.to_be_bytes()).await?;
+        self.file.flush().await
+    }
+}
+
+impl Drop for DiskWriter {
+    fn drop(&mut self) {
+        // Gone already once committed
+        let _ = fs::remove_file(&self.tmp);
+    }
+}
+
+/// Reads a cache file's key and entry, and where its body is.
+fn read_header(path: &Path) -> Option<(String, Entry, DiskFile)> {
+    let mut file = fs::File::open(path).ok()?;
+    let mut header = [0; HEADER_LEN as usize];
+    file.read_exact(&mut header).ok()?;
+    let (meta_len, body_len) = parse_header(&header)?;
+    let offset = HEADER_LEN + meta_len;
+    // A file cut short, e.g. by a crash, is not used
+    if offset.checked_add(body_len) != Some(file.metadata().ok()?.len()) {
+        return None;
+    }
+    let mut meta = vec![0; meta_len as usize];
+    file.read_exact(&mut meta).ok()?;
+    let (key, entry) = serde_json::from_slice::<Meta>(&meta).ok()?.entry()?;
+    Some((key, entry, DiskFile { path: path.to_path_buf(), offset, len: body_len }))
+}
+
+/// The metadata and body lengths from a file's header.
+fn parse_header(header: &[u8; HEADER_LEN as usize]) -> Option<(u64, u64)> {
+    if &header[..8] != MAGIC {
+        return None;
+    }
+    let meta_len = u32::from_be_bytes(header[8..12].try_into().ok()?) as u64;
+    if meta_len > MAX_META_LEN {
+        return None;
+    }
+    let body_len = u64::from_be_bytes(header[12..20].try_into().ok()?);
+    Some((meta_len, body_len))
+}
+
+fn cache_path(dir: &Path, key: &str) -> PathBuf {
+    let hash = digest(&SHA256, key.as_bytes());
+    let name: String = hash.as_ref().iter().map(|b| format!("{:02x}", b)).collect();
+    dir.join(name)
+}
+
+fn is_cache_name(name: &str) -> bool {
+    name.len() == NAME_LEN && name.bytes().all(|b| b.is_ascii_hexdigit())
+}
+
+fn unix_millis(instant: Instant) -> u64 {
+    let (now, system_now) = (Instant::now(), SystemTime::now());
+    let time = match instant.checked_duration_since(now) {
+        Some(ahead) => system_now + ahead,
+        None => system_now - now.duration_since(instant),
+    };
+    time.duration_since(UNIX_EPOCH).map_or(0, |since| since.as_millis() as u64)
+}
+
+fn instant_of(unix_millis: u64) -> Instant {
+    let (now, system_now) = (Instant::now(), SystemTime::now());
+    let time = UNIX_EPOCH + Duration::from_millis(unix_millis);
+    match time.duration_since(system_now) {
+        Ok(ahead) => now.checked_add(ahead).unwrap_or(now),
+        // Before the monotonic clock started counts as now, e.g. just after boot
+        Err(e) => now.checked_sub(e.duration()).unwrap_or(now),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    /// An empty directory for one test, removed when dropped.
+    struct TempDir(PathBuf);
+
+    impl TempDir {
+        fn new(name: &str) -> Self {
+            let dir = std::env::temp_dir().join(format!("riffy-disk-cache-{}-{}", name, std::process::id()));
+            let _ = fs::remove_dir_all(&dir);
+            TempDir(dir)
+        }
+
+        fn config(&self) -> DiskCacheConfig {
+            DiskCacheConfig { path: Some(self.0.display().to_string()), ..DiskCacheConfig::default() }
+        }
+    }
+
+    impl Drop for TempDir {
+        fn drop(&mut self) {
+            let _ = fs::remove_dir_all(&self.0);
+        }
+    }
+
+    fn entry() -> Entry {
+        let now = Instant::now();
+        let mut headers = HeaderMap::new();
+        headers.insert("content-type", HeaderValue::from_static("text/plain"));
+        Entry {
+            status: StatusCode::OK,
+            headers,
+            body: Bytes::new(),
+            vary: vec![(HeaderName::from_static("accept-encoding"), Some(HeaderValue::from_static("gzip")))],
+            surrogate_keys: vec!["products".to_string()],
+            stored_at: now,
+            fresh_until: now + Duration::from_secs(60),
+            stale_while_revalidate: Duration::ZERO,
+            stale_if_error: Duration::ZERO,
+            revalidating: None,
+            tick: 0,
+        }
+    }
+
+    async fn store(cache: &DiskCache, key: &str, body: &[u8]) {
+        let mut writer = cache.create(key, &entry()).await.unwrap();
+        writer.write(body).await.unwrap();
+        writer.finish().await.unwrap();
+        cache.commit(key.to_string(), entry(), writer).unwrap();
+    }
+
+    #[tokio::test]
+    async fn reopens_stored_responses() {
+        let dir = TempDir::new("reopen");
+        store(&DiskCache::open(&dir.config()).unwrap(), "example.com/a", b"hello").await;
+
+        let cache = DiskCache::open(&dir.config()).unwrap();
+        assert_eq!(cache.usage().0, 1);
+        let mut gzip = HeaderMap::new();
+        gzip.insert("accept-encoding", HeaderValue::from_static("gzip"));
+        let (entry, file, ()) = cache.find("example.com/a", &gzip, |_, _| Some(())).unwrap();
+        assert_eq!(entry.headers["content-type"], "text/plain");
+        assert_eq!(entry.surrogate_keys, ["products"]);
+        assert_eq!(cache.read("example.com/a", &file).await.unwrap(), &b"hello"[..]);
+        // Stored for another Accept-Encoding
+        assert!(cache.find("example.com/a", &HeaderMap::new(), |_, _| Some(())).is_none());
+    }
+
+    #[tokio::test]
+    async fn failed_commit_keeps_the_previous_response() {
+        let dir = TempDir::new("failed-commit");
+        let cache = DiskCache::open(&dir.config()).unwrap();
+        store(&cache, "example.com/a", b"hello").await;
+        let mut writer = cache.create("example.com/a", &entry()).await.unwrap();
+        writer.write(b"bye").await.unwrap();
+        writer.finish().await.unwrap();
+        fs::remove_file(&writer.tmp).unwrap();
+        assert!(cache.commit("example.com/a".to_string(), entry(), writer).is_err());
+
+        let mut gzip = HeaderMap::new();
+        gzip.insert("accept-encoding", HeaderValue::from_static("gzip"));
+        let (_, file, ()) = cache.find("example.com/a", &gzip, |_, _| Some(())).unwrap();
+        assert_eq!(cache.read("example.com/a", &file).await.unwrap(), &b"hello"[..]);
+    }
+
+    #[tokio::test]
+    async fn skips_corrupt_files() {
+        let dir = TempDir::new("corrupt");
+        store(&DiskCache::open(&dir.config()).unwrap(), "example.com/a", b"hello").await;
+        let path = cache_path(&dir.0, "example.com/a");
+        let valid = fs::read(&path).unwrap();
+
+        let mut wrong_magic = valid.clone();
+        wrong_magic[0] = b'X';
+        let mut huge_meta = valid.clone();
+        huge_meta[8..12].copy_from_slice(&u32::MAX.to_be_bytes());
+        let mut huge_body = valid.clone();
+        huge_body[12..20].copy_from_slice(&u64::MAX.to_be_bytes());
+        let mut bad_json = valid.clone();
+        bad_json[HEADER_LEN as usize] = b'[';
+        let mut longer = valid.clone();
+        longer.push(b'!');
+        let corrupt = [
+            ("wrong magic", wrong_magic),
+            ("metadata too large", huge_meta),
+            ("body longer than the file", huge_body),
+            ("invalid metadata", bad_json),
+            ("cut short", valid[..valid.len() - 1].to_vec()),
+            ("header cut short", valid[..12].to_vec()),
+            ("trailing bytes", longer),
+            ("empty", Vec::new()),
+        ];
+        for (problem, contents) in corrupt {
+            fs::write(&path, contents).unwrap();
+            assert_eq!(DiskCache::open(&dir.config()).unwrap().usage().0, 0, "{}", problem);
+            assert!(!path.exists(), "{}", problem);
+        }
+
+        // A file whose key is not the one its name is made from
+        fs::write(dir.0.join("0".repeat(NAME_LEN)), &valid).unwrap();
+        assert_eq!(DiskCache::open(&dir.config()).unwrap().usage().0, 0);
+        assert!(!dir.0.join("0".repeat(NAME_LEN)).exists());
+    }
+
+    #[tokio::test]
+    async fn cleans_up_only_its_own_files() {
+        let dir = TempDir::new("cleanup");
+        DiskCache::open(&dir.config()).unwrap();
+        let left_over = dir.0.join(format!("{}.0123456789abcdef.tmp", "a".repeat(NAME_LEN)));
+        fs::write(&left_over, b"partial").unwrap();
+        fs::write(dir.0.join("README"), b"not ours").unwrap();
+        fs::write(dir.0.join("notes.tmp"), b"not ours").unwrap();
+        DiskCache::open(&dir.config()).unwrap();
+        assert!(!left_over.exists());
+        assert!(dir.0.join("README").exists());
+        assert!(dir.0.join("notes.tmp").exists());
+    }
+
+    #[test]
+    fn rejects_bad_headers() {
+        let header = |meta_len: u32| {
+            let mut header = [0; HEADER_LEN as usize];
+            header[..8].copy_from_slice(MAGIC);
+            header[8..12].copy_from_slice(&meta_len.to_be_bytes());
+            header[12..].copy_from_slice(&5u64.to_be_bytes());
+            header
+        };
+        assert_eq!(parse_header(&header(100)), Some((100, 5)));
+        assert_eq!(parse_header(&header(MAX_META_LEN as u32)), Some((MAX_META_LEN, 5)));
+        assert_eq!(parse_header(&header(MAX_META_LEN as u32 + 1)), None);
+        let mut other_version = header(100);
+        other_version[6] = b'2';
+        assert_eq!(parse_header(&other_version), None);
+    }
+}
@@ -0,0 +1,190 @@
+use hickory_resolver::error::ResolveError;
+use hickory_resolver::TokioAsyncResolver;
+use hyper::Uri;
+use std::net::IpAddr;
+use std::sync::{Arc, Weak};
+use std::time::{Duration, Instant};
+use tracing::{info, warn};
+
+use crate::balancer::{Balancer, Upstream, MAX_WEIGHT};
+use crate::config::{DnsSettings, SrvSettings};
+use crate::discovery::Membership;
+use crate::health::{self, HealthCheckConfig};
+use crate::tls::UpstreamConnector;
+
+/// A configured upstream whose hostname stands for one upstream per address
+/// it resolves to.
+#[derive(Debug, Clone)]
+pub struct Target {
+    url: String,
+    host: String,
+    weight: u32,
+    backup: bool,
+}
+
+impl Target {
+    /// The upstream for one resolved address.
+    fn member(&self, address: IpAddr) -> Upstream {
+        Upstream::new(self.url.clone(), self.weight).with_backup(self.backup).with_address(address)
+    }
+
+    fn members(&self, balancer: &Balancer) -> Vec<Arc<Upstream>> {
+        balancer.upstreams().into_iter().filter(|u| u.url == self.url && u.address.is_some()).collect()
+    }
+
+    /// The resolved upstreams of this target in `previous`, so a reload does
+    /// not wait for the first lookup.
+    pub fn seed(&self, previous: Option<&Balancer>) -> Vec<Upstream> {
+        let previous = previous.map(|balancer| self.members(balancer)).unwrap_or_default();
+        previous.iter().filter_map(|old| old.address).map(|address| self.member(address)).collect()
+    }
+
+    /// Adds upstreams for new addresses and removes those for addresses the
+    /// hostname no longer resolves to.
+    fn update(&self, balancer: &Balancer, addresses: &[IpAddr], health: Option<&(HealthCheckConfig, UpstreamConnector)>) {
+        let current = self.members(balancer);
+        for upstream in &current {
+            if !upstream.address.is_some_and(|address| addresses.contains(&address)) {
+                balancer.remove(&upstream.id);
+                info!("Upstream {} no longer resolves to {}, removed", self.url, upstream.address.expect("resolved upstream"));
+            }
+        }
+        for &address in addresses {
+            if current.iter().any(|upstream| upstream.address == Some(address)) {
+                continue;
+            }
+            if let Ok(upstream) = balancer.add(self.member(address)) {
+                info!("Upstream {} resolved to {}, added", self.url, address);
+                if let Some((config, connector)) = health {
+                    health::spawn_one(&upstream, config.clone(), connector.clone());
+                }
+            }
+        }
+    }
+}
+
+/// Separates the upstreams whose URL names a host rather than an IP address.
+pub fn split(upstreams: Vec<Upstream>) -> (Vec<Upstream>, Vec<Target>) {
+    let mut fixed = Vec::new();
+    let mut targets = Vec::new();
+    for upstream in upstreams {
+        let host = upstream.url.parse::<Uri>().ok().and_then(|uri| uri.host().map(|host| host.trim_start_matches('[').trim_end_matches(']').to_string()));
+        match host {
+            Some(host) if host.parse::<IpAddr>().is_err() => targets.push(Target { url: upstream.url.clone(), host, weight: upstream.weight(), backup: upstream.backup }),
+            _ => fixed.push(upstream),
+        }
+    }
+    (fixed, targets)
+}
+
+/// Spawns one resolver task per target, keeping the balancer's upstreams
+/// in line with the target's A/AAAA records. Resolved upstreams get health
+/// checks when `health` is set. Each task stops once the balancer has been
+/// dropped, e.g. after a config reload.
+pub fn spawn(balancer: &Arc<Balancer>, targets: Vec<Target>, settings: &DnsSettings, health: Option<(HealthCheckConfig, UpstreamConnector)>) -> Result<(), String> {
+    if targets.is_empty() {
+        return Ok(());
+    }
+    let resolver = system_resolver()?;
+    let (min_ttl, max_ttl) = (Duration::from_secs(settings.min_ttl), Duration::from_secs(settings.max_ttl));
+    for target in targets {
+        let (balancer, resolver, health) = (Arc::downgrade(balancer), resolver.clone(), health.clone());
+        tokio::spawn(async move { resolve_loop(balancer, target, resolver, min_ttl, max_ttl, health).await });
+    }
+    Ok(())
+}
+
+async fn resolve_loop(balancer: Weak<Balancer>, target: Target, resolver: TokioAsyncResolver, min_ttl: Duration, max_ttl: Duration, health: Option<(HealthCheckConfig, UpstreamConnector)>) {
+    while balancer.strong_count() > 0 {
+        let lookup = resolver.lookup_ip(target.host.as_str()).await;
+        // A failed lookup keeps the last known addresses
+        let ttl = {
+            let balancer = match balancer.upgrade() {
+                Some(balancer) => balancer,
+                None => return,
+            };
+            match lookup {
+                Ok(lookup) => {
+                    let addresses: Vec<IpAddr> = lookup.iter().collect();
+                    target.update(&balancer, &addresses, health.as_ref());
+                    lookup.valid_until().saturating_duration_since(Instant::now())
+                }
+                Err(e) => {
+                    warn!("Failed to resolve upstream {}: {}", target.url, e);
+                    min_ttl
+                }
+            }
+        };
+        tokio::time::sleep(ttl.clamp(min_ttl, max_ttl)).await;
+    }
+}
+
+/// Spawns the task that keeps a pool in line with the SRV records of
+/// `srv.name`, within the lookup bounds of `dns`. Targets with the lowest
+/// priority are the primary upstreams, the others backups. The task stops
+/// once the pool has been dropped, e.g. after a config reload.
+pub fn spawn_srv(balancer: &Arc<Balancer>, srv: &SrvSettings, dns: &DnsSettings, health: Option<(HealthCheckConfig, UpstreamConnector)>) -> Result<(), String> {
+    let resolver = system_resolver()?;
+    let membership = Membership::new(balancer, health, format!("SRV record {}", srv.name));
+    let (name, scheme) = (srv.name.clone(), srv.scheme.clone());
+    let (min_ttl, max_ttl) = (Duration::from_secs(dns.min_ttl), Duration::from_secs(dns.max_ttl));
+    tokio::spawn(async move {
+        while membership.is_active() {
+            // A failed lookup keeps the last known targets
+            let ttl = match resolve_srv(&resolver, &name, &scheme).await {
+                Ok((upstreams, valid_until)) => {
+                    if !membership.sync(upstreams) {
+                        return;
+                    }
+                    valid_until.saturating_duration_since(Instant::now())
+                }
+                Err(e) => {
+                    warn!("Failed to resolve SRV record {}: {}", name, e);
+                    min_ttl
+                }
+            };
+            tokio::time::sleep(ttl.clamp(min_ttl, max_ttl)).await;
+        }
+    });
+    Ok(())
+}
+
+/// One upstream per address of each SRV target, and when the shortest-lived
+/// of the records expires. A target that does not resolve is left out, so
+/// the others stay current; only when none resolves does the lookup fail.
+/// Targets of `.` mean the service is not available there (RFC 2782).
+async fn resolve_srv(resolver: &TokioAsyncResolver, name: &str, scheme: &str) -> Result<(Vec<Upstream>, Instant), ResolveError> {
+    let records = resolver.srv_lookup(name).await?;
+    let mut valid_until = records.as_lookup().valid_until();
+    let available: Vec<_> = records.iter().filter(|record| !record.target().is_root()).collect();
+    let primary = available.iter().map(|record| record.priority()).min();
+    let mut upstreams = Vec::new();
+    let mut failure = None;
+    for record in &available {
+        let target = record.target().to_utf8();
+        let target = target.trim_end_matches('.');
+        let addresses = match resolver.lookup_ip(target).await {
+            Ok(addresses) => addresses,
+            Err(e) => {
+                warn!("Failed to resolve {}, a target of SRV record {}, leaving it out: {}", target, name, e);
+                failure = Some(e);
+                continue;
+            }
+        };
+        valid_until = valid_until.min(addresses.valid_until());
+        // Weight 0 marks targets that should rarely be chosen; they still get the smallest share
+        let weight = u32::from(record.weight()).clamp(1, MAX_WEIGHT);
+        let url = format!("{}://{}:{}", scheme, target, record.port());
+        for address in addresses.iter() {
+            upstreams.push(Upstream::new(url.clone(), weight).with_backup(Some(record.priority()) != primary).with_address(address));
+        }
+    }
+    match failure {
+        Some(e) if upstreams.is_empty() => Err(e),
+        _ => Ok((upstreams, valid_until)),
+    }
+}
+
+fn system_resolver() -> Result<TokioAsyncResolver, String> {
+    TokioAsyncResolver::tokio_from_system_conf().map_err(|e| format!("failed to read the system DNS configuration: {}", e))
+}
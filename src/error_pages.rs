@@ -0,0 +1,90 @@
+use hyper::header::{HeaderValue, CONTENT_LENGTH, CONTENT_TYPE};
+use hyper::{Body, Response};
+use std::collections::{BTreeMap, HashMap};
+
+use crate::config::ErrorPageConfig;
+
+/// Marks a response whose body is already a page of its own, such as a
+/// route's maintenance page, so no error page replaces it.
+#[derive(Debug, Clone, Copy)]
+pub struct Rendered;
+
+/// Templates for one status code.
+#[derive(Debug, Default)]
+struct Page {
+    html: Option<String>,
+    json: Option<String>,
+}
+
+/// Branded bodies for the error responses Riffy generates itself, such as a
+/// 503 when a pool is overloaded or a 504 when an upstream times out. Error
+/// responses from upstreams are passed through untouched.
+#[derive(Debug, Default)]
+pub struct ErrorPages {
+    pages: HashMap<u16, Page>,
+}
+
+impl ErrorPages {
+    /// Reads the templates; they are read again on reload.
+    pub fn load(config: &BTreeMap<String, ErrorPageConfig>) -> Result<Self, String> {
+        let read = |path: &Option<String>| path.as_ref().map(|path| std::fs::read_to_string(path).map_err(|e| format!("failed to read error page {}: {}", path, e))).transpose();
+        let mut pages = HashMap::new();
+        for (status, page) in config {
+            let status = status.parse().map_err(|_| format!("invalid error page status: {}", status))?;
+            pages.insert(status, Page { html: read(&page.html)?, json: read(&page.json)? });
+        }
+        Ok(ErrorPages { pages })
+    }
+
+    /// Replaces the body of `res` with the page for its status, in JSON when
+    /// the client prefers that to HTML.
+    pub fn render(&self, res: &mut Response<Body>, accept: Option<&HeaderValue>, request_id: Option<&str>) {
+        let status = res.status();
+        let page = match self.pages.get(&status.as_u16()) {
+            Some(page) if res.extensions().get::<Rendered>().is_none() => page,
+            _ => return,
+        };
+        let wants_json = accept.and_then(|v| v.to_str().ok()).is_some_and(prefers_json);
+        let (template, content_type, escape): (_, _, fn(&str) -> String) = match (&page.html, &page.json, wants_json) {
+            (Some(_), Some(json), true) | (None, Some(json), _) => (json, "application/json", escape_json),
+            (Some(html), _, _) => (html, "text/html; charset=utf-8", escape_html),
+            (None, None, _) => return,
+        };
+        let reason = status.canonical_reason().unwrap_or("");
+        let body = template
+            .replace("{{status}}", status.as_str())
+            .replace("{{reason}}", &escape(reason))
+            .replace("{{request_id}}", &escape(request_id.unwrap_or("")));
+        res.headers_mut().insert(CONTENT_TYPE, HeaderValue::from_static(content_type));
+        res.headers_mut().insert(CONTENT_LENGTH, HeaderValue::from(body.len()));
+        res.extensions_mut().insert(Rendered);
+        *res.body_mut() = Body::from(body);
+    }
+}
+
+/// Whether an `Accept` header ranks `application/json` above `text/html`.
+fn prefers_json(accept: &str) -> bool {
+    // Media ranges without a q parameter have a weight of 1
+    let weight = |wanted: &str| {
+        accept
+            .split(',')
+            .filter_map(|range| {
+                let mut params = range.split(';');
+                let media = params.next()?.trim();
+                let q = params.filter_map(|p| p.trim().strip_prefix("q=")).find_map(|q| q.parse::<f32>().ok()).unwrap_or(1.0);
+                media.eq_ignore_ascii_case(wanted).then_some(q)
+            })
+            .fold(0.0, f32::max)
+    };
+    weight("application/json") > weight("text/html")
+}
+
+/// Escapes `s` for use inside a JSON string.
+fn escape_json(s: &str) -> String {
+    let quoted = serde_json::to_string(s).expect("strings serialize");
+    quoted[1..quoted.len() - 1].to_string()
+}
+
+fn escape_html(s: &str) -> String {
+    s.replace('&', "&amp;").replace('<', "&lt;").replace('>', "&gt;").replace('"', "&quot;").replace('\'', "&#39;")
+}
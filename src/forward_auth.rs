@@ -0,0 +1,221 @@
+use hyper::client::Client;
+use hyper::header::{HeaderName, HeaderValue, CONTENT_LENGTH, HOST, TRANSFER_ENCODING};
+use hyper::{Body, Request, Response, StatusCode, Uri};
+use std::net::IpAddr;
+use std::time::Duration;
+use tracing::warn;
+
+use crate::access_log::UpstreamUsed;
+use crate::config::{ForwardAuthConfig, UpstreamsConfig};
+use crate::headers;
+use crate::tls::{self, ClientConnector};
+
+/// Asks an external service about each request of a route before it is
+/// proxied, like Traefik's ForwardAuth: the service gets a GET with the
+/// request's headers and its method, host and URI in `X-Forwarded-*`
+/// headers. A 2xx answer lets the request through; any other answer is sent
+/// to the client instead, so the service can redirect to a login page.
+#[derive(Debug)]
+pub struct ForwardAuth {
+    address: Uri,
+    request_headers: Vec<HeaderName>,
+    response_headers: Vec<HeaderName>,
+    timeout: Duration,
+    client: Client<ClientConnector>,
+    /// Whether the route's responses may be cached
+    pub cache: bool,
+}
+
+impl ForwardAuth {
+    pub fn new(config: &ForwardAuthConfig) -> Result<ForwardAuth, String> {
+        let address = config.address.parse().map_err(|e| format!("invalid forward_auth.address {}: {}", config.address, e))?;
+        let names = |names: &[String]| names.iter().map(|name| HeaderName::from_bytes(name.as_bytes()).map_err(|e| format!("invalid forward_auth header {}: {}", name, e))).collect::<Result<Vec<_>, String>>();
+        let timeout = Duration::from_secs(config.timeout);
+        Ok(ForwardAuth {
+            address,
+            request_headers: names(&config.request_headers)?,
+            response_headers: names(&config.response_headers)?,
+            timeout,
+            client: tls::upstream_connector(&UpstreamsConfig::default(), Some(timeout))?.client(None),
+            cache: config.cache,
+        })
+    }
+
+    /// Checks `req` from `client_ip` with the service, copying the configured
+    /// headers of an approval into it. Returns the response for the client
+    /// when the request is turned down.
+    pub async fn check(&self, req: &mut Request<Body>, client_ip: IpAddr, tls: bool) -> Option<Response<Body>> {
+        let mut auth = Request::get(self.address.clone()).body(Body::empty()).expect("valid auth request");
+        let headers = auth.headers_mut();
+        if self.request_headers.is_empty() {
+            headers.extend(req.headers().iter().map(|(name, value)| (name.clone(), value.clone())));
+            headers::strip_hop_by_hop(headers);
+            // The request to the service has no body, and its own host
+            for name in [HOST, CONTENT_LENGTH, TRANSFER_ENCODING] {
+                headers.remove(name);
+            }
+        } else {
+            for name in &self.request_headers {
+                for value in req.headers().get_all(name) {
+                    headers.append(name.clone(), value.clone());
+                }
+            }
+        }
+        let forwarded = [
+            ("x-forwarded-method", Some(req.method().to_string())),
+            ("x-forwarded-proto", Some(if tls { "https" } else { "http" }.to_string())),
+            ("x-forwarded-host", req.headers().get(HOST).and_then(|v| v.to_str().ok()).map(str::to_string).or_else(|| req.uri().authority().map(|a| a.to_string()))),
+            ("x-forwarded-uri", req.uri().path_and_query().map(|pq| pq.to_string())),
+            // The resolved client rather than the header's chain, which the client may have forged
+            ("x-forwarded-for", Some(client_ip.to_string())),
+        ];
+        for (name, value) in forwarded {
+            if let Some(value) = value.and_then(|v| HeaderValue::from_str(&v).ok()) {
+                headers.insert(name, value);
+            }
+        }
+
+        let res = match tokio::time::timeout(self.timeout, self.client.request(auth)).await {
+            Ok(Ok(res)) => res,
+            Ok(Err(e)) => return Some(unavailable(&self.address, &e.to_string())),
+            Err(_) => return Some(unavailable(&self.address, "timed out")),
+        };
+        if !res.status().is_success() {
+            let (mut parts, body) = res.into_parts();
+            headers::strip_hop_by_hop(&mut parts.headers);
+            // The service's own answer, which error pages leave alone like an upstream's
+            parts.extensions.insert(UpstreamUsed(self.address.to_string()));
+            return Some(Response::from_parts(parts, body));
+        }
+        for name in &self.response_headers {
+            req.headers_mut().remove(name);
+            for value in res.headers().get_all(name) {
+                req.headers_mut().append(name.clone(), value.clone());
+            }
+        }
+        None
+    }
+}
+
+fn unavailable(address: &Uri, error: &str) -> Response<Body> {
+    warn!("Forward auth request to {} failed: {}", address, error);
+    Response::builder().status(StatusCode::SERVICE_UNAVAILABLE).body(Body::from("Service Unavailable")).unwrap()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use hyper::header::{HeaderMap, AUTHORIZATION, CONNECTION, COOKIE, LOCATION};
+    use hyper::service::{make_service_fn, service_fn};
+    use std::convert::Infallible;
+    use std::net::{Ipv4Addr, SocketAddr};
+    use std::sync::{Arc, Mutex};
+
+    const CLIENT: IpAddr = IpAddr::V4(Ipv4Addr::new(192, 0, 2, 7));
+
+    /// Starts a service approving requests with the `session=ok` cookie as
+    /// `alice`, and redirecting others to a login page. Returns its address
+    /// and the headers of the last request it got.
+    async fn service() -> (SocketAddr, Arc<Mutex<HeaderMap>>) {
+        let seen = Arc::new(Mutex::new(HeaderMap::new()));
+        let recorded = Arc::clone(&seen);
+        let make_service = make_service_fn(move |_| {
+            let recorded = Arc::clone(&recorded);
+            async move {
+                Ok::<_, Infallible>(service_fn(move |req: Request<Body>| {
+                    *recorded.lock().unwrap() = req.headers().clone();
+                    let approved = req.headers().get(COOKIE).is_some_and(|v| v == "session=ok");
+                    let res = if approved {
+                        Response::builder().header("x-user", "alice").header("x-internal", "1")
+                    } else {
+                        Response::builder().status(StatusCode::FOUND).header(LOCATION, "/login").header("connection", "x-hop").header("x-hop", "1")
+                    };
+                    async { Ok::<_, Infallible>(res.body(Body::empty()).unwrap()) }
+                }))
+            }
+        });
+        let server = hyper::Server::bind(&"127.0.0.1:0".parse().unwrap()).serve(make_service);
+        let addr = server.local_addr();
+        tokio::spawn(server);
+        (addr, seen)
+    }
+
+    fn forward_auth(addr: SocketAddr, request_headers: &[&str], response_headers: &[&str]) -> ForwardAuth {
+        let names = |names: &[&str]| names.iter().map(|name| name.to_string()).collect();
+        ForwardAuth::new(&ForwardAuthConfig { address: format!("http://{}/verify", addr), request_headers: names(request_headers), response_headers: names(response_headers), timeout: 5, cache: false }).unwrap()
+    }
+
+    fn request(headers: &[(&str, &str)]) -> Request<Body> {
+        let mut req = Request::post("/orders?id=1").header(HOST, "shop.example.com");
+        for (name, value) in headers {
+            req = req.header(*name, *value);
+        }
+        req.body(Body::from("order")).unwrap()
+    }
+
+    #[tokio::test]
+    async fn tells_the_service_about_the_request() {
+        let (addr, seen) = service().await;
+        let auth = forward_auth(addr, &[], &[]);
+        let mut req = request(&[("cookie", "session=ok"), ("content-length", "5"), ("x-forwarded-for", "10.9.9.9"), ("connection", "keep-alive")]);
+        assert!(auth.check(&mut req, CLIENT, true).await.is_none());
+
+        let seen = seen.lock().unwrap();
+        assert_eq!(seen["x-forwarded-method"], "POST");
+        assert_eq!(seen["x-forwarded-proto"], "https");
+        assert_eq!(seen["x-forwarded-host"], "shop.example.com");
+        assert_eq!(seen["x-forwarded-uri"], "/orders?id=1");
+        // The resolved client, not the chain the client sent
+        assert_eq!(seen["x-forwarded-for"], "192.0.2.7");
+        assert_eq!(seen[COOKIE], "session=ok");
+        assert_eq!(seen[HOST], addr.to_string());
+        assert!(!seen.contains_key(CONTENT_LENGTH));
+        assert!(!seen.contains_key(CONNECTION));
+    }
+
+    #[tokio::test]
+    async fn sends_only_the_listed_request_headers() {
+        let (addr, seen) = service().await;
+        let auth = forward_auth(addr, &["cookie"], &[]);
+        let mut req = request(&[("cookie", "session=ok"), ("authorization", "Bearer secret")]);
+        assert!(auth.check(&mut req, CLIENT, false).await.is_none());
+        let seen = seen.lock().unwrap();
+        assert_eq!(seen[COOKIE], "session=ok");
+        assert!(!seen.contains_key(AUTHORIZATION));
+        assert_eq!(seen["x-forwarded-proto"], "http");
+    }
+
+    #[tokio::test]
+    async fn copies_the_listed_headers_of_an_approval() {
+        let (addr, _) = service().await;
+        let auth = forward_auth(addr, &[], &["x-user"]);
+        let mut req = request(&[("cookie", "session=ok"), ("x-user", "mallory")]);
+        assert!(auth.check(&mut req, CLIENT, false).await.is_none());
+        assert_eq!(req.headers().get_all("x-user").iter().collect::<Vec<_>>(), ["alice"]);
+        assert!(!req.headers().contains_key("x-internal"));
+    }
+
+    #[tokio::test]
+    async fn returns_any_other_answer_to_the_client() {
+        let (addr, _) = service().await;
+        let auth = forward_auth(addr, &[], &["x-user"]);
+        let mut req = request(&[("x-user", "mallory")]);
+        let res = auth.check(&mut req, CLIENT, false).await.expect("turned down");
+        assert_eq!(res.status(), StatusCode::FOUND);
+        assert_eq!(res.headers()[LOCATION], "/login");
+        assert!(!res.headers().contains_key("x-hop"));
+        // Marked as the service's own, so no error page replaces it
+        assert!(res.extensions().get::<UpstreamUsed>().is_some());
+        // Nothing is copied from a denial
+        assert_eq!(req.headers()["x-user"], "mallory");
+    }
+
+    #[tokio::test]
+    async fn an_unreachable_service_turns_requests_down() {
+        let closed = std::net::TcpListener::bind("127.0.0.1:0").unwrap().local_addr().unwrap();
+        let auth = forward_auth(closed, &[], &[]);
+        let res = auth.check(&mut request(&[("cookie", "session=ok")]), CLIENT, false).await.expect("turned down");
+        assert_eq!(res.status(), StatusCode::SERVICE_UNAVAILABLE);
+        assert!(res.extensions().get::<UpstreamUsed>().is_none());
+    }
+}
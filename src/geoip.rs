@@ -0,0 +1,85 @@
+use async_trait::async_trait;
+use hyper::header::{HeaderName, HeaderValue};
+use hyper::{Body, Request, Response, StatusCode};
+use maxminddb::{geoip2, Reader};
+use std::net::IpAddr;
+
+use crate::config::GeoIpConfig;
+use crate::middleware::{Context, Middleware};
+
+/// Where a client is, as found in the GeoIP databases. Stored in the request's
+/// extensions so routes can match on the country.
+#[derive(Debug, Clone, Default)]
+pub struct GeoInfo {
+    /// Upper-case ISO 3166 code
+    pub country: Option<String>,
+    pub asn: Option<u32>,
+}
+
+/// Middleware looking up each client in MaxMind databases, passing its
+/// country and ASN upstream in headers and refusing denied countries.
+pub struct GeoIp {
+    countries: Option<Reader<Vec<u8>>>,
+    networks: Option<Reader<Vec<u8>>>,
+    country_header: Option<HeaderName>,
+    asn_header: Option<HeaderName>,
+    deny: Vec<String>,
+    allow: Vec<String>,
+}
+
+impl GeoIp {
+    /// Reads the databases into memory; they are read again on reload.
+    pub fn open(config: &GeoIpConfig) -> Result<Self, String> {
+        let open = |path: &Option<String>| path.as_ref().map(|path| Reader::open_readfile(path).map_err(|e| format!("failed to open GeoIP database {}: {}", path, e))).transpose();
+        let header = |name: &str| (!name.is_empty()).then(|| HeaderName::from_bytes(name.as_bytes()).map_err(|e| format!("invalid geoip header {}: {}", name, e))).transpose();
+        let codes = |codes: &[String]| codes.iter().map(|code| code.to_ascii_uppercase()).collect();
+        Ok(GeoIp {
+            countries: open(&config.country_database)?,
+            networks: open(&config.asn_database)?,
+            country_header: header(&config.country_header)?,
+            asn_header: header(&config.asn_header)?,
+            deny: codes(&config.deny_countries),
+            allow: codes(&config.allow_countries),
+        })
+    }
+
+    /// Looks up `ip`; addresses missing from a database, such as private ones, have no entry in it.
+    pub fn lookup(&self, ip: IpAddr) -> GeoInfo {
+        let ip = ip.to_canonical();
+        let country = self.countries.as_ref().and_then(|reader| {
+            let entry = reader.lookup::<geoip2::Country>(ip).ok()?;
+            // Anonymous proxies and the like have only a registered country
+            let country = entry.country.or(entry.registered_country)?;
+            country.iso_code.map(str::to_ascii_uppercase)
+        });
+        let asn = self.networks.as_ref().and_then(|reader| reader.lookup::<geoip2::Asn>(ip).ok()?.autonomous_system_number);
+        GeoInfo { country, asn }
+    }
+
+    fn permits(&self, country: Option<&str>) -> bool {
+        let listed = |codes: &[String]| country.is_some_and(|country| codes.iter().any(|code| code == country));
+        !listed(&self.deny) && (self.allow.is_empty() || listed(&self.allow))
+    }
+}
+
+#[async_trait]
+impl Middleware for GeoIp {
+    async fn on_request(&self, req: &mut Request<Body>, ctx: &mut Context) -> Option<Response<Body>> {
+        let info = self.lookup(ctx.client_addr.ip());
+        if !self.permits(info.country.as_deref()) {
+            return Some(Response::builder().status(StatusCode::FORBIDDEN).body(Body::from("Forbidden")).unwrap());
+        }
+        // Whatever the client sent in these headers is replaced, so upstreams can trust them
+        let values = [(&self.country_header, info.country.clone()), (&self.asn_header, info.asn.map(|asn| asn.to_string()))];
+        for (name, value) in values {
+            if let Some(name) = name {
+                req.headers_mut().remove(name);
+                if let Some(value) = value.and_then(|value| HeaderValue::from_str(&value).ok()) {
+                    req.headers_mut().insert(name.clone(), value);
+                }
+            }
+        }
+        req.extensions_mut().insert(info);
+        None
+    }
+}
@@ -0,0 +1,136 @@
+use hyper::body::{Bytes, HttpBody};
+use hyper::client::Client;
+use hyper::header::{HeaderMap, HeaderValue, CONTENT_TYPE, TE};
+use hyper::{Body, Request, Response, StatusCode, Uri};
+use std::time::Duration;
+
+use crate::tls::ClientConnector;
+
+/// Status reported by clients when a stream ends without one.
+const UNKNOWN: u32 = 2;
+/// Status for a stream the upstream broke off.
+const UNAVAILABLE: u32 = 14;
+
+/// Whether a request or response is gRPC, by its `Content-Type`.
+pub fn is_grpc(headers: &HeaderMap) -> bool {
+    headers.get(CONTENT_TYPE).and_then(|v| v.to_str().ok()).is_some_and(|v| v.starts_with("application/grpc"))
+}
+
+/// The `grpc-status` in a response's headers or trailers.
+pub fn status(headers: &HeaderMap) -> Option<u32> {
+    headers.get("grpc-status")?.to_str().ok()?.trim().parse().ok()
+}
+
+/// Whether a status means the upstream failed, counting against it like an
+/// HTTP 5xx: UNKNOWN, DEADLINE_EXCEEDED, INTERNAL, UNAVAILABLE and DATA_LOSS.
+/// Other statuses are answers about the call itself.
+pub fn is_failure(code: u32) -> bool {
+    matches!(code, 2 | 4 | 13 | 14 | 15)
+}
+
+/// Calls `done` with the response's gRPC status once it is known: straight
+/// away for a trailers-only response, otherwise after the trailers carrying
+/// it have been passed on to the client.
+pub fn observe_status(res: &mut Response<Body>, done: impl FnOnce(u32) + Send + 'static) {
+    if let Some(code) = status(res.headers()) {
+        return done(code);
+    }
+    let mut body = std::mem::replace(res.body_mut(), Body::empty());
+    let (mut sender, forwarded) = Body::channel();
+    *res.body_mut() = forwarded;
+    tokio::spawn(async move {
+        while let Some(chunk) = body.data().await {
+            match chunk {
+                Ok(chunk) => {
+                    // The client went away, so there is no outcome to report
+                    if sender.send_data(chunk).await.is_err() {
+                        return;
+                    }
+                }
+                Err(_) => {
+                    sender.abort();
+                    return done(UNAVAILABLE);
+                }
+            }
+        }
+        let trailers = match body.trailers().await {
+            Ok(trailers) => trailers,
+            Err(_) => {
+                sender.abort();
+                return done(UNAVAILABLE);
+            }
+        };
+        let code = trailers.as_ref().and_then(status).unwrap_or(UNKNOWN);
+        if let Some(trailers) = trailers {
+            if sender.send_trailers(trailers).await.is_err() {
+                return;
+            }
+        }
+        done(code);
+    });
+}
+
+/// Probes `upstream` with the gRPC health checking protocol
+/// (`grpc.health.v1.Health/Check`), which passes when it reports `service`
+/// as SERVING.
+pub async fn check_health(client: &Client<ClientConnector>, upstream: &str, service: &str, timeout: Duration) -> bool {
+    let uri: Uri = match format!("{}/grpc.health.v1.Health/Check", upstream.trim_end_matches('/')).parse() {
+        Ok(uri) => uri,
+        Err(_) => return false,
+    };
+    let req = Request::post(uri)
+        .header(CONTENT_TYPE, HeaderValue::from_static("application/grpc"))
+        .header(TE, HeaderValue::from_static("trailers"))
+        .body(Body::from(frame(&health_request(service))))
+        .expect("valid health check request");
+    let exchange = async {
+        let res = client.request(req).await.ok()?;
+        if res.status() != StatusCode::OK {
+            return None;
+        }
+        if let Some(code) = status(res.headers()) {
+            return Some((code, Vec::new()));
+        }
+        let mut body = res.into_body();
+        let mut message = Vec::new();
+        while let Some(chunk) = body.data().await {
+            message.extend_from_slice(&chunk.ok()?);
+        }
+        let code = body.trailers().await.ok()?.as_ref().and_then(status).unwrap_or(UNKNOWN);
+        Some((code, message))
+    };
+    match tokio::time::timeout(timeout, exchange).await {
+        Ok(Some((0, message))) => serving(&message),
+        _ => false,
+    }
+}
+
+/// A `HealthCheckRequest`, whose only field is the service name.
+fn health_request(service: &str) -> Vec<u8> {
+    let mut message = Vec::new();
+    if !service.is_empty() {
+        message.push(0x0a);
+        let mut len = service.len();
+        while len >= 0x80 {
+            message.push((len as u8 & 0x7f) | 0x80);
+            len >>= 7;
+        }
+        message.push(len as u8);
+        message.extend_from_slice(service.as_bytes());
+    }
+    message
+}
+
+/// Wraps a message in a gRPC frame: an uncompressed flag and the length.
+fn frame(message: &[u8]) -> Bytes {
+    let mut framed = vec![0];
+    framed.extend_from_slice(&(message.len() as u32).to_be_bytes());
+    framed.extend_from_slice(message);
+    Bytes::from(framed)
+}
+
+/// Whether a framed `HealthCheckResponse` has the status SERVING (1).
+fn serving(framed: &[u8]) -> bool {
+    // The status is the response's only field, a varint numbered 1
+    framed.len() >= 5 && framed[0] == 0 && framed[5..].starts_with(&[0x08, 0x01])
+}
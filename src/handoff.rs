@@ -0,0 +1,352 @@
+use std::sync::OnceLock;
+use tokio_util::sync::CancellationToken;
+
+#[cfg(unix)]
+pub use self::unix::{adopt, discard_unused, inherit, register, serve, take};
+
+/// Cancelled once a new process has taken over the listening sockets.
+fn handover() -> &'static CancellationToken {
+    static HANDOVER: OnceLock<CancellationToken> = OnceLock::new();
+    HANDOVER.get_or_init(CancellationToken::new)
+}
+
+/// Completes once a new process has taken over the listening sockets, at
+/// which point this one stops accepting and drains its connections.
+pub async fn handed_over() {
+    handover().cancelled().await
+}
+
+/// Passing listening sockets from a running process to its replacement over
+/// a Unix control socket, with SCM_RIGHTS.
+///
+/// The new process connects to the control socket before binding anything,
+/// and receives every socket the old one listens on, labelled with what it
+/// is bound to. Binding an address or path it was given takes the inherited
+/// socket instead, so both processes accept from the same kernel queue and
+/// no connection is refused. Once the new process has started, it says so;
+/// the old one then stops accepting, drains and exits, and the new one
+/// takes over the control socket for the next upgrade.
+#[cfg(unix)]
+mod unix {
+    use std::collections::BTreeMap;
+    use std::io::{self, Read, Write};
+    use std::os::fd::{AsRawFd, FromRawFd, OwnedFd, RawFd};
+    use std::os::unix::fs::PermissionsExt;
+    use std::os::unix::net::{UnixListener, UnixStream};
+    use std::path::{Path, PathBuf};
+    use std::sync::Mutex;
+    use std::time::Duration;
+    use tracing::{error, info, warn};
+
+    /// The most descriptors Linux passes in one message.
+    const MAX_FDS: usize = 253;
+    /// Sent by the new process once it is serving.
+    const READY: u8 = b'R';
+    /// How long the new process waits for the old one to let go of the control socket.
+    const RELEASE_TIMEOUT: Duration = Duration::from_secs(10);
+
+    /// Sockets received from the old process, by what they are bound to.
+    static INHERITED: Mutex<BTreeMap<String, Vec<OwnedFd>>> = Mutex::new(BTreeMap::new());
+    /// Sockets this process listens on, to hand to its successor.
+    static BOUND: Mutex<Vec<(String, OwnedFd)>> = Mutex::new(Vec::new());
+    /// The connection to the old process, while this one starts.
+    static PREDECESSOR: Mutex<Option<UnixStream>> = Mutex::new(None);
+
+    /// Takes an inherited socket bound to `key`, if one was passed.
+    pub fn take(key: &str) -> Option<OwnedFd> {
+        let mut inherited = INHERITED.lock().unwrap();
+        let fds = inherited.get_mut(key)?;
+        let fd = fds.pop();
+        if fds.is_empty() {
+            inherited.remove(key);
+        }
+        fd
+    }
+
+    /// Offers a socket bound by someone else, such as systemd, to the binds of
+    /// this process, as if it had been handed over.
+    pub fn adopt(key: String, fd: OwnedFd) {
+        INHERITED.lock().unwrap().entry(key).or_default().push(fd);
+    }
+
+    /// Closes the inherited sockets nothing has taken, such as spare acceptors
+    /// or a systemd socket the configuration does not bind.
+    pub fn discard_unused() {
+        for (key, fds) in std::mem::take(&mut *INHERITED.lock().unwrap()) {
+            warn!("Closing {} inherited socket(s) on {}: no listener is configured there", fds.len(), key);
+        }
+    }
+
+    /// Records a socket this process listens on under `key`, such as
+    /// `tcp:0.0.0.0:443`, so it can be handed to a successor.
+    pub fn register(key: String, fd: OwnedFd) {
+        BOUND.lock().unwrap().push((key, fd));
+    }
+
+    /// Connects to a running Riffy's control socket at `path` and receives its
+    /// listening sockets. Returns whether there was one to take over from.
+    pub fn inherit(path: &Path) -> Result<bool, String> {
+        let stream = match UnixStream::connect(path) {
+            Ok(stream) => stream,
+            // No process to take over from: start afresh
+            Err(e) if matches!(e.kind(), io::ErrorKind::NotFound | io::ErrorKind::ConnectionRefused) => return Ok(false),
+            Err(e) => return Err(format!("failed to connect to upgrade socket {}: {}", path.display(), e)),
+        };
+        let (payload, fds) = recv_fds(&stream).map_err(|e| format!("failed to receive sockets over {}: {}", path.display(), e))?;
+        let keys: Vec<&str> = std::str::from_utf8(&payload).map_err(|_| "invalid socket handoff".to_string())?.lines().collect();
+        if keys.len() != fds.len() {
+            return Err(format!("socket handoff over {} named {} sockets but passed {}", path.display(), keys.len(), fds.len()));
+        }
+        info!("Inherited {} listening sockets over {}", fds.len(), path.display());
+        let mut inherited = INHERITED.lock().unwrap();
+        for (key, fd) in keys.into_iter().zip(fds) {
+            inherited.entry(key.to_string()).or_default().push(fd);
+        }
+        *PREDECESSOR.lock().unwrap() = Some(stream);
+        Ok(true)
+    }
+
+    /// Tells the old process, if any, that this one is serving, waits for it to
+    /// let go of the control socket, and then listens on it for a successor.
+    pub fn serve(path: PathBuf) -> Result<(), String> {
+        if let Some(mut stream) = PREDECESSOR.lock().unwrap().take() {
+            stream.write_all(&[READY]).map_err(|e| format!("failed to signal the old process: {}", e))?;
+            stream.set_read_timeout(Some(RELEASE_TIMEOUT)).map_err(|e| e.to_string())?;
+            let _ = stream.read_to_end(&mut Vec::new());
+        }
+
+        if std::fs::symlink_metadata(&path).is_ok_and(|meta| std::os::unix::fs::FileTypeExt::is_socket(&meta.file_type())) {
+            std::fs::remove_file(&path).map_err(|e| format!("failed to remove stale socket {}: {}", path.display(), e))?;
+        }
+        let listener = UnixListener::bind(&path).map_err(|e| format!("failed to bind upgrade socket {}: {}", path.display(), e))?;
+        // Whoever connects gets every listening socket, so the umask is not trusted with it
+        std::fs::set_permissions(&path, std::fs::Permissions::from_mode(0o600)).map_err(|e| format!("failed to restrict upgrade socket {}: {}", path.display(), e))?;
+        std::thread::spawn(move || {
+            for stream in listener.incoming() {
+                let stream = match stream {
+                    Ok(stream) => stream,
+                    Err(e) => return error!("Upgrade socket stopped accepting: {}", e),
+                };
+                match hand_over(&stream) {
+                    Ok(()) => {
+                        info!("Handed the listening sockets to a new process; draining connections");
+                        super::handover().cancel();
+                        // Closing the connection, after the listener, lets the new process bind the path
+                        drop(listener);
+                        return drop(stream);
+                    }
+                    Err(e) => error!("Upgrade aborted, still serving: {}", e),
+                }
+            }
+        });
+        Ok(())
+    }
+
+    /// Sends the listening sockets to a new process and waits for it to be ready.
+    /// Only a process running as the same user, or as root, is answered.
+    fn hand_over(mut stream: &UnixStream) -> io::Result<()> {
+        let uid = peer_uid(stream)?;
+        // SAFETY: geteuid cannot fail
+        let own = unsafe { libc::geteuid() };
+        if uid != own && uid != 0 {
+            return Err(io::Error::new(io::ErrorKind::PermissionDenied, format!("refusing to hand sockets to uid {}", uid)));
+        }
+        {
+            let bound = BOUND.lock().unwrap();
+            if bound.len() > MAX_FDS {
+                return Err(io::Error::other(format!("too many sockets to hand over: {}", bound.len())));
+            }
+            let payload: String = bound.iter().map(|(key, _)| format!("{}\n", key)).collect();
+            let fds: Vec<RawFd> = bound.iter().map(|(_, fd)| fd.as_raw_fd()).collect();
+            send_fds(stream, payload.as_bytes(), &fds)?;
+        }
+        let mut ready = [0];
+        stream.read_exact(&mut ready)?;
+        if ready[0] != READY {
+            return Err(io::Error::other("unexpected reply from the new process"));
+        }
+        Ok(())
+    }
+
+    /// The effective user ID of the process at the other end of `stream`.
+    #[cfg(any(target_os = "linux", target_os = "android"))]
+    fn peer_uid(stream: &UnixStream) -> io::Result<libc::uid_t> {
+        let mut cred = libc::ucred { pid: 0, uid: 0, gid: 0 };
+        let mut len = std::mem::size_of::<libc::ucred>() as libc::socklen_t;
+        // SAFETY: `cred` and `len` outlive the call, and `len` is the size of `cred`
+        let ret = unsafe { libc::getsockopt(stream.as_raw_fd(), libc::SOL_SOCKET, libc::SO_PEERCRED, &mut cred as *mut libc::ucred as *mut libc::c_void, &mut len) };
+        if ret < 0 {
+            return Err(io::Error::last_os_error());
+        }
+        Ok(cred.uid)
+    }
+
+    /// The effective user ID of the process at the other end of `stream`.
+    #[cfg(not(any(target_os = "linux", target_os = "android")))]
+    fn peer_uid(stream: &UnixStream) -> io::Result<libc::uid_t> {
+        let (mut uid, mut gid) = (0, 0);
+        // SAFETY: `uid` and `gid` outlive the call
+        if unsafe { libc::getpeereid(stream.as_raw_fd(), &mut uid, &mut gid) } < 0 {
+            return Err(io::Error::last_os_error());
+        }
+        Ok(uid)
+    }
+
+    fn send_fds(stream: &UnixStream, payload: &[u8], fds: &[RawFd]) -> io::Result<()> {
+        let mut iov = libc::iovec { iov_base: payload.as_ptr() as *mut libc::c_void, iov_len: payload.len() };
+        // u64 keeps the control buffer aligned for cmsghdr
+        let mut control = vec![0u64; control_len(fds.len()).div_ceil(8)];
+        // SAFETY: the message points at `iov` and `control`, which outlive the call, and
+        // the control buffer has room for a header and `fds`
+        unsafe {
+            let mut msg: libc::msghdr = std::mem::zeroed();
+            msg.msg_iov = &mut iov;
+            msg.msg_iovlen = 1;
+            if !fds.is_empty() {
+                msg.msg_control = control.as_mut_ptr() as *mut libc::c_void;
+                msg.msg_controllen = control_len(fds.len()) as _;
+                let cmsg = libc::CMSG_FIRSTHDR(&msg);
+                (*cmsg).cmsg_level = libc::SOL_SOCKET;
+                (*cmsg).cmsg_type = libc::SCM_RIGHTS;
+                (*cmsg).cmsg_len = libc::CMSG_LEN(std::mem::size_of_val(fds) as u32) as _;
+                std::ptr::copy_nonoverlapping(fds.as_ptr(), libc::CMSG_DATA(cmsg) as *mut RawFd, fds.len());
+            }
+            if libc::sendmsg(stream.as_raw_fd(), &msg, 0) < 0 {
+                return Err(io::Error::last_os_error());
+            }
+        }
+        Ok(())
+    }
+
+    fn recv_fds(stream: &UnixStream) -> io::Result<(Vec<u8>, Vec<OwnedFd>)> {
+        let mut payload = vec![0u8; 64 * 1024];
+        let mut iov = libc::iovec { iov_base: payload.as_mut_ptr() as *mut libc::c_void, iov_len: payload.len() };
+        let mut control = vec![0u64; control_len(MAX_FDS).div_ceil(8)];
+        let mut fds = Vec::new();
+        // SAFETY: as in `send_fds`; the kernel fills in at most `msg_controllen` bytes of
+        // control data, and each descriptor it passes is new and owned by this process
+        unsafe {
+            let mut msg: libc::msghdr = std::mem::zeroed();
+            msg.msg_iov = &mut iov;
+            msg.msg_iovlen = 1;
+            msg.msg_control = control.as_mut_ptr() as *mut libc::c_void;
+            msg.msg_controllen = control_len(MAX_FDS) as _;
+            #[cfg(any(target_os = "linux", target_os = "android"))]
+            let flags = libc::MSG_CMSG_CLOEXEC;
+            #[cfg(not(any(target_os = "linux", target_os = "android")))]
+            let flags = 0;
+            let n = libc::recvmsg(stream.as_raw_fd(), &mut msg, flags);
+            if n < 0 {
+                return Err(io::Error::last_os_error());
+            }
+            let mut cmsg = libc::CMSG_FIRSTHDR(&msg);
+            while !cmsg.is_null() {
+                if (*cmsg).cmsg_level == libc::SOL_SOCKET && (*cmsg).cmsg_type == libc::SCM_RIGHTS {
+                    let data = libc::CMSG_DATA(cmsg) as *const RawFd;
+                    let count = ((*cmsg).cmsg_len as usize - libc::CMSG_LEN(0) as usize) / std::mem::size_of::<RawFd>();
+                    for i in 0..count {
+                        fds.push(OwnedFd::from_raw_fd(std::ptr::read_unaligned(data.add(i))));
+                    }
+                }
+                cmsg = libc::CMSG_NXTHDR(&msg, cmsg);
+            }
+            if msg.msg_flags & libc::MSG_CTRUNC != 0 {
+                return Err(io::Error::other("sockets were lost in the handoff"));
+            }
+            payload.truncate(n as usize);
+        }
+        Ok((payload, fds))
+    }
+
+    /// Bytes of control data for a message passing `count` descriptors.
+    fn control_len(count: usize) -> usize {
+        // SAFETY: CMSG_SPACE only does arithmetic
+        unsafe { libc::CMSG_SPACE((count * std::mem::size_of::<RawFd>()) as u32) as usize }
+    }
+
+    #[cfg(test)]
+    mod tests {
+        use super::*;
+        use std::net::{TcpListener, TcpStream};
+
+        fn listener() -> (TcpListener, String) {
+            let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+            let addr = listener.local_addr().unwrap().to_string();
+            (listener, addr)
+        }
+
+        /// Accepts a connection on `fd`, a listening socket bound to `addr`.
+        fn accepts(fd: OwnedFd, addr: &str) {
+            let listener = TcpListener::from(fd);
+            let _client = TcpStream::connect(addr).unwrap();
+            listener.accept().unwrap();
+        }
+
+        #[test]
+        fn passes_listening_sockets_with_their_keys() {
+            let (old, new) = UnixStream::pair().unwrap();
+            let (first, first_addr) = listener();
+            let (second, second_addr) = listener();
+            send_fds(&old, b"tcp:first\ntcp:second\n", &[first.as_raw_fd(), second.as_raw_fd()]).unwrap();
+            // The sender's copies can go; the received ones stay open
+            drop((first, second));
+
+            let (payload, mut fds) = recv_fds(&new).unwrap();
+            assert_eq!(payload, b"tcp:first\ntcp:second\n");
+            assert_eq!(fds.len(), 2);
+            accepts(fds.remove(1), &second_addr);
+            accepts(fds.remove(0), &first_addr);
+        }
+
+        #[test]
+        fn inherited_sockets_are_taken_once() {
+            let (first, _) = listener();
+            let (second, _) = listener();
+            adopt("tcp:test-take".to_string(), first.into());
+            adopt("tcp:test-take".to_string(), second.into());
+            assert!(take("tcp:test-take").is_some());
+            assert!(take("tcp:test-take").is_some());
+            assert!(take("tcp:test-take").is_none());
+            assert!(take("tcp:test-never-bound").is_none());
+        }
+
+        #[test]
+        fn hands_over_and_waits_for_the_new_process() {
+            let (bound, addr) = listener();
+            register("tcp:test-hand-over".to_string(), bound.into());
+            for (reply, succeeds) in [(READY, true), (b'?', false)] {
+                let (old, mut new) = UnixStream::pair().unwrap();
+                let successor = std::thread::spawn(move || {
+                    let (payload, fds) = recv_fds(&new).unwrap();
+                    let payload = String::from_utf8(payload).unwrap();
+                    let fd = payload.lines().zip(fds).find(|(key, _)| *key == "tcp:test-hand-over").map(|(_, fd)| fd);
+                    new.write_all(&[reply]).unwrap();
+                    fd
+                });
+                assert_eq!(hand_over(&old).is_ok(), succeeds);
+                accepts(successor.join().unwrap().expect("handed over"), &addr);
+            }
+        }
+
+        #[test]
+        fn knows_who_is_connecting() {
+            let (old, _new) = UnixStream::pair().unwrap();
+            assert_eq!(peer_uid(&old).unwrap(), unsafe { libc::geteuid() });
+        }
+
+        #[test]
+        fn upgrade_socket_is_private_to_its_user() {
+            let path = std::env::temp_dir().join(format!("riffy-upgrade-mode-{}.sock", std::process::id()));
+            serve(path.clone()).unwrap();
+            let mode = std::fs::metadata(&path).unwrap().permissions().mode();
+            let _ = std::fs::remove_file(&path);
+            assert_eq!(mode & 0o777, 0o600);
+        }
+
+        #[test]
+        fn starts_afresh_without_a_running_process() {
+            let path = std::env::temp_dir().join(format!("riffy-no-upgrade-{}.sock", std::process::id()));
+            assert_eq!(inherit(&path), Ok(false));
+        }
+    }
+}
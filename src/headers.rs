@@ -0,0 +1,475 @@
+use async_trait::async_trait;
+use hyper::header::{HeaderMap, HeaderName, HeaderValue, CONNECTION, CONTENT_LOCATION, CONTENT_TYPE, COOKIE, LOCATION, REFRESH, SET_COOKIE, STRICT_TRANSPORT_SECURITY, TE, UPGRADE};
+use hyper::{Body, Request, Response, Uri};
+use regex::Regex;
+use std::collections::BTreeMap;
+use std::net::IpAddr;
+
+use crate::config::{HeaderRulesConfig, HeadersConfig, ResponseRewriteConfig, SecurityHeadersConfig};
+use crate::middleware::{Context, Middleware};
+
+/// Hop-by-hop headers from RFC 7230 section 6.1, which apply to a single
+/// connection and must not be forwarded by proxies.
+const HOP_BY_HOP: [&str; 8] = [
+    "connection",
+    "keep-alive",
+    "proxy-authenticate",
+    "proxy-authorization",
+    "te",
+    "trailer",
+    "transfer-encoding",
+    "upgrade",
+];
+
+/// Removes hop-by-hop headers, including any extra ones named in `Connection`.
+pub fn strip_hop_by_hop(headers: &mut HeaderMap) {
+    let listed: Vec<HeaderName> = headers
+        .get_all(CONNECTION)
+        .iter()
+        .filter_map(|v| v.to_str().ok())
+        .flat_map(|v| v.split(','))
+        .filter_map(|name| HeaderName::from_bytes(name.trim().as_bytes()).ok())
+        .collect();
+
+    for name in listed {
+        headers.remove(name);
+    }
+    for name in HOP_BY_HOP.iter() {
+        headers.remove(*name);
+    }
+}
+
+/// Whether the client accepts trailers, as gRPC clients say with `TE: trailers`.
+pub fn accepts_trailers(headers: &HeaderMap) -> bool {
+    headers
+        .get_all(TE)
+        .iter()
+        .filter_map(|v| v.to_str().ok())
+        .flat_map(|v| v.split(','))
+        .any(|token| token.trim().eq_ignore_ascii_case("trailers"))
+}
+
+/// Returns the requested protocol (e.g. `websocket`) when the headers ask for
+/// a connection upgrade via `Connection: upgrade` and `Upgrade`.
+pub fn upgrade_protocol(headers: &HeaderMap) -> Option<HeaderValue> {
+    let wants_upgrade = headers
+        .get_all(CONNECTION)
+        .iter()
+        .filter_map(|v| v.to_str().ok())
+        .flat_map(|v| v.split(','))
+        .any(|token| token.trim().eq_ignore_ascii_case("upgrade"));
+
+    if wants_upgrade {
+        headers.get(UPGRADE).cloned()
+    } else {
+        None
+    }
+}
+
+/// Restores the upgrade headers after hop-by-hop stripping.
+pub fn set_upgrade(headers: &mut HeaderMap, protocol: HeaderValue) {
+    headers.insert(CONNECTION, HeaderValue::from_static("upgrade"));
+    headers.insert(UPGRADE, protocol);
+}
+
+/// Adds X-Forwarded-For, X-Forwarded-Proto and X-Real-IP so upstreams can see
+/// the client. An existing X-Forwarded-For chain is extended with the peer,
+/// not replaced; X-Real-IP is the client, which differs from the peer behind
+/// trusted proxies.
+pub fn add_forwarded_headers(headers: &mut HeaderMap, peer_ip: IpAddr, client_ip: IpAddr, tls: bool) {
+    let peer_ip = peer_ip.to_string();
+
+    let forwarded_for = match headers.get("x-forwarded-for").and_then(|v| v.to_str().ok()) {
+        Some(existing) if !existing.trim().is_empty() => format!("{}, {}", existing, peer_ip),
+        _ => peer_ip,
+    };
+
+    if let Ok(value) = HeaderValue::from_str(&forwarded_for) {
+        headers.insert("x-forwarded-for", value);
+    }
+    headers.insert("x-forwarded-proto", HeaderValue::from_static(if tls { "https" } else { "http" }));
+    if let Ok(value) = HeaderValue::from_str(&client_ip.to_string()) {
+        headers.insert("x-real-ip", value);
+    }
+}
+
+/// Middleware adding the forwarded headers to every request.
+pub struct ForwardedHeaders;
+
+#[async_trait]
+impl Middleware for ForwardedHeaders {
+    async fn on_request(&self, req: &mut Request<Body>, ctx: &mut Context) -> Option<Response<Body>> {
+        add_forwarded_headers(req.headers_mut(), ctx.peer_addr.ip(), ctx.client_addr.ip(), ctx.tls);
+        None
+    }
+}
+
+/// Middleware passing the client certificate subject to upstreams in a
+/// header, replacing any value the client sent itself.
+pub struct ClientCertHeader(pub HeaderName);
+
+#[async_trait]
+impl Middleware for ClientCertHeader {
+    async fn on_request(&self, req: &mut Request<Body>, ctx: &mut Context) -> Option<Response<Body>> {
+        req.headers_mut().remove(&self.0);
+        if let Some(value) = ctx.client_cert_subject.as_deref().and_then(|s| HeaderValue::from_str(s).ok()) {
+            req.headers_mut().insert(self.0.clone(), value);
+        }
+        None
+    }
+}
+
+/// Returns the value of the named cookie from the request's Cookie headers.
+pub fn cookie_value(headers: &HeaderMap, name: &str) -> Option<String> {
+    headers
+        .get_all(COOKIE)
+        .iter()
+        .filter_map(|v| v.to_str().ok())
+        .flat_map(|v| v.split(';'))
+        .filter_map(|pair| pair.trim().split_once('='))
+        .find(|(key, _)| *key == name)
+        .map(|(_, value)| value.trim_matches('"').to_string())
+}
+
+/// Request and response header rules of the global config or a route.
+#[derive(Debug, Clone, Default)]
+pub struct HeaderRuleSet {
+    pub request: HeaderRules,
+    pub response: HeaderRules,
+}
+
+impl HeaderRuleSet {
+    pub fn new(config: &HeadersConfig) -> Result<Self, String> {
+        Ok(HeaderRuleSet {
+            request: HeaderRules::new(&config.request).map_err(|e| format!("request: {}", e))?,
+            response: HeaderRules::new(&config.response).map_err(|e| format!("response: {}", e))?,
+        })
+    }
+}
+
+/// Security headers of the global config or a route, added to responses
+/// unless the upstream already set them.
+#[derive(Debug, Clone, Default)]
+pub struct SecurityHeaders {
+    headers: Vec<(HeaderName, HeaderValue)>,
+    /// Only sent over TLS, as browsers ignore it on plain HTTP
+    hsts: Option<HeaderValue>,
+}
+
+impl SecurityHeaders {
+    pub fn new(config: &SecurityHeadersConfig) -> Result<Self, String> {
+        if !config.enabled {
+            return Ok(SecurityHeaders::default());
+        }
+        let mut headers = Vec::new();
+        if config.content_type_options {
+            headers.push((HeaderName::from_static("x-content-type-options"), HeaderValue::from_static("nosniff")));
+        }
+        if !config.frame_options.is_empty() {
+            headers.push((HeaderName::from_static("x-frame-options"), HeaderValue::from_str(&config.frame_options.to_ascii_uppercase()).map_err(|e| e.to_string())?));
+        }
+        if let Some(policy) = &config.content_security_policy {
+            headers.push((HeaderName::from_static("content-security-policy"), HeaderValue::from_str(policy).map_err(|_| format!("invalid content security policy: {}", policy))?));
+        }
+        let hsts = (config.hsts_max_age > 0).then(|| {
+            let mut value = format!("max-age={}", config.hsts_max_age);
+            if config.hsts_include_subdomains {
+                value.push_str("; includeSubDomains");
+            }
+            if config.hsts_preload {
+                value.push_str("; preload");
+            }
+            HeaderValue::from_str(&value).expect("valid HSTS value")
+        });
+        Ok(SecurityHeaders { headers, hsts })
+    }
+
+    pub fn apply(&self, headers: &mut HeaderMap, tls: bool) {
+        let hsts = self.hsts.iter().filter(|_| tls).map(|value| (STRICT_TRANSPORT_SECURITY, value));
+        for (name, value) in self.headers.iter().map(|(name, value)| (name.clone(), value)).chain(hsts) {
+            if !headers.contains_key(&name) {
+                headers.insert(name, value.clone());
+            }
+        }
+    }
+}
+
+/// Marks a response carrying Server-Sent Events, which is passed to the
+/// client event by event: it is neither compressed nor cached.
+#[derive(Debug, Clone, Copy)]
+pub struct EventStream;
+
+/// Whether a response's `Content-Type` is `text/event-stream`.
+pub fn is_event_stream(headers: &HeaderMap) -> bool {
+    headers
+        .get(CONTENT_TYPE)
+        .and_then(|v| v.to_str().ok())
+        .and_then(|v| v.split(';').next())
+        .is_some_and(|media| media.trim().eq_ignore_ascii_case("text/event-stream"))
+}
+
+/// Rewrites of upstream response headers that point at the upstream itself,
+/// like nginx's `proxy_redirect` and `proxy_cookie_domain`/`proxy_cookie_path`.
+#[derive(Debug, Clone, Default)]
+pub struct ResponseRewrite {
+    location: bool,
+    refresh: bool,
+    /// Lowercased, without a leading dot
+    cookie_domains: Vec<(String, String)>,
+    /// Longest prefix first
+    cookie_paths: Vec<(String, String)>,
+}
+
+impl ResponseRewrite {
+    pub fn new(config: &ResponseRewriteConfig) -> Self {
+        let mut cookie_paths: Vec<_> = config.cookie_paths.iter().map(|(from, to)| (from.clone(), to.clone())).collect();
+        cookie_paths.sort_by_key(|(from, _)| std::cmp::Reverse(from.len()));
+        ResponseRewrite {
+            location: config.location,
+            refresh: config.refresh,
+            cookie_domains: config.cookie_domains.iter().map(|(from, to)| (from.trim_start_matches('.').to_ascii_lowercase(), to.clone())).collect(),
+            cookie_paths,
+        }
+    }
+
+    /// Rewrites the headers of a response from `upstream` to a request for
+    /// `public`, the scheme and host the client used, such as `https://example.com`.
+    pub fn apply(&self, headers: &mut HeaderMap, upstream: &str, public: Option<&str>) {
+        if let (Some(public), Ok(upstream)) = (public, upstream.parse::<Uri>()) {
+            let mut names = Vec::new();
+            if self.location {
+                names.extend([LOCATION, CONTENT_LOCATION]);
+            }
+            if self.refresh {
+                names.push(REFRESH);
+            }
+            for name in names {
+                let rewritten = headers.get(&name).and_then(|v| v.to_str().ok()).and_then(|v| rewrite_url_in(v, &upstream, public, name == REFRESH));
+                if let Some(value) = rewritten.and_then(|v| HeaderValue::from_str(&v).ok()) {
+                    headers.insert(name, value);
+                }
+            }
+        }
+
+        if self.cookie_domains.is_empty() && self.cookie_paths.is_empty() {
+            return;
+        }
+        let cookies: Vec<HeaderValue> = headers
+            .get_all(SET_COOKIE)
+            .iter()
+            .map(|value| match value.to_str() {
+                Ok(cookie) => HeaderValue::from_str(&self.rewrite_cookie(cookie)).unwrap_or_else(|_| value.clone()),
+                Err(_) => value.clone(),
+            })
+            .collect();
+        headers.remove(SET_COOKIE);
+        for cookie in cookies {
+            headers.append(SET_COOKIE, cookie);
+        }
+    }
+
+    fn rewrite_cookie(&self, cookie: &str) -> String {
+        let mut parts = cookie.split(';');
+        let mut rewritten = vec![parts.next().unwrap_or("").trim().to_string()];
+        for attribute in parts.map(str::trim) {
+            let (key, value) = attribute.split_once('=').unwrap_or((attribute, ""));
+            if key.eq_ignore_ascii_case("domain") {
+                let domain = value.trim_start_matches('.').to_ascii_lowercase();
+                match self.cookie_domains.iter().find(|(from, _)| *from == domain) {
+                    Some((_, to)) if to.is_empty() => continue,
+                    Some((_, to)) => rewritten.push(format!("{}={}", key, to)),
+                    None => rewritten.push(attribute.to_string()),
+                }
+            } else if key.eq_ignore_ascii_case("path") {
+                match self.cookie_paths.iter().find(|(from, _)| value.starts_with(from.as_str())) {
+                    Some((from, to)) => rewritten.push(format!("{}={}{}", key, to, &value[from.len()..])),
+                    None => rewritten.push(attribute.to_string()),
+                }
+            } else {
+                rewritten.push(attribute.to_string());
+            }
+        }
+        rewritten.join("; ")
+    }
+}
+
+/// Replaces the origin of a URL on `upstream` with `public`. A `Refresh`
+/// value keeps its delay, as in `5; url=https://example.com/next`; other
+/// values are whole URLs, which may hold `url=` themselves.
+fn rewrite_url_in(value: &str, upstream: &Uri, public: &str, refresh: bool) -> Option<String> {
+    let (prefix, url) = match value.to_ascii_lowercase().find("url=") {
+        Some(start) if refresh => value.split_at(start + 4),
+        _ => ("", value),
+    };
+    let url_uri = url.trim().parse::<Uri>().ok()?;
+    let port = |uri: &Uri| uri.port_u16().or(match uri.scheme_str() {
+        Some("https") => Some(443),
+        Some("http") => Some(80),
+        _ => None,
+    });
+    let same_origin = url_uri.scheme() == upstream.scheme()
+        && url_uri.host().zip(upstream.host()).is_some_and(|(a, b)| a.eq_ignore_ascii_case(b))
+        && port(&url_uri) == port(upstream);
+    if !same_origin {
+        return None;
+    }
+    let rest = url_uri.path_and_query().map_or("/", |pq| pq.as_str());
+    Some(format!("{}{}{}", prefix, public, rest))
+}
+
+/// Compiled header changes, applied in the order remove, rewrite, set, append.
+#[derive(Debug, Clone, Default)]
+pub struct HeaderRules {
+    /// Lowercased names, or prefixes for names given with a trailing `*`
+    remove: Vec<(String, bool)>,
+    rewrite: Vec<(HeaderName, Regex, String)>,
+    set: Vec<(HeaderName, HeaderValue)>,
+    append: Vec<(HeaderName, HeaderValue)>,
+}
+
+impl HeaderRules {
+    pub fn new(config: &HeaderRulesConfig) -> Result<Self, String> {
+        let remove = config
+            .remove
+            .iter()
+            .map(|name| {
+                let name = name.trim().to_ascii_lowercase();
+                match name.strip_suffix('*') {
+                    Some(prefix) => Ok((prefix.to_string(), true)),
+                    None => header_name(&name).map(|_| (name, false)),
+                }
+            })
+            .collect::<Result<_, String>>()?;
+        let rewrite = config
+            .rewrite
+            .iter()
+            .map(|rule| {
+                let pattern = Regex::new(&rule.pattern).map_err(|e| format!("invalid pattern for {}: {}", rule.name, e))?;
+                Ok((header_name(&rule.name)?, pattern, rule.replacement.clone()))
+            })
+            .collect::<Result<_, String>>()?;
+        let pairs = |headers: &BTreeMap<String, String>| {
+            headers
+                .iter()
+                .map(|(name, value)| {
+                    let value = HeaderValue::from_str(value).map_err(|_| format!("invalid value for header {}: {}", name, value))?;
+                    Ok((header_name(name)?, value))
+                })
+                .collect::<Result<Vec<_>, String>>()
+        };
+        Ok(HeaderRules { remove, rewrite, set: pairs(&config.set)?, append: pairs(&config.append)? })
+    }
+
+    pub fn apply(&self, headers: &mut HeaderMap) {
+        if !self.remove.is_empty() {
+            let doomed: Vec<HeaderName> = headers.keys().filter(|name| self.removes(name.as_str())).cloned().collect();
+            for name in doomed {
+                headers.remove(name);
+            }
+        }
+        for (name, pattern, replacement) in &self.rewrite {
+            let values: Vec<HeaderValue> = headers
+                .get_all(name)
+                .iter()
+                .map(|value| match value.to_str() {
+                    Ok(text) => HeaderValue::from_str(&pattern.replace_all(text, replacement.as_str())).unwrap_or_else(|_| value.clone()),
+                    Err(_) => value.clone(),
+                })
+                .collect();
+            if let Some((first, rest)) = values.split_first() {
+                headers.insert(name.clone(), first.clone());
+                for value in rest {
+                    headers.append(name.clone(), value.clone());
+                }
+            }
+        }
+        for (name, value) in &self.set {
+            headers.insert(name.clone(), value.clone());
+        }
+        for (name, value) in &self.append {
+            headers.append(name.clone(), value.clone());
+        }
+    }
+
+    fn removes(&self, name: &str) -> bool {
+        self.remove.iter().any(|(pattern, prefix)| if *prefix { name.starts_with(pattern.as_str()) } else { name == pattern })
+    }
+}
+
+fn header_name(name: &str) -> Result<HeaderName, String> {
+    HeaderName::from_bytes(name.trim().as_bytes()).map_err(|_| format!("invalid header name: {}", name))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    /// The headers of a response from `http://10.0.0.5:8080` to a request for `https://example.com`.
+    fn rewrite(headers: &[(HeaderName, &'static str)]) -> HeaderMap {
+        let rewrite = ResponseRewrite::new(&ResponseRewriteConfig { location: true, refresh: true, ..ResponseRewriteConfig::default() });
+        let mut headers: HeaderMap = headers.iter().map(|(name, value)| (name.clone(), HeaderValue::from_static(value))).collect();
+        rewrite.apply(&mut headers, "http://10.0.0.5:8080", Some("https://example.com"));
+        headers
+    }
+
+    #[test]
+    fn rewrites_location_urls_whole() {
+        let headers = rewrite(&[(LOCATION, "http://10.0.0.5:8080/login?next=/cart"), (CONTENT_LOCATION, "http://10.0.0.5:8080/items/1")]);
+        assert_eq!(headers[LOCATION], "https://example.com/login?next=/cart");
+        assert_eq!(headers[CONTENT_LOCATION], "https://example.com/items/1");
+        // A query holding `url=` is part of the URL, not where it starts
+        let headers = rewrite(&[(LOCATION, "http://10.0.0.5:8080/login?url=http://10.0.0.5:8080/cart")]);
+        assert_eq!(headers[LOCATION], "https://example.com/login?url=http://10.0.0.5:8080/cart");
+        let headers = rewrite(&[(LOCATION, "https://sso.example.net/auth?return_url=http://10.0.0.5:8080/cart")]);
+        assert_eq!(headers[LOCATION], "https://sso.example.net/auth?return_url=http://10.0.0.5:8080/cart");
+        // Other origins and relative URLs are left alone
+        for location in ["http://10.0.0.5:9090/", "http://10.0.0.6:8080/", "/relative"] {
+            assert_eq!(rewrite(&[(LOCATION, location)])[LOCATION], location);
+        }
+    }
+
+    #[test]
+    fn rewrites_the_url_of_a_refresh() {
+        assert_eq!(rewrite(&[(REFRESH, "5; url=http://10.0.0.5:8080/next")])[REFRESH], "5; url=https://example.com/next");
+        assert_eq!(rewrite(&[(REFRESH, "0;URL=http://10.0.0.5:8080/")])[REFRESH], "0;URL=https://example.com/");
+        assert_eq!(rewrite(&[(REFRESH, "0; Url=http://10.0.0.5:8080/")])[REFRESH], "0; Url=https://example.com/");
+        assert_eq!(rewrite(&[(REFRESH, "5")])[REFRESH], "5");
+    }
+
+    #[test]
+    fn leaves_headers_alone_unless_asked() {
+        let mut headers: HeaderMap = vec![(LOCATION, HeaderValue::from_static("http://10.0.0.5:8080/next")), (REFRESH, HeaderValue::from_static("0; url=http://10.0.0.5:8080/"))].into_iter().collect();
+        ResponseRewrite::new(&ResponseRewriteConfig::default()).apply(&mut headers, "http://10.0.0.5:8080", Some("https://example.com"));
+        assert_eq!(headers[LOCATION], "http://10.0.0.5:8080/next");
+        assert_eq!(headers[REFRESH], "0; url=http://10.0.0.5:8080/");
+        // Without the host the client used there is nothing to point at
+        let rewrite = ResponseRewrite::new(&ResponseRewriteConfig { location: true, ..ResponseRewriteConfig::default() });
+        rewrite.apply(&mut headers, "http://10.0.0.5:8080", None);
+        assert_eq!(headers[LOCATION], "http://10.0.0.5:8080/next");
+    }
+
+    #[test]
+    fn rewrites_cookie_domains_and_paths() {
+        let map = |pairs: &[(&str, &str)]| pairs.iter().map(|(from, to)| (from.to_string(), to.to_string())).collect();
+        let rewrite = ResponseRewrite::new(&ResponseRewriteConfig {
+            cookie_domains: map(&[(".Backend.internal", "example.com"), ("legacy.internal", "")]),
+            cookie_paths: map(&[("/", "/app/"), ("/api/", "/app/api/v2/")]),
+            ..ResponseRewriteConfig::default()
+        });
+        let mut headers = HeaderMap::new();
+        for cookie in ["session=abc; Domain=backend.internal; Path=/; HttpOnly", "theme=dark; domain=.legacy.internal; path=/api/users", "other=1; Domain=elsewhere.com; Secure"] {
+            headers.append(SET_COOKIE, HeaderValue::from_static(cookie));
+        }
+        rewrite.apply(&mut headers, "http://10.0.0.5:8080", Some("https://example.com"));
+        let cookies: Vec<_> = headers.get_all(SET_COOKIE).iter().map(|v| v.to_str().unwrap()).collect();
+        assert_eq!(
+            cookies,
+            [
+                "session=abc; Domain=example.com; Path=/app/; HttpOnly",
+                // The longest matching prefix wins, and an empty domain drops the attribute
+                "theme=dark; path=/app/api/v2/users",
+                "other=1; Domain=elsewhere.com; Secure",
+            ]
+        );
+    }
+}
@@ -0,0 +1,103 @@
+use hyper::service::Service;
+use hyper::Uri;
+use std::sync::{Arc, Weak};
+use std::time::Duration;
+use tracing::{debug, info, warn};
+
+use crate::balancer::{Balancer, Upstream};
+use crate::grpc;
+use crate::tls::UpstreamConnector;
+
+/// Settings for the active upstream health checker.
+#[derive(Debug, Clone)]
+pub struct HealthCheckConfig {
+    pub path: String,
+    pub interval: Duration,
+    pub timeout: Duration,
+    /// Consecutive successful probes needed to put an upstream back into rotation
+    pub healthy_threshold: u32,
+    /// Consecutive failed probes needed to take an upstream out of rotation
+    pub unhealthy_threshold: u32,
+    /// Probe with the gRPC health checking protocol, asking about this service
+    pub grpc: Option<String>,
+    /// Probe by opening a TCP connection instead of requesting `path`
+    pub tcp: bool,
+}
+
+/// Spawns one background probe task per upstream in the balancer. Each task
+/// stops once its upstream has been dropped, e.g. after a config reload.
+pub fn spawn(balancer: &Arc<Balancer>, config: HealthCheckConfig, connector: UpstreamConnector) {
+    for upstream in balancer.upstreams() {
+        spawn_one(&upstream, config.clone(), connector.clone());
+    }
+}
+
+/// Spawns the probe task for a single upstream, e.g. one added at runtime.
+pub fn spawn_one(upstream: &Arc<Upstream>, config: HealthCheckConfig, connector: UpstreamConnector) {
+    let upstream = Arc::downgrade(upstream);
+    tokio::spawn(async move { check_loop(upstream, config, connector).await });
+}
+
+async fn check_loop(upstream: Weak<Upstream>, config: HealthCheckConfig, connector: UpstreamConnector) {
+    // Probe the resolved address the upstream is pinned to, or its socket, in the protocol it speaks
+    let connector = match upstream.upgrade() {
+        Some(upstream) => connector.for_upstream(&upstream),
+        None => return,
+    };
+    let client = connector.client(None);
+    let mut interval = tokio::time::interval(config.interval);
+    let mut successes = 0;
+    let mut failures = 0;
+
+    loop {
+        interval.tick().await;
+
+        let upstream = match upstream.upgrade() {
+            Some(upstream) => upstream,
+            None => return,
+        };
+
+        let ok = if let (Some(service), false) = (&config.grpc, config.tcp) {
+            grpc::check_health(&client, &upstream.request_url(), service, config.timeout).await
+        } else if config.tcp {
+            let uri: Uri = match upstream.request_url().parse() {
+                Ok(uri) => uri,
+                Err(e) => {
+                    warn!("Invalid upstream address {}: {}", upstream.url, e);
+                    return;
+                }
+            };
+            let mut tcp = connector.tcp(None);
+            matches!(tokio::time::timeout(config.timeout, tcp.call(uri)).await, Ok(Ok(_)))
+        } else {
+            let uri: Uri = match format!("{}{}", upstream.request_url(), config.path).parse() {
+                Ok(uri) => uri,
+                Err(e) => {
+                    warn!("Invalid health check URI for {}: {}", upstream.url, e);
+                    return;
+                }
+            };
+            match tokio::time::timeout(config.timeout, client.get(uri)).await {
+                Ok(Ok(res)) => res.status().is_success() || res.status().is_redirection(),
+                _ => false,
+            }
+        };
+
+        debug!("Health check of {} {}", upstream.label(), if ok { "passed" } else { "failed" });
+        if ok {
+            successes += 1;
+            failures = 0;
+            if !upstream.is_healthy() && successes >= config.healthy_threshold {
+                info!("Upstream {} is healthy again", upstream.label());
+                upstream.set_healthy(true);
+            }
+        } else {
+            failures += 1;
+            successes = 0;
+            if upstream.is_healthy() && failures >= config.unhealthy_threshold {
+                warn!("Upstream {} failed {} health checks, removing from rotation", upstream.label(), failures);
+                upstream.set_healthy(false);
+            }
+        }
+    }
+}
@@ -0,0 +1,132 @@
+use h3::server::RequestStream;
+use hyper::body::{Buf, Bytes, HttpBody};
+use hyper::{Body, Request, Response};
+use std::net::SocketAddr;
+use std::sync::Arc;
+use tracing::{error, info, warn};
+
+use crate::config::DEFAULT_LISTENER;
+use crate::proxy::{self, ClientInfo, Runtime};
+
+/// Serves HTTP/3 over QUIC on a bound endpoint, passing requests through the
+/// same middleware, routes and pools as the TCP listener.
+pub async fn serve(endpoint: quinn::Endpoint, runtime: Arc<Runtime>) {
+    if let Ok(addr) = endpoint.local_addr() {
+        info!("Listening on udp://{} (HTTP/3)", addr);
+    }
+
+    while let Some(connecting) = endpoint.accept().await {
+        let runtime = Arc::clone(&runtime);
+        tokio::spawn(async move {
+            let connection = match connecting.await {
+                Ok(connection) => connection,
+                Err(e) => {
+                    runtime.metrics.record_tls_handshake_failure();
+                    warn!("Failed to accept QUIC connection: {}", e);
+                    return;
+                }
+            };
+            let peer_addr = connection.remote_address();
+            runtime.metrics.connection_opened();
+            // Counted alongside the client's TCP connections
+            let max = runtime.state().max_connections_per_ip;
+            match runtime.connections.open(peer_addr.ip(), max) {
+                Some(_connection) => serve_connection(connection, peer_addr, runtime.clone()).await,
+                None => {
+                    warn!("Rejected connection from {}: too many open connections from this address", peer_addr);
+                    runtime.metrics.record_connection_rejected();
+                }
+            }
+            runtime.metrics.connection_closed();
+        });
+    }
+}
+
+/// Accepts the requests of one QUIC connection until the client closes it.
+async fn serve_connection(connection: quinn::Connection, peer_addr: SocketAddr, runtime: Arc<Runtime>) {
+    let mut connection = match h3::server::Connection::<_, Bytes>::new(h3_quinn::Connection::new(connection)).await {
+        Ok(connection) => connection,
+        Err(e) => {
+            warn!("Failed to start HTTP/3 connection from {}: {}", peer_addr, e);
+            return;
+        }
+    };
+    loop {
+        match connection.accept().await {
+            Ok(Some((req, stream))) => {
+                let runtime = Arc::clone(&runtime);
+                tokio::spawn(async move {
+                    if let Err(e) = serve_request(req, stream, peer_addr, runtime).await {
+                        warn!("HTTP/3 stream error: {}", e);
+                    }
+                });
+            }
+            Ok(None) => return,
+            Err(e) => {
+                // Clients closing the connection end up here as well
+                if !matches!(e.get_error_level(), h3::error::ErrorLevel::StreamError) {
+                    return;
+                }
+                warn!("HTTP/3 error from {}: {}", peer_addr, e);
+            }
+        }
+    }
+}
+
+/// Proxies one request, streaming its body in and the response back out.
+async fn serve_request<S>(req: Request<()>, stream: RequestStream<S, Bytes>, peer_addr: SocketAddr, runtime: Arc<Runtime>) -> Result<(), h3::Error>
+where
+    S: h3::quic::BidiStream<Bytes> + Send + 'static,
+    S::RecvStream: Send + 'static,
+{
+    let (mut send, mut recv) = stream.split();
+
+    let (mut sender, body) = Body::channel();
+    tokio::spawn(async move {
+        loop {
+            match recv.recv_data().await {
+                Ok(Some(mut chunk)) => {
+                    let chunk = chunk.copy_to_bytes(chunk.remaining());
+                    if sender.send_data(chunk).await.is_err() {
+                        return;
+                    }
+                }
+                Ok(None) => break,
+                Err(_) => return sender.abort(),
+            }
+        }
+        match recv.recv_trailers().await {
+            Ok(Some(trailers)) => {
+                let _ = sender.send_trailers(trailers).await;
+            }
+            Ok(None) => {}
+            Err(_) => sender.abort(),
+        }
+    });
+
+    let client = ClientInfo { addr: peer_addr, tls: true, cert_subject: None, listener: Arc::from(DEFAULT_LISTENER) };
+    let res = match proxy::proxy(req.map(|()| body), client, runtime).await {
+        Ok(res) => res,
+        Err(e) => {
+            error!("Server error: {}", e);
+            Response::builder().status(500).body(Body::from("Internal Server Error")).unwrap()
+        }
+    };
+
+    let (parts, mut body) = res.into_parts();
+    send.send_response(Response::from_parts(parts, ())).await?;
+    while let Some(chunk) = body.data().await {
+        match chunk {
+            Ok(chunk) => send.send_data(chunk).await?,
+            // The upstream broke off; resetting the stream tells the client the response is incomplete
+            Err(_) => {
+                send.stop_stream(h3::error::Code::H3_INTERNAL_ERROR);
+                return Ok(());
+            }
+        }
+    }
+    if let Ok(Some(trailers)) = body.trailers().await {
+        send.send_trailers(trailers).await?;
+    }
+    send.finish().await
+}
@@ -0,0 +1,487 @@
+use async_trait::async_trait;
+use base64::engine::general_purpose::URL_SAFE_NO_PAD;
+use base64::Engine;
+use hyper::client::Client;
+use hyper::header::{HeaderName, HeaderValue, AUTHORIZATION, WWW_AUTHENTICATE};
+use hyper::{Body, Request, Response, StatusCode, Uri};
+use ring::signature::{self, RsaPublicKeyComponents, UnparsedPublicKey};
+use serde_json::Value;
+use std::sync::RwLock;
+use std::time::{Duration, Instant, SystemTime, UNIX_EPOCH};
+use tracing::warn;
+
+use crate::config::{JwtConfig, UpstreamsConfig};
+use crate::middleware::{Context, Middleware};
+use crate::tls::{self, ClientConnector};
+
+/// Shortest time between fetches of the key set triggered by unknown key IDs,
+/// so tokens with made-up IDs cannot flood the JWKS endpoint.
+const MIN_REFETCH: Duration = Duration::from_secs(10);
+
+/// A public key from the key set.
+#[derive(Debug)]
+struct Jwk {
+    kid: Option<String>,
+    key: PublicKey,
+}
+
+#[derive(Debug)]
+enum PublicKey {
+    Rsa { n: Vec<u8>, e: Vec<u8> },
+    /// Uncompressed point of a P-256 or P-384 key
+    Ec { curve: String, point: Vec<u8> },
+    Ed25519(Vec<u8>),
+}
+
+impl PublicKey {
+    /// Checks `signature` over `message` with the JWS algorithm `alg`.
+    fn verify(&self, alg: &str, message: &[u8], sig: &[u8]) -> bool {
+        let rsa = |params| match self {
+            PublicKey::Rsa { n, e } => RsaPublicKeyComponents { n, e }.verify(params, message, sig).is_ok(),
+            _ => false,
+        };
+        let ec = |curve: &str, algorithm| match self {
+            PublicKey::Ec { curve: key_curve, point } if key_curve == curve => UnparsedPublicKey::new(algorithm, point).verify(message, sig).is_ok(),
+            _ => false,
+        };
+        match alg {
+            "RS256" => rsa(&signature::RSA_PKCS1_2048_8192_SHA256),
+            "RS384" => rsa(&signature::RSA_PKCS1_2048_8192_SHA384),
+            "RS512" => rsa(&signature::RSA_PKCS1_2048_8192_SHA512),
+            "PS256" => rsa(&signature::RSA_PSS_2048_8192_SHA256),
+            "PS384" => rsa(&signature::RSA_PSS_2048_8192_SHA384),
+            "PS512" => rsa(&signature::RSA_PSS_2048_8192_SHA512),
+            "ES256" => ec("P-256", &signature::ECDSA_P256_SHA256_FIXED),
+            "ES384" => ec("P-384", &signature::ECDSA_P384_SHA384_FIXED),
+            "EdDSA" => match self {
+                PublicKey::Ed25519(key) => UnparsedPublicKey::new(&signature::ED25519, key).verify(message, sig).is_ok(),
+                _ => false,
+            },
+            // `none` and the shared-secret HS* algorithms are never accepted
+            _ => false,
+        }
+    }
+}
+
+#[derive(Debug, Default)]
+struct KeySet {
+    keys: Vec<Jwk>,
+    fetched: Option<Instant>,
+}
+
+/// Why a request was turned away.
+enum Rejection {
+    /// No valid token: 401
+    Unauthenticated(&'static str),
+    /// A valid token that is not meant for this service: 403
+    Forbidden,
+}
+
+/// Middleware admitting only requests with a valid `Authorization: Bearer`
+/// JWT, signed by a key from the configured JWKS endpoint. Selected claims
+/// are passed to upstreams as headers.
+pub struct JwtAuth {
+    config: JwtConfig,
+    jwks_url: Uri,
+    claim_headers: Vec<(String, HeaderName)>,
+    client: Client<ClientConnector>,
+    keys: RwLock<KeySet>,
+    /// Serializes key set fetches
+    fetching: tokio::sync::Mutex<()>,
+}
+
+impl JwtAuth {
+    pub fn new(config: &JwtConfig) -> Result<JwtAuth, String> {
+        let jwks_url = config.jwks_url.parse().map_err(|e| format!("invalid jwt.jwks_url {}: {}", config.jwks_url, e))?;
+        let claim_headers = config
+            .forward_claims
+            .iter()
+            .map(|(claim, header)| Ok((claim.clone(), HeaderName::from_bytes(header.as_bytes()).map_err(|e| format!("invalid header for claim {}: {}", claim, e))?)))
+            .collect::<Result<_, String>>()?;
+        let client = tls::upstream_connector(&UpstreamsConfig::default(), Some(Duration::from_secs(10)))?.client(None);
+        Ok(JwtAuth { config: config.clone(), jwks_url, claim_headers, client, keys: RwLock::new(KeySet::default()), fetching: tokio::sync::Mutex::new(()) })
+    }
+
+    /// Whether this filter was created from `config`, so its cached keys can be kept across a reload.
+    pub fn uses(&self, config: &JwtConfig) -> bool {
+        self.config == *config
+    }
+
+    /// Verifies the token and returns its claims.
+    async fn validate(&self, token: &str) -> Result<Value, Rejection> {
+        let invalid = Rejection::Unauthenticated("invalid token");
+        let mut parts = token.split('.');
+        let (header, payload, sig) = match (parts.next(), parts.next(), parts.next(), parts.next()) {
+            (Some(header), Some(payload), Some(sig), None) => (header, payload, sig),
+            _ => return Err(invalid),
+        };
+        let decode_json = |part: &str| URL_SAFE_NO_PAD.decode(part).ok().and_then(|bytes| serde_json::from_slice::<Value>(&bytes).ok());
+        let (header_json, claims) = match (decode_json(header), decode_json(payload)) {
+            (Some(header), Some(claims)) if claims.is_object() => (header, claims),
+            _ => return Err(invalid),
+        };
+        let sig = URL_SAFE_NO_PAD.decode(sig).map_err(|_| Rejection::Unauthenticated("invalid token"))?;
+        let alg = header_json["alg"].as_str().unwrap_or_default();
+        let kid = header_json["kid"].as_str();
+        let message = &token.as_bytes()[..header.len() + 1 + payload.len()];
+
+        if !self.check_signature(alg, kid, message, &sig).await {
+            return Err(Rejection::Unauthenticated("invalid signature"));
+        }
+
+        let now = SystemTime::now().duration_since(UNIX_EPOCH).unwrap_or_default().as_secs_f64();
+        let leeway = self.config.leeway as f64;
+        // A token without an expiry would be good forever
+        let exp = claims["exp"].as_f64().ok_or(Rejection::Unauthenticated("missing exp"))?;
+        if now > exp + leeway {
+            return Err(Rejection::Unauthenticated("token expired"));
+        }
+        if claims["nbf"].as_f64().is_some_and(|nbf| now + leeway < nbf) {
+            return Err(Rejection::Unauthenticated("token not yet valid"));
+        }
+        if let Some(issuer) = &self.config.issuer {
+            if claims["iss"].as_str() != Some(issuer.as_str()) {
+                return Err(Rejection::Forbidden);
+            }
+        }
+        if let Some(audience) = &self.config.audience {
+            let matches = match &claims["aud"] {
+                Value::String(aud) => aud == audience,
+                Value::Array(auds) => auds.iter().any(|aud| aud.as_str() == Some(audience.as_str())),
+                _ => false,
+            };
+            if !matches {
+                return Err(Rejection::Forbidden);
+            }
+        }
+        Ok(claims)
+    }
+
+    /// Checks the signature against the cached keys, fetching the key set
+    /// first when it is stale or does not know the token's key.
+    async fn check_signature(&self, alg: &str, kid: Option<&str>, message: &[u8], sig: &[u8]) -> bool {
+        if let Some(verified) = self.verify_cached(alg, kid, message, sig) {
+            return verified;
+        }
+        let _fetching = self.fetching.lock().await;
+        // Another request may have fetched the keys while this one waited
+        if let Some(verified) = self.verify_cached(alg, kid, message, sig) {
+            return verified;
+        }
+        match self.fetch_keys().await {
+            Ok(keys) => *self.keys.write().unwrap() = KeySet { keys, fetched: Some(Instant::now()) },
+            Err(e) => {
+                warn!("Failed to fetch JWKS from {}: {}", self.jwks_url, e);
+                // The old keys stay in use; counting this as a fetch keeps failing requests from retrying at once
+                self.keys.write().unwrap().fetched = Some(Instant::now());
+            }
+        }
+        self.verify_cached(alg, kid, message, sig).unwrap_or(false)
+    }
+
+    /// Verifies with the cached keys, or returns `None` when the key set
+    /// should be fetched first: it is older than the cache lifetime, or does
+    /// not hold the token's key and was not fetched within `MIN_REFETCH`.
+    fn verify_cached(&self, alg: &str, kid: Option<&str>, message: &[u8], sig: &[u8]) -> Option<bool> {
+        let keys = self.keys.read().unwrap();
+        let age = keys.fetched?.elapsed();
+        let candidates: Vec<&Jwk> = keys.keys.iter().filter(|jwk| kid.is_none() || jwk.kid.as_deref() == kid).collect();
+        if age >= Duration::from_secs(self.config.jwks_cache_ttl) || (candidates.is_empty() && age >= MIN_REFETCH) {
+            return None;
+        }
+        Some(candidates.iter().any(|jwk| jwk.key.verify(alg, message, sig)))
+    }
+
+    async fn fetch_keys(&self) -> Result<Vec<Jwk>, String> {
+        let req = Request::get(self.jwks_url.clone()).body(Body::empty()).map_err(|e| e.to_string())?;
+        let exchange = async {
+            let res = self.client.request(req).await.map_err(|e| e.to_string())?;
+            if !res.status().is_success() {
+                return Err(format!("JWKS endpoint returned {}", res.status()));
+            }
+            hyper::body::to_bytes(res.into_body()).await.map_err(|e| e.to_string())
+        };
+        let bytes = tokio::time::timeout(Duration::from_secs(10), exchange).await.map_err(|_| "request timed out".to_string())??;
+        let set: Value = serde_json::from_slice(&bytes).map_err(|e| format!("invalid key set: {}", e))?;
+        Ok(set["keys"].as_array().into_iter().flatten().filter_map(parse_jwk).collect())
+    }
+}
+
+/// Reads a signing key; keys of other types or uses are skipped.
+fn parse_jwk(jwk: &Value) -> Option<Jwk> {
+    if jwk["use"].as_str().is_some_and(|usage| usage != "sig") {
+        return None;
+    }
+    let field = |name: &str| jwk[name].as_str().and_then(|value| URL_SAFE_NO_PAD.decode(value).ok());
+    let key = match jwk["kty"].as_str()? {
+        "RSA" => PublicKey::Rsa { n: field("n")?, e: field("e")? },
+        "EC" => {
+            let mut point = vec![0x04];
+            point.extend(field("x")?);
+            point.extend(field("y")?);
+            PublicKey::Ec { curve: jwk["crv"].as_str()?.to_string(), point }
+        }
+        "OKP" if jwk["crv"].as_str() == Some("Ed25519") => PublicKey::Ed25519(field("x")?),
+        _ => return None,
+    };
+    Some(Jwk { kid: jwk["kid"].as_str().map(str::to_string), key })
+}
+
+/// A claim as a header value: strings as they are, anything else as JSON.
+fn claim_value(claim: &Value) -> Option<HeaderValue> {
+    match claim {
+        Value::Null => None,
+        Value::String(s) => HeaderValue::from_str(s).ok(),
+        other => HeaderValue::from_str(&other.to_string()).ok(),
+    }
+}
+
+fn reject(rejection: Rejection) -> Response<Body> {
+    match rejection {
+        Rejection::Unauthenticated(reason) => {
+            let challenge = match reason {
+                "missing token" => "Bearer".to_string(),
+                reason => format!("Bearer error=\"invalid_token\", error_description=\"{}\"", reason),
+            };
+            Response::builder().status(StatusCode::UNAUTHORIZED).header(WWW_AUTHENTICATE, challenge).body(Body::from("Unauthorized")).unwrap()
+        }
+        Rejection::Forbidden => Response::builder().status(StatusCode::FORBIDDEN).body(Body::from("Forbidden")).unwrap(),
+    }
+}
+
+#[async_trait]
+impl Middleware for JwtAuth {
+    async fn on_request(&self, req: &mut Request<Body>, _ctx: &mut Context) -> Option<Response<Body>> {
+        // Claim headers only ever come from a verified token
+        for (_, header) in &self.claim_headers {
+            req.headers_mut().remove(header);
+        }
+        let token = req
+            .headers()
+            .get(AUTHORIZATION)
+            .and_then(|v| v.to_str().ok())
+            .and_then(|v| v.split_once(' '))
+            .filter(|(scheme, _)| scheme.eq_ignore_ascii_case("bearer"))
+            .map(|(_, token)| token.trim().to_string());
+        let token = match token {
+            Some(token) => token,
+            None => return Some(reject(Rejection::Unauthenticated("missing token"))),
+        };
+        let claims = match self.validate(&token).await {
+            Ok(claims) => claims,
+            Err(rejection) => return Some(reject(rejection)),
+        };
+        for (claim, header) in &self.claim_headers {
+            if let Some(value) = claim_value(&claims[claim.as_str()]) {
+                req.headers_mut().insert(header.clone(), value);
+            }
+        }
+        None
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use hyper::service::{make_service_fn, service_fn};
+    use ring::rand::SystemRandom;
+    use ring::signature::{Ed25519KeyPair, KeyPair};
+    use serde_json::json;
+    use std::convert::Infallible;
+    use std::net::SocketAddr;
+    use std::sync::atomic::{AtomicUsize, Ordering};
+    use std::sync::Arc;
+
+    fn key_pair() -> Ed25519KeyPair {
+        let pkcs8 = Ed25519KeyPair::generate_pkcs8(&SystemRandom::new()).unwrap();
+        Ed25519KeyPair::from_pkcs8(pkcs8.as_ref()).unwrap()
+    }
+
+    fn jwk(kid: &str, key: &Ed25519KeyPair) -> Value {
+        json!({ "kty": "OKP", "crv": "Ed25519", "kid": kid, "x": URL_SAFE_NO_PAD.encode(key.public_key().as_ref()) })
+    }
+
+    fn sign(header: Value, claims: Value, key: &Ed25519KeyPair) -> String {
+        let message = format!("{}.{}", URL_SAFE_NO_PAD.encode(header.to_string()), URL_SAFE_NO_PAD.encode(claims.to_string()));
+        format!("{}.{}", message, URL_SAFE_NO_PAD.encode(key.sign(message.as_bytes())))
+    }
+
+    fn now() -> u64 {
+        SystemTime::now().duration_since(UNIX_EPOCH).unwrap().as_secs()
+    }
+
+    /// Claims good for an hour, with `extra` added.
+    fn claims(extra: Value) -> Value {
+        let mut claims = json!({ "sub": "alice", "exp": now() + 3600 });
+        claims.as_object_mut().unwrap().extend(extra.as_object().unwrap().clone());
+        claims
+    }
+
+    /// A filter already holding `keys`, as if just fetched.
+    fn auth(config: JwtConfig, keys: &[Value]) -> JwtAuth {
+        let auth = JwtAuth::new(&JwtConfig { enabled: true, jwks_url: "http://127.0.0.1:9/jwks".to_string(), ..config }).unwrap();
+        *auth.keys.write().unwrap() = KeySet { keys: keys.iter().filter_map(parse_jwk).collect(), fetched: Some(Instant::now()) };
+        auth
+    }
+
+    /// Sends a request with `token` and `headers`, returning the rejection,
+    /// if any, and the request as it would go upstream.
+    async fn check(auth: &JwtAuth, token: Option<&str>, headers: &[(&str, &str)]) -> (Option<Response<Body>>, Request<Body>) {
+        let mut req = Request::get("/");
+        if let Some(token) = token {
+            req = req.header(AUTHORIZATION, format!("Bearer {}", token));
+        }
+        for (name, value) in headers {
+            req = req.header(*name, *value);
+        }
+        let mut req = req.body(Body::empty()).unwrap();
+        let mut ctx = Context::new(([192, 0, 2, 1], 4000).into(), false);
+        let res = auth.on_request(&mut req, &mut ctx).await;
+        (res, req)
+    }
+
+    async fn status(auth: &JwtAuth, token: &str) -> Option<StatusCode> {
+        check(auth, Some(token), &[]).await.0.map(|res| res.status())
+    }
+
+    #[tokio::test]
+    async fn accepts_tokens_signed_by_a_known_key() {
+        let key = key_pair();
+        let auth = auth(JwtConfig::default(), &[jwk("k1", &key)]);
+        assert_eq!(status(&auth, &sign(json!({ "alg": "EdDSA", "kid": "k1" }), claims(json!({})), &key)).await, None);
+        // Without a key ID every key is tried
+        assert_eq!(status(&auth, &sign(json!({ "alg": "EdDSA" }), claims(json!({})), &key)).await, None);
+    }
+
+    #[tokio::test]
+    async fn rejects_bad_signatures() {
+        let (key, other) = (key_pair(), key_pair());
+        let auth = auth(JwtConfig::default(), &[jwk("k1", &key)]);
+        let header = json!({ "alg": "EdDSA", "kid": "k1" });
+        let valid = sign(header.clone(), claims(json!({})), &key);
+        let (head, rest) = valid.split_once('.').unwrap();
+        let (_, sig) = rest.split_once('.').unwrap();
+        let tampered = format!("{}.{}.{}", head, URL_SAFE_NO_PAD.encode(claims(json!({ "sub": "mallory" })).to_string()), sig);
+        for token in [tampered, sign(header, claims(json!({})), &other), "not.a.token".to_string(), format!("{}.extra", valid), String::new()] {
+            assert_eq!(status(&auth, &token).await, Some(StatusCode::UNAUTHORIZED), "{}", token);
+        }
+        assert_eq!(check(&auth, None, &[]).await.0.unwrap().status(), StatusCode::UNAUTHORIZED);
+    }
+
+    #[tokio::test]
+    async fn rejects_unsafe_algorithms() {
+        let key = key_pair();
+        let auth = auth(JwtConfig::default(), &[jwk("k1", &key)]);
+        let unsigned = format!("{}.{}.", URL_SAFE_NO_PAD.encode(json!({ "alg": "none" }).to_string()), URL_SAFE_NO_PAD.encode(claims(json!({})).to_string()));
+        assert_eq!(status(&auth, &unsigned).await, Some(StatusCode::UNAUTHORIZED));
+        // A valid signature only counts for the algorithm of its key
+        for alg in ["HS256", "ES256", "RS256", "none"] {
+            let token = sign(json!({ "alg": alg, "kid": "k1" }), claims(json!({})), &key);
+            assert_eq!(status(&auth, &token).await, Some(StatusCode::UNAUTHORIZED), "{}", alg);
+        }
+    }
+
+    #[tokio::test]
+    async fn checks_expiry_and_not_before() {
+        let key = key_pair();
+        let auth = auth(JwtConfig { leeway: 60, ..JwtConfig::default() }, &[jwk("k1", &key)]);
+        let token = |claims: Value| sign(json!({ "alg": "EdDSA" }), claims, &key);
+        let now = now();
+        assert_eq!(status(&auth, &token(json!({ "exp": now - 120 }))).await, Some(StatusCode::UNAUTHORIZED));
+        assert_eq!(status(&auth, &token(json!({ "exp": now - 30 }))).await, None);
+        assert_eq!(status(&auth, &token(json!({ "exp": now + 3600, "nbf": now + 120 }))).await, Some(StatusCode::UNAUTHORIZED));
+        assert_eq!(status(&auth, &token(json!({ "exp": now + 3600, "nbf": now + 30 }))).await, None);
+        // A token without an expiry would be good forever
+        let res = check(&auth, Some(&token(json!({ "sub": "alice" }))), &[]).await.0.unwrap();
+        assert_eq!(res.status(), StatusCode::UNAUTHORIZED);
+        assert!(res.headers()[WWW_AUTHENTICATE].to_str().unwrap().contains("missing exp"));
+        assert_eq!(status(&auth, &token(json!({ "exp": "tomorrow" }))).await, Some(StatusCode::UNAUTHORIZED));
+    }
+
+    #[tokio::test]
+    async fn checks_issuer_and_audience() {
+        let key = key_pair();
+        let config = JwtConfig { issuer: Some("https://id.example.com".to_string()), audience: Some("shop".to_string()), ..JwtConfig::default() };
+        let auth = auth(config, &[jwk("k1", &key)]);
+        let token = |extra: Value| sign(json!({ "alg": "EdDSA" }), claims(extra), &key);
+        let iss = "https://id.example.com";
+        assert_eq!(status(&auth, &token(json!({ "iss": iss, "aud": "shop" }))).await, None);
+        assert_eq!(status(&auth, &token(json!({ "iss": iss, "aud": ["billing", "shop"] }))).await, None);
+        for extra in [json!({ "iss": iss, "aud": "billing" }), json!({ "iss": iss, "aud": ["billing"] }), json!({ "iss": iss }), json!({ "iss": "https://evil.example.com", "aud": "shop" }), json!({ "aud": "shop" })] {
+            assert_eq!(status(&auth, &token(extra.clone())).await, Some(StatusCode::FORBIDDEN), "{}", extra);
+        }
+    }
+
+    #[tokio::test]
+    async fn claim_headers_only_come_from_verified_tokens() {
+        let key = key_pair();
+        let forward_claims = [("sub", "x-user"), ("roles", "x-roles")].iter().map(|(claim, header)| (claim.to_string(), header.to_string())).collect();
+        let auth = auth(JwtConfig { forward_claims, ..JwtConfig::default() }, &[jwk("k1", &key)]);
+        let forged = [("x-user", "admin"), ("x-roles", "[\"admin\"]")];
+
+        let token = sign(json!({ "alg": "EdDSA" }), claims(json!({ "roles": ["viewer"] })), &key);
+        let (rejected, req) = check(&auth, Some(&token), &forged).await;
+        assert!(rejected.is_none());
+        assert_eq!(req.headers()["x-user"], "alice");
+        assert_eq!(req.headers()["x-roles"], "[\"viewer\"]");
+
+        // A claim the token lacks leaves no header, not the client's
+        let token = sign(json!({ "alg": "EdDSA" }), claims(json!({})), &key);
+        let (_, req) = check(&auth, Some(&token), &forged).await;
+        assert_eq!(req.headers()["x-user"], "alice");
+        assert!(!req.headers().contains_key("x-roles"));
+
+        let (rejected, req) = check(&auth, Some("invalid"), &forged).await;
+        assert_eq!(rejected.unwrap().status(), StatusCode::UNAUTHORIZED);
+        assert!(!req.headers().contains_key("x-user") && !req.headers().contains_key("x-roles"));
+    }
+
+    /// Starts a JWKS endpoint serving `keys`, and returns its address and the
+    /// number of requests it got.
+    async fn jwks_endpoint(keys: Value) -> (SocketAddr, Arc<AtomicUsize>) {
+        let requests = Arc::new(AtomicUsize::new(0));
+        let counted = Arc::clone(&requests);
+        let body = json!({ "keys": keys }).to_string();
+        let make_service = make_service_fn(move |_| {
+            let (counted, body) = (Arc::clone(&counted), body.clone());
+            async move {
+                Ok::<_, Infallible>(service_fn(move |_req: Request<Body>| {
+                    counted.fetch_add(1, Ordering::SeqCst);
+                    let body = body.clone();
+                    async move { Ok::<_, Infallible>(Response::new(Body::from(body))) }
+                }))
+            }
+        });
+        let server = hyper::Server::bind(&"127.0.0.1:0".parse().unwrap()).serve(make_service);
+        let addr = server.local_addr();
+        tokio::spawn(server);
+        (addr, requests)
+    }
+
+    #[tokio::test]
+    async fn unknown_key_ids_refetch_the_key_set_at_most_every_min_refetch() {
+        let key = key_pair();
+        let (addr, requests) = jwks_endpoint(json!([jwk("k1", &key)])).await;
+        let auth = JwtAuth::new(&JwtConfig { enabled: true, jwks_url: format!("http://{}/jwks", addr), ..JwtConfig::default() }).unwrap();
+
+        assert_eq!(status(&auth, &sign(json!({ "alg": "EdDSA", "kid": "k1" }), claims(json!({})), &key)).await, None);
+        assert_eq!(requests.load(Ordering::SeqCst), 1);
+        // Made-up key IDs do not reach the endpoint while the set is fresh
+        for i in 0..5 {
+            let token = sign(json!({ "alg": "EdDSA", "kid": format!("made-up-{}", i) }), claims(json!({})), &key);
+            assert_eq!(status(&auth, &token).await, Some(StatusCode::UNAUTHORIZED));
+        }
+        assert_eq!(requests.load(Ordering::SeqCst), 1);
+
+        {
+            let mut keys = auth.keys.write().unwrap();
+            keys.fetched = keys.fetched.and_then(|fetched| fetched.checked_sub(MIN_REFETCH));
+        }
+        let token = sign(json!({ "alg": "EdDSA", "kid": "rotated" }), claims(json!({})), &key);
+        assert_eq!(status(&auth, &token).await, Some(StatusCode::UNAUTHORIZED));
+        assert_eq!(requests.load(Ordering::SeqCst), 2);
+        // Known keys keep working meanwhile
+        assert_eq!(status(&auth, &sign(json!({ "alg": "EdDSA", "kid": "k1" }), claims(json!({})), &key)).await, None);
+        assert_eq!(requests.load(Ordering::SeqCst), 2);
+    }
+}
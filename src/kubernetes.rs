@@ -0,0 +1,212 @@
+use futures_util::StreamExt;
+use hyper::client::Client;
+use hyper::header::AUTHORIZATION;
+use hyper::{Body, Request};
+use serde_json::Value;
+use std::collections::HashMap;
+use std::convert::TryFrom;
+use std::env;
+use std::net::{IpAddr, SocketAddr};
+use std::path::Path;
+use std::sync::Arc;
+use std::time::Duration;
+use tracing::warn;
+
+use crate::balancer::{Balancer, Upstream};
+use crate::config::{KubernetesSettings, UpstreamTlsSettings, UpstreamsConfig};
+use crate::discovery::Membership;
+use crate::health::HealthCheckConfig;
+use crate::tls::{self, ClientConnector, UpstreamConnector};
+
+/// Credentials mounted into every pod.
+const SERVICE_ACCOUNT_DIR: &str = "/var/run/secrets/kubernetes.io/serviceaccount";
+/// The API server ends each watch after this many seconds, and the
+/// EndpointSlices are listed again.
+const WATCH_TIMEOUT_SECS: u64 = 300;
+/// Wait before retrying after a failed list or watch.
+const RETRY_DELAY: Duration = Duration::from_secs(5);
+
+/// Keeps a pool in line with the ready endpoints of a Service.
+struct Watcher {
+    client: Client<ClientConnector>,
+    /// EndpointSlices of the Service
+    url: String,
+    port: Option<String>,
+    scheme: String,
+    membership: Membership,
+}
+
+/// Starts watching the EndpointSlices of the configured Service. The watch
+/// stops once the pool has been dropped, e.g. after a config reload.
+pub fn spawn(balancer: &Arc<Balancer>, settings: &KubernetesSettings, health: Option<(HealthCheckConfig, UpstreamConnector)>) -> Result<(), String> {
+    let account = Path::new(SERVICE_ACCOUNT_DIR);
+    let namespace = match &settings.namespace {
+        Some(namespace) => namespace.clone(),
+        None => std::fs::read_to_string(account.join("namespace")).map(|ns| ns.trim().to_string()).unwrap_or_else(|_| "default".to_string()),
+    };
+
+    let mut pinned = None;
+    let api_server = match &settings.api_server {
+        Some(url) => url.trim_end_matches('/').to_string(),
+        None => {
+            let host = env::var("KUBERNETES_SERVICE_HOST").map_err(|_| "kubernetes.api_server is required outside a cluster".to_string())?;
+            let port = env::var("KUBERNETES_SERVICE_PORT").unwrap_or_else(|_| "443".to_string());
+            // The API server's certificate names kubernetes.default.svc rather than its service IP
+            match host.parse::<IpAddr>() {
+                Ok(address) => {
+                    pinned = Some(address);
+                    format!("https://kubernetes.default.svc:{}", port)
+                }
+                Err(_) => format!("https://{}:{}", host, port),
+            }
+        }
+    };
+
+    let ca = account.join("ca.crt");
+    let tls = UpstreamsConfig {
+        tls: UpstreamTlsSettings { ca_bundle: ca.exists().then(|| ca.to_string_lossy().into_owned()), ..UpstreamTlsSettings::default() },
+        ..UpstreamsConfig::default()
+    };
+    let mut connector = tls::upstream_connector(&tls, Some(Duration::from_secs(10)))?;
+    if let Some(address) = pinned {
+        connector = connector.pinned(address);
+    }
+
+    let watcher = Watcher {
+        client: connector.client(None),
+        url: format!("{}/apis/discovery.k8s.io/v1/namespaces/{}/endpointslices?labelSelector=kubernetes.io%2Fservice-name%3D{}", api_server, namespace, settings.service),
+        port: settings.port.clone(),
+        scheme: settings.scheme.clone(),
+        membership: Membership::new(balancer, health, format!("Kubernetes service {}/{}", namespace, settings.service)),
+    };
+    tokio::spawn(watcher.run());
+    Ok(())
+}
+
+impl Watcher {
+    async fn run(self) {
+        // Endpoints by EndpointSlice name
+        let mut slices: HashMap<String, Vec<String>> = HashMap::new();
+        while self.membership.is_active() {
+            let result = match self.list(&mut slices).await {
+                Ok(version) if self.sync(&slices) => self.watch(&mut slices, version).await,
+                Ok(_) => return,
+                Err(e) => Err(e),
+            };
+            // The last known endpoints stay in use until the API server answers again
+            if let Err(e) = result {
+                warn!("Kubernetes discovery from {} failed: {}", self.url, e);
+                tokio::time::sleep(RETRY_DELAY).await;
+            }
+        }
+    }
+
+    async fn get(&self, url: &str) -> Result<Body, String> {
+        let mut req = Request::get(url);
+        if let Ok(token) = std::fs::read_to_string(Path::new(SERVICE_ACCOUNT_DIR).join("token")) {
+            req = req.header(AUTHORIZATION, format!("Bearer {}", token.trim()));
+        }
+        let req = req.body(Body::empty()).map_err(|e| e.to_string())?;
+        let res = self.client.request(req).await.map_err(|e| e.to_string())?;
+        if !res.status().is_success() {
+            return Err(format!("API server returned {}", res.status()));
+        }
+        Ok(res.into_body())
+    }
+
+    /// Lists the Service's EndpointSlices, returning the resource version to watch from.
+    async fn list(&self, slices: &mut HashMap<String, Vec<String>>) -> Result<String, String> {
+        let body = self.get(&self.url).await?;
+        let bytes = tokio::time::timeout(Duration::from_secs(30), hyper::body::to_bytes(body))
+            .await
+            .map_err(|_| "listing timed out".to_string())?
+            .map_err(|e| e.to_string())?;
+        let list: Value = serde_json::from_slice(&bytes).map_err(|e| format!("invalid EndpointSlice list: {}", e))?;
+        slices.clear();
+        for slice in list["items"].as_array().into_iter().flatten() {
+            if let Some(name) = slice["metadata"]["name"].as_str() {
+                slices.insert(name.to_string(), self.endpoints(slice));
+            }
+        }
+        Ok(list["metadata"]["resourceVersion"].as_str().unwrap_or_default().to_string())
+    }
+
+    /// Applies watch events until the API server ends the watch.
+    async fn watch(&self, slices: &mut HashMap<String, Vec<String>>, version: String) -> Result<(), String> {
+        let url = format!("{}&watch=true&allowWatchBookmarks=true&timeoutSeconds={}&resourceVersion={}", self.url, WATCH_TIMEOUT_SECS, version);
+        let mut body = self.get(&url).await?;
+        let mut buffer = Vec::new();
+        let idle_limit = Duration::from_secs(WATCH_TIMEOUT_SECS + 30);
+        loop {
+            let chunk = match tokio::time::timeout(idle_limit, body.next()).await {
+                Ok(Some(chunk)) => chunk.map_err(|e| e.to_string())?,
+                Ok(None) => return Ok(()),
+                Err(_) => return Err("watch stalled".to_string()),
+            };
+            buffer.extend_from_slice(&chunk);
+
+            // Events arrive as one JSON object per line
+            let mut changed = false;
+            while let Some(end) = buffer.iter().position(|&b| b == b'\n') {
+                let line: Vec<u8> = buffer.drain(..=end).collect();
+                let event: Value = match serde_json::from_slice(&line) {
+                    Ok(event) => event,
+                    Err(_) => continue,
+                };
+                let slice = &event["object"];
+                let name = slice["metadata"]["name"].as_str().unwrap_or_default().to_string();
+                match event["type"].as_str() {
+                    Some("ADDED") | Some("MODIFIED") => {
+                        slices.insert(name, self.endpoints(slice));
+                        changed = true;
+                    }
+                    Some("DELETED") => {
+                        slices.remove(&name);
+                        changed = true;
+                    }
+                    // The resource version is too old; list again right away
+                    Some("ERROR") if slice["code"].as_u64() == Some(410) => return Ok(()),
+                    Some("ERROR") => return Err(format!("watch error: {}", slice["message"].as_str().unwrap_or("unknown"))),
+                    _ => {}
+                }
+            }
+            if changed && !self.sync(slices) {
+                return Ok(());
+            }
+        }
+    }
+
+    /// Upstream URLs of a slice's ready endpoints on the configured port.
+    fn endpoints(&self, slice: &Value) -> Vec<String> {
+        if !matches!(slice["addressType"].as_str(), Some("IPv4") | Some("IPv6")) {
+            return Vec::new();
+        }
+        let port = slice["ports"].as_array().into_iter().flatten().find(|port| match &self.port {
+            Some(wanted) => port["name"].as_str() == Some(wanted.as_str()) || port["port"].as_u64().map(|n| n.to_string()).as_ref() == Some(wanted),
+            None => true,
+        });
+        let port = match port.and_then(|port| port["port"].as_u64()).and_then(|port| u16::try_from(port).ok()) {
+            Some(port) => port,
+            None => return Vec::new(),
+        };
+        slice["endpoints"]
+            .as_array()
+            .into_iter()
+            .flatten()
+            // An endpoint without a ready condition counts as ready
+            .filter(|endpoint| endpoint["conditions"]["ready"].as_bool() != Some(false))
+            .flat_map(|endpoint| endpoint["addresses"].as_array().into_iter().flatten())
+            .filter_map(|address| address.as_str()?.parse::<IpAddr>().ok())
+            .map(|address| format!("{}://{}", self.scheme, SocketAddr::new(address, port)))
+            .collect()
+    }
+
+    /// Applies the endpoints of all slices to the pool; `false` once the pool is gone.
+    fn sync(&self, slices: &HashMap<String, Vec<String>>) -> bool {
+        // An endpoint can briefly appear in two slices
+        let mut urls: Vec<&String> = slices.values().flatten().collect();
+        urls.sort();
+        urls.dedup();
+        self.membership.sync(urls.into_iter().map(|url| Upstream::new(url.clone(), 1)).collect())
+    }
+}
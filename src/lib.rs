@@ -0,0 +1,62 @@
+//! Riffy, a lightweight reverse proxy. The binary in `main.rs` is a thin
+//! wrapper around [`ProxyBuilder`]; other programs can embed the proxy the same way.
+
+mod access_log;
+pub mod acl;
+mod admin;
+mod basic_auth;
+pub mod balancer;
+mod bandwidth;
+mod cache;
+mod check;
+mod circuit;
+mod compression;
+mod conditional;
+mod connlimit;
+mod consul;
+mod cors;
+pub mod config;
+mod discovery;
+mod disk_cache;
+mod dns;
+mod error_pages;
+mod forward_auth;
+mod geoip;
+mod grpc;
+mod handoff;
+mod headers;
+mod health;
+#[cfg(feature = "http3")]
+mod http3;
+mod jwt;
+mod kubernetes;
+mod listen;
+pub mod logging;
+mod metrics;
+pub mod middleware;
+#[cfg(unix)]
+mod privileges;
+mod probes;
+pub mod proxy;
+mod proxy_protocol;
+mod ratelimit;
+mod redirect;
+mod request_id;
+mod retry;
+mod route_access;
+mod router;
+mod shedding;
+mod slow_requests;
+mod sni;
+mod static_files;
+mod syslog;
+#[cfg(unix)]
+mod systemd;
+mod telemetry;
+pub mod tls;
+mod token_bucket;
+
+pub use check::check;
+pub use config::Config;
+pub use middleware::{Context, Middleware};
+pub use proxy::{ProxyBuilder, Riffy};
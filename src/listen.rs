@@ -0,0 +1,92 @@
+use socket2::{Domain, Protocol, Socket, Type};
+use std::io;
+use std::net::SocketAddr;
+#[cfg(unix)]
+use std::path::Path;
+#[cfg(unix)]
+use tokio::net::UnixListener;
+
+#[cfg(unix)]
+use crate::handoff;
+
+/// Connections the kernel queues for a listener before they are accepted.
+const BACKLOG: i32 = 1024;
+
+/// Binds a TCP listener on `addr`, or takes the one bound there over from
+/// the old process during an upgrade or from systemd. The unspecified IPv6
+/// address `[::]` accepts IPv4 clients as well, whatever the system's
+/// default for IPv6-only sockets.
+pub fn bind_tcp(addr: SocketAddr) -> io::Result<std::net::TcpListener> {
+    bind(addr, false)
+}
+
+/// Binds a TCP listener on `addr` with SO_REUSEPORT, so that several of them
+/// can share the port and the kernel balances new connections between them.
+#[cfg(unix)]
+pub fn bind_tcp_reuse_port(addr: SocketAddr) -> io::Result<std::net::TcpListener> {
+    bind(addr, true)
+}
+
+fn bind(addr: SocketAddr, reuse_port: bool) -> io::Result<std::net::TcpListener> {
+    #[cfg(unix)]
+    if let Some(fd) = handoff::take(&tcp_key(addr)) {
+        let listener = std::net::TcpListener::from(fd);
+        listener.set_nonblocking(true)?;
+        handoff::register(tcp_key(addr), listener.try_clone()?.into());
+        return Ok(listener);
+    }
+
+    let socket = Socket::new(Domain::for_address(addr), Type::STREAM, Some(Protocol::TCP))?;
+    if addr.is_ipv6() {
+        socket.set_only_v6(!addr.ip().is_unspecified())?;
+    }
+    socket.set_reuse_address(true)?;
+    #[cfg(unix)]
+    socket.set_reuse_port(reuse_port)?;
+    #[cfg(not(unix))]
+    let _ = reuse_port;
+    socket.set_nonblocking(true)?;
+    socket.bind(&addr.into())?;
+    socket.listen(BACKLOG)?;
+    let listener: std::net::TcpListener = socket.into();
+    #[cfg(unix)]
+    handoff::register(tcp_key(addr), listener.try_clone()?.into());
+    Ok(listener)
+}
+
+/// What a TCP listener is known by when it is handed to a new process.
+#[cfg(unix)]
+fn tcp_key(addr: SocketAddr) -> String {
+    format!("tcp:{}", addr)
+}
+
+/// Binds a Unix socket at `path`, replacing a socket left behind by an
+/// earlier run, and gives it the permission bits `mode`. Like TCP listeners,
+/// it is taken over from the old process during an upgrade or from systemd.
+#[cfg(unix)]
+pub fn bind_unix(path: &Path, mode: Option<u32>) -> Result<UnixListener, String> {
+    use std::os::unix::fs::{FileTypeExt, PermissionsExt};
+
+    let key = format!("unix:{}", path.display());
+    let listener = match handoff::take(&key) {
+        // The socket file belongs to the inherited socket, so it stays
+        Some(fd) => {
+            let listener = std::os::unix::net::UnixListener::from(fd);
+            handoff::register(key, listener.try_clone().map_err(|e| e.to_string())?.into());
+            listener
+        }
+        None => {
+            if std::fs::symlink_metadata(path).is_ok_and(|meta| meta.file_type().is_socket()) {
+                std::fs::remove_file(path).map_err(|e| format!("failed to remove stale socket {}: {}", path.display(), e))?;
+            }
+            let listener = std::os::unix::net::UnixListener::bind(path).map_err(|e| format!("failed to bind unix:{}: {}", path.display(), e))?;
+            handoff::register(key, listener.try_clone().map_err(|e| e.to_string())?.into());
+            listener
+        }
+    };
+    if let Some(mode) = mode {
+        std::fs::set_permissions(path, std::fs::Permissions::from_mode(mode)).map_err(|e| format!("failed to set permissions of {}: {}", path.display(), e))?;
+    }
+    listener.set_nonblocking(true).map_err(|e| e.to_string())?;
+    UnixListener::from_std(listener).map_err(|e| format!("failed to listen on unix:{}: {}", path.display(), e))
+}
@@ -0,0 +1,49 @@
+use std::io::IsTerminal;
+use std::sync::{Mutex, OnceLock};
+use tracing::Level;
+use tracing_subscriber::fmt::writer::MakeWriterExt;
+use tracing_subscriber::prelude::*;
+use tracing_subscriber::{reload, EnvFilter, Registry};
+
+/// The filter installed by `init`, and the directives it was made from.
+static FILTER: OnceLock<(reload::Handle<EnvFilter, Registry>, Mutex<String>)> = OnceLock::new();
+
+/// Parses `level` as `tracing_subscriber` filter directives, such as `info`
+/// or `warn,riffy::health=debug`.
+pub fn parse(level: &str) -> Result<EnvFilter, String> {
+    EnvFilter::builder().parse(level).map_err(|e| format!("invalid log level '{}': {}", level, e))
+}
+
+/// Installs Riffy's log output: warnings and errors go to stderr and
+/// everything else to stdout, filtered by `level`. Applications embedding
+/// Riffy can install a `tracing` subscriber of their own instead.
+pub fn init(level: &str) -> Result<(), String> {
+    let (filter, handle) = reload::Layer::new(parse(level)?);
+    let writer = std::io::stderr.with_max_level(Level::WARN).or_else(std::io::stdout);
+    let ansi = std::io::stdout().is_terminal() && std::io::stderr().is_terminal();
+    tracing_subscriber::registry()
+        .with(filter)
+        .with(tracing_subscriber::fmt::layer().with_writer(writer).with_ansi(ansi))
+        .try_init()
+        .map_err(|e| format!("failed to set up logging: {}", e))?;
+    let _ = FILTER.set((handle, Mutex::new(level.to_string())));
+    Ok(())
+}
+
+/// The directives currently filtering the log, unless `init` was not called.
+pub fn level() -> Option<String> {
+    FILTER.get().map(|(_, level)| level.lock().unwrap().clone())
+}
+
+/// Replaces the filter installed by `init`. Returns false when there is
+/// none to replace, as when Riffy is embedded.
+pub fn set_level(level: &str) -> Result<bool, String> {
+    let filter = parse(level)?;
+    let (handle, current) = match FILTER.get() {
+        Some(installed) => installed,
+        None => return Ok(false),
+    };
+    handle.reload(filter).map_err(|e| format!("failed to change the log level: {}", e))?;
+    *current.lock().unwrap() = level.to_string();
+    Ok(true)
+}
@@ -1,135 +1,122 @@
-use hyper::{service::{make_service_fn, service_fn}, Body, Client, Request, Response, Server, Uri};
-use tokio_rustls::rustls::{Certificate, PrivateKey, ServerConfig, NoClientAuth};
-use tokio_rustls::TlsAcceptor;
-use std::{convert::Infallible, net::SocketAddr, sync::Arc};
-use std::fs::File;
-use std::io::BufReader;
-use rustls_pemfile::{certs, pkcs8_private_keys};
+use clap::{Args, Parser, Subcommand};
 use dotenv::dotenv;
-use std::env;
-use std::sync::atomic::{AtomicUsize, Ordering};
-use tokio::net::TcpListener;
-use hyper::server::conn::Http;
-
-/// Proxies the incoming request to the upstream server.
-async fn handle_proxy(req: Request<Body>, upstream_servers: Arc<Vec<String>>, counter: Arc<AtomicUsize>) -> Result<Response<Body>, Box<dyn std::error::Error + Send + Sync>> {
-    let client = Client::new();
-
-    // Round-robin load balancing: Get the next upstream server from the list
-    let index = counter.fetch_add(1, Ordering::SeqCst) % upstream_servers.len();
-    let upstream_server = &upstream_servers[index];
-
-    // Construct the URI correctly
-    let uri_string = format!("{}{}", upstream_server, req.uri());
-    let uri: Uri = uri_string.parse()?;
+use riffy::config::{BindAddress, Overrides};
+use riffy::{Config, ProxyBuilder};
+
+/// A lightweight reverse proxy and load balancer.
+///
+/// Settings come from the config file, then environment variables, then the
+/// flags below, each overriding the one before.
+#[derive(Parser)]
+#[command(name = "riffy", version)]
+struct Cli {
+    /// TOML config file, or YAML for .yaml and .yml files, re-read on SIGHUP
+    #[arg(short, long, global = true, value_name = "PATH")]
+    config: Option<String>,
+
+    #[command(flatten)]
+    overrides: OverrideArgs,
+
+    #[command(subcommand)]
+    command: Option<Command>,
+}
 
-    let proxy_req = Request::builder()
-        .method(req.method())
-        .uri(uri)
-        .body(req.into_body()).map_err(|e| Box::new(e) as Box<dyn std::error::Error + Send + Sync>)?;
+#[derive(Subcommand)]
+enum Command {
+    /// Serve traffic; the default without a subcommand
+    Serve,
+    /// Load and validate the configuration, then exit
+    CheckConfig,
+    /// Check the configuration, its certificates and files, and that upstream
+    /// hostnames resolve, reporting every problem; binds no ports
+    Check,
+    /// Print the version and exit
+    Version,
+}
 
-    let res = client.request(proxy_req).await?;
+#[derive(Args)]
+struct OverrideArgs {
+    /// Address to listen on [overrides LISTEN_ADDR]
+    #[arg(long, global = true, value_name = "ADDR")]
+    address: Option<BindAddress>,
+    /// Port to listen on [overrides LISTEN_PORT]
+    #[arg(short, long, global = true)]
+    port: Option<u16>,
+    /// Port of the admin server [overrides ADMIN_PORT]
+    #[arg(long, global = true, value_name = "PORT")]
+    admin_port: Option<u16>,
+    /// Upstream server, replacing those of [upstreams]; repeat for several [overrides UPSTREAM_SERVERS]
+    #[arg(short, long = "upstream", global = true, value_name = "URL")]
+    upstreams: Vec<String>,
+    /// Worker threads serving connections [overrides WORKER_THREADS]
+    #[arg(long, global = true, value_name = "N")]
+    worker_threads: Option<usize>,
+}
 
-    Ok(res)
+impl From<OverrideArgs> for Overrides {
+    fn from(args: OverrideArgs) -> Self {
+        Overrides { listen_address: args.address, listen_port: args.port, admin_port: args.admin_port, upstreams: args.upstreams, worker_threads: args.worker_threads }
+    }
 }
 
-#[tokio::main]
-async fn main() -> Result<(), Box<dyn std::error::Error>> {
+fn main() -> Result<(), Box<dyn std::error::Error + Send + Sync>> {
+    let Cli { config: config_path, overrides, command } = Cli::parse();
+
     // Load environment variables from the .env file
     dotenv().ok();
 
-    // Get comma-separated list of upstream servers from environment
-    let upstream_servers_str = env::var("UPSTREAM_SERVERS").unwrap_or_else(|_| "http://localhost:8080".to_string());
-    let upstream_servers: Vec<String> = upstream_servers_str.split(',').map(|s| s.trim().to_string()).collect();
-
-    // Use an atomic counter for round-robin load balancing
-    let counter = Arc::new(AtomicUsize::new(0));
-
-    // Shared upstream server list
-    let upstream_servers = Arc::new(upstream_servers);
-
-    // Get the port from environment, default to 443 if SSL is enabled or 80 if not
-    let ssl_enabled = env::var("SSL_ENABLED").unwrap_or_else(|_| "false".to_string()) == "true";
-    let listen_port: u16 = if ssl_enabled {
-        env::var("LISTEN_PORT").unwrap_or_else(|_| "443".to_string()).parse().expect("Invalid port number")
-    } else {
-        env::var("LISTEN_PORT").unwrap_or_else(|_| "80".to_string()).parse().expect("Invalid port number")
-    };
-
-    let addr = SocketAddr::from(([0, 0, 0, 0], listen_port));
-
-    if ssl_enabled {
-        // SSL certificate and key
-        let ssl_cert_path = env::var("SSL_CERT_PATH").expect("SSL_CERT_PATH not set");
-        let ssl_key_path = env::var("SSL_KEY_PATH").expect("SSL_KEY_PATH not set");
-
-        // Load SSL certificate and key
-        let cert_file = &mut BufReader::new(File::open(ssl_cert_path).expect("Certificate not found"));
-        let key_file = &mut BufReader::new(File::open(ssl_key_path).expect("Private key not found"));
-
-        let certs = certs(cert_file).unwrap()
-            .into_iter().map(Certificate).collect::<Vec<_>>();
-        let mut keys = pkcs8_private_keys(key_file).unwrap();
-
-        // Create the server config with no client authentication
-        let mut config = ServerConfig::new(NoClientAuth::new());
-        config.set_single_cert(certs, PrivateKey(keys.remove(0)))
-            .expect("Invalid certificate or key");
-
-        // Create a TlsAcceptor to wrap the server
-        let tls_acceptor = TlsAcceptor::from(Arc::new(config));
-
-        // Create a TCP listener to listen for incoming TLS connections
-        let listener = TcpListener::bind(&addr).await.expect("Failed to bind");
-
-        println!("Listening on https://{}", addr);
-
-        loop {
-            let (stream, _) = listener.accept().await?;
-
-            let tls_acceptor = tls_acceptor.clone();
-            let upstream_servers = Arc::clone(&upstream_servers);
-            let counter = Arc::clone(&counter);
-
-            tokio::spawn(async move {
-                let stream = match tls_acceptor.accept(stream).await {
-                    Ok(stream) => stream,
-                    Err(e) => {
-                        eprintln!("Failed to accept TLS connection: {:?}", e);
-                        return;
-                    }
-                };
-
-                let service = service_fn(move |req| {
-                    handle_proxy(req, Arc::clone(&upstream_servers), Arc::clone(&counter))
-                });
-
-                let http = Http::new();
-                if let Err(e) = http.serve_connection(stream, service).await {
-                    eprintln!("Server error: {}", e);
-                }
-            });
+    if let Some(Command::Version) = command {
+        println!("riffy {}", env!("CARGO_PKG_VERSION"));
+        return Ok(());
+    }
+
+    let overrides = Overrides::from(overrides);
+    if let Some(Command::Check) = command {
+        let problems = tokio::runtime::Builder::new_current_thread().enable_all().build()?.block_on(riffy::check(config_path.as_deref(), &overrides));
+        if problems.is_empty() {
+            println!("Configuration OK");
+            return Ok(());
         }
-    } else {
-        // Non-SSL setup: Bind and listen for plain HTTP connections
-        let make_svc = make_service_fn(move |_conn| {
-            let upstream_servers = Arc::clone(&upstream_servers);
-            let counter = Arc::clone(&counter);
-            async {
-                Ok::<_, Infallible>(service_fn(move |req| {
-                    handle_proxy(req, Arc::clone(&upstream_servers), Arc::clone(&counter))
-                }))
-            }
-        });
+        for problem in &problems {
+            eprintln!("error: {}", problem);
+        }
+        eprintln!("{} problem(s) found", problems.len());
+        std::process::exit(1);
+    }
 
-        let server = Server::bind(&addr).serve(make_svc);
+    // Load the optional config file, then apply environment variables and flags
+    let config = Config::load_with(config_path.as_deref(), &overrides).unwrap_or_else(|e| {
+        eprintln!("Configuration error: {}", e);
+        std::process::exit(1);
+    });
 
-        println!("Listening on http://{}", addr);
+    if let Some(Command::CheckConfig) = command {
+        println!("Configuration OK");
+        return Ok(());
+    }
 
-        if let Err(e) = server.await {
-            eprintln!("server error: {}", e);
-        }
+    if let Err(e) = riffy::logging::init(&config.logging.level) {
+        eprintln!("{}", e);
+        std::process::exit(1);
     }
 
-    Ok(())
+    // The runtime is built by hand, once the config has said how many threads to give it
+    let mut runtime = tokio::runtime::Builder::new_multi_thread();
+    runtime.enable_all();
+    if let Some(threads) = config.runtime.worker_threads {
+        runtime.worker_threads(threads);
+    }
+
+    runtime.build()?.block_on(async {
+        let mut builder = ProxyBuilder::new(config).overrides(overrides);
+        if let Some(path) = config_path {
+            builder = builder.config_path(path);
+        }
+        let proxy = builder.build().unwrap_or_else(|e| {
+            eprintln!("Configuration error: {}", e);
+            std::process::exit(1);
+        });
+
+        proxy.serve().await
+    })
 }
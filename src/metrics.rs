@@ -0,0 +1,240 @@
+use std::collections::BTreeMap;
+use std::fmt::Write;
+use std::sync::atomic::{AtomicI64, AtomicU64, Ordering};
+use std::sync::Mutex;
+use std::time::Duration;
+
+use crate::proxy::ProxyState;
+
+/// Upper bounds (in seconds) of the upstream latency histogram buckets.
+const LATENCY_BUCKETS: [f64; 11] = [0.005, 0.01, 0.025, 0.05, 0.1, 0.25, 0.5, 1.0, 2.5, 5.0, 10.0];
+
+#[derive(Debug, Default)]
+struct Histogram {
+    buckets: [u64; LATENCY_BUCKETS.len()],
+    count: u64,
+    sum: f64,
+}
+
+impl Histogram {
+    fn observe(&mut self, seconds: f64) {
+        for (bucket, bound) in self.buckets.iter_mut().zip(LATENCY_BUCKETS.iter()) {
+            if seconds <= *bound {
+                *bucket += 1;
+            }
+        }
+        self.count += 1;
+        self.sum += seconds;
+    }
+}
+
+/// Process-wide counters exported in the Prometheus text format.
+#[derive(Debug, Default)]
+pub struct Metrics {
+    requests_by_status: Mutex<BTreeMap<u16, u64>>,
+    request_errors: AtomicU64,
+    active_connections: AtomicI64,
+    tls_handshake_failures: AtomicU64,
+    /// Connections closed on accept because their IP had too many open
+    rejected_connections: AtomicU64,
+    upstream_latency: Mutex<BTreeMap<String, Histogram>>,
+    /// Mirrored requests per shadow pool that succeeded and failed
+    mirrored: Mutex<BTreeMap<String, [u64; 2]>>,
+    /// gRPC calls per pool and status
+    grpc_statuses: Mutex<BTreeMap<(String, u32), u64>>,
+}
+
+impl Metrics {
+    pub fn new() -> Self {
+        Metrics::default()
+    }
+
+    /// Counts a response sent to a client.
+    pub fn record_response(&self, status: u16) {
+        *self.requests_by_status.lock().unwrap().entry(status).or_insert(0) += 1;
+    }
+
+    /// Counts a request that failed without producing a response.
+    pub fn record_error(&self) {
+        self.request_errors.fetch_add(1, Ordering::Relaxed);
+    }
+
+    pub fn record_tls_handshake_failure(&self) {
+        self.tls_handshake_failures.fetch_add(1, Ordering::Relaxed);
+    }
+
+    pub fn record_connection_rejected(&self) {
+        self.rejected_connections.fetch_add(1, Ordering::Relaxed);
+    }
+
+    pub fn connection_opened(&self) {
+        self.active_connections.fetch_add(1, Ordering::Relaxed);
+    }
+
+    pub fn connection_closed(&self) {
+        self.active_connections.fetch_sub(1, Ordering::Relaxed);
+    }
+
+    pub fn active_connections(&self) -> i64 {
+        self.active_connections.load(Ordering::Relaxed)
+    }
+
+    /// Records how long an upstream took to return response headers.
+    pub fn observe_upstream_latency(&self, upstream: &str, elapsed: Duration) {
+        let mut latency = self.upstream_latency.lock().unwrap();
+        latency.entry(upstream.to_string()).or_default().observe(elapsed.as_secs_f64());
+    }
+
+    /// Counts a mirrored request by whether the shadow pool answered without a 5xx.
+    pub fn record_mirror(&self, pool: &str, ok: bool) {
+        let mut mirrored = self.mirrored.lock().unwrap();
+        mirrored.entry(pool.to_string()).or_default()[if ok { 0 } else { 1 }] += 1;
+    }
+
+    /// Counts a gRPC call proxied to a pool by its `grpc-status`.
+    pub fn record_grpc_status(&self, pool: &str, code: u32) {
+        *self.grpc_statuses.lock().unwrap().entry((pool.to_string(), code)).or_insert(0) += 1;
+    }
+
+    /// Renders all metrics, plus per-upstream gauges from each pool's balancer
+    /// and the load shedding and cache statistics.
+    pub fn render(&self, state: &ProxyState) -> String {
+        let pools = &state.pools;
+        let mut out = String::new();
+
+        out.push_str("# HELP riffy_requests_total Responses sent to clients, by status code.\n");
+        out.push_str("# TYPE riffy_requests_total counter\n");
+        for (status, count) in self.requests_by_status.lock().unwrap().iter() {
+            let _ = writeln!(out, "riffy_requests_total{{code=\"{}\"}} {}", status, count);
+        }
+
+        out.push_str("# HELP riffy_request_errors_total Requests that failed without a response.\n");
+        out.push_str("# TYPE riffy_request_errors_total counter\n");
+        let _ = writeln!(out, "riffy_request_errors_total {}", self.request_errors.load(Ordering::Relaxed));
+
+        out.push_str("# HELP riffy_active_connections Open client connections.\n");
+        out.push_str("# TYPE riffy_active_connections gauge\n");
+        let _ = writeln!(out, "riffy_active_connections {}", self.active_connections.load(Ordering::Relaxed));
+
+        out.push_str("# HELP riffy_connections_rejected_total Client connections closed for exceeding the per-IP limit.\n");
+        out.push_str("# TYPE riffy_connections_rejected_total counter\n");
+        let _ = writeln!(out, "riffy_connections_rejected_total {}", self.rejected_connections.load(Ordering::Relaxed));
+
+        out.push_str("# HELP riffy_tls_handshake_failures_total Failed TLS handshakes with clients.\n");
+        out.push_str("# TYPE riffy_tls_handshake_failures_total counter\n");
+        let _ = writeln!(out, "riffy_tls_handshake_failures_total {}", self.tls_handshake_failures.load(Ordering::Relaxed));
+
+        out.push_str("# HELP riffy_upstream_active_requests In-flight requests per upstream.\n");
+        out.push_str("# TYPE riffy_upstream_active_requests gauge\n");
+        for pool in pools {
+            for upstream in pool.balancer.upstreams() {
+                let _ = writeln!(out, "riffy_upstream_active_requests{{pool=\"{}\",upstream=\"{}\"}} {}", escape_label(&pool.name), escape_label(&upstream.label()), upstream.active_connections());
+            }
+        }
+
+        out.push_str("# HELP riffy_pool_queued_requests Requests waiting for an upstream below its concurrency limit.\n");
+        out.push_str("# TYPE riffy_pool_queued_requests gauge\n");
+        for pool in pools {
+            let _ = writeln!(out, "riffy_pool_queued_requests{{pool=\"{}\"}} {}", escape_label(&pool.name), pool.balancer.queued());
+        }
+
+        out.push_str("# HELP riffy_upstream_available Whether an upstream is in rotation (1) or not (0).\n");
+        out.push_str("# TYPE riffy_upstream_available gauge\n");
+        for pool in pools {
+            for upstream in pool.balancer.upstreams() {
+                let _ = writeln!(out, "riffy_upstream_available{{pool=\"{}\",upstream=\"{}\"}} {}", escape_label(&pool.name), escape_label(&upstream.label()), upstream.is_available() as u8);
+            }
+        }
+
+        out.push_str("# HELP riffy_upstream_circuit_open Whether an upstream's circuit breaker is open (1) or not (0).\n");
+        out.push_str("# TYPE riffy_upstream_circuit_open gauge\n");
+        for pool in pools {
+            for upstream in pool.balancer.upstreams() {
+                let _ = writeln!(out, "riffy_upstream_circuit_open{{pool=\"{}\",upstream=\"{}\"}} {}", escape_label(&pool.name), escape_label(&upstream.label()), upstream.is_circuit_open() as u8);
+            }
+        }
+
+        out.push_str("# HELP riffy_upstream_response_seconds Time until upstream response headers arrive.\n");
+        out.push_str("# TYPE riffy_upstream_response_seconds histogram\n");
+        for (upstream, histogram) in self.upstream_latency.lock().unwrap().iter() {
+            let label = escape_label(upstream);
+            for (bound, count) in LATENCY_BUCKETS.iter().zip(histogram.buckets.iter()) {
+                let _ = writeln!(out, "riffy_upstream_response_seconds_bucket{{upstream=\"{}\",le=\"{}\"}} {}", label, bound, count);
+            }
+            let _ = writeln!(out, "riffy_upstream_response_seconds_bucket{{upstream=\"{}\",le=\"+Inf\"}} {}", label, histogram.count);
+            let _ = writeln!(out, "riffy_upstream_response_seconds_sum{{upstream=\"{}\"}} {}", label, histogram.sum);
+            let _ = writeln!(out, "riffy_upstream_response_seconds_count{{upstream=\"{}\"}} {}", label, histogram.count);
+        }
+
+        out.push_str("# HELP riffy_upstream_connections_opened_total Connections opened to a pool's upstreams.\n");
+        out.push_str("# TYPE riffy_upstream_connections_opened_total counter\n");
+        for pool in pools {
+            let _ = writeln!(out, "riffy_upstream_connections_opened_total{{pool=\"{}\"}} {}", escape_label(&pool.name), pool.connection_stats().opened());
+        }
+
+        out.push_str("# HELP riffy_upstream_connections_open Open connections to a pool's upstreams, busy or idle.\n");
+        out.push_str("# TYPE riffy_upstream_connections_open gauge\n");
+        for pool in pools {
+            let _ = writeln!(out, "riffy_upstream_connections_open{{pool=\"{}\"}} {}", escape_label(&pool.name), pool.connection_stats().open());
+        }
+
+        out.push_str("# HELP riffy_mirror_requests_total Requests copied to shadow pools, by outcome.\n");
+        out.push_str("# TYPE riffy_mirror_requests_total counter\n");
+        for (pool, [ok, failed]) in self.mirrored.lock().unwrap().iter() {
+            let _ = writeln!(out, "riffy_mirror_requests_total{{pool=\"{}\",result=\"ok\"}} {}", escape_label(pool), ok);
+            let _ = writeln!(out, "riffy_mirror_requests_total{{pool=\"{}\",result=\"error\"}} {}", escape_label(pool), failed);
+        }
+
+        out.push_str("# HELP riffy_grpc_responses_total gRPC calls proxied to each pool, by grpc-status.\n");
+        out.push_str("# TYPE riffy_grpc_responses_total counter\n");
+        for ((pool, code), count) in self.grpc_statuses.lock().unwrap().iter() {
+            let _ = writeln!(out, "riffy_grpc_responses_total{{pool=\"{}\",code=\"{}\"}} {}", escape_label(pool), code, count);
+        }
+
+        if let Some(shedder) = &state.shedder {
+            out.push_str("# HELP riffy_load_shedding_limit Requests allowed in flight at current latency.\n");
+            out.push_str("# TYPE riffy_load_shedding_limit gauge\n");
+            let _ = writeln!(out, "riffy_load_shedding_limit {}", shedder.limit());
+            out.push_str("# HELP riffy_load_shedding_in_flight Requests in flight counted against the limit.\n");
+            out.push_str("# TYPE riffy_load_shedding_in_flight gauge\n");
+            let _ = writeln!(out, "riffy_load_shedding_in_flight {}", shedder.in_flight());
+            out.push_str("# HELP riffy_load_shed_total Requests rejected with a 503 over the limit.\n");
+            out.push_str("# TYPE riffy_load_shed_total counter\n");
+            let _ = writeln!(out, "riffy_load_shed_total {}", shedder.shed());
+        }
+
+        if let Some(cache) = &state.cache {
+            let (entries, size) = cache.usage();
+            out.push_str("# HELP riffy_cache_hits_total Requests answered from the response cache.\n");
+            out.push_str("# TYPE riffy_cache_hits_total counter\n");
+            let _ = writeln!(out, "riffy_cache_hits_total {}", cache.hits());
+            out.push_str("# HELP riffy_cache_misses_total GET requests not answered from the cache.\n");
+            out.push_str("# TYPE riffy_cache_misses_total counter\n");
+            let _ = writeln!(out, "riffy_cache_misses_total {}", cache.misses());
+            out.push_str("# HELP riffy_cache_coalesced_total Requests answered with a response fetched for a concurrent request.\n");
+            out.push_str("# TYPE riffy_cache_coalesced_total counter\n");
+            let _ = writeln!(out, "riffy_cache_coalesced_total {}", cache.coalesced());
+            out.push_str("# HELP riffy_cache_entries Responses currently cached.\n");
+            out.push_str("# TYPE riffy_cache_entries gauge\n");
+            let _ = writeln!(out, "riffy_cache_entries {}", entries);
+            out.push_str("# HELP riffy_cache_size_bytes Total size of cached response bodies.\n");
+            out.push_str("# TYPE riffy_cache_size_bytes gauge\n");
+            let _ = writeln!(out, "riffy_cache_size_bytes {}", size);
+            if let Some(disk) = cache.disk() {
+                let (entries, size) = disk.usage();
+                out.push_str("# HELP riffy_cache_disk_entries Responses currently cached on disk.\n");
+                out.push_str("# TYPE riffy_cache_disk_entries gauge\n");
+                let _ = writeln!(out, "riffy_cache_disk_entries {}", entries);
+                out.push_str("# HELP riffy_cache_disk_size_bytes Total size of the disk cache files.\n");
+                out.push_str("# TYPE riffy_cache_disk_size_bytes gauge\n");
+                let _ = writeln!(out, "riffy_cache_disk_size_bytes {}", size);
+            }
+        }
+
+        out
+    }
+}
+
+fn escape_label(value: &str) -> String {
+    value.replace('\\', "\\\\").replace('"', "\\\"").replace('\n', "\\n")
+}
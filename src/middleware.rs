@@ -0,0 +1,52 @@
+use async_trait::async_trait;
+use hyper::http::Extensions;
+use hyper::{Body, Request, Response};
+use std::net::SocketAddr;
+use std::sync::Arc;
+
+use crate::config::DEFAULT_LISTENER;
+
+/// Per-request data shared by the middleware chain.
+pub struct Context {
+    /// Address of the downstream client; behind trusted proxies, the one they forwarded
+    pub client_addr: SocketAddr,
+    /// Address the connection came from, the nearest proxy when behind trusted ones
+    pub peer_addr: SocketAddr,
+    /// Whether the client connected over TLS
+    pub tls: bool,
+    /// Name of the listener the request came in on
+    pub listener: Arc<str>,
+    /// Subject of the verified client certificate, with mutual TLS
+    pub client_cert_subject: Option<String>,
+    /// ID of this request, when request IDs are enabled
+    pub request_id: Option<String>,
+    /// Set for requests Riffy sends on its own, such as cache refreshes, which
+    /// are neither charged to the client nor logged as theirs
+    pub background: bool,
+    /// Typed storage for passing data from `on_request` to `on_response`
+    pub extensions: Extensions,
+}
+
+impl Context {
+    pub fn new(client_addr: SocketAddr, tls: bool) -> Self {
+        Context { client_addr, peer_addr: client_addr, tls, listener: Arc::from(DEFAULT_LISTENER), client_cert_subject: None, request_id: None, background: false, extensions: Extensions::new() }
+    }
+}
+
+/// Custom request/response processing around the proxy. Request hooks run in
+/// registration order and response hooks in reverse order.
+#[async_trait]
+pub trait Middleware: Send + Sync {
+    /// Inspects or modifies the request before it is routed. Returning a
+    /// response answers the client directly, skipping later middleware and
+    /// the upstream.
+    async fn on_request(&self, _req: &mut Request<Body>, _ctx: &mut Context) -> Option<Response<Body>> {
+        None
+    }
+
+    /// Inspects or modifies the response before it is sent to the client.
+    async fn on_response(&self, _res: &mut Response<Body>, _ctx: &mut Context) {}
+
+    /// Called instead of `on_response` when the request failed without a response.
+    async fn on_error(&self, _error: &(dyn std::error::Error + Send + Sync), _ctx: &mut Context) {}
+}
@@ -0,0 +1,137 @@
+use std::ffi::CString;
+use std::io;
+use tracing::info;
+
+/// Switches the process to `user` and `group`, given by name or numeric id,
+/// once everything is bound. Without a group, the user's primary group is
+/// taken. Supplementary groups are cleared, so only the given group's access
+/// remains.
+pub fn drop_to(user: Option<&str>, group: Option<&str>) -> Result<(), String> {
+    let account = user.map(lookup_user).transpose()?;
+    let gid = match (group, &account) {
+        (Some(group), _) => Some(lookup_group(group)?),
+        (None, Some(account)) => account.gid,
+        (None, None) => None,
+    };
+    let uid = account.map(|account| account.uid);
+    if uid.is_none_or(|uid| uid == current_uid()) && gid.is_none_or(|gid| gid == current_gid()) {
+        return Ok(());
+    }
+
+    // The group goes first, as changing it needs the privileges the user change gives up
+    // SAFETY: these calls take plain ids, and the group list outlives `setgroups`
+    unsafe {
+        if let Some(gid) = gid {
+            if libc::setgroups(1, &gid) != 0 || libc::setgid(gid) != 0 {
+                return Err(format!("failed to switch to group {}: {}", gid, io::Error::last_os_error()));
+            }
+        }
+        if let Some(uid) = uid {
+            if libc::setuid(uid) != 0 {
+                return Err(format!("failed to switch to user {}: {}", uid, io::Error::last_os_error()));
+            }
+            // A process that could regain root has not really dropped it
+            if libc::setuid(0) == 0 && uid != 0 {
+                return Err("dropped privileges could be regained".to_string());
+            }
+        }
+    }
+    info!("Running as user {} and group {}", current_uid(), current_gid());
+    Ok(())
+}
+
+struct Account {
+    uid: libc::uid_t,
+    /// The primary group, unknown when the user is given by an id missing from the user database
+    gid: Option<libc::gid_t>,
+}
+
+fn lookup_user(user: &str) -> Result<Account, String> {
+    let name = CString::new(user).map_err(|_| format!("invalid user name: {}", user))?;
+    let mut buf = vec![0 as libc::c_char; 4096];
+    loop {
+        // SAFETY: `passwd` and `buf` outlive the call, and `found` points into them when set
+        let (code, found) = unsafe {
+            let mut passwd: libc::passwd = std::mem::zeroed();
+            let mut found = std::ptr::null_mut();
+            let code = match user.parse::<libc::uid_t>() {
+                Ok(uid) => libc::getpwuid_r(uid, &mut passwd, buf.as_mut_ptr(), buf.len(), &mut found),
+                Err(_) => libc::getpwnam_r(name.as_ptr(), &mut passwd, buf.as_mut_ptr(), buf.len(), &mut found),
+            };
+            (code, (!found.is_null()).then_some(Account { uid: passwd.pw_uid, gid: Some(passwd.pw_gid) }))
+        };
+        match (code, found, user.parse::<libc::uid_t>()) {
+            (libc::ERANGE, _, _) => buf.resize(buf.len() * 2, 0),
+            (0, Some(account), _) => return Ok(account),
+            (0, None, Ok(uid)) => return Ok(Account { uid, gid: None }),
+            (0, None, Err(_)) => return Err(format!("no such user: {}", user)),
+            (code, _, _) => return Err(format!("failed to look up user {}: {}", user, io::Error::from_raw_os_error(code))),
+        }
+    }
+}
+
+fn lookup_group(group: &str) -> Result<libc::gid_t, String> {
+    if let Ok(gid) = group.parse() {
+        return Ok(gid);
+    }
+    let name = CString::new(group).map_err(|_| format!("invalid group name: {}", group))?;
+    let mut buf = vec![0 as libc::c_char; 4096];
+    loop {
+        // SAFETY: as in `lookup_user`
+        let (code, found) = unsafe {
+            let mut entry: libc::group = std::mem::zeroed();
+            let mut found = std::ptr::null_mut();
+            let code = libc::getgrnam_r(name.as_ptr(), &mut entry, buf.as_mut_ptr(), buf.len(), &mut found);
+            (code, (!found.is_null()).then_some(entry.gr_gid))
+        };
+        match (code, found) {
+            (libc::ERANGE, _) => buf.resize(buf.len() * 2, 0),
+            (0, Some(gid)) => return Ok(gid),
+            (0, None) => return Err(format!("no such group: {}", group)),
+            (code, _) => return Err(format!("failed to look up group {}: {}", group, io::Error::from_raw_os_error(code))),
+        }
+    }
+}
+
+fn current_uid() -> libc::uid_t {
+    // SAFETY: getuid cannot fail
+    unsafe { libc::getuid() }
+}
+
+fn current_gid() -> libc::gid_t {
+    // SAFETY: getgid cannot fail
+    unsafe { libc::getgid() }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn looks_up_users_by_name_or_id() {
+        let root = lookup_user("root").unwrap();
+        assert_eq!((root.uid, root.gid), (0, Some(0)));
+        let by_id = lookup_user("0").unwrap();
+        assert_eq!((by_id.uid, by_id.gid), (0, Some(0)));
+        // An id missing from the user database is used as it is, with no primary group
+        let unknown = lookup_user("3999999").unwrap();
+        assert_eq!((unknown.uid, unknown.gid), (3_999_999, None));
+        assert_eq!(lookup_user("riffy-no-such-user").err().unwrap(), "no such user: riffy-no-such-user");
+        assert!(lookup_user("ro\0ot").is_err());
+    }
+
+    #[test]
+    fn looks_up_groups_by_name_or_id() {
+        assert_eq!(lookup_group("root"), Ok(0));
+        assert_eq!(lookup_group("3999999"), Ok(3_999_999));
+        assert_eq!(lookup_group("riffy-no-such-group"), Err("no such group: riffy-no-such-group".to_string()));
+    }
+
+    #[test]
+    fn staying_the_same_account_changes_nothing() {
+        drop_to(None, None).unwrap();
+        drop_to(Some(&current_uid().to_string()), Some(&current_gid().to_string())).unwrap();
+        assert!(drop_to(Some("riffy-no-such-user"), None).is_err());
+        assert!(drop_to(None, Some("riffy-no-such-group")).is_err());
+    }
+}
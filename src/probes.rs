@@ -0,0 +1,54 @@
+use hyper::header::{CACHE_CONTROL, CONTENT_TYPE};
+use hyper::{Body, Method, Request, Response, StatusCode};
+use serde_json::{json, Value};
+
+use crate::proxy::{ProxyState, Runtime};
+
+/// Answers GET and HEAD requests for the configured probe paths.
+pub fn answer(req: &Request<Body>, runtime: &Runtime, state: &ProxyState) -> Option<Response<Body>> {
+    if req.method() != Method::GET && req.method() != Method::HEAD {
+        return None;
+    }
+    let path = req.uri().path();
+    if path == state.probes.liveness_path {
+        Some(liveness(runtime))
+    } else if path == state.probes.readiness_path {
+        Some(readiness(runtime, state))
+    } else {
+        None
+    }
+}
+
+/// Liveness: Riffy is running and its listener accepts connections.
+fn liveness(runtime: &Runtime) -> Response<Body> {
+    let listening = runtime.is_listening();
+    let status = if listening { StatusCode::OK } else { StatusCode::SERVICE_UNAVAILABLE };
+    respond(status, json!({ "status": if listening { "ok" } else { "starting" }, "listening": listening }))
+}
+
+/// Readiness: the listener is up and every pool has an upstream that can take traffic.
+fn readiness(runtime: &Runtime, state: &ProxyState) -> Response<Body> {
+    let listening = runtime.is_listening();
+    let pools: Vec<Value> = state
+        .pools
+        .iter()
+        .map(|pool| {
+            let upstreams = pool.balancer.upstreams();
+            let available = upstreams.iter().filter(|u| u.is_available()).count();
+            json!({ "name": pool.name, "available": available, "total": upstreams.len() })
+        })
+        .collect();
+    let ready = listening && pools.iter().all(|pool| pool["available"].as_u64() > Some(0));
+
+    let status = if ready { StatusCode::OK } else { StatusCode::SERVICE_UNAVAILABLE };
+    respond(status, json!({ "status": if ready { "ready" } else { "not ready" }, "listening": listening, "pools": pools }))
+}
+
+fn respond(status: StatusCode, body: Value) -> Response<Body> {
+    Response::builder()
+        .status(status)
+        .header(CONTENT_TYPE, "application/json")
+        .header(CACHE_CONTROL, "no-store")
+        .body(Body::from(body.to_string()))
+        .unwrap()
+}
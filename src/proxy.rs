@@ -0,0 +1,1727 @@
+use futures_util::StreamExt;
+use hyper::{header::{HeaderName, HeaderValue, ACCEPT, ALT_SVC, CONTENT_LENGTH, HOST, SET_COOKIE, TE}, service::{service_fn, Service}, Body, Client, Request, Response, StatusCode, Uri};
+use hyper::server::conn::Http;
+use regex::Regex;
+use std::borrow::Cow;
+use std::collections::HashMap;
+use std::future::Future;
+use std::pin::Pin;
+use std::{fmt, net::{IpAddr, SocketAddr}, path::{Path, PathBuf}, sync::{Arc, Mutex, RwLock}};
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::time::{Duration, Instant};
+use tokio::io::{AsyncRead, AsyncWrite, AsyncWriteExt};
+use tokio::net::TcpListener;
+#[cfg(unix)]
+use tokio::net::UnixListener;
+use tokio_rustls::rustls::Session;
+use tokio_rustls::TlsAcceptor;
+use tracing::{debug, error, info, warn};
+
+use crate::access_log::{AccessLog, UpstreamUsed};
+use crate::acl::{self, AccessList, Cidr, ForwardedHeader};
+use crate::admin;
+use crate::balancer::{AcquireError, Balancer, Upstream};
+use crate::bandwidth::Bandwidth;
+use crate::basic_auth::BasicAuth;
+use crate::cache::{Cache, CacheKey, Revalidate, Revalidation};
+use crate::conditional::ConditionalRequests;
+use crate::disk_cache::DiskCache;
+use crate::compression::Compression;
+use crate::consul;
+use crate::config::{Config, HostHeader, ListenerMode, Overrides, ProbesConfig, UpstreamsConfig, DEFAULT_LISTENER, DEFAULT_POOL};
+use crate::connlimit::ConnectionLimiter;
+use crate::cors::Cors;
+use crate::dns;
+use crate::error_pages::ErrorPages;
+use crate::forward_auth::ForwardAuth;
+use crate::geoip::GeoIp;
+use crate::grpc;
+use crate::handoff;
+use crate::headers::{self, ClientCertHeader, EventStream, ForwardedHeaders, HeaderRuleSet, ResponseRewrite, SecurityHeaders};
+use crate::health::{self, HealthCheckConfig};
+use crate::jwt::JwtAuth;
+use crate::kubernetes;
+use crate::listen::bind_tcp;
+#[cfg(unix)]
+use crate::listen::{bind_tcp_reuse_port, bind_unix};
+use crate::logging;
+use crate::metrics::Metrics;
+use crate::middleware::{Context, Middleware};
+#[cfg(unix)]
+use crate::privileges;
+use crate::probes;
+use crate::proxy_protocol::{self, ConnectTiming, ConnectionStats};
+use crate::ratelimit::RateLimiter;
+use crate::redirect::{self, RedirectRule, Redirects};
+use crate::request_id::RequestId;
+use crate::retry::RetryPolicy;
+use crate::route_access::RouteAccess;
+use crate::router::{self, BlueGreen, Maintenance, Route, Router, TrafficShare};
+use crate::shedding::LoadShedder;
+use crate::slow_requests::{SlowRequests, UpstreamTimings};
+use crate::sni;
+use crate::static_files::StaticFiles;
+#[cfg(unix)]
+use crate::systemd;
+use crate::telemetry::Tracer;
+use crate::tls::{self, ClientConnector, ServerTls, UpstreamConnector};
+
+type BoxError = Box<dyn std::error::Error + Send + Sync>;
+
+/// How long a new connection may take to send its PROXY protocol header.
+const PROXY_HEADER_TIMEOUT: Duration = Duration::from_secs(5);
+/// How long a TLS passthrough client may take to send its ClientHello.
+const CLIENT_HELLO_TIMEOUT: Duration = Duration::from_secs(10);
+
+/// An upstream's URL and the address it is pinned to, if any.
+type UpstreamKey = (String, Option<IpAddr>);
+
+/// A named group of upstreams with its own balancing, TLS and retry settings.
+pub struct Pool {
+    pub name: String,
+    pub balancer: Arc<Balancer>,
+    connector: UpstreamConnector,
+    /// Shared by all requests, so upstream connections are reused
+    http_client: Client<ClientConnector>,
+    /// The same for h2c upstreams, over HTTP/2 only
+    h2c_client: Client<ClientConnector>,
+    /// Clients for upstreams pinned to a resolved address or a Unix socket, one per upstream
+    pinned_clients: Mutex<HashMap<UpstreamKey, Client<ClientConnector>>>,
+    retry: RetryPolicy,
+    sticky_cookie: Option<String>,
+    host_header: HostHeader,
+    health_check: Option<HealthCheckConfig>,
+    /// Overrides of the global timeouts
+    response_header_timeout: Option<Duration>,
+    request_timeout: Option<Duration>,
+    /// Set once the admin API changes the upstreams, which a reload undoes
+    changed_at_runtime: AtomicBool,
+}
+
+impl Pool {
+    /// Builds a pool from its settings. Upstreams that also exist in `previous`
+    /// keep their health status across a reload; `tcp` pools are health
+    /// checked by connecting only.
+    fn from_config(name: &str, settings: &UpstreamsConfig, connect_timeout: Option<Duration>, tcp: bool, previous: Option<&Pool>) -> Result<Pool, String> {
+        // Discovered upstreams replace `servers`, starting from the ones already known
+        let mut upstreams = if settings.discovers_upstreams() {
+            let known = previous.map(|pool| pool.balancer.upstreams()).unwrap_or_default();
+            known.iter().map(|upstream| upstream.duplicate()).collect()
+        } else {
+            settings.build_upstreams()?
+        };
+        // Hostnames stand for their resolved addresses, starting from the ones already known
+        let mut targets = Vec::new();
+        if settings.dns.enabled {
+            let (fixed, resolved) = dns::split(upstreams);
+            upstreams = fixed;
+            for target in &resolved {
+                upstreams.extend(target.seed(previous.map(|pool| pool.balancer.as_ref())));
+            }
+            targets = resolved;
+        }
+        if let Some(previous) = previous {
+            for upstream in &upstreams {
+                if let Some(old) = previous.balancer.upstreams().iter().find(|old| old.id == upstream.id) {
+                    upstream.set_healthy(old.is_healthy());
+                }
+            }
+        }
+
+        let balancer = Arc::new(
+            Balancer::new(upstreams, settings.strategy, settings.passive_health(), settings.circuit_breaker()).with_limit(settings.concurrency_limit()),
+        );
+        let mut connector = tls::upstream_connector(settings, connect_timeout)?;
+        if let Some(previous) = previous {
+            // Keep counting connections across reloads
+            connector.stats = Arc::clone(&previous.connector.stats);
+        }
+        let http_client = connector.client(None);
+        let h2c_client = connector.http2_only().client(None);
+
+        // Optional active health checks that take failing upstreams out of rotation
+        let health_check = settings.health_check().map(|health| HealthCheckConfig { tcp, ..health });
+        if let Some(health_config) = &health_check {
+            health::spawn(&balancer, health_config.clone(), connector.clone());
+        }
+        let discovery_health = || health_check.clone().map(|config| (config, connector.clone()));
+        dns::spawn(&balancer, targets, &settings.dns, discovery_health())?;
+        if settings.kubernetes.enabled {
+            kubernetes::spawn(&balancer, &settings.kubernetes, discovery_health())?;
+        }
+        if settings.consul.enabled {
+            consul::spawn(&balancer, &settings.consul, discovery_health())?;
+        }
+        if settings.srv.enabled {
+            dns::spawn_srv(&balancer, &settings.srv, &settings.dns, discovery_health())?;
+        }
+
+        Ok(Pool {
+            name: name.to_string(),
+            balancer,
+            connector,
+            http_client,
+            h2c_client,
+            pinned_clients: Mutex::default(),
+            retry: settings.retry_policy(),
+            sticky_cookie: settings.sticky_cookie(),
+            host_header: settings.host_header,
+            health_check,
+            response_header_timeout: settings.timeouts.response_header.map(Duration::from_secs),
+            request_timeout: settings.timeouts.request.map(Duration::from_secs),
+            changed_at_runtime: AtomicBool::new(false),
+        })
+    }
+
+    /// Notes that the upstreams were changed at runtime, for the next reload to warn about.
+    pub fn mark_changed(&self) {
+        self.changed_at_runtime.store(true, Ordering::Relaxed);
+    }
+
+    /// Adds an upstream at runtime, starting its health checks if enabled.
+    pub fn add_upstream(&self, upstream: Upstream) -> Result<Arc<Upstream>, String> {
+        let upstream = self.balancer.add(upstream)?;
+        if let Some(health_config) = &self.health_check {
+            health::spawn_one(&upstream, health_config.clone(), self.connector.clone());
+        }
+        Ok(upstream)
+    }
+
+    /// The client for requests to `upstream` on behalf of `client`.
+    fn http_client(&self, client: SocketAddr, upstream: &Upstream) -> Client<ClientConnector> {
+        // Connections that start with the client's PROXY header cannot be shared
+        if self.connector.per_client() {
+            return self.connector.for_upstream(upstream).client(Some(client));
+        }
+        match (upstream.address, upstream.unix_socket()) {
+            (None, None) if upstream.is_h2c() => return self.h2c_client.clone(),
+            (None, None) => return self.http_client.clone(),
+            _ => {}
+        }
+
+        let key = (upstream.url.clone(), upstream.address);
+        let mut clients = self.pinned_clients.lock().unwrap();
+        if let Some(http_client) = clients.get(&key) {
+            return http_client.clone();
+        }
+        // Forget upstreams the pool no longer has, such as addresses it stopped resolving to
+        let upstreams = self.balancer.upstreams();
+        clients.retain(|(url, address), _| upstreams.iter().any(|u| &u.url == url && u.address == *address));
+        clients.entry(key).or_insert_with(|| self.connector.for_upstream(upstream).client(None)).clone()
+    }
+
+    pub fn connection_stats(&self) -> &ConnectionStats {
+        &self.connector.stats
+    }
+}
+
+/// Shared state used by every proxied request.
+pub struct ProxyState {
+    /// The `[upstreams]` pool first, then the named pools
+    pub pools: Vec<Pool>,
+    router: Router,
+    /// Built-in middleware, the custom middleware, then the cache
+    middleware: Vec<Arc<dyn Middleware>>,
+    pub cache: Option<Arc<Cache>>,
+    pub shedder: Option<Arc<LoadShedder>>,
+    response_header_timeout: Option<Duration>,
+    request_timeout: Option<Duration>,
+    pub probes: ProbesConfig,
+    /// Largest request body in bytes; 0 means no limit
+    max_body_size: u64,
+    /// Largest upstream response body in bytes; 0 means no limit
+    max_response_size: u64,
+    /// Response bandwidth per client, unless a route has its own
+    bandwidth: Arc<Bandwidth>,
+    /// Open connections allowed per client IP; 0 means no limit
+    pub(crate) max_connections_per_ip: usize,
+    header_timeout: Option<Duration>,
+    /// Clients allowed on the listener, checked before a route's own list
+    access: AccessList,
+    trusted_proxies: Vec<Cidr>,
+    trusted_proxies_header: ForwardedHeader,
+    headers: HeaderRuleSet,
+    security_headers: SecurityHeaders,
+    response_rewrite: ResponseRewrite,
+    error_pages: ErrorPages,
+    /// Advertises the HTTP/3 listener on responses over TLS
+    alt_svc: Option<HeaderValue>,
+    tracer: Option<Arc<Tracer>>,
+    access_log: Option<AccessLog>,
+    jwt: Option<Arc<JwtAuth>>,
+}
+
+impl ProxyState {
+    /// Names of the pools whose upstreams were changed through the admin API,
+    /// changes a reload resets to the configuration.
+    pub(crate) fn pools_changed_at_runtime(&self) -> Vec<&str> {
+        self.pools.iter().filter(|pool| pool.changed_at_runtime.load(Ordering::Relaxed)).map(|pool| pool.name.as_str()).collect()
+    }
+
+    /// Builds the request-handling state from a validated config, carrying
+    /// upstream health over from `previous` pools with the same name.
+    fn from_config(config: &Config, previous: Option<&ProxyState>, custom: &[Arc<dyn Middleware>]) -> Result<ProxyState, String> {
+        let connect_timeout = |settings: &UpstreamsConfig| settings.timeouts.connect.or(config.timeouts.connect).map(Duration::from_secs);
+        let previous_pool = |name: &str| previous.and_then(|state| state.pools.iter().find(|pool| pool.name == name));
+
+        let tcp = config.listener.mode != ListenerMode::Http;
+        let mut pools = vec![Pool::from_config(DEFAULT_POOL, &config.upstreams, connect_timeout(&config.upstreams), tcp, previous_pool(DEFAULT_POOL))?];
+        for (name, settings) in &config.pools {
+            pools.push(Pool::from_config(name, settings, connect_timeout(settings), tcp, previous_pool(name))?);
+        }
+
+        let pool_index = |name: &str| pools.iter().position(|pool| pool.name == name).expect("routes are validated");
+        let routes: Vec<Route> = config
+            .routes
+            .iter()
+            .map(|route| {
+                Ok(Route {
+                    hosts: route.hosts.clone(),
+                    countries: route.countries.clone(),
+                    path_prefix: route.path_prefix.clone(),
+                    listeners: route.listeners.clone(),
+                    strip_prefix: route.strip_prefix,
+                    rewrite: route.rewrite.regex.as_ref().map(|pattern| Regex::new(pattern).map(|regex| (regex, route.rewrite.replacement.clone()))).transpose().map_err(|e| e.to_string())?,
+                    add_prefix: route.rewrite.add_prefix.clone(),
+                    name: route.name.clone(),
+                    pool: pool_index(&route.pool),
+                    blue_green: route.blue_green.as_ref().map(|pair| Arc::new(BlueGreen::new(pool_index(&route.pool), pool_index(&pair.standby)))),
+                    canary: route.canary.as_ref().map(|canary| Arc::new(TrafficShare::new(pool_index(&canary.pool), canary.percent))),
+                    mirror: route.mirror.as_ref().map(|mirror| Arc::new(TrafficShare::new(pool_index(&mirror.pool), mirror.percent))),
+                    response_header_timeout: route.timeouts.response_header.map(Duration::from_secs),
+                    request_timeout: route.timeouts.request.map(Duration::from_secs),
+                    sse: route.sse,
+                    max_body_size: route.max_body_size,
+                    max_response_size: route.max_response_size,
+                    bandwidth: route.bandwidth.as_ref().map(|bandwidth| Arc::new(Bandwidth::new(bandwidth))),
+                    cache_key: route.cache_key.as_ref().map(|key| Arc::new(CacheKey::new(key))),
+                    static_files: route.static_files.as_ref().map(|files| StaticFiles::new(files).map(Arc::new)).transpose()?,
+                    headers: HeaderRuleSet::new(&route.headers)?,
+                    security_headers: route.security_headers.as_ref().map(SecurityHeaders::new).transpose()?,
+                    response_rewrite: route.response_rewrite.as_ref().map(ResponseRewrite::new),
+                    access: route.access.clone(),
+                    forward_auth: route.forward_auth.as_ref().map(|auth| ForwardAuth::new(auth).map(Arc::new)).transpose()?,
+                    basic_auth: route.basic_auth.as_ref().map(|auth| BasicAuth::load(auth).map(Arc::new)).transpose()?,
+                    maintenance: Arc::new(Maintenance::new(&route.maintenance)?),
+                })
+            })
+            .collect::<Result<_, String>>()?;
+        if let Some(previous) = previous {
+            for route in &routes {
+                carry_over_route(route, previous, &pools);
+            }
+        }
+
+        // The request ID is assigned first so every later middleware can use it,
+        // and the access log runs next so it also sees responses from later middleware
+        let mut middleware: Vec<Arc<dyn Middleware>> = Vec::new();
+        if config.request_id.enabled {
+            let header = HeaderName::from_bytes(config.request_id.header.as_bytes()).map_err(|e| format!("invalid request ID header: {}", e))?;
+            middleware.push(Arc::new(RequestId(header)));
+        }
+        // Kept across reloads with unchanged settings so queued spans are not lost
+        let tracer = match previous.and_then(|state| state.tracer.as_ref()).filter(|tracer| tracer.uses(&config.telemetry)) {
+            Some(tracer) => Some(Arc::clone(tracer)),
+            None if config.telemetry.enabled => Some(Tracer::spawn(&config.telemetry)?),
+            None => None,
+        };
+        if let Some(tracer) = &tracer {
+            middleware.push(Arc::clone(tracer) as Arc<dyn Middleware>);
+        }
+        // Kept across reloads with unchanged settings so rotation goes on where
+        // it was, but the file is reopened in case it was moved away
+        let access_log = match previous.and_then(|state| state.access_log.as_ref()).filter(|log| log.uses(&config.access_log)) {
+            Some(log) => {
+                log.reopen().map_err(|e| format!("failed to reopen access log: {}", e))?;
+                Some(log.clone())
+            }
+            None if config.access_log.enabled => Some(AccessLog::open(&config.access_log).map_err(|e| format!("failed to open access log: {}", e))?),
+            None => None,
+        };
+        if let Some(log) = &access_log {
+            middleware.push(Arc::new(log.clone()));
+        }
+        if config.slow_requests.enabled {
+            middleware.push(Arc::new(SlowRequests::new(&config.slow_requests)));
+        }
+        // Kept across reloads with unchanged settings so the learned limit and requests in flight carry over
+        let shedder = match previous.and_then(|state| state.shedder.as_ref()).filter(|shedder| shedder.uses(&config.load_shedding)) {
+            Some(shedder) => Some(Arc::clone(shedder)),
+            None if config.load_shedding.enabled => Some(Arc::new(LoadShedder::new(&config.load_shedding))),
+            None => None,
+        };
+        if let Some(shedder) = &shedder {
+            middleware.push(Arc::clone(shedder) as Arc<dyn Middleware>);
+        }
+        if config.rate_limit.enabled {
+            middleware.push(Arc::new(RateLimiter::new(config.rate_limit.requests_per_second, config.rate_limit.burst)));
+        }
+        if config.geoip.enabled {
+            middleware.push(Arc::new(GeoIp::open(&config.geoip)?));
+        }
+        // Preflights are answered ahead of authentication, as browsers send them without credentials
+        if config.cors.enabled {
+            middleware.push(Arc::new(Cors::new(&config.cors)?));
+        }
+        if !config.redirects.is_empty() {
+            let rules = config.redirects.iter().map(RedirectRule::new).collect::<Result<_, String>>()?;
+            middleware.push(Arc::new(Redirects(rules)));
+        }
+        // Kept across reloads with unchanged settings so the cached keys are not fetched again
+        let jwt = match previous.and_then(|state| state.jwt.as_ref()).filter(|jwt| jwt.uses(&config.jwt)) {
+            Some(jwt) => Some(Arc::clone(jwt)),
+            None if config.jwt.enabled => Some(Arc::new(JwtAuth::new(&config.jwt)?)),
+            None => None,
+        };
+        if let Some(jwt) = &jwt {
+            middleware.push(Arc::clone(jwt) as Arc<dyn Middleware>);
+        }
+        // Compression runs before the cache in the chain so the cache stores uncompressed bodies
+        if config.compression.enabled {
+            middleware.push(Arc::new(Compression::new(config.compression.min_size, &config.compression.content_types)));
+        }
+        middleware.push(Arc::new(ForwardedHeaders));
+        // Any TLS listener may ask for client certificates, and on every listener a client
+        // could send the header itself, so it is replaced whenever one terminates TLS
+        let client_auth = &config.tls.client_auth;
+        if config.serves_tls() && !client_auth.subject_header.is_empty() {
+            let name = HeaderName::from_bytes(client_auth.subject_header.as_bytes()).map_err(|e| format!("invalid subject header: {}", e))?;
+            middleware.push(Arc::new(ClientCertHeader(name)));
+        }
+        middleware.extend(custom.iter().cloned());
+        // Right before the cache, to see the client's conditions before the cache drops them and
+        // to answer with 304 before earlier middleware, such as the access log, see the response
+        middleware.push(Arc::new(ConditionalRequests));
+
+        // The cache comes last so that hits still pass through the route's access checks and
+        // custom middleware, and routes requests itself to find their cache keys
+        let router = Router::new(routes);
+        middleware.push(Arc::new(RouteAccess::new(router.clone())));
+        // Kept across reloads with unchanged settings so the directory is not indexed again
+        let disk = match previous.and_then(|state| state.cache.as_ref()).and_then(|cache| cache.disk()).filter(|disk| disk.uses(&config.cache.disk)) {
+            Some(disk) => Some(Arc::clone(disk)),
+            None if config.cache.enabled && config.cache.disk.path.is_some() => Some(Arc::new(DiskCache::open(&config.cache.disk)?)),
+            None => None,
+        };
+        let cache = config.cache.cache().map(|cache_config| {
+            let cache = Cache::new(cache_config).with_purge_from(config.cache.purge_from.clone()).with_router(router.clone());
+            Arc::new(match disk {
+                Some(disk) => cache.with_disk(disk),
+                None => cache,
+            })
+        });
+        if let Some(cache) = &cache {
+            middleware.push(Arc::clone(cache) as Arc<dyn Middleware>);
+        }
+
+        Ok(ProxyState {
+            pools,
+            router,
+            middleware,
+            cache,
+            shedder,
+            response_header_timeout: config.timeouts.response_header.map(Duration::from_secs),
+            request_timeout: config.timeouts.request.map(Duration::from_secs),
+            probes: config.probes.clone(),
+            max_body_size: config.limits.max_body_size,
+            max_response_size: config.limits.max_response_size,
+            bandwidth: Arc::new(Bandwidth::new(&config.bandwidth)),
+            max_connections_per_ip: config.limits.max_connections_per_ip,
+            header_timeout: config.limits.header_timeout.map(Duration::from_secs),
+            access: config.listener.access.clone(),
+            trusted_proxies: config.listener.trusted_proxies.clone(),
+            trusted_proxies_header: config.listener.trusted_proxies_header,
+            headers: HeaderRuleSet::new(&config.headers)?,
+            security_headers: SecurityHeaders::new(&config.security_headers)?,
+            response_rewrite: ResponseRewrite::new(&config.response_rewrite),
+            error_pages: ErrorPages::load(&config.error_pages)?,
+            alt_svc: config.http3.enabled.then(|| HeaderValue::from_str(&format!("h3=\":{}\"; ma={}", config.http3.port(config.listen_port()), config.http3.alt_svc_max_age)).expect("valid Alt-Svc")),
+            tracer,
+            access_log,
+            jwt,
+        })
+    }
+
+    /// Picks the pool for a request routed by `route`, which may be the
+    /// route's canary pool.
+    fn select_pool(&self, route: Option<&Route>) -> &Pool {
+        &self.pools[route.map_or(0, |route| route.pick_pool())]
+    }
+
+    /// All routes, in matching order.
+    pub fn routes(&self) -> &[Route] {
+        self.router.routes()
+    }
+
+    /// The route with the given name or index, and its index.
+    pub fn find_route(&self, id: &str) -> Option<(usize, &Route)> {
+        self.router.find(id)
+    }
+
+    /// Time allowed for the whole request; the route's timeout takes
+    /// precedence over the pool's, which takes precedence over the global one.
+    /// SSE routes, whose upstreams may hold back the headers until the first
+    /// event, only have their own.
+    fn request_timeout(&self, route: Option<&Route>, pool: &Pool) -> Option<Duration> {
+        match route {
+            Some(route) if route.sse => route.request_timeout,
+            _ => route.and_then(|route| route.request_timeout).or(pool.request_timeout).or(self.request_timeout),
+        }
+    }
+
+    /// Time allowed for a single attempt to return response headers.
+    fn attempt_timeout(&self, route: Option<&Route>, pool: &Pool) -> Option<Duration> {
+        let response_header = match route {
+            Some(route) if route.sse => route.response_header_timeout,
+            _ => route.and_then(|route| route.response_header_timeout).or(pool.response_header_timeout).or(self.response_header_timeout),
+        };
+        match (pool.retry.per_try_timeout, response_header) {
+            (Some(a), Some(b)) => Some(a.min(b)),
+            (a, b) => a.or(b),
+        }
+    }
+}
+
+/// Keeps the blue-green switch and canary split made through the admin API
+/// for a named route whose pools are unchanged in the reloaded config.
+fn carry_over_route(route: &Route, previous: &ProxyState, pools: &[Pool]) {
+    let old = match route.name.as_deref().and_then(|name| previous.router.find(name)) {
+        Some((_, old)) => old,
+        None => return,
+    };
+    let same_pool = |new: usize, old: usize| pools[new].name == previous.pools[old].name;
+    if let (Some(pair), Some(old_pair)) = (&route.blue_green, &old.blue_green) {
+        if same_pool(pair.pools[0], old_pair.pools[0]) && same_pool(pair.pools[1], old_pair.pools[1]) {
+            pair.carry_over(old_pair);
+        }
+    }
+    if let (Some(canary), Some(old_canary)) = (&route.canary, &old.canary) {
+        if same_pool(canary.pool, old_canary.pool) {
+            canary.carry_over(old_canary);
+        }
+    }
+    route.maintenance.carry_over(&old.maintenance);
+}
+
+/// The live state and TLS acceptor, swapped atomically on reload. In-flight
+/// requests keep the `Arc` they started with, so nothing is dropped.
+pub struct Runtime {
+    state: RwLock<Arc<ProxyState>>,
+    tls: RwLock<Option<ServerTls>>,
+    pub metrics: Arc<Metrics>,
+    pub(crate) connections: ConnectionLimiter,
+    custom_middleware: Vec<Arc<dyn Middleware>>,
+    /// Bearer token required by the admin API; the API is disabled without one
+    pub admin_token: Option<String>,
+    /// Set once the listener accepts connections
+    listening: AtomicBool,
+}
+
+impl Runtime {
+    pub fn state(&self) -> Arc<ProxyState> {
+        Arc::clone(&self.state.read().unwrap())
+    }
+
+    pub fn is_listening(&self) -> bool {
+        self.listening.load(Ordering::Relaxed)
+    }
+
+    fn tls_acceptor(&self) -> Option<TlsAcceptor> {
+        self.tls.read().unwrap().as_ref().map(|tls| tls.acceptor.clone())
+    }
+
+    /// Re-reads the listener's certificate files. Handshakes already in
+    /// progress finish with the certificates they started with.
+    pub fn reload_certificates(&self) -> Result<(), String> {
+        match self.tls.read().unwrap().as_ref() {
+            Some(tls) => tls.reload_certificates(),
+            None => Err("TLS is not enabled".to_string()),
+        }
+    }
+
+    fn reload_certificates_if_changed(&self) -> Result<bool, String> {
+        match self.tls.read().unwrap().as_ref() {
+            Some(tls) => tls.reload_if_changed(),
+            None => Ok(false),
+        }
+    }
+
+    /// Re-reads the configuration and swaps in new upstreams and certificates.
+    fn reload(&self, config_path: Option<&str>, overrides: &Overrides, current: &Config) -> Result<Config, String> {
+        let config = Config::load_with(config_path, overrides)?;
+
+        if config.listen_port() != current.listen_port() || config.tls.enabled != current.tls.enabled || config.listeners != current.listeners {
+            warn!("Listener port, TLS enablement and [[listeners]] changes require a restart and were not applied");
+        }
+
+        let tls = if current.serves_tls() { Some(tls::load_acceptor(&config.tls)?) } else { None };
+        let previous = self.state();
+        let state = ProxyState::from_config(&config, Some(&previous), &self.custom_middleware)?;
+        for pool in previous.pools_changed_at_runtime() {
+            warn!("Reload resets the upstreams of pool {} to the configuration, dropping the changes made through the admin API", pool);
+        }
+
+        *self.state.write().unwrap() = Arc::new(state);
+        if tls.is_some() {
+            *self.tls.write().unwrap() = tls;
+        }
+        // Also undoes a level set through the admin API
+        logging::set_level(&config.logging.level)?;
+        Ok(config)
+    }
+}
+
+/// The downstream connection a request arrived on.
+#[derive(Debug, Clone)]
+pub(crate) struct ClientInfo {
+    pub(crate) addr: SocketAddr,
+    pub(crate) tls: bool,
+    pub(crate) cert_subject: Option<String>,
+    /// Name of the listener, for picking its routes
+    pub(crate) listener: Arc<str>,
+}
+
+/// An upstream did not answer within one of the configured timeouts.
+#[derive(Debug)]
+struct UpstreamTimeout(&'static str);
+
+impl fmt::Display for UpstreamTimeout {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(f, "{} timed out", self.0)
+    }
+}
+
+impl std::error::Error for UpstreamTimeout {}
+
+/// The request body exceeded the configured size limit.
+#[derive(Debug)]
+struct BodyTooLarge;
+
+impl fmt::Display for BodyTooLarge {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(f, "request body too large")
+    }
+}
+
+impl std::error::Error for BodyTooLarge {}
+
+/// The upstream response exceeded the configured size limit.
+#[derive(Debug)]
+struct ResponseTooLarge(u64);
+
+impl fmt::Display for ResponseTooLarge {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(f, "upstream response larger than {} bytes", self.0)
+    }
+}
+
+impl std::error::Error for ResponseTooLarge {}
+
+fn is_body_too_large(err: &(dyn std::error::Error + 'static)) -> bool {
+    let mut source = Some(err);
+    while let Some(err) = source {
+        if err.is::<BodyTooLarge>() {
+            return true;
+        }
+        source = err.source();
+    }
+    false
+}
+
+/// Whether an error means the pool is at its concurrency limit and could not queue the request.
+fn is_overloaded(err: &(dyn std::error::Error + 'static)) -> bool {
+    matches!(err.downcast_ref::<AcquireError>(), Some(AcquireError::QueueFull | AcquireError::QueueTimeout))
+}
+
+fn log_upstream_failure(err: &(dyn std::error::Error + 'static), request_id: Option<&str>) {
+    warn!(request_id, "Upstream request failed: {}", err);
+}
+
+/// Whether the pool had no upstream in rotation for the request, as when it
+/// has none at all.
+fn is_unavailable(err: &(dyn std::error::Error + 'static)) -> bool {
+    matches!(err.downcast_ref::<AcquireError>(), Some(AcquireError::Unavailable))
+}
+
+/// Passes `body` through, failing with the error `too_large` makes once more than `limit` bytes arrived.
+fn limit_body(body: Body, limit: u64, too_large: impl Fn() -> BoxError + Send + 'static) -> Body {
+    let mut received = 0u64;
+    Body::wrap_stream(body.map(move |chunk| {
+        let chunk = chunk?;
+        received += chunk.len() as u64;
+        if received > limit {
+            return Err(too_large());
+        }
+        Ok(chunk)
+    }))
+}
+
+/// Passes `body` through, failing once `deadline` passes before it has ended.
+fn deadline_body(body: Body, deadline: tokio::time::Instant) -> Body {
+    Body::wrap_stream(futures_util::stream::unfold(Some(body), move |body| async move {
+        let mut body = body?;
+        match tokio::time::timeout_at(deadline, body.next()).await {
+            Ok(Some(chunk)) => Some((chunk.map_err(BoxError::from), Some(body))),
+            Ok(None) => None,
+            Err(_) => Some((Err(UpstreamTimeout("request").into()), None)),
+        }
+    }))
+}
+
+/// Whether an error was caused by a timeout, including connect timeouts
+/// reported by the HTTP client as I/O errors.
+fn is_timeout(err: &(dyn std::error::Error + 'static)) -> bool {
+    let mut source = Some(err);
+    while let Some(err) = source {
+        if err.is::<UpstreamTimeout>() {
+            return true;
+        }
+        if let Some(io) = err.downcast_ref::<std::io::Error>() {
+            if io.kind() == std::io::ErrorKind::TimedOut {
+                return true;
+            }
+        }
+        source = err.source();
+    }
+    false
+}
+
+/// Proxies the incoming request to the upstream server.
+async fn handle_proxy(mut req: Request<Body>, client: ClientInfo, state: &ProxyState, route: Option<&Route>, pool: &Pool, metrics: &Arc<Metrics>) -> Result<Response<Body>, BoxError> {
+    let balancer = &pool.balancer;
+
+    // Upgrade requests (e.g. WebSocket) keep their upgrade headers and are tunneled after a 101
+    let upgrade = headers::upgrade_protocol(req.headers());
+    let client_upgrade = upgrade.as_ref().map(|_| hyper::upgrade::on(&mut req));
+
+    // Oversized bodies are rejected up front when declared, and cut off while streaming otherwise
+    let max_body_size = route.and_then(|route| route.max_body_size).unwrap_or(state.max_body_size);
+    if max_body_size > 0 {
+        let declared = req.headers().get(CONTENT_LENGTH).and_then(|v| v.to_str().ok()).and_then(|v| v.parse::<u64>().ok());
+        if declared.is_some_and(|len| len > max_body_size) {
+            return Err(BodyTooLarge.into());
+        }
+    }
+
+    // Forward the client's headers minus hop-by-hop ones, then identify the client
+    let (parts, body) = req.into_parts();
+    let body = if max_body_size > 0 { limit_body(body, max_body_size, || BodyTooLarge.into()) } else { body };
+    let mut headers = parts.headers;
+    let accepts_trailers = headers::accepts_trailers(&headers);
+    headers::strip_hop_by_hop(&mut headers);
+    // `TE: trailers` is end to end for HTTP/2, where gRPC servers expect it
+    if accepts_trailers {
+        headers.insert(TE, HeaderValue::from_static("trailers"));
+    }
+    if !headers.contains_key(HOST) {
+        // HTTP/2 clients send the host as the :authority pseudo-header instead
+        if let Some(authority) = parts.uri.authority() {
+            headers.insert(HOST, HeaderValue::from_str(authority.as_str())?);
+        }
+    }
+    state.headers.request.apply(&mut headers);
+    if let Some(route) = route {
+        route.headers.request.apply(&mut headers);
+    }
+    if let Some(protocol) = &upgrade {
+        headers::set_upgrade(&mut headers, protocol.clone());
+    }
+    if pool.host_header == HostHeader::Upstream && !headers.contains_key("x-forwarded-host") {
+        if let Some(host) = headers.get(HOST).cloned() {
+            headers.insert("x-forwarded-host", host);
+        }
+    }
+
+    // Upstream the client is pinned to by its sticky session cookie, if any
+    let sticky_id = pool.sticky_cookie.as_deref().and_then(|name| headers::cookie_value(&headers, name));
+
+    // HTTP/2 requests carry an absolute URI, so only the path and query are appended
+    let path_and_query = parts.uri.path_and_query().map(|p| p.as_str()).unwrap_or("/");
+    let path_and_query = match route {
+        Some(route) => route.upstream_path(path_and_query),
+        None => path_and_query.into(),
+    };
+
+    // Idempotent requests may be retried, and mirrored requests are sent twice;
+    // their body is buffered so it can be replayed
+    let retry = &pool.retry;
+    let attempts = if upgrade.is_none() && retry.allows_method(&parts.method) { retry.retries + 1 } else { 1 };
+    let mirror = route.and_then(|route| route.mirror.as_ref()).filter(|mirror| upgrade.is_none() && mirror.take());
+    let mut body = Some(body);
+    let replay_body = if attempts > 1 || mirror.is_some() {
+        Some(hyper::body::to_bytes(body.take().expect("body not yet consumed")).await?)
+    } else {
+        None
+    };
+
+    if let (Some(mirror), Some(bytes)) = (mirror, &replay_body) {
+        let shadow = &state.pools[mirror.pool];
+        let mut copy = Request::builder().method(parts.method.clone()).uri(path_and_query.as_ref()).body(Body::from(bytes.clone()))?;
+        *copy.headers_mut() = headers.clone();
+        spawn_mirror(shadow, client.addr, copy, state.attempt_timeout(route, shadow), Arc::clone(metrics));
+    }
+
+    let mut tried = Vec::new();
+    let mut attempt = 0;
+    // The outcome of the previous attempt, which stands when no upstream is left to retry on
+    let mut previous = None;
+    let (mut res, upstream, guard, timings) = loop {
+        attempt += 1;
+        if attempt > 1 {
+            tokio::time::sleep(retry.backoff_for(attempt - 1)).await;
+        }
+
+        // Pick an upstream server; the guard tracks the request as in flight until dropped
+        let queued = Instant::now();
+        let guard = match (balancer.acquire(client.addr.ip(), sticky_id.as_deref(), &tried).await, previous.take()) {
+            (Ok(guard), _) => guard,
+            (Err(_), Some(Ok((res, upstream, timings)))) => break (res, upstream, None, timings),
+            (Err(_), Some(Err(e))) => return Err(e),
+            (Err(e), None) => return Err(e.into()),
+        };
+        let queue = queued.elapsed();
+        let upstream_server = guard.upstream().label();
+        let http_client = pool.http_client(client.addr, guard.upstream());
+
+        // Construct the URI correctly
+        let uri_string = format!("{}{}", guard.upstream().request_url(), path_and_query);
+        let uri: Uri = uri_string.parse()?;
+
+        let attempt_body = match &replay_body {
+            Some(bytes) => Body::from(bytes.clone()),
+            None => body.take().expect("body is only sent once without retries"),
+        };
+
+        let mut proxy_req = Request::builder()
+            .method(parts.method.clone())
+            .uri(uri)
+            .body(attempt_body)?;
+        *proxy_req.headers_mut() = headers.clone();
+        if pool.host_header == HostHeader::Upstream {
+            if let Some(authority) = proxy_req.uri().authority() {
+                let host = HeaderValue::from_str(authority.as_str())?;
+                proxy_req.headers_mut().insert(HOST, host);
+            }
+        }
+
+        // Connection errors and 5xx responses count against the upstream for passive ejection
+        let started = Instant::now();
+        let result = match state.attempt_timeout(route, pool) {
+            Some(limit) => match tokio::time::timeout(limit, http_client.request(proxy_req)).await {
+                Ok(result) => result.map_err(BoxError::from),
+                Err(_) => Err(UpstreamTimeout("upstream response").into()),
+            },
+            None => http_client.request(proxy_req).await.map_err(BoxError::from),
+        };
+
+        match result {
+            Ok(res) => {
+                debug!("{} {} answered {} by {} in {:?}", parts.method, path_and_query, res.status(), upstream_server, started.elapsed());
+                metrics.observe_upstream_latency(&upstream_server, started.elapsed());
+                balancer.record_latency(guard.upstream(), started.elapsed());
+                // The outcome of a gRPC call is in its status, which may only come with the trailers
+                if !(grpc::is_grpc(res.headers()) && res.status() == StatusCode::OK) {
+                    balancer.record_result(guard.upstream(), !res.status().is_server_error());
+                }
+                // A connection opened before this attempt was reused, at no cost to it
+                let connect = res.extensions().get::<ConnectTiming>().filter(|timing| timing.started >= started).map(|timing| timing.took);
+                let timings = UpstreamTimings { attempts: attempt, queue, connect, ttfb: started.elapsed() };
+                if attempt < attempts && RetryPolicy::is_retryable_status(res.status()) {
+                    warn!("Upstream {} returned {}, retrying", upstream_server, res.status());
+                    tried.push(Arc::clone(guard.upstream()));
+                    // Without the guard, so the upstream's slot is free for the retry
+                    previous = Some(Ok((res, Arc::clone(guard.upstream()), timings)));
+                    continue;
+                }
+                break (res, Arc::clone(guard.upstream()), Some(guard), timings);
+            }
+            Err(e) if is_body_too_large(e.as_ref()) => return Err(e),
+            Err(e) => {
+                balancer.record_result(guard.upstream(), false);
+                balancer.record_failed_latency(guard.upstream(), started.elapsed());
+                if attempt < attempts {
+                    warn!("Upstream {} failed ({}), retrying", upstream_server, e);
+                    tried.push(Arc::clone(guard.upstream()));
+                    previous = Some(Err(e));
+                    continue;
+                }
+                return Err(e);
+            }
+        }
+    };
+
+    // Likewise oversized responses, which would otherwise tie up the client's
+    // connection; gRPC streams are left whole, as rewrapping drops their trailers
+    let max_response_size = route.and_then(|route| route.max_response_size).unwrap_or(state.max_response_size);
+    if max_response_size > 0 && res.status() != StatusCode::SWITCHING_PROTOCOLS && !grpc::is_grpc(res.headers()) {
+        let declared = res.headers().get(CONTENT_LENGTH).and_then(|v| v.to_str().ok()).and_then(|v| v.parse::<u64>().ok());
+        if declared.is_some_and(|len| len > max_response_size) {
+            return Err(ResponseTooLarge(max_response_size).into());
+        }
+        let label = upstream.label();
+        let body = std::mem::take(res.body_mut());
+        *res.body_mut() = limit_body(body, max_response_size, move || {
+            warn!("Response from {} cut off after {} bytes", label, max_response_size);
+            ResponseTooLarge(max_response_size).into()
+        });
+    }
+
+    let upstream_upgrade = headers::upgrade_protocol(res.headers());
+    headers::strip_hop_by_hop(res.headers_mut());
+    let public = headers.get(HOST).and_then(|host| host.to_str().ok()).map(|host| format!("{}://{}", if client.tls { "https" } else { "http" }, host));
+    let rewrite = route.and_then(|route| route.response_rewrite.as_ref()).unwrap_or(&state.response_rewrite);
+    rewrite.apply(res.headers_mut(), &upstream.request_url(), public.as_deref());
+    state.headers.response.apply(res.headers_mut());
+    if let Some(route) = route {
+        route.headers.response.apply(res.headers_mut());
+    }
+    res.extensions_mut().insert(UpstreamUsed(upstream.label()));
+    res.extensions_mut().insert(timings);
+    if grpc::is_grpc(res.headers()) && res.status() == StatusCode::OK {
+        let (balancer, upstream, metrics, pool) = (Arc::clone(balancer), Arc::clone(&upstream), Arc::clone(metrics), pool.name.clone());
+        grpc::observe_status(&mut res, move |code| {
+            balancer.record_result(&upstream, !grpc::is_failure(code));
+            metrics.record_grpc_status(&pool, code);
+        });
+    }
+    if route.is_some_and(|route| route.sse) || headers::is_event_stream(res.headers()) {
+        res.extensions_mut().insert(EventStream);
+        // Nor should a proxy in front of Riffy hold the events back
+        res.headers_mut().entry("x-accel-buffering").or_insert(HeaderValue::from_static("no"));
+    }
+
+    // (Re-)pin the client when it had no session or its upstream failed over
+    if let Some(cookie) = &pool.sticky_cookie {
+        if sticky_id.as_deref() != Some(upstream.id.as_str()) {
+            let value = format!("{}={}; Path=/; HttpOnly", cookie, upstream.id);
+            res.headers_mut().append(SET_COOKIE, HeaderValue::from_str(&value)?);
+        }
+    }
+
+    if res.status() == StatusCode::SWITCHING_PROTOCOLS {
+        if let (Some(client_upgrade), Some(protocol)) = (client_upgrade, upstream_upgrade) {
+            headers::set_upgrade(res.headers_mut(), protocol);
+            let upstream_io = hyper::upgrade::on(&mut res);
+
+            // Copy bytes both ways once each side has switched protocols; the guard
+            // keeps the tunnel counted as in flight for least-connections balancing
+            tokio::spawn(async move {
+                let _guard = guard;
+                match (client_upgrade.await, upstream_io.await) {
+                    (Ok(mut downstream), Ok(mut upstream)) => {
+                        if let Err(e) = tokio::io::copy_bidirectional(&mut downstream, &mut upstream).await {
+                            warn!("Upgraded connection error: {}", e);
+                        }
+                    }
+                    (Err(e), _) | (_, Err(e)) => warn!("Connection upgrade failed: {}", e),
+                }
+            });
+        }
+    }
+
+    Ok(res)
+}
+
+/// Builds a [`Riffy`] proxy from a [`Config`]:
+///
+/// ```no_run
+/// # async fn run() -> Result<(), Box<dyn std::error::Error + Send + Sync>> {
+/// let config = riffy::Config::load(Some("riffy.toml"))?;
+/// let proxy = riffy::ProxyBuilder::new(config).config_path("riffy.toml").build()?;
+/// proxy.serve().await
+/// # }
+/// ```
+pub struct ProxyBuilder {
+    config: Config,
+    config_path: Option<String>,
+    overrides: Overrides,
+    middleware: Vec<Arc<dyn Middleware>>,
+}
+
+impl ProxyBuilder {
+    pub fn new(config: Config) -> Self {
+        ProxyBuilder { config, config_path: None, overrides: Overrides::default(), middleware: Vec::new() }
+    }
+
+    /// Adds middleware that runs after the built-in middleware.
+    pub fn middleware(mut self, middleware: impl Middleware + 'static) -> Self {
+        self.middleware.push(Arc::new(middleware));
+        self
+    }
+
+    /// Config file re-read on SIGHUP; without one, a reload only re-applies environment variables.
+    pub fn config_path(mut self, path: impl Into<String>) -> Self {
+        self.config_path = Some(path.into());
+        self
+    }
+
+    /// Settings, e.g. from command line flags, applied over the config file
+    /// and environment again on every reload.
+    pub fn overrides(mut self, overrides: Overrides) -> Self {
+        self.overrides = overrides;
+        self
+    }
+
+    /// Validates the config and loads upstreams, certificates and the access log.
+    pub fn build(self) -> Result<Riffy, String> {
+        let config = self.config;
+        config.validate()?;
+
+        let state = ProxyState::from_config(&config, None, &self.middleware)?;
+        let tls = if config.serves_tls() {
+            Some(tls::load_acceptor(&config.tls).map_err(|e| format!("TLS error: {}", e))?)
+        } else {
+            None
+        };
+
+        let runtime = Arc::new(Runtime {
+            state: RwLock::new(Arc::new(state)),
+            tls: RwLock::new(tls),
+            metrics: Arc::new(Metrics::new()),
+            connections: ConnectionLimiter::new(),
+            custom_middleware: self.middleware,
+            admin_token: config.admin.token.clone(),
+            listening: AtomicBool::new(false),
+        });
+
+        Ok(Riffy { runtime, config, config_path: self.config_path, overrides: self.overrides })
+    }
+}
+
+/// A configured proxy, ready to serve.
+pub struct Riffy {
+    runtime: Arc<Runtime>,
+    config: Config,
+    config_path: Option<String>,
+    overrides: Overrides,
+}
+
+impl Riffy {
+    #[cfg(test)]
+    pub(crate) fn runtime(&self) -> &Arc<Runtime> {
+        &self.runtime
+    }
+
+    /// Binds the configured listener port, or Unix socket, and serves until
+    /// accepting fails.
+    pub async fn serve(self) -> Result<(), BoxError> {
+        // Take over the sockets passed by systemd, or those of a running Riffy, before binding any
+        #[cfg(unix)]
+        systemd::adopt_sockets();
+        #[cfg(unix)]
+        if let Some(path) = &self.config.upgrade.socket {
+            handoff::inherit(Path::new(path))?;
+        }
+
+        #[cfg(unix)]
+        if let Some(path) = self.config.listener.socket.clone() {
+            let listener = bind_unix(Path::new(&path), self.config.listener.socket_mode()?)?;
+            return self.serve_unix_listener(listener).await;
+        }
+
+        let addr = self.config.listen_addr();
+        let bind_error = |e| format!("failed to bind {}: {}", addr, e);
+
+        // Create a TCP listener for incoming connections, TLS-wrapped when SSL is enabled
+        #[cfg(unix)]
+        if self.config.listener.acceptors() > 1 {
+            let first = bind_tcp_reuse_port(addr).map_err(bind_error)?;
+            // The others join the first on its port, even when the configured one is 0
+            let addr = first.local_addr()?;
+            let mut listeners = vec![TcpListener::from_std(first)?];
+            for _ in 1..self.config.listener.acceptors() {
+                listeners.push(TcpListener::from_std(bind_tcp_reuse_port(addr).map_err(bind_error)?)?);
+            }
+            return self.serve_listeners(listeners).await;
+        }
+        let listener = TcpListener::from_std(bind_tcp(addr).map_err(bind_error)?)?;
+        self.serve_listener(listener).await
+    }
+
+    /// Serves on an already bound listener, e.g. one on port 0 in tests.
+    pub async fn serve_listener(self, listener: TcpListener) -> Result<(), BoxError> {
+        self.serve_listeners(vec![listener]).await
+    }
+
+    /// Serves on listeners sharing one port, accepting on each in a task of
+    /// its own. Returns when accepting on the last of them fails, or once
+    /// connections have drained after an upgrade.
+    async fn serve_listeners(self, mut listeners: Vec<TcpListener>) -> Result<(), BoxError> {
+        let last = listeners.pop().ok_or("no listener to serve")?;
+        let drain_timeout = Duration::from_secs(self.config.upgrade.drain_timeout);
+        let acceptor = self.start(Bound::Tcp(last.local_addr()?)).await?;
+        for listener in listeners {
+            let acceptor = acceptor.clone();
+            tokio::spawn(async move {
+                while let Some(accepted) = accept(listener.accept()).await {
+                    match accepted {
+                        Ok((stream, peer_addr)) => acceptor.spawn(stream, peer_addr),
+                        Err(e) => return error!("Acceptor stopped accepting: {}", e),
+                    }
+                }
+            });
+        }
+        while let Some(accepted) = accept(last.accept()).await {
+            let (stream, peer_addr) = accepted?;
+            acceptor.spawn(stream, peer_addr);
+        }
+        drain(&acceptor.runtime, drain_timeout).await;
+        Ok(())
+    }
+
+    /// Serves on an already bound Unix socket. Its clients count as
+    /// connecting from 127.0.0.1, unless a PROXY header says otherwise.
+    #[cfg(unix)]
+    pub async fn serve_unix_listener(self, listener: UnixListener) -> Result<(), BoxError> {
+        let addr = listener.local_addr()?;
+        let path = addr.as_pathname().map_or_else(|| "(unnamed)".into(), |path| path.display().to_string());
+        let drain_timeout = Duration::from_secs(self.config.upgrade.drain_timeout);
+        let acceptor = self.start(Bound::Unix(&path)).await?;
+        while let Some(accepted) = accept(listener.accept()).await {
+            let (stream, _) = accepted?;
+            acceptor.spawn(stream, UNIX_PEER);
+        }
+        drain(&acceptor.runtime, drain_timeout).await;
+        Ok(())
+    }
+
+    /// Starts everything that runs next to the listener, such as the admin
+    /// server, the `[[listeners]]` and the reload handler, returning what
+    /// serves the listener's connections.
+    async fn start(self, bound: Bound<'_>) -> Result<Acceptor, BoxError> {
+        let Riffy { runtime, config, config_path, overrides } = self;
+
+        for listener in &config.listeners {
+            let acceptor = Acceptor {
+                runtime: Arc::clone(&runtime),
+                mode: ListenerMode::Http,
+                proxy_protocol: listener.proxy_protocol,
+                tls: listener.tls,
+                listener: Arc::from(listener.name.as_str()),
+            };
+            let scheme = if listener.tls { "https" } else { "http" };
+            match (&listener.socket, listener.addr()) {
+                #[cfg(unix)]
+                (Some(path), _) => {
+                    let bound = bind_unix(Path::new(path), listener.socket_mode()?)?;
+                    info!("Listening on unix:{} ({}, listener {})", path, scheme, listener.name);
+                    tokio::spawn(async move {
+                        while let Some(accepted) = accept(bound.accept()).await {
+                            match accepted {
+                                Ok((stream, _)) => acceptor.spawn(stream, UNIX_PEER),
+                                Err(e) => return error!("Listener {} stopped accepting: {}", acceptor.listener, e),
+                            }
+                        }
+                    });
+                }
+                (_, Some(addr)) => {
+                    let bound = TcpListener::from_std(bind_tcp(addr).map_err(|e| format!("failed to bind {} for listener {}: {}", addr, listener.name, e))?)?;
+                    info!("Listening on {}://{} (listener {})", scheme, addr, listener.name);
+                    tokio::spawn(async move {
+                        while let Some(accepted) = accept(bound.accept()).await {
+                            match accepted {
+                                Ok((stream, peer_addr)) => acceptor.spawn(stream, peer_addr),
+                                Err(e) => return error!("Listener {} stopped accepting: {}", acceptor.listener, e),
+                            }
+                        }
+                    });
+                }
+                _ => return Err(format!("listener {} has neither a port nor a socket", listener.name).into()),
+            }
+        }
+
+        // Optional admin server for metrics and upstream management on a separate port
+        if let Some(admin_port) = config.admin.port {
+            let admin_addr = SocketAddr::new(config.admin.address.unwrap_or_default().0, admin_port);
+            // Bound here rather than in its task, so an upgrade hands over the port it inherited
+            match bind_tcp(admin_addr) {
+                Ok(listener) => {
+                    tokio::spawn(admin::serve(listener, admin_addr, Arc::clone(&runtime)));
+                }
+                Err(e) => error!("Admin server failed to bind {}: {}", admin_addr, e),
+            }
+        }
+
+        // Optional plain HTTP listener redirecting to this one
+        if let (true, Bound::Tcp(addr)) = (config.redirect.enabled, &bound) {
+            let redirect_addr = SocketAddr::new(addr.ip(), config.redirect.port);
+            let acme_dir = config.redirect.acme_challenge_dir.as_ref().map(PathBuf::from);
+            match bind_tcp(redirect_addr) {
+                Ok(listener) => {
+                    tokio::spawn(redirect::serve(listener, redirect_addr, addr.port(), acme_dir));
+                }
+                Err(e) => error!("Redirect server failed to bind {}: {}", redirect_addr, e),
+            }
+        }
+
+        // Optional HTTP/3 listener on the same port over UDP
+        #[cfg(feature = "http3")]
+        if let (true, Bound::Tcp(addr)) = (config.http3.enabled, &bound) {
+            let quic = runtime.tls.read().unwrap().as_ref().map(ServerTls::quic_config);
+            if let Some(quic) = quic {
+                let quic_addr = SocketAddr::new(addr.ip(), config.http3.port(addr.port()));
+                match quinn::Endpoint::server(quic, quic_addr) {
+                    Ok(endpoint) => {
+                        tokio::spawn(crate::http3::serve(endpoint, Arc::clone(&runtime)));
+                    }
+                    Err(e) => error!("Failed to bind HTTP/3 listener on udp://{}: {}", quic_addr, e),
+                }
+            }
+        }
+
+        let mode = config.listener.mode;
+        let (scheme, suffix) = match mode {
+            ListenerMode::Tcp => ("tcp", ""),
+            ListenerMode::TlsPassthrough => ("tcp", " (TLS passthrough)"),
+            ListenerMode::Http if config.tls.enabled => ("https", ""),
+            ListenerMode::Http => ("http", ""),
+        };
+        match bound {
+            Bound::Tcp(addr) => info!("Listening on {}://{}{}", scheme, addr, suffix),
+            Bound::Unix(path) => info!("Listening on unix:{} ({}{})", path, scheme, suffix),
+        }
+
+        // Pick up renewed certificates without waiting for a SIGHUP
+        if let (true, Some(interval)) = (config.serves_tls(), config.tls.watch_interval) {
+            spawn_certificate_watcher(Arc::clone(&runtime), Duration::from_secs(interval));
+        }
+
+        let (proxy_protocol, tls) = (config.listener.proxy_protocol, config.tls.enabled);
+
+        // Everything is bound: let the old process, if any, drain, and wait for the next upgrade
+        #[cfg(unix)]
+        handoff::discard_unused();
+        #[cfg(unix)]
+        if let Some(path) = &config.upgrade.socket {
+            handoff::serve(PathBuf::from(path))?;
+        }
+        #[cfg(unix)]
+        privileges::drop_to(config.process.user.as_deref(), config.process.group.as_deref())?;
+
+        // Reload upstreams and certificates on SIGHUP
+        spawn_reload_handler(Arc::clone(&runtime), config, config_path, overrides);
+        spawn_reopen_handler(Arc::clone(&runtime));
+
+        runtime.listening.store(true, Ordering::Relaxed);
+        #[cfg(unix)]
+        {
+            systemd::notify_ready();
+            systemd::spawn_watchdog();
+        }
+        Ok(Acceptor { runtime, mode, proxy_protocol, tls, listener: Arc::from(DEFAULT_LISTENER) })
+    }
+}
+
+/// Accepts the next connection, or returns `None` once a new process has
+/// taken over the listening sockets.
+async fn accept<T>(accepting: impl Future<Output = std::io::Result<T>>) -> Option<std::io::Result<T>> {
+    tokio::select! {
+        accepted = accepting => Some(accepted),
+        _ = handoff::handed_over() => None,
+    }
+}
+
+/// Waits, up to `timeout`, for the connections left open after an upgrade to finish.
+async fn drain(runtime: &Runtime, timeout: Duration) {
+    let deadline = Instant::now() + timeout;
+    while runtime.metrics.active_connections() > 0 && Instant::now() < deadline {
+        tokio::time::sleep(Duration::from_millis(100)).await;
+    }
+    match runtime.metrics.active_connections() {
+        0 => info!("Connections drained, exiting"),
+        open => info!("Drain timeout reached with {} connections open, exiting", open),
+    }
+}
+
+/// Where the listener is bound.
+enum Bound<'a> {
+    Tcp(SocketAddr),
+    Unix(&'a str),
+}
+
+/// The client address given to connections on a Unix socket.
+const UNIX_PEER: SocketAddr = SocketAddr::new(IpAddr::V4(std::net::Ipv4Addr::LOCALHOST), 0);
+
+/// Serves the connections accepted by a listener, each in a task of its own.
+#[derive(Clone)]
+struct Acceptor {
+    runtime: Arc<Runtime>,
+    mode: ListenerMode,
+    proxy_protocol: bool,
+    /// Terminate TLS on the connections
+    tls: bool,
+    listener: Arc<str>,
+}
+
+impl Acceptor {
+    fn spawn<S>(&self, mut stream: S, mut peer_addr: SocketAddr)
+    where
+        S: AsyncRead + AsyncWrite + Unpin + Send + 'static,
+    {
+        let (mode, proxy_protocol) = (self.mode, self.proxy_protocol);
+        let tls_acceptor = self.runtime.tls_acceptor().filter(|_| self.tls);
+        let runtime = Arc::clone(&self.runtime);
+        let listener = Arc::clone(&self.listener);
+
+        // IPv4 clients of a dual-stack listener are known by their IPv4 address
+        peer_addr.set_ip(peer_addr.ip().to_canonical());
+
+        tokio::spawn(async move {
+            runtime.metrics.connection_opened();
+
+            // Behind an L4 balancer the real client address comes from the PROXY header
+            if proxy_protocol {
+                let header = match tokio::time::timeout(PROXY_HEADER_TIMEOUT, proxy_protocol::read_header(&mut stream)).await {
+                    Ok(header) => header.map_err(|e| e.to_string()),
+                    Err(_) => Err("timed out waiting for PROXY header".to_string()),
+                };
+                match header {
+                    Ok(Some(source)) => peer_addr = source,
+                    Ok(None) => {}
+                    Err(e) => {
+                        warn!("Rejected connection from {}: {}", peer_addr, e);
+                        runtime.metrics.connection_closed();
+                        return;
+                    }
+                }
+            }
+
+            // Counted by the real client address, and held until the connection closes
+            let state = runtime.state();
+            let _connection = match runtime.connections.open(peer_addr.ip(), state.max_connections_per_ip) {
+                Some(connection) => connection,
+                None => {
+                    warn!("Rejected connection from {}: too many open connections from this address", peer_addr);
+                    runtime.metrics.record_connection_rejected();
+                    runtime.metrics.connection_closed();
+                    return;
+                }
+            };
+            let header_timeout = state.header_timeout;
+            // Without HTTP there are no forwarded headers; the peer is the client
+            if mode != ListenerMode::Http && !state.access.permits(peer_addr.ip()) {
+                warn!("Rejected connection from {}: address not allowed", peer_addr);
+                runtime.metrics.connection_closed();
+                return;
+            }
+            drop(state);
+
+            let client = ClientInfo { addr: peer_addr, tls: tls_acceptor.is_some(), cert_subject: None, listener };
+            match (mode, tls_acceptor) {
+                (ListenerMode::Tcp, _) => proxy_tcp(stream, peer_addr, &runtime.state().pools[0], &[]).await,
+                (ListenerMode::TlsPassthrough, _) => proxy_tls_passthrough(stream, peer_addr, runtime.state()).await,
+                (ListenerMode::Http, Some(tls_acceptor)) => match handshake(tls_acceptor.accept(stream), header_timeout).await {
+                    Ok(stream) => {
+                        // Serve HTTP/2 when the client negotiated it via ALPN
+                        let session = stream.get_ref().1;
+                        let http2 = session.get_alpn_protocol() == Some(b"h2".as_ref());
+                        let cert_subject = session.get_peer_certificates().and_then(|certs| tls::client_cert_subject(&certs));
+                        serve_connection(stream, ClientInfo { cert_subject, ..client }, http2, header_timeout, Arc::clone(&runtime)).await
+                    }
+                    Err(e) => {
+                        runtime.metrics.record_tls_handshake_failure();
+                        warn!("Failed to accept TLS connection: {:?}", e);
+                    }
+                },
+                (ListenerMode::Http, None) => serve_connection(stream, client, false, header_timeout, Arc::clone(&runtime)).await,
+            }
+
+            runtime.metrics.connection_closed();
+        });
+    }
+}
+
+/// Reads the client's TLS ClientHello and forwards the still-encrypted
+/// stream to the pool routed to by its SNI hostname.
+async fn proxy_tls_passthrough<S: AsyncRead + AsyncWrite + Unpin>(mut downstream: S, client: SocketAddr, state: Arc<ProxyState>) {
+    let (hello, sni) = match tokio::time::timeout(CLIENT_HELLO_TIMEOUT, sni::read_client_hello(&mut downstream)).await {
+        Ok(Ok(hello)) => hello,
+        Ok(Err(e)) => {
+            warn!("Rejected connection from {}: {}", client, e);
+            return;
+        }
+        Err(_) => {
+            warn!("Rejected connection from {}: timed out waiting for TLS ClientHello", client);
+            return;
+        }
+    };
+    let route = state.router.route_host(sni.as_deref());
+    if !route.is_none_or(|route| route.access.permits(client.ip())) {
+        warn!("Rejected connection from {}: address not allowed for {}", client, sni.as_deref().unwrap_or("the route"));
+        return;
+    }
+    let pool = state.select_pool(route);
+    proxy_tcp(downstream, client, pool, &hello).await
+}
+
+/// Forwards a raw TCP connection to an upstream of `pool`, sending `preamble`
+/// (bytes already read from the client) first. Upstreams that refuse the
+/// connection are retried like failed HTTP requests.
+async fn proxy_tcp<S: AsyncRead + AsyncWrite + Unpin>(mut downstream: S, client: SocketAddr, pool: &Pool, preamble: &[u8]) {
+    let mut tried = Vec::new();
+    for attempt in 1..=pool.retry.retries + 1 {
+        if attempt > 1 {
+            tokio::time::sleep(pool.retry.backoff_for(attempt - 1)).await;
+        }
+
+        // Waits in the pool's queue like an HTTP request when every upstream is at its limit
+        let guard = match pool.balancer.acquire(client.ip(), None, &tried).await {
+            Ok(guard) => guard,
+            Err(e) => {
+                warn!("Rejected connection from {}: {}", client, e);
+                return;
+            }
+        };
+        let upstream = guard.upstream();
+        let uri: Uri = match upstream.request_url().parse() {
+            Ok(uri) => uri,
+            Err(e) => {
+                warn!("Invalid upstream address {}: {}", upstream.url, e);
+                return;
+            }
+        };
+
+        match pool.connector.for_upstream(upstream).tcp(Some(client)).call(uri).await {
+            Ok(mut upstream) => {
+                pool.balancer.record_result(guard.upstream(), true);
+                if let Err(e) = upstream.write_all(preamble).await {
+                    warn!("TCP connection error: {}", e);
+                    return;
+                }
+                // The guard keeps the connection counted as in flight until either side closes
+                if let Err(e) = tokio::io::copy_bidirectional(&mut downstream, &mut upstream).await {
+                    warn!("TCP connection error: {}", e);
+                }
+                return;
+            }
+            Err(e) => {
+                warn!("Upstream {} failed ({})", upstream.label(), e);
+                pool.balancer.record_result(guard.upstream(), false);
+                tried.push(Arc::clone(guard.upstream()));
+            }
+        }
+    }
+}
+
+/// Completes a TLS handshake, giving up after `limit` so clients cannot hold
+/// connections open by stalling it.
+async fn handshake<F, S>(accept: F, limit: Option<Duration>) -> std::io::Result<S>
+where
+    F: Future<Output = std::io::Result<S>>,
+{
+    match limit {
+        Some(limit) => tokio::time::timeout(limit, accept).await.unwrap_or_else(|_| Err(std::io::Error::new(std::io::ErrorKind::TimedOut, "handshake timed out"))),
+        None => accept.await,
+    }
+}
+
+/// Serves HTTP on an accepted (and possibly TLS-wrapped) client connection.
+/// With `header_timeout`, HTTP/1 clients that take longer to send a
+/// request's headers are disconnected.
+async fn serve_connection<S>(stream: S, client: ClientInfo, http2: bool, header_timeout: Option<Duration>, runtime: Arc<Runtime>)
+where
+    S: AsyncRead + AsyncWrite + Unpin + Send + 'static,
+{
+    let service = service_fn(move |req| proxy(req, client.clone(), Arc::clone(&runtime)));
+
+    let mut http = Http::new();
+    http.http2_only(http2);
+    if let Some(limit) = header_timeout {
+        http.http1_header_read_timeout(limit);
+    }
+    let connection = http.serve_connection(stream, service).with_upgrades();
+    tokio::pin!(connection);
+    let result = tokio::select! {
+        result = connection.as_mut() => result,
+        // Once a new process has taken over, idle connections close and busy ones after their response
+        _ = handoff::handed_over() => {
+            connection.as_mut().graceful_shutdown();
+            connection.await
+        }
+    };
+    if let Err(e) = result {
+        error!("Server error: {}", e);
+    }
+}
+
+/// Sends a copy of a request to an upstream in the shadow pool in the
+/// background. The response is read and discarded; only its outcome is counted.
+fn spawn_mirror(shadow: &Pool, client: SocketAddr, mut req: Request<Body>, timeout: Option<Duration>, metrics: Arc<Metrics>) {
+    let pool = shadow.name.clone();
+    let guard = match shadow.balancer.select(client.ip(), None, &[]) {
+        Some(guard) => guard,
+        None => return metrics.record_mirror(&pool, false),
+    };
+    let uri = match format!("{}{}", guard.upstream().request_url(), req.uri()).parse::<Uri>() {
+        Ok(uri) => uri,
+        Err(_) => return metrics.record_mirror(&pool, false),
+    };
+    if shadow.host_header == HostHeader::Upstream {
+        if let Some(host) = uri.authority().and_then(|authority| HeaderValue::from_str(authority.as_str()).ok()) {
+            req.headers_mut().insert(HOST, host);
+        }
+    }
+    *req.uri_mut() = uri;
+
+    let http_client = shadow.http_client(client, guard.upstream());
+    let balancer = Arc::clone(&shadow.balancer);
+    tokio::spawn(async move {
+        // Reading the body lets the connection be reused
+        let exchange = async {
+            let res = http_client.request(req).await?;
+            let ok = !res.status().is_server_error();
+            hyper::body::to_bytes(res.into_body()).await?;
+            Ok::<_, hyper::Error>(ok)
+        };
+        let ok = match timeout {
+            Some(limit) => matches!(tokio::time::timeout(limit, exchange).await, Ok(Ok(true))),
+            None => matches!(exchange.await, Ok(true)),
+        };
+        balancer.record_result(guard.upstream(), ok);
+        metrics.record_mirror(&pool, ok);
+    });
+}
+
+/// Proxies a request with the current state through the middleware chain
+/// and records request metrics.
+pub(crate) async fn proxy(mut req: Request<Body>, client: ClientInfo, runtime: Arc<Runtime>) -> Result<Response<Body>, BoxError> {
+    // Everything from routing to the upstream sees the path the route matched
+    if let Cow::Owned(path) = router::normalize_path(req.uri().path()) {
+        let path_and_query = match req.uri().query() {
+            Some(query) => format!("{}?{}", path, query),
+            None => path,
+        };
+        let mut parts = req.uri().clone().into_parts();
+        parts.path_and_query = path_and_query.parse().ok();
+        if let Ok(uri) = Uri::from_parts(parts) {
+            *req.uri_mut() = uri;
+        }
+    }
+
+    let state = runtime.state();
+
+    // Probes are answered locally, ahead of rate limiting and the other middleware
+    if state.probes.enabled {
+        if let Some(res) = probes::answer(&req, &runtime, &state) {
+            return Ok(res);
+        }
+    }
+
+    // Behind trusted proxies, logging, rate limits, access lists and balancing all use the forwarded client
+    let peer = client.addr;
+    let client = ClientInfo { addr: SocketAddr::new(acl::client_ip(req.headers(), peer.ip(), &state.trusted_proxies, state.trusted_proxies_header), peer.port()), ..client };
+
+    let mut ctx = Context::new(client.addr, client.tls);
+    ctx.peer_addr = peer;
+    ctx.listener = Arc::clone(&client.listener);
+    ctx.client_cert_subject = client.cert_subject.clone();
+    ctx.background = req.extensions().get::<Revalidation>().is_some();
+    // Kept for picking the error page format once the request has been sent on
+    let accept = req.headers().get(ACCEPT).cloned();
+    // The HTTP/3 listener serves the routes of the main one
+    let advertise_h3 = client.tls && &*client.listener == DEFAULT_LISTENER;
+
+    // Middleware may answer the request itself, e.g. when rate limiting; clients the listener
+    // does not allow get nothing from it, not even cache hits
+    let mut ran = 0;
+    let mut early = None;
+    if !state.access.permits(client.addr.ip()) {
+        early = Some(Response::builder().status(StatusCode::FORBIDDEN).body(Body::from("Forbidden"))?);
+    } else {
+        for middleware in &state.middleware {
+            ran += 1;
+            if let Some(res) = middleware.on_request(&mut req, &mut ctx).await {
+                early = Some(res);
+                break;
+            }
+        }
+    }
+
+    // A stale cache hit is refreshed in the background, by a request of its own
+    if let Some(revalidate) = ctx.extensions.remove::<Revalidate>() {
+        tokio::spawn(revalidate_cache(revalidate.into_request(), ClientInfo { addr: peer, ..client.clone() }, Arc::clone(&runtime)));
+    }
+
+    let mut bandwidth = &state.bandwidth;
+    let result = match early {
+        Some(res) => Ok(res),
+        None => {
+            // Routed after the middleware, which may have changed the request
+            let route = state.router.route(&req, &client.listener);
+            bandwidth = route.and_then(|route| route.bandwidth.as_ref()).unwrap_or(&state.bandwidth);
+            let result = if let Some((route, files)) = route.and_then(|route| Some((route, route.static_files.as_ref()?))) {
+                Ok(files.serve(&req, &route.upstream_path(req.uri().path())).await)
+            } else {
+                let pool = state.select_pool(route);
+                match state.request_timeout(route, pool) {
+                    // The response body is held to what is left of the limit once the headers are in
+                    Some(limit) => {
+                        let deadline = tokio::time::Instant::now() + limit;
+                        match tokio::time::timeout_at(deadline, handle_proxy(req, client, &state, route, pool, &runtime.metrics)).await {
+                            Ok(Ok(mut res)) if res.status() != StatusCode::SWITCHING_PROTOCOLS => {
+                                let body = std::mem::take(res.body_mut());
+                                *res.body_mut() = deadline_body(body, deadline);
+                                Ok(res)
+                            }
+                            Ok(result) => result,
+                            Err(_) => Err(UpstreamTimeout("request").into()),
+                        }
+                    }
+                    None => handle_proxy(req, client, &state, route, pool, &runtime.metrics).await,
+                }
+            };
+            // Before the middleware sees the response, so the cache stores it with these headers
+            result.map(|mut res| {
+                let security = route.and_then(|route| route.security_headers.as_ref()).unwrap_or(&state.security_headers);
+                security.apply(res.headers_mut(), ctx.tls);
+                res
+            })
+        }
+    };
+
+    // Failures are answered with an error response rather than dropping the
+    // connection, which stays open for the client's next request
+    let result = match result {
+        Err(e) if is_timeout(e.as_ref()) => {
+            log_upstream_failure(e.as_ref(), ctx.request_id.as_deref());
+            Ok(Response::builder().status(StatusCode::GATEWAY_TIMEOUT).body(Body::from("Gateway Timeout"))?)
+        }
+        Err(e) if is_body_too_large(e.as_ref()) => {
+            Ok(Response::builder().status(StatusCode::PAYLOAD_TOO_LARGE).body(Body::from("Payload Too Large"))?)
+        }
+        Err(e) if is_overloaded(e.as_ref()) || is_unavailable(e.as_ref()) => {
+            warn!("Rejected request: {}", e);
+            Ok(Response::builder().status(StatusCode::SERVICE_UNAVAILABLE).body(Body::from("Service Unavailable"))?)
+        }
+        // Anything else went wrong talking to the upstream: refused, reset or not speaking HTTP
+        Err(e) => {
+            log_upstream_failure(e.as_ref(), ctx.request_id.as_deref());
+            Ok(Response::builder().status(StatusCode::BAD_GATEWAY).body(Body::from("Bad Gateway"))?)
+        }
+        result => result,
+    };
+
+    // Error responses generated here or by middleware get the configured page; upstream ones are left alone
+    let result = result.map(|mut res| {
+        if res.extensions().get::<UpstreamUsed>().is_none() {
+            state.error_pages.render(&mut res, accept.as_ref(), ctx.request_id.as_deref());
+        }
+        if let (true, Some(alt_svc)) = (advertise_h3, &state.alt_svc) {
+            res.headers_mut().entry(ALT_SVC).or_insert_with(|| alt_svc.clone());
+        }
+        res
+    });
+
+    let result = match result {
+        Ok(mut res) => {
+            for middleware in state.middleware[..ran].iter().rev() {
+                middleware.on_response(&mut res, &mut ctx).await;
+            }
+            // Paced last, so cache hits and compressed bodies count as sent
+            if res.status() != StatusCode::SWITCHING_PROTOCOLS && !grpc::is_grpc(res.headers()) && !ctx.background {
+                let body = std::mem::take(res.body_mut());
+                *res.body_mut() = bandwidth.throttle(body, ctx.client_addr.ip());
+            }
+            Ok(res)
+        }
+        Err(e) => {
+            for middleware in state.middleware[..ran].iter().rev() {
+                middleware.on_error(e.as_ref(), &mut ctx).await;
+            }
+            Err(e)
+        }
+    };
+
+    match &result {
+        Ok(res) => runtime.metrics.record_response(res.status().as_u16()),
+        Err(_) => runtime.metrics.record_error(),
+    }
+    result
+}
+
+/// Sends a request refreshing a stale cache entry through the middleware
+/// chain, reading the response through so the cache stores it. Marked as a
+/// background request, it is not rate limited, shed, compressed or logged.
+fn revalidate_cache(req: Request<Body>, client: ClientInfo, runtime: Arc<Runtime>) -> Pin<Box<dyn Future<Output = ()> + Send>> {
+    Box::pin(async move {
+        let result = match proxy(req, client, runtime).await {
+            Ok(res) => hyper::body::to_bytes(res.into_body()).await.map(drop).map_err(BoxError::from),
+            Err(e) => Err(e),
+        };
+        if let Err(e) = result {
+            warn!("Failed to refresh a stale cache entry: {}", e);
+        }
+    })
+}
+
+/// Reloads the configuration every time the process receives SIGHUP.
+#[cfg(unix)]
+fn spawn_reload_handler(runtime: Arc<Runtime>, mut config: Config, config_path: Option<String>, overrides: Overrides) {
+    use tokio::signal::unix::{signal, SignalKind};
+
+    tokio::spawn(async move {
+        let mut hangups = match signal(SignalKind::hangup()) {
+            Ok(hangups) => hangups,
+            Err(e) => {
+                error!("Failed to install SIGHUP handler: {}", e);
+                return;
+            }
+        };
+
+        while hangups.recv().await.is_some() {
+            match runtime.reload(config_path.as_deref(), &overrides, &config) {
+                Ok(new_config) => {
+                    info!("Configuration reloaded");
+                    config = new_config;
+                }
+                Err(e) => error!("Configuration reload failed, keeping previous configuration: {}", e),
+            }
+        }
+    });
+}
+
+/// Reopens the access log every time the process receives SIGUSR1, for
+/// logrotate and similar tools that move the file away.
+#[cfg(unix)]
+fn spawn_reopen_handler(runtime: Arc<Runtime>) {
+    use tokio::signal::unix::{signal, SignalKind};
+
+    tokio::spawn(async move {
+        let mut signals = match signal(SignalKind::user_defined1()) {
+            Ok(signals) => signals,
+            Err(e) => {
+                error!("Failed to install SIGUSR1 handler: {}", e);
+                return;
+            }
+        };
+
+        while signals.recv().await.is_some() {
+            if let Some(log) = &runtime.state().access_log {
+                match log.reopen() {
+                    Ok(()) => info!("Access log reopened"),
+                    Err(e) => warn!("Failed to reopen access log, still writing to the previous file: {}", e),
+                }
+            }
+        }
+    });
+}
+
+fn spawn_certificate_watcher(runtime: Arc<Runtime>, interval: Duration) {
+    tokio::spawn(async move {
+        let mut ticks = tokio::time::interval(interval);
+        loop {
+            ticks.tick().await;
+            match runtime.reload_certificates_if_changed() {
+                Ok(true) => info!("TLS certificates reloaded"),
+                Ok(false) => {}
+                Err(e) => error!("TLS certificate reload failed, keeping previous certificates: {}", e),
+            }
+        }
+    });
+}
+
+#[cfg(not(unix))]
+fn spawn_reload_handler(_runtime: Arc<Runtime>, _config: Config, _config_path: Option<String>, _overrides: Overrides) {}
+
+#[cfg(not(unix))]
+fn spawn_reopen_handler(_runtime: Arc<Runtime>) {}
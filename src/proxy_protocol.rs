@@ -0,0 +1,453 @@
+use hyper::client::connect::{Connected, Connection};
+use hyper::client::HttpConnector;
+use hyper::service::Service;
+use hyper::Uri;
+use std::future::Future;
+use std::io;
+use std::net::{IpAddr, Ipv4Addr, Ipv6Addr, SocketAddr};
+use std::path::PathBuf;
+use std::pin::Pin;
+use std::sync::atomic::{AtomicU64, AtomicUsize, Ordering};
+use std::sync::Arc;
+use std::task::{Context, Poll};
+use std::time::{Duration, Instant};
+use tokio::io::{AsyncRead, AsyncReadExt, AsyncWrite, AsyncWriteExt, ReadBuf};
+use tokio::net::TcpStream;
+#[cfg(unix)]
+use tokio::net::UnixStream;
+
+/// Every PROXY protocol v2 header starts with this signature.
+const V2_SIGNATURE: [u8; 12] = *b"\r\n\r\n\0\r\nQUIT\n";
+/// Longest possible v1 header, including the CRLF.
+const V1_MAX_LEN: usize = 107;
+
+/// Reads a PROXY protocol v1 or v2 header from the start of `stream`,
+/// consuming exactly the header bytes. Returns the client address it
+/// conveys, or `None` for `LOCAL`/`UNKNOWN` connections such as health checks.
+pub async fn read_header<S: AsyncRead + Unpin>(stream: &mut S) -> io::Result<Option<SocketAddr>> {
+    // Both versions are at least 12 bytes long (`PROXY UNKNOWN\r\n` is the shortest v1 header)
+    let mut start = [0u8; 12];
+    stream.read_exact(&mut start).await?;
+
+    if start == V2_SIGNATURE {
+        read_v2(stream).await
+    } else if start.starts_with(b"PROXY ") {
+        read_v1(stream, &start).await
+    } else {
+        Err(invalid("missing PROXY protocol header"))
+    }
+}
+
+async fn read_v1<S: AsyncRead + Unpin>(stream: &mut S, start: &[u8]) -> io::Result<Option<SocketAddr>> {
+    let mut line = start.to_vec();
+    while !line.ends_with(b"\r\n") {
+        if line.len() >= V1_MAX_LEN {
+            return Err(invalid("PROXY v1 header too long"));
+        }
+        line.push(stream.read_u8().await?);
+    }
+
+    let line = std::str::from_utf8(&line[..line.len() - 2]).map_err(|_| invalid("PROXY v1 header is not ASCII"))?;
+    let parts: Vec<&str> = line.split(' ').collect();
+    match parts.as_slice() {
+        ["PROXY", "UNKNOWN", ..] => Ok(None),
+        ["PROXY", family @ ("TCP4" | "TCP6"), src, _dst, src_port, _dst_port] => {
+            let ip: IpAddr = src.parse().map_err(|_| invalid("invalid PROXY v1 source address"))?;
+            let port: u16 = src_port.parse().map_err(|_| invalid("invalid PROXY v1 source port"))?;
+            if ip.is_ipv4() != (*family == "TCP4") {
+                return Err(invalid("PROXY v1 address does not match its family"));
+            }
+            Ok(Some(SocketAddr::new(ip, port)))
+        }
+        _ => Err(invalid("malformed PROXY v1 header")),
+    }
+}
+
+async fn read_v2<S: AsyncRead + Unpin>(stream: &mut S) -> io::Result<Option<SocketAddr>> {
+    let mut header = [0u8; 4];
+    stream.read_exact(&mut header).await?;
+    let [version_command, family, len_hi, len_lo] = header;
+    if version_command >> 4 != 2 {
+        return Err(invalid("unsupported PROXY protocol version"));
+    }
+
+    // The address block and any TLVs; TLVs are read but not interpreted
+    let mut body = vec![0u8; u16::from_be_bytes([len_hi, len_lo]) as usize];
+    stream.read_exact(&mut body).await?;
+
+    match version_command & 0x0f {
+        // LOCAL: connection opened by the balancer itself
+        0x0 => return Ok(None),
+        0x1 => {}
+        _ => return Err(invalid("unsupported PROXY v2 command")),
+    }
+
+    // Only TCP and UDP over IPv4/IPv6 carry a usable address
+    match family {
+        0x11 | 0x12 if body.len() >= 12 => {
+            let ip = Ipv4Addr::new(body[0], body[1], body[2], body[3]);
+            let port = u16::from_be_bytes([body[8], body[9]]);
+            Ok(Some(SocketAddr::new(IpAddr::V4(ip), port)))
+        }
+        0x21 | 0x22 if body.len() >= 36 => {
+            let mut octets = [0u8; 16];
+            octets.copy_from_slice(&body[..16]);
+            let port = u16::from_be_bytes([body[32], body[33]]);
+            Ok(Some(SocketAddr::new(IpAddr::V6(Ipv6Addr::from(octets)), port)))
+        }
+        0x11 | 0x12 | 0x21 | 0x22 => Err(invalid("PROXY v2 address block too short")),
+        _ => Ok(None),
+    }
+}
+
+/// Encodes a PROXY protocol v2 header for a connection to `dest` on behalf
+/// of `source`, or a `LOCAL` header when there is no client.
+pub fn encode_v2(source: Option<SocketAddr>, dest: SocketAddr) -> Vec<u8> {
+    let mut header = V2_SIGNATURE.to_vec();
+    let source = match source {
+        Some(source) => source,
+        None => {
+            header.extend_from_slice(&[0x20, 0x00, 0, 0]);
+            return header;
+        }
+    };
+
+    let (family, addresses) = match (source.ip(), dest.ip()) {
+        (IpAddr::V4(src), IpAddr::V4(dst)) => (0x11, [src.octets().to_vec(), dst.octets().to_vec()].concat()),
+        // Mixed families are sent as IPv6, with IPv4 addresses mapped
+        (src, dst) => (0x21, [to_ipv6(src).octets().to_vec(), to_ipv6(dst).octets().to_vec()].concat()),
+    };
+    header.push(0x21);
+    header.push(family);
+    header.extend_from_slice(&(addresses.len() as u16 + 4).to_be_bytes());
+    header.extend_from_slice(&addresses);
+    header.extend_from_slice(&source.port().to_be_bytes());
+    header.extend_from_slice(&dest.port().to_be_bytes());
+    header
+}
+
+fn to_ipv6(ip: IpAddr) -> Ipv6Addr {
+    match ip {
+        IpAddr::V4(ip) => ip.to_ipv6_mapped(),
+        IpAddr::V6(ip) => ip,
+    }
+}
+
+/// TCP connector that optionally starts each upstream connection with a
+/// PROXY protocol v2 header carrying the client's address.
+#[derive(Clone)]
+pub struct ProxyProtocolConnector {
+    http: HttpConnector,
+    /// Send a header on every connection
+    enabled: bool,
+    /// Client the connections are made for; `None` for Riffy's own requests
+    source: Option<SocketAddr>,
+    /// Connect here instead of to the address the URI's host resolves to
+    address: Option<IpAddr>,
+    /// Unix socket every connection goes to instead
+    socket: Option<PathBuf>,
+    stats: Arc<ConnectionStats>,
+}
+
+impl ProxyProtocolConnector {
+    pub fn new(http: HttpConnector, enabled: bool, source: Option<SocketAddr>, stats: Arc<ConnectionStats>) -> Self {
+        ProxyProtocolConnector { http, enabled, source, address: None, socket: None, stats }
+    }
+
+    /// Connects to `address`, if set, on the URI's port.
+    pub fn pinned(mut self, address: Option<IpAddr>) -> Self {
+        self.address = address;
+        self
+    }
+
+    /// Connects to the Unix socket at `socket`, if set, whatever the URI.
+    pub fn unix(mut self, socket: Option<PathBuf>) -> Self {
+        self.socket = socket;
+        self
+    }
+}
+
+impl Service<Uri> for ProxyProtocolConnector {
+    type Response = UpstreamStream;
+    type Error = Box<dyn std::error::Error + Send + Sync>;
+    type Future = Pin<Box<dyn Future<Output = Result<UpstreamStream, Self::Error>> + Send>>;
+
+    fn poll_ready(&mut self, cx: &mut Context<'_>) -> Poll<Result<(), Self::Error>> {
+        self.http.poll_ready(cx).map_err(Into::into)
+    }
+
+    fn call(&mut self, dst: Uri) -> Self::Future {
+        let (enabled, source, stats) = (self.enabled, self.source, Arc::clone(&self.stats));
+        let started = Instant::now();
+        if let Some(socket) = self.socket.clone() {
+            return Box::pin(async move {
+                let mut stream = UpstreamStream::new(connect_unix(&socket).await?, stats, started);
+                if enabled {
+                    // A socket has no address of its own, so the destination is left unspecified
+                    let unspecified = match source.map(|source| source.ip()) {
+                        Some(IpAddr::V6(_)) => IpAddr::V6(Ipv6Addr::UNSPECIFIED),
+                        _ => IpAddr::V4(Ipv4Addr::UNSPECIFIED),
+                    };
+                    stream.write_all(&encode_v2(source, SocketAddr::new(unspecified, 0))).await?;
+                }
+                Ok(stream)
+            });
+        }
+        // TLS has already taken the server name from the original URI
+        let dst = match self.address {
+            Some(address) => match pinned_uri(&dst, address) {
+                Ok(uri) => uri,
+                Err(e) => return Box::pin(async move { Err(e.into()) }),
+            },
+            None => dst,
+        };
+        let connecting = self.http.call(dst);
+        Box::pin(async move {
+            let mut stream = connecting.await?;
+            if enabled {
+                let header = encode_v2(source, stream.peer_addr()?);
+                stream.write_all(&header).await?;
+            }
+            Ok(UpstreamStream::new(Stream::Tcp(stream), stats, started))
+        })
+    }
+}
+
+#[cfg(unix)]
+async fn connect_unix(socket: &std::path::Path) -> io::Result<Stream> {
+    UnixStream::connect(socket).await.map(Stream::Unix).map_err(|e| io::Error::new(e.kind(), format!("{}: {}", socket.display(), e)))
+}
+
+#[cfg(not(unix))]
+async fn connect_unix(_socket: &std::path::Path) -> io::Result<Stream> {
+    Err(io::Error::new(io::ErrorKind::Unsupported, "Unix sockets are not supported on this platform"))
+}
+
+/// `dst` with its host replaced by `address`, keeping the scheme's port.
+fn pinned_uri(dst: &Uri, address: IpAddr) -> Result<Uri, hyper::http::Error> {
+    let scheme = dst.scheme_str().unwrap_or("http");
+    let port = dst.port_u16().unwrap_or(if scheme == "https" { 443 } else { 80 });
+    let authority = SocketAddr::new(address, port).to_string();
+    Uri::builder().scheme(scheme).authority(authority.as_str()).path_and_query("/").build()
+}
+
+/// Counts of the upstream connections made by a pool.
+#[derive(Debug, Default)]
+pub struct ConnectionStats {
+    opened: AtomicU64,
+    open: AtomicUsize,
+}
+
+impl ConnectionStats {
+    /// Connections opened since startup
+    pub fn opened(&self) -> u64 {
+        self.opened.load(Ordering::Relaxed)
+    }
+
+    /// Connections currently open, whether busy or idle
+    pub fn open(&self) -> usize {
+        self.open.load(Ordering::Relaxed)
+    }
+}
+
+/// When an upstream connection started to be opened and how long that took,
+/// up to the end of any TLS handshake. Hyper adds it to the extensions of
+/// every response received on the connection.
+#[derive(Debug, Clone, Copy)]
+pub struct ConnectTiming {
+    pub started: Instant,
+    pub took: Duration,
+}
+
+/// An upstream connection, counted as open until dropped.
+pub struct UpstreamStream {
+    inner: Stream,
+    stats: Arc<ConnectionStats>,
+    started: Instant,
+}
+
+/// The socket under an upstream connection.
+enum Stream {
+    Tcp(TcpStream),
+    #[cfg(unix)]
+    Unix(UnixStream),
+}
+
+impl UpstreamStream {
+    fn new(inner: Stream, stats: Arc<ConnectionStats>, started: Instant) -> Self {
+        stats.opened.fetch_add(1, Ordering::Relaxed);
+        stats.open.fetch_add(1, Ordering::Relaxed);
+        UpstreamStream { inner, stats, started }
+    }
+}
+
+impl Drop for UpstreamStream {
+    fn drop(&mut self) {
+        self.stats.open.fetch_sub(1, Ordering::Relaxed);
+    }
+}
+
+impl AsyncRead for UpstreamStream {
+    fn poll_read(mut self: Pin<&mut Self>, cx: &mut Context<'_>, buf: &mut ReadBuf<'_>) -> Poll<io::Result<()>> {
+        match &mut self.inner {
+            Stream::Tcp(stream) => Pin::new(stream).poll_read(cx, buf),
+            #[cfg(unix)]
+            Stream::Unix(stream) => Pin::new(stream).poll_read(cx, buf),
+        }
+    }
+}
+
+impl AsyncWrite for UpstreamStream {
+    fn poll_write(mut self: Pin<&mut Self>, cx: &mut Context<'_>, buf: &[u8]) -> Poll<io::Result<usize>> {
+        match &mut self.inner {
+            Stream::Tcp(stream) => Pin::new(stream).poll_write(cx, buf),
+            #[cfg(unix)]
+            Stream::Unix(stream) => Pin::new(stream).poll_write(cx, buf),
+        }
+    }
+
+    fn poll_flush(mut self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<io::Result<()>> {
+        match &mut self.inner {
+            Stream::Tcp(stream) => Pin::new(stream).poll_flush(cx),
+            #[cfg(unix)]
+            Stream::Unix(stream) => Pin::new(stream).poll_flush(cx),
+        }
+    }
+
+    fn poll_shutdown(mut self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<io::Result<()>> {
+        match &mut self.inner {
+            Stream::Tcp(stream) => Pin::new(stream).poll_shutdown(cx),
+            #[cfg(unix)]
+            Stream::Unix(stream) => Pin::new(stream).poll_shutdown(cx),
+        }
+    }
+}
+
+impl Connection for UpstreamStream {
+    fn connected(&self) -> Connected {
+        let connected = match &self.inner {
+            Stream::Tcp(stream) => stream.connected(),
+            #[cfg(unix)]
+            Stream::Unix(_) => Connected::new(),
+        };
+        // Asked for once the connector, TLS included, has finished
+        connected.extra(ConnectTiming { started: self.started, took: self.started.elapsed() })
+    }
+}
+
+fn invalid(message: &str) -> io::Error {
+    io::Error::new(io::ErrorKind::InvalidData, message)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    /// Reads a header from `input` and returns what is left after it.
+    async fn read(input: &[u8]) -> (io::Result<Option<SocketAddr>>, &[u8]) {
+        let mut stream = input;
+        let result = read_header(&mut stream).await;
+        (result, stream)
+    }
+
+    fn v2(command: u8, family: u8, body: &[u8]) -> Vec<u8> {
+        let mut header = V2_SIGNATURE.to_vec();
+        header.extend_from_slice(&[0x20 | command, family]);
+        header.extend_from_slice(&(body.len() as u16).to_be_bytes());
+        header.extend_from_slice(body);
+        header
+    }
+
+    #[tokio::test]
+    async fn reads_v1_headers() {
+        let (result, rest) = read(b"PROXY TCP4 192.0.2.1 198.51.100.2 56324 443\r\nGET /").await;
+        assert_eq!(result.unwrap(), Some("192.0.2.1:56324".parse().unwrap()));
+        assert_eq!(rest, b"GET /");
+        let (result, _) = read(b"PROXY TCP6 2001:db8::1 2001:db8::2 56324 443\r\n").await;
+        assert_eq!(result.unwrap(), Some("[2001:db8::1]:56324".parse().unwrap()));
+        let (result, rest) = read(b"PROXY UNKNOWN\r\nGET /").await;
+        assert_eq!(result.unwrap(), None);
+        assert_eq!(rest, b"GET /");
+    }
+
+    #[tokio::test]
+    async fn rejects_bad_v1_headers() {
+        let longest = format!("PROXY UNKNOWN {0} {0} 65535 65535\r\n", "ffff:ffff:ffff:ffff:ffff:ffff:ffff:ffff");
+        assert_eq!(longest.len(), V1_MAX_LEN);
+        assert!(read(longest.as_bytes()).await.0.is_ok());
+        let oversized = format!("PROXY UNKNOWN {}\r\n", "x".repeat(V1_MAX_LEN));
+        for input in [
+            oversized.as_bytes(),
+            b"PROXY TCP4 192.0.2.1 198.51.100.2 56324",
+            b"PROXY TCP4 192.0.2.1 198.51.100.2 56324 443\n",
+            b"PROXY TCP4 192.0.2.1 198.51.100.2 56324\r\n",
+            b"PROXY TCP4 2001:db8::1 2001:db8::2 56324 443\r\n",
+            b"PROXY TCP6 192.0.2.1 198.51.100.2 56324 443\r\n",
+            b"PROXY TCP4 192.0.2.256 198.51.100.2 56324 443\r\n",
+            b"PROXY TCP4 192.0.2.1 198.51.100.2 65536 443\r\n",
+            b"PROXY UDP4 192.0.2.1 198.51.100.2 56324 443\r\n",
+            b"PROXY TCP4 \xff.0.2.1 198.51.100.2 56324 443\r\n",
+            b"GET / HTTP/1.1\r\nHost: example.com\r\n\r\n",
+            b"PROXY",
+        ] {
+            assert!(read(input).await.0.is_err(), "{:?}", String::from_utf8_lossy(input));
+        }
+    }
+
+    #[tokio::test]
+    async fn reads_v2_headers() {
+        let inet = [192, 0, 2, 1, 198, 51, 100, 2, 0xdc, 0x04, 0x01, 0xbb];
+        let mut input = v2(0x1, 0x11, &inet);
+        input.extend_from_slice(b"GET /");
+        let (result, rest) = read(&input).await;
+        assert_eq!(result.unwrap(), Some("192.0.2.1:56324".parse().unwrap()));
+        assert_eq!(rest, b"GET /");
+
+        let src: Ipv6Addr = "2001:db8::1".parse().unwrap();
+        let dst: Ipv6Addr = "2001:db8::2".parse().unwrap();
+        let inet6 = [&src.octets()[..], &dst.octets(), &[0xdc, 0x04, 0x01, 0xbb]].concat();
+        assert_eq!(read(&v2(0x1, 0x21, &inet6)).await.0.unwrap(), Some("[2001:db8::1]:56324".parse().unwrap()));
+
+        // TLVs after the addresses are skipped
+        let mut with_tlv = inet.to_vec();
+        with_tlv.extend_from_slice(&[0x04, 0x00, 0x02, 0xab, 0xcd]);
+        let mut input = v2(0x1, 0x11, &with_tlv);
+        input.extend_from_slice(b"GET /");
+        let (result, rest) = read(&input).await;
+        assert_eq!(result.unwrap(), Some("192.0.2.1:56324".parse().unwrap()));
+        assert_eq!(rest, b"GET /");
+
+        // Unix sockets and LOCAL connections carry no client address
+        assert_eq!(read(&v2(0x1, 0x31, &[0; 216])).await.0.unwrap(), None);
+        assert_eq!(read(&v2(0x0, 0x11, &inet)).await.0.unwrap(), None);
+        assert_eq!(read(&v2(0x0, 0x00, &[])).await.0.unwrap(), None);
+    }
+
+    #[tokio::test]
+    async fn rejects_bad_v2_headers() {
+        let inet = [192, 0, 2, 1, 198, 51, 100, 2, 0xdc, 0x04, 0x01, 0xbb];
+        let mut wrong_version = v2(0x1, 0x11, &inet);
+        wrong_version[12] = 0x11;
+        // Announces more bytes than follow
+        let mut truncated = v2(0x1, 0x11, &inet);
+        truncated.truncate(truncated.len() - 1);
+        let mut oversized = v2(0x1, 0x11, &inet);
+        oversized[14..16].copy_from_slice(&u16::MAX.to_be_bytes());
+        for input in [wrong_version, v2(0x2, 0x11, &inet), v2(0x1, 0x11, &inet[..11]), v2(0x1, 0x21, &inet), truncated, oversized, V2_SIGNATURE[..8].to_vec()] {
+            assert!(read(&input).await.0.is_err(), "{:?}", input);
+        }
+    }
+
+    #[tokio::test]
+    async fn encoded_headers_read_back() {
+        let dest = "198.51.100.2:443".parse().unwrap();
+        for source in ["192.0.2.1:56324", "[2001:db8::1]:56324"] {
+            let source: SocketAddr = source.parse().unwrap();
+            let header = encode_v2(Some(source), dest);
+            let (result, rest) = read(&header).await;
+            assert_eq!(result.unwrap(), Some(source));
+            assert!(rest.is_empty());
+        }
+        assert_eq!(read(&encode_v2(None, dest)).await.0.unwrap(), None);
+    }
+}
@@ -0,0 +1,103 @@
+use async_trait::async_trait;
+use hyper::header::RETRY_AFTER;
+use hyper::{Body, Request, Response, StatusCode};
+use std::net::IpAddr;
+use std::time::Duration;
+
+use crate::middleware::{Context, Middleware};
+use crate::token_bucket::TokenBuckets;
+
+/// Token-bucket rate limiter keyed by client IP. Each client may send `burst`
+/// requests at once, refilled at `rate` requests per second.
+#[derive(Debug)]
+pub struct RateLimiter {
+    buckets: TokenBuckets,
+}
+
+impl RateLimiter {
+    pub fn new(rate: f64, burst: u32) -> Self {
+        RateLimiter { buckets: TokenBuckets::new(rate, burst as f64) }
+    }
+
+    /// Takes a token for `client`, or returns how long until one is available.
+    pub fn check(&self, client: IpAddr) -> Result<(), Duration> {
+        self.buckets.take(client, 1.0)
+    }
+}
+
+#[async_trait]
+impl Middleware for RateLimiter {
+    async fn on_request(&self, _req: &mut Request<Body>, ctx: &mut Context) -> Option<Response<Body>> {
+        if ctx.background {
+            return None;
+        }
+        let wait = self.check(ctx.client_addr.ip()).err()?;
+        let res = Response::builder()
+            .status(StatusCode::TOO_MANY_REQUESTS)
+            .header(RETRY_AFTER, wait.as_secs_f64().ceil().max(1.0).to_string())
+            .body(Body::from("Too Many Requests"))
+            .unwrap();
+        Some(res)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const CLIENT: IpAddr = IpAddr::V4(std::net::Ipv4Addr::new(192, 0, 2, 1));
+    const OTHER: IpAddr = IpAddr::V4(std::net::Ipv4Addr::new(192, 0, 2, 2));
+
+    /// Moves `client`'s last refill `by` into the past.
+    fn wait(limiter: &RateLimiter, client: IpAddr, by: Duration) {
+        limiter.buckets.rewind(client, by);
+    }
+
+    #[test]
+    fn allows_a_burst_then_refills_at_the_rate() {
+        let limiter = RateLimiter::new(2.0, 3);
+        assert!((0..3).all(|_| limiter.check(CLIENT).is_ok()));
+        let retry = limiter.check(CLIENT).unwrap_err();
+        assert!(retry > Duration::from_millis(400) && retry <= Duration::from_millis(500), "{:?}", retry);
+        // Each client has a bucket of its own
+        assert!(limiter.check(OTHER).is_ok());
+
+        // Half a second at 2 per second brings one token back
+        wait(&limiter, CLIENT, Duration::from_millis(500));
+        assert!(limiter.check(CLIENT).is_ok());
+        assert!(limiter.check(CLIENT).is_err());
+        // A long pause refills no more than the burst
+        wait(&limiter, CLIENT, Duration::from_secs(60));
+        assert!((0..3).all(|_| limiter.check(CLIENT).is_ok()));
+        assert!(limiter.check(CLIENT).is_err());
+    }
+
+    #[tokio::test]
+    async fn answers_429_with_retry_after() {
+        let limiter = RateLimiter::new(0.1, 1);
+        let mut ctx = Context::new((CLIENT, 4000).into(), false);
+        let mut req = Request::get("/").body(Body::empty()).unwrap();
+        assert!(limiter.on_request(&mut req, &mut ctx).await.is_none());
+        let res = limiter.on_request(&mut req, &mut ctx).await.expect("limited");
+        assert_eq!(res.status(), StatusCode::TOO_MANY_REQUESTS);
+        // Ten seconds until the next token, rounded up to whole seconds
+        assert_eq!(res.headers()[RETRY_AFTER], "10");
+
+        let fast = RateLimiter::new(100.0, 1);
+        assert!(fast.on_request(&mut req, &mut ctx).await.is_none());
+        let res = fast.on_request(&mut req, &mut ctx).await.expect("limited");
+        assert_eq!(res.headers()[RETRY_AFTER], "1");
+    }
+
+    #[tokio::test]
+    async fn background_requests_spend_no_tokens() {
+        let limiter = RateLimiter::new(0.1, 1);
+        let mut ctx = Context::new((CLIENT, 4000).into(), false);
+        ctx.background = true;
+        let mut req = Request::get("/").body(Body::empty()).unwrap();
+        assert!(limiter.on_request(&mut req, &mut ctx).await.is_none());
+        assert!(limiter.on_request(&mut req, &mut ctx).await.is_none());
+        // The client still has its whole burst
+        assert!(limiter.check(CLIENT).is_ok());
+    }
+}
@@ -0,0 +1,271 @@
+use async_trait::async_trait;
+use hyper::header::{HeaderValue, CONTENT_TYPE, HOST, LOCATION};
+use hyper::service::{make_service_fn, service_fn};
+use hyper::{Body, Request, Response, Server, StatusCode};
+use regex::Regex;
+use std::convert::Infallible;
+use std::net::SocketAddr;
+use std::path::{Path, PathBuf};
+use std::sync::Arc;
+use tracing::{error, info};
+
+use crate::config::RedirectRuleConfig;
+use crate::middleware::{Context, Middleware};
+use crate::router::{host_matches, path_matches, request_host};
+
+const ACME_CHALLENGE_PREFIX: &str = "/.well-known/acme-challenge/";
+
+/// Runs a plain HTTP server that redirects every request to the HTTPS
+/// listener on `https_port`, optionally answering ACME HTTP-01 challenges
+/// from `acme_dir` first.
+pub async fn serve(listener: std::net::TcpListener, addr: SocketAddr, https_port: u16, acme_dir: Option<PathBuf>) {
+    let acme_dir = Arc::new(acme_dir);
+    let make_svc = make_service_fn(move |_conn| {
+        let acme_dir = Arc::clone(&acme_dir);
+        async move {
+            Ok::<_, Infallible>(service_fn(move |req| {
+                let acme_dir = Arc::clone(&acme_dir);
+                async move { Ok::<_, Infallible>(handle(req, https_port, acme_dir.as_deref()).await) }
+            }))
+        }
+    });
+
+    info!("Redirecting http://{} to HTTPS", addr);
+
+    let server = match Server::from_tcp(listener) {
+        Ok(server) => server,
+        Err(e) => return error!("Redirect server failed to listen on {}: {}", addr, e),
+    };
+    // After an upgrade the new process serves the port
+    if let Err(e) = server.serve(make_svc).with_graceful_shutdown(crate::handoff::handed_over()).await {
+        error!("Redirect server error: {}", e);
+    }
+}
+
+async fn handle(req: Request<Body>, https_port: u16, acme_dir: Option<&Path>) -> Response<Body> {
+    if let (Some(dir), Some(token)) = (acme_dir, req.uri().path().strip_prefix(ACME_CHALLENGE_PREFIX)) {
+        return acme_challenge(dir, token).await;
+    }
+
+    let host = match req.headers().get(HOST).and_then(|h| h.to_str().ok()) {
+        Some(host) => host,
+        None => return text(StatusCode::BAD_REQUEST, "Missing Host header"),
+    };
+    let host = match host.rfind(':') {
+        // Keep bracketed IPv6 addresses intact
+        Some(i) if !host[i..].contains(']') => &host[..i],
+        _ => host,
+    };
+    let path = req.uri().path_and_query().map(|p| p.as_str()).unwrap_or("/");
+    let location = if https_port == 443 {
+        format!("https://{}{}", host, path)
+    } else {
+        format!("https://{}:{}{}", host, https_port, path)
+    };
+
+    match Response::builder().status(StatusCode::MOVED_PERMANENTLY).header(LOCATION, location).body(Body::empty()) {
+        Ok(res) => res,
+        Err(_) => text(StatusCode::BAD_REQUEST, "Invalid Host header"),
+    }
+}
+
+/// Serves the key authorization stored in `dir/<token>`.
+async fn acme_challenge(dir: &Path, token: &str) -> Response<Body> {
+    // Tokens are base64url, which also keeps requests inside the directory
+    let valid = !token.is_empty() && token.bytes().all(|b| b.is_ascii_alphanumeric() || b == b'-' || b == b'_');
+    if !valid {
+        return text(StatusCode::NOT_FOUND, "Not Found");
+    }
+    match tokio::fs::read(dir.join(token)).await {
+        Ok(contents) => Response::builder().header(CONTENT_TYPE, "application/octet-stream").body(Body::from(contents)).unwrap(),
+        Err(_) => text(StatusCode::NOT_FOUND, "Not Found"),
+    }
+}
+
+fn text(status: StatusCode, message: &'static str) -> Response<Body> {
+    Response::builder().status(status).body(Body::from(message)).unwrap()
+}
+
+/// What a redirect rule matches the request path against.
+#[derive(Debug)]
+enum PathMatch {
+    Exact(String),
+    Prefix(String),
+    Regex(Regex),
+}
+
+/// One rule of `[[redirects]]`.
+#[derive(Debug)]
+pub struct RedirectRule {
+    hosts: Vec<String>,
+    path: PathMatch,
+    to: String,
+    status: StatusCode,
+    preserve_query: bool,
+}
+
+impl RedirectRule {
+    pub fn new(config: &RedirectRuleConfig) -> Result<Self, String> {
+        let path = match (&config.path, &config.prefix, &config.regex) {
+            (Some(path), None, None) => PathMatch::Exact(path.clone()),
+            (None, Some(prefix), None) => PathMatch::Prefix(prefix.trim_end_matches('/').to_string()),
+            (None, None, Some(pattern)) => PathMatch::Regex(Regex::new(pattern).map_err(|e| format!("invalid regex {}: {}", pattern, e))?),
+            _ => return Err("set exactly one of path, prefix and regex".to_string()),
+        };
+        if let PathMatch::Exact(path) | PathMatch::Prefix(path) = &path {
+            if !path.starts_with('/') && !path.is_empty() {
+                return Err(format!("path and prefix must start with '/': {}", path));
+            }
+        }
+        let status = match config.status {
+            301 | 302 | 303 | 307 | 308 => StatusCode::from_u16(config.status).expect("valid redirect status"),
+            status => return Err(format!("status must be 301, 302, 303, 307 or 308, not {}", status)),
+        };
+        Ok(RedirectRule {
+            hosts: config.hosts.iter().map(|host| host.to_ascii_lowercase()).collect(),
+            path,
+            to: config.to.clone(),
+            status,
+            preserve_query: config.preserve_query,
+        })
+    }
+
+    /// Where `req` is redirected to, if this rule matches it.
+    fn location<B>(&self, req: &Request<B>) -> Option<String> {
+        if !self.hosts.is_empty() {
+            let host = request_host(req)?;
+            if !self.hosts.iter().any(|pattern| host_matches(pattern, &host)) {
+                return None;
+            }
+        }
+        let path = req.uri().path();
+        let mut location = match &self.path {
+            PathMatch::Exact(exact) if path == exact => self.to.clone(),
+            PathMatch::Exact(_) => return None,
+            PathMatch::Prefix(prefix) if path_matches(prefix, path) => format!("{}{}", self.to.trim_end_matches('/'), &path[prefix.len()..]),
+            PathMatch::Prefix(_) => return None,
+            PathMatch::Regex(regex) => {
+                let captures = regex.captures(path)?;
+                let mut location = String::new();
+                captures.expand(&self.to, &mut location);
+                location
+            }
+        };
+        if location.is_empty() {
+            location.push('/');
+        }
+        if let Some(query) = req.uri().query().filter(|_| self.preserve_query) {
+            location.push(if location.contains('?') { '&' } else { '?' });
+            location.push_str(query);
+        }
+        Some(location)
+    }
+}
+
+/// Middleware answering requests that match a redirect rule, so backends do
+/// not need one-off redirect handlers.
+pub struct Redirects(pub Vec<RedirectRule>);
+
+#[async_trait]
+impl Middleware for Redirects {
+    async fn on_request(&self, req: &mut Request<Body>, _ctx: &mut Context) -> Option<Response<Body>> {
+        let (rule, location) = self.0.iter().find_map(|rule| Some((rule, rule.location(req)?)))?;
+        // A location that is not a valid header value is skipped rather than sent broken
+        let location = HeaderValue::from_str(&location).ok()?;
+        Some(Response::builder().status(rule.status).header(LOCATION, location).body(Body::empty()).unwrap())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn rule(path: (&str, &str), to: &str, configure: impl FnOnce(&mut RedirectRuleConfig)) -> RedirectRule {
+        let mut config = RedirectRuleConfig { hosts: Vec::new(), path: None, prefix: None, regex: None, to: to.to_string(), status: 301, preserve_query: true };
+        let (kind, value) = path;
+        match kind {
+            "path" => config.path = Some(value.to_string()),
+            "prefix" => config.prefix = Some(value.to_string()),
+            _ => config.regex = Some(value.to_string()),
+        }
+        configure(&mut config);
+        RedirectRule::new(&config).unwrap()
+    }
+
+    fn location(rule: &RedirectRule, uri: &str) -> Option<String> {
+        rule.location(&Request::get(uri).header(HOST, "Example.com:8080").body(()).unwrap())
+    }
+
+    #[test]
+    fn matches_exact_paths() {
+        let rule = rule(("path", "/old"), "/new", |_| ());
+        assert_eq!(location(&rule, "/old").as_deref(), Some("/new"));
+        assert_eq!(location(&rule, "/old?page=2").as_deref(), Some("/new?page=2"));
+        assert_eq!(location(&rule, "/old/"), None);
+        assert_eq!(location(&rule, "/older"), None);
+    }
+
+    #[test]
+    fn matches_prefixes_on_whole_segments() {
+        let rule = rule(("prefix", "/blog/"), "https://blog.example.com/", |_| ());
+        assert_eq!(location(&rule, "/blog").as_deref(), Some("https://blog.example.com"));
+        assert_eq!(location(&rule, "/blog/2024/post?ref=x").as_deref(), Some("https://blog.example.com/2024/post?ref=x"));
+        assert_eq!(location(&rule, "/blogroll"), None);
+        // Nothing left of a path becomes the root
+        let rule = self::rule(("prefix", "/docs"), "", |_| ());
+        assert_eq!(location(&rule, "/docs").as_deref(), Some("/"));
+    }
+
+    #[test]
+    fn expands_regex_captures() {
+        let rule = rule(("regex", "^/products/([0-9]+)/(?P<slug>[a-z-]+)$"), "/p/$1?name=$slug", |_| ());
+        assert_eq!(location(&rule, "/products/42/blue-shoe").as_deref(), Some("/p/42?name=blue-shoe"));
+        // A query joins the one in the target
+        assert_eq!(location(&rule, "/products/42/blue-shoe?utm=mail").as_deref(), Some("/p/42?name=blue-shoe&utm=mail"));
+        assert_eq!(location(&rule, "/products/x/blue-shoe"), None);
+    }
+
+    #[test]
+    fn applies_to_its_hosts_only() {
+        let rule = rule(("prefix", "/"), "https://www.example.com/", |config| config.hosts = vec!["example.com".to_string(), "*.Example.org".to_string()]);
+        assert_eq!(location(&rule, "/about").as_deref(), Some("https://www.example.com/about"));
+        let on = |host: &str| rule.location(&Request::get("/about").header(HOST, host).body(()).unwrap());
+        assert!(on("shop.example.org").is_some());
+        assert!(on("example.org").is_none());
+        assert!(on("www.example.com").is_none());
+        assert!(rule.location(&Request::get("/about").body(()).unwrap()).is_none());
+    }
+
+    #[test]
+    fn can_drop_the_query() {
+        let rule = rule(("path", "/old"), "/new", |config| config.preserve_query = false);
+        assert_eq!(location(&rule, "/old?session=1").as_deref(), Some("/new"));
+    }
+
+    #[test]
+    fn refuses_invalid_rules() {
+        let base = RedirectRuleConfig { hosts: Vec::new(), path: Some("/a".to_string()), prefix: None, regex: None, to: "/b".to_string(), status: 301, preserve_query: true };
+        let invalid = [
+            RedirectRuleConfig { prefix: Some("/a".to_string()), ..base.clone() },
+            RedirectRuleConfig { path: None, ..base.clone() },
+            RedirectRuleConfig { path: Some("a".to_string()), ..base.clone() },
+            RedirectRuleConfig { path: None, regex: Some("(".to_string()), ..base.clone() },
+            RedirectRuleConfig { status: 200, ..base.clone() },
+            RedirectRuleConfig { status: 304, ..base.clone() },
+        ];
+        for config in invalid {
+            assert!(RedirectRule::new(&config).is_err(), "{:?}", config);
+        }
+    }
+
+    #[tokio::test]
+    async fn answers_with_the_first_matching_rule() {
+        let redirects = Redirects(vec![rule(("path", "/a"), "/first", |config| config.status = 308), rule(("prefix", "/"), "/second", |config| config.status = 302)]);
+        let mut ctx = Context::new(([192, 0, 2, 1], 4000).into(), false);
+        let res = redirects.on_request(&mut Request::get("/a").body(Body::empty()).unwrap(), &mut ctx).await.unwrap();
+        assert_eq!((res.status(), &res.headers()[LOCATION]), (StatusCode::PERMANENT_REDIRECT, &HeaderValue::from_static("/first")));
+        let res = redirects.on_request(&mut Request::get("/b").body(Body::empty()).unwrap(), &mut ctx).await.unwrap();
+        assert_eq!((res.status(), &res.headers()[LOCATION]), (StatusCode::FOUND, &HeaderValue::from_static("/second/b")));
+        assert!(Redirects(Vec::new()).on_request(&mut Request::get("/a").body(Body::empty()).unwrap(), &mut ctx).await.is_none());
+    }
+}
@@ -0,0 +1,48 @@
+use async_trait::async_trait;
+use hyper::header::{HeaderName, HeaderValue};
+use hyper::{Body, Request, Response};
+use rand::RngCore;
+
+use crate::middleware::{Context, Middleware};
+
+/// Longest client-supplied ID that is passed through rather than replaced.
+const MAX_ID_LEN: usize = 128;
+
+/// Middleware giving every request an ID in the named header. A well-formed
+/// ID from the client or a proxy in front is kept so traces line up across
+/// the stack; otherwise a random UUID is generated. The ID is echoed on the
+/// response.
+pub struct RequestId(pub HeaderName);
+
+#[async_trait]
+impl Middleware for RequestId {
+    async fn on_request(&self, req: &mut Request<Body>, ctx: &mut Context) -> Option<Response<Body>> {
+        let incoming = req.headers().get(&self.0).and_then(|v| v.to_str().ok()).filter(|id| is_valid(id)).map(str::to_string);
+        let id = incoming.unwrap_or_else(generate);
+        // Generated and validated IDs are always valid header values
+        req.headers_mut().insert(self.0.clone(), HeaderValue::from_str(&id).expect("valid request ID"));
+        ctx.request_id = Some(id);
+        None
+    }
+
+    async fn on_response(&self, res: &mut Response<Body>, ctx: &mut Context) {
+        if let Some(value) = ctx.request_id.as_deref().and_then(|id| HeaderValue::from_str(id).ok()) {
+            res.headers_mut().insert(self.0.clone(), value);
+        }
+    }
+}
+
+/// Accepts IDs made of letters, digits and common separators.
+fn is_valid(id: &str) -> bool {
+    !id.is_empty() && id.len() <= MAX_ID_LEN && id.bytes().all(|b| b.is_ascii_alphanumeric() || b"-_.:/+=".contains(&b))
+}
+
+/// A random (version 4) UUID.
+fn generate() -> String {
+    let mut bytes = [0u8; 16];
+    rand::thread_rng().fill_bytes(&mut bytes);
+    bytes[6] = (bytes[6] & 0x0f) | 0x40;
+    bytes[8] = (bytes[8] & 0x3f) | 0x80;
+    let hex: String = bytes.iter().map(|b| format!("{:02x}", b)).collect();
+    format!("{}-{}-{}-{}-{}", &hex[..8], &hex[8..12], &hex[12..16], &hex[16..20], &hex[20..])
+}
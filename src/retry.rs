@@ -0,0 +1,32 @@
+use hyper::{Method, StatusCode};
+use std::time::Duration;
+
+/// How failed upstream requests are retried against other upstreams.
+#[derive(Debug, Clone)]
+pub struct RetryPolicy {
+    /// Extra attempts after the first one; 0 disables retries
+    pub retries: u32,
+    /// Limit for a single attempt, so a hung upstream doesn't use up the whole request
+    pub per_try_timeout: Option<Duration>,
+    /// Delay before the first retry, doubled for each further retry
+    pub backoff: Duration,
+    pub max_backoff: Duration,
+}
+
+impl RetryPolicy {
+    /// Only idempotent methods are retried, since a failed attempt may have reached the upstream.
+    pub fn allows_method(&self, method: &Method) -> bool {
+        self.retries > 0
+            && matches!(*method, Method::GET | Method::HEAD | Method::OPTIONS | Method::PUT | Method::DELETE | Method::TRACE)
+    }
+
+    pub fn is_retryable_status(status: StatusCode) -> bool {
+        status == StatusCode::BAD_GATEWAY || status == StatusCode::SERVICE_UNAVAILABLE
+    }
+
+    /// Exponential backoff before retry number `retry` (starting at 1).
+    pub fn backoff_for(&self, retry: u32) -> Duration {
+        let factor = 2u32.saturating_pow(retry.saturating_sub(1));
+        self.backoff.saturating_mul(factor).min(self.max_backoff)
+    }
+}
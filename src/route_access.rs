@@ -0,0 +1,60 @@
+use async_trait::async_trait;
+use hyper::header::{CONTENT_TYPE, RETRY_AFTER};
+use hyper::{Body, Request, Response, StatusCode};
+
+use crate::cache::Uncacheable;
+use crate::error_pages::Rendered;
+use crate::middleware::{Context, Middleware};
+use crate::router::{Maintenance, Router};
+
+/// Middleware turning away the requests a route does not let through,
+/// answering for routes in maintenance and checking credentials, itself or
+/// with a forward-auth service. It runs right before the cache, so cached
+/// responses only reach clients the upstream would have answered.
+pub struct RouteAccess {
+    router: Router,
+}
+
+impl RouteAccess {
+    pub fn new(router: Router) -> Self {
+        RouteAccess { router }
+    }
+}
+
+#[async_trait]
+impl Middleware for RouteAccess {
+    async fn on_request(&self, req: &mut Request<Body>, ctx: &mut Context) -> Option<Response<Body>> {
+        let route = self.router.route(req, &ctx.listener)?;
+        if !route.access.permits(ctx.client_addr.ip()) {
+            return Some(Response::builder().status(StatusCode::FORBIDDEN).body(Body::from("Forbidden")).unwrap());
+        }
+        if route.maintenance.active() {
+            return Some(maintenance_page(&route.maintenance));
+        }
+        if let Some(auth) = &route.basic_auth {
+            if let Some(res) = auth.check(req).await {
+                return Some(res);
+            }
+        }
+        let auth = route.forward_auth.as_ref()?;
+        // What the service approves usually rides on cookies, so the responses may differ per client
+        if !auth.cache {
+            ctx.extensions.insert(Uncacheable);
+        }
+        auth.check(req, ctx.client_addr.ip(), ctx.tls).await
+    }
+}
+
+/// The 503 answered for a route in maintenance. Without a page of its own
+/// the `503` error page, if any, is filled in later.
+fn maintenance_page(maintenance: &Maintenance) -> Response<Body> {
+    let mut res = Response::builder().status(StatusCode::SERVICE_UNAVAILABLE).header(RETRY_AFTER, maintenance.retry_after);
+    let body = match &maintenance.page {
+        Some(page) => {
+            res = res.header(CONTENT_TYPE, "text/html; charset=utf-8").extension(Rendered);
+            Body::from(page.clone())
+        }
+        None => Body::from("Service Unavailable: down for maintenance"),
+    };
+    res.body(body).unwrap()
+}
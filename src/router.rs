@@ -0,0 +1,522 @@
+use hyper::header::HOST;
+use hyper::Request;
+use rand::Rng;
+use regex::Regex;
+use std::borrow::Cow;
+use std::sync::atomic::{AtomicBool, AtomicU32, AtomicUsize, Ordering};
+use std::sync::Arc;
+use std::time::Duration;
+
+use crate::acl::AccessList;
+use crate::bandwidth::Bandwidth;
+use crate::basic_auth::BasicAuth;
+use crate::cache::CacheKey;
+use crate::config::MaintenanceConfig;
+use crate::forward_auth::ForwardAuth;
+use crate::geoip::GeoInfo;
+use crate::headers::{HeaderRuleSet, ResponseRewrite, SecurityHeaders};
+use crate::static_files::StaticFiles;
+
+/// Sends requests for matching hostnames and paths to a pool, identified by its index.
+#[derive(Debug, Clone)]
+pub struct Route {
+    /// Optional name for addressing the route in the admin API
+    pub name: Option<String>,
+    /// Hostnames to match; any host when empty
+    pub hosts: Vec<String>,
+    /// Client countries to match, upper-case; any country when empty
+    pub countries: Vec<String>,
+    /// Path prefix to match, such as `/api`; any path when unset
+    pub path_prefix: Option<String>,
+    /// Listeners the route is served on; every listener when empty
+    pub listeners: Vec<String>,
+    /// Remove the matched prefix before forwarding
+    pub strip_prefix: bool,
+    /// Pattern replaced in the path after stripping, and its replacement
+    pub rewrite: Option<(Regex, String)>,
+    /// Prefix added to the path last
+    pub add_prefix: Option<String>,
+    pub pool: usize,
+    /// Pair of pools replacing `pool`, of which the active one gets the traffic
+    pub blue_green: Option<Arc<BlueGreen>>,
+    /// Share of the traffic sent to a canary pool instead
+    pub canary: Option<Arc<TrafficShare>>,
+    /// Share of the traffic also copied to a shadow pool
+    pub mirror: Option<Arc<TrafficShare>>,
+    /// Overrides of the pool's timeouts
+    pub response_header_timeout: Option<Duration>,
+    pub request_timeout: Option<Duration>,
+    /// Server-Sent Events endpoint, never compressed, cached or timed out by the pool's or global limits
+    pub sse: bool,
+    /// Override of the request body limit; 0 means no limit
+    pub max_body_size: Option<u64>,
+    /// Override of the response body limit; 0 means no limit
+    pub max_response_size: Option<u64>,
+    /// Replaces the global bandwidth limit
+    pub bandwidth: Option<Arc<Bandwidth>>,
+    /// Replaces the default cache key of host, path and query
+    pub cache_key: Option<Arc<CacheKey>>,
+    /// Serves files from a directory in place of the pool
+    pub static_files: Option<Arc<StaticFiles>>,
+    /// Applied after the global header rules
+    pub headers: HeaderRuleSet,
+    /// Replaces the global security headers
+    pub security_headers: Option<SecurityHeaders>,
+    /// Replaces the global response rewrites
+    pub response_rewrite: Option<ResponseRewrite>,
+    /// Clients allowed to use the route, besides the listener's list
+    pub access: AccessList,
+    /// Service approving each request before it is proxied
+    pub forward_auth: Option<Arc<ForwardAuth>>,
+    /// Users of an htpasswd file allowed to use the route
+    pub basic_auth: Option<Arc<BasicAuth>>,
+    pub maintenance: Arc<Maintenance>,
+}
+
+impl Route {
+    /// The pool taking the route's traffic: the active blue-green pool, if any.
+    pub fn primary_pool(&self) -> usize {
+        self.blue_green.as_ref().map_or(self.pool, |pair| pair.active())
+    }
+
+    /// The pool for one request, sending the canary's share to the canary pool.
+    pub fn pick_pool(&self) -> usize {
+        match &self.canary {
+            Some(canary) if canary.take() => canary.pool,
+            _ => self.primary_pool(),
+        }
+    }
+
+    fn serves(&self, listener: &str) -> bool {
+        self.listeners.is_empty() || self.listeners.iter().any(|name| name == listener)
+    }
+
+    fn matches(&self, host: Option<&str>, path: &str, country: Option<&str>) -> bool {
+        let host_ok = self.hosts.is_empty() || host.is_some_and(|host| self.hosts.iter().any(|pattern| host_matches(pattern, host)));
+        let path_ok = self.path_prefix.as_deref().is_none_or(|prefix| path_matches(prefix, path));
+        let country_ok = self.countries.is_empty() || country.is_some_and(|country| self.countries.iter().any(|code| code == country));
+        host_ok && path_ok && country_ok
+    }
+
+    /// The path and query to send upstream, with the prefix removed and the
+    /// rewrites applied if configured. The query string is left alone.
+    pub fn upstream_path<'a>(&self, path_and_query: &'a str) -> Cow<'a, str> {
+        let path: Cow<str> = match (self.strip_prefix, self.path_prefix.as_deref()) {
+            (true, Some(prefix)) => {
+                let rest = path_and_query.get(prefix.len()..).unwrap_or("");
+                if rest.starts_with('/') {
+                    Cow::Borrowed(rest)
+                } else {
+                    Cow::Owned(format!("/{}", rest))
+                }
+            }
+            _ => Cow::Borrowed(path_and_query),
+        };
+        if self.rewrite.is_none() && self.add_prefix.is_none() {
+            return path;
+        }
+
+        let (mut rewritten, query) = match path.split_once('?') {
+            Some((path, query)) => (path.to_string(), Some(query.to_string())),
+            None => (path.to_string(), None),
+        };
+        if let Some((pattern, replacement)) = &self.rewrite {
+            rewritten = pattern.replace(&rewritten, replacement.as_str()).into_owned();
+        }
+        if let Some(prefix) = &self.add_prefix {
+            rewritten = format!("{}{}", prefix.trim_end_matches('/'), rewritten);
+        }
+        if !rewritten.starts_with('/') {
+            rewritten.insert(0, '/');
+        }
+        if let Some(query) = query {
+            rewritten.push('?');
+            rewritten.push_str(&query);
+        }
+        Cow::Owned(rewritten)
+    }
+}
+
+/// Two pools of which one is active, switched atomically at runtime.
+#[derive(Debug)]
+pub struct BlueGreen {
+    pub pools: [usize; 2],
+    /// Index into `pools`
+    active: AtomicUsize,
+}
+
+impl BlueGreen {
+    /// A pair with `active` taking the traffic and `standby` idle.
+    pub fn new(active: usize, standby: usize) -> Self {
+        BlueGreen { pools: [active, standby], active: AtomicUsize::new(0) }
+    }
+
+    pub fn active(&self) -> usize {
+        self.pools[self.active.load(Ordering::Relaxed)]
+    }
+
+    pub fn standby(&self) -> usize {
+        self.pools[1 - self.active.load(Ordering::Relaxed)]
+    }
+
+    /// Takes over which pool of `previous`, a pair of the same pools, is active.
+    pub fn carry_over(&self, previous: &BlueGreen) {
+        self.active.store(previous.active.load(Ordering::Relaxed), Ordering::Relaxed);
+    }
+
+    /// Sends all new requests to `pool`. Returns false if it is not one of the pair.
+    pub fn activate(&self, pool: usize) -> bool {
+        match self.pools.iter().position(|&p| p == pool) {
+            Some(index) => {
+                self.active.store(index, Ordering::Relaxed);
+                true
+            }
+            None => false,
+        }
+    }
+}
+
+/// Maintenance mode of a route: while on, its requests get a 503 page
+/// instead of being proxied.
+#[derive(Debug)]
+pub struct Maintenance {
+    active: AtomicBool,
+    /// Whether the config starts the route in maintenance
+    configured: bool,
+    /// Contents of the configured page
+    pub page: Option<String>,
+    pub retry_after: u64,
+}
+
+impl Maintenance {
+    pub fn new(config: &MaintenanceConfig) -> Result<Self, String> {
+        let page = config.page.as_ref().map(|path| std::fs::read_to_string(path).map_err(|e| format!("failed to read maintenance page {}: {}", path, e))).transpose()?;
+        Ok(Maintenance { active: AtomicBool::new(config.enabled), configured: config.enabled, page, retry_after: config.retry_after })
+    }
+
+    pub fn active(&self) -> bool {
+        self.active.load(Ordering::Relaxed)
+    }
+
+    pub fn set_active(&self, active: bool) {
+        self.active.store(active, Ordering::Relaxed);
+    }
+
+    /// Takes over whether `previous` is in maintenance, unless the configured state has changed.
+    pub fn carry_over(&self, previous: &Maintenance) {
+        if previous.configured == self.configured {
+            self.set_active(previous.active());
+        }
+    }
+}
+
+/// A percentage of a route's requests sent to another pool, adjustable at runtime.
+#[derive(Debug)]
+pub struct TrafficShare {
+    pub pool: usize,
+    /// Share in hundredths of a percent
+    basis_points: AtomicU32,
+    /// Share set in the config
+    configured: u32,
+}
+
+impl TrafficShare {
+    pub fn new(pool: usize, percent: f64) -> Self {
+        let configured = to_basis_points(percent);
+        TrafficShare { pool, basis_points: AtomicU32::new(configured), configured }
+    }
+
+    /// Takes over the runtime share of `previous`, a share of the same pool,
+    /// unless the configured share has changed.
+    pub fn carry_over(&self, previous: &TrafficShare) {
+        if previous.configured == self.configured {
+            self.basis_points.store(previous.basis_points.load(Ordering::Relaxed), Ordering::Relaxed);
+        }
+    }
+
+    pub fn percent(&self) -> f64 {
+        self.basis_points.load(Ordering::Relaxed) as f64 / 100.0
+    }
+
+    pub fn set_percent(&self, percent: f64) {
+        self.basis_points.store(to_basis_points(percent), Ordering::Relaxed);
+    }
+
+    /// Whether the next request is part of the share.
+    pub fn take(&self) -> bool {
+        let basis_points = self.basis_points.load(Ordering::Relaxed);
+        basis_points > 0 && rand::thread_rng().gen_range(0..10_000) < basis_points
+    }
+}
+
+fn to_basis_points(percent: f64) -> u32 {
+    (percent.clamp(0.0, 100.0) * 100.0).round() as u32
+}
+
+/// Picks the route for a request. The first matching route wins; requests
+/// that match none go to pool 0.
+#[derive(Debug, Clone, Default)]
+pub struct Router {
+    routes: Vec<Route>,
+}
+
+impl Router {
+    pub fn new(routes: Vec<Route>) -> Self {
+        let routes = routes
+            .into_iter()
+            .map(|route| Route {
+                hosts: route.hosts.iter().map(|h| h.to_ascii_lowercase()).collect(),
+                countries: route.countries.iter().map(|c| c.to_ascii_uppercase()).collect(),
+                // `/api/*` and `/api/` are accepted as spellings of `/api`
+                path_prefix: route.path_prefix.map(|p| p.trim_end_matches('*').trim_end_matches('/').to_string()),
+                ..route
+            })
+            .collect();
+        Router { routes }
+    }
+
+    /// The first route on `listener` matching `req`, if any. The path is
+    /// matched normalised, so other spellings of it cannot slip past a route.
+    pub fn route<B>(&self, req: &Request<B>, listener: &str) -> Option<&Route> {
+        let host = request_host(req);
+        let path = normalize_path(req.uri().path());
+        let country = req.extensions().get::<GeoInfo>().and_then(|info| info.country.as_deref());
+        self.routes.iter().find(|route| route.serves(listener) && route.matches(host.as_deref(), &path, country))
+    }
+
+    /// All routes, in matching order.
+    pub fn routes(&self) -> &[Route] {
+        &self.routes
+    }
+
+    /// The route with the given name, or at the given position, and its position.
+    pub fn find(&self, id: &str) -> Option<(usize, &Route)> {
+        match self.routes.iter().position(|route| route.name.as_deref() == Some(id)) {
+            Some(index) => Some((index, &self.routes[index])),
+            None => id.parse::<usize>().ok().and_then(|index| Some((index, self.routes.get(index)?))),
+        }
+    }
+
+    /// The first route matching a hostname alone, e.g. a TLS SNI name.
+    pub fn route_host(&self, host: Option<&str>) -> Option<&Route> {
+        self.routes.iter().find(|route| route.matches(host, "/", None))
+    }
+}
+
+/// The lowercased hostname from the Host header, or the URI authority for
+/// HTTP/2, without the port.
+pub fn request_host<B>(req: &Request<B>) -> Option<String> {
+    let host = match req.headers().get(HOST).and_then(|h| h.to_str().ok()) {
+        Some(host) => host,
+        None => req.uri().host()?,
+    };
+    let host = match host.rfind(':') {
+        // Keep bracketed IPv6 addresses intact
+        Some(i) if !host[i..].contains(']') => &host[..i],
+        _ => host,
+    };
+    Some(host.to_ascii_lowercase())
+}
+
+/// Matches a hostname against `pattern`, where `*.example.com` matches exactly one extra label.
+pub fn host_matches(pattern: &str, host: &str) -> bool {
+    match pattern.strip_prefix("*.") {
+        Some(suffix) => host.split_once('.').is_some_and(|(_, parent)| parent == suffix),
+        None => pattern == host,
+    }
+}
+
+/// The path with percent-escaped unreserved characters decoded, repeated
+/// slashes collapsed and `.` and `..` segments resolved, so `//admin`,
+/// `/./admin`, `/x/../admin` and `/%61dmin` all become `/admin`. `..` never
+/// climbs above the root.
+pub fn normalize_path(path: &str) -> Cow<'_, str> {
+    let dot_segment = path.split('/').any(|segment| segment == "." || segment == "..");
+    if !path.starts_with('/') || (!dot_segment && !path.contains("//") && !path.contains('%')) {
+        return Cow::Borrowed(path);
+    }
+    let decoded = decode_unreserved(path);
+    let mut segments = Vec::new();
+    for segment in decoded.split('/') {
+        match segment {
+            "" | "." => {}
+            ".." => {
+                segments.pop();
+            }
+            segment => segments.push(segment),
+        }
+    }
+    let mut normalized = String::with_capacity(decoded.len());
+    for segment in &segments {
+        normalized.push('/');
+        normalized.push_str(segment);
+    }
+    // A path naming a directory keeps naming one
+    if normalized.is_empty() || matches!(decoded.rsplit('/').next(), Some("" | "." | "..")) {
+        normalized.push('/');
+    }
+    if normalized == path {
+        Cow::Borrowed(path)
+    } else {
+        Cow::Owned(normalized)
+    }
+}
+
+/// Decodes the percent escapes of letters, digits and `-._~`, which mean the
+/// same escaped or not; others, such as `%2F`, are left as they are.
+fn decode_unreserved(path: &str) -> String {
+    let bytes = path.as_bytes();
+    let mut decoded = Vec::with_capacity(bytes.len());
+    let mut i = 0;
+    while i < bytes.len() {
+        let escaped = bytes.get(i + 1..i + 3).filter(|_| bytes[i] == b'%').and_then(|hex| u8::from_str_radix(std::str::from_utf8(hex).ok()?, 16).ok());
+        match escaped {
+            Some(byte) if byte.is_ascii_alphanumeric() || b"-._~".contains(&byte) => {
+                decoded.push(byte);
+                i += 3;
+            }
+            _ => {
+                decoded.push(bytes[i]);
+                i += 1;
+            }
+        }
+    }
+    // Only ASCII is decoded, so the rest stays as valid as it was
+    String::from_utf8_lossy(&decoded).into_owned()
+}
+
+/// Matches whole path segments, so `/api` matches `/api` and `/api/x` but not `/apix`.
+pub fn path_matches(prefix: &str, path: &str) -> bool {
+    match path.strip_prefix(prefix) {
+        Some(rest) => prefix.is_empty() || rest.is_empty() || rest.starts_with('/'),
+        None => false,
+    }
+}
+
+#[cfg(test)]
+pub(crate) mod tests {
+    use super::*;
+    use crate::config::{MaintenanceConfig, DEFAULT_LISTENER};
+
+    pub(crate) fn route(path_prefix: &str) -> Route {
+        Route {
+            name: None,
+            hosts: Vec::new(),
+            countries: Vec::new(),
+            path_prefix: Some(path_prefix.to_string()),
+            listeners: Vec::new(),
+            strip_prefix: false,
+            rewrite: None,
+            add_prefix: None,
+            pool: 1,
+            blue_green: None,
+            canary: None,
+            mirror: None,
+            response_header_timeout: None,
+            request_timeout: None,
+            sse: false,
+            max_body_size: None,
+            max_response_size: None,
+            bandwidth: None,
+            cache_key: None,
+            static_files: None,
+            headers: HeaderRuleSet::default(),
+            security_headers: None,
+            response_rewrite: None,
+            access: AccessList::default(),
+            forward_auth: None,
+            basic_auth: None,
+            maintenance: Arc::new(Maintenance::new(&MaintenanceConfig::default()).unwrap()),
+        }
+    }
+
+    fn request(path: &str) -> Request<()> {
+        Request::get(path).header(HOST, "example.com").body(()).unwrap()
+    }
+
+    #[test]
+    fn normalizes_paths() {
+        let cases = [
+            ("/admin", "/admin"),
+            ("//admin", "/admin"),
+            ("/admin//users/", "/admin/users/"),
+            ("/./admin", "/admin"),
+            ("/x/../admin", "/admin"),
+            ("/../../admin", "/admin"),
+            ("/admin/.", "/admin/"),
+            ("/admin/x/..", "/admin/"),
+            ("/..", "/"),
+            ("//", "/"),
+            ("/%61dmin", "/admin"),
+            ("/%2e%2e/admin", "/admin"),
+            ("/%2E/admin", "/admin"),
+            ("/a%7Eb%2D", "/a~b-"),
+            // Reserved characters keep their escapes
+            ("/a%2Fb", "/a%2Fb"),
+            ("/a%3Fb%25", "/a%3Fb%25"),
+            // Malformed escapes are left alone
+            ("/a%", "/a%"),
+            ("/a%6", "/a%6"),
+            ("/a%zz", "/a%zz"),
+            ("*", "*"),
+        ];
+        for (path, expected) in cases {
+            assert_eq!(normalize_path(path), expected, "{}", path);
+        }
+        assert!(matches!(normalize_path("/already/normal/"), Cow::Borrowed(_)));
+    }
+
+    #[test]
+    fn other_spellings_of_a_path_match_its_route() {
+        let router = Router::new(vec![route("/admin")]);
+        for path in ["/admin", "//admin", "/./admin", "/%61dmin", "/x/../admin/users", "/%2e%2e/admin", "///admin//users"] {
+            assert!(router.route(&request(path), DEFAULT_LISTENER).is_some(), "{}", path);
+        }
+        for path in ["/", "/administrator", "/admin%2Fusers", "/x/admin", "/admin/../public"] {
+            assert!(router.route(&request(path), DEFAULT_LISTENER).is_none(), "{}", path);
+        }
+    }
+
+    fn stripping(path_prefix: &str) -> Route {
+        Route { strip_prefix: true, ..route(path_prefix) }
+    }
+
+    #[test]
+    fn strips_the_prefix() {
+        let route = stripping("/api");
+        assert_eq!(route.upstream_path("/api/users"), "/users");
+        assert_eq!(route.upstream_path("/api/"), "/");
+        // Nothing left becomes the root
+        assert_eq!(route.upstream_path("/api"), "/");
+        assert_eq!(route.upstream_path("/api?page=2"), "/?page=2");
+        assert_eq!(route.upstream_path("/api/users?page=2&sort=name"), "/users?page=2&sort=name");
+        // A prefix ending in a slash keeps the slash of what follows
+        assert_eq!(stripping("/api/").upstream_path("/api/users"), "/users");
+        assert!(matches!(route.upstream_path("/api/users"), Cow::Borrowed(_)));
+        // Without stripping the path is sent as it came
+        assert_eq!(self::route("/api").upstream_path("/api/users?page=2"), "/api/users?page=2");
+    }
+
+    #[test]
+    fn rewrites_the_path_but_not_the_query() {
+        let route = Route { rewrite: Some((Regex::new("^/v1/(?P<resource>[a-z]+)/([0-9]+)$").unwrap(), "/$resource/by-id/$2".to_string())), ..stripping("/api") };
+        assert_eq!(route.upstream_path("/api/v1/users/42?fields=name"), "/users/by-id/42?fields=name");
+        // Unmatched paths pass unchanged, and the query is never matched against
+        assert_eq!(route.upstream_path("/api/v2/users/42"), "/v2/users/42");
+        assert_eq!(route.upstream_path("/api/other?/v1/users/42"), "/other?/v1/users/42");
+
+        let route = Route { rewrite: Some((Regex::new("^/old/(.*)").unwrap(), "new/$1".to_string())), ..self::route("/old") };
+        // The result always starts with a slash
+        assert_eq!(route.upstream_path("/old/page?x=%2F"), "/new/page?x=%2F");
+        assert_eq!(route.upstream_path("/old/"), "/new/");
+    }
+
+    #[test]
+    fn adds_a_prefix_last() {
+        let route = Route { add_prefix: Some("/backend/".to_string()), ..stripping("/api") };
+        assert_eq!(route.upstream_path("/api/users?page=2"), "/backend/users?page=2");
+        assert_eq!(route.upstream_path("/api"), "/backend/");
+        let route = Route { rewrite: Some((Regex::new("^/(.*)$").unwrap(), "/v2/$1".to_string())), add_prefix: Some("/svc".to_string()), ..stripping("/api") };
+        assert_eq!(route.upstream_path("/api/users?q=a?b"), "/svc/v2/users?q=a?b");
+    }
+}
@@ -0,0 +1,154 @@
+use async_trait::async_trait;
+use hyper::header::RETRY_AFTER;
+use hyper::{Body, Request, Response, StatusCode};
+use std::sync::atomic::{AtomicU64, AtomicUsize, Ordering};
+use std::sync::{Arc, Mutex};
+use std::time::Instant;
+
+use crate::config::LoadSheddingConfig;
+use crate::middleware::{Context, Middleware};
+
+/// Weight of each latency sample in the short-term average, roughly the last 10 requests.
+const SHORT_WEIGHT: f64 = 0.1;
+/// Weight of each latency sample in the baseline, roughly the last 600 requests.
+const LONG_WEIGHT: f64 = 1.0 / 600.0;
+/// Share of each newly computed limit in the limit used, so single slow requests do not swing it.
+const SMOOTHING: f64 = 0.2;
+
+/// Latency averages and the limit derived from them.
+#[derive(Debug)]
+struct Gradient {
+    limit: f64,
+    /// Recent latency in seconds, `None` until the first request finishes
+    short: Option<f64>,
+    /// Baseline latency in seconds
+    long: f64,
+}
+
+/// Middleware that caps the requests in flight at a limit adapted to
+/// latency, in the style of a gradient concurrency limiter: while recent
+/// latency stays within `tolerance` of the long-term baseline the limit
+/// grows, and once it rises above that the limit shrinks in proportion.
+/// Requests over the limit get an immediate 503 instead of queueing up
+/// behind slow ones.
+pub struct LoadShedder {
+    config: LoadSheddingConfig,
+    in_flight: Arc<AtomicUsize>,
+    shed: AtomicU64,
+    gradient: Mutex<Gradient>,
+}
+
+/// A request counted against the limit until it is dropped with the context.
+struct Admitted {
+    in_flight: Arc<AtomicUsize>,
+    start: Instant,
+    /// Requests in flight when this one was admitted
+    load: usize,
+}
+
+impl Drop for Admitted {
+    fn drop(&mut self) {
+        self.in_flight.fetch_sub(1, Ordering::AcqRel);
+    }
+}
+
+impl LoadShedder {
+    pub fn new(config: &LoadSheddingConfig) -> Self {
+        LoadShedder {
+            config: config.clone(),
+            in_flight: Arc::new(AtomicUsize::new(0)),
+            shed: AtomicU64::new(0),
+            gradient: Mutex::new(Gradient { limit: config.min_limit as f64, short: None, long: 0.0 }),
+        }
+    }
+
+    /// Whether this shedder was created from `config`, so its learned limit can be kept across a reload.
+    pub fn uses(&self, config: &LoadSheddingConfig) -> bool {
+        self.config == *config
+    }
+
+    /// The current limit on requests in flight.
+    pub fn limit(&self) -> usize {
+        self.gradient.lock().unwrap().limit as usize
+    }
+
+    pub fn in_flight(&self) -> usize {
+        self.in_flight.load(Ordering::Acquire)
+    }
+
+    /// Requests rejected so far.
+    pub fn shed(&self) -> u64 {
+        self.shed.load(Ordering::Relaxed)
+    }
+
+    /// Counts a request in, or returns `None` when the limit is reached.
+    fn admit(&self) -> Option<Admitted> {
+        let limit = self.limit();
+        let load = self.in_flight.fetch_update(Ordering::AcqRel, Ordering::Acquire, |n| (n < limit).then(|| n + 1)).ok()?;
+        Some(Admitted { in_flight: Arc::clone(&self.in_flight), start: Instant::now(), load: load + 1 })
+    }
+
+    /// Adapts the limit to the latency of a finished request.
+    fn observe(&self, admitted: &Admitted) {
+        let latency = admitted.start.elapsed().as_secs_f64();
+        let mut gradient = self.gradient.lock().unwrap();
+        let short = match gradient.short {
+            Some(short) => short + (latency - short) * SHORT_WEIGHT,
+            None => {
+                gradient.long = latency;
+                latency
+            }
+        };
+        gradient.short = Some(short);
+        gradient.long += (latency - gradient.long) * LONG_WEIGHT;
+        // After a lasting drop in latency the baseline catches up quickly
+        if gradient.long > short * 2.0 {
+            gradient.long *= 0.95;
+        }
+
+        // Well below the limit, latency says nothing about how far it could go
+        if (admitted.load as f64) < gradient.limit / 2.0 {
+            return;
+        }
+        let ratio = if short > 0.0 { self.config.tolerance * gradient.long / short } else { 1.0 };
+        let target = gradient.limit * ratio.clamp(0.5, 1.0) + gradient.limit.sqrt();
+        let limit = gradient.limit * (1.0 - SMOOTHING) + target * SMOOTHING;
+        gradient.limit = limit.clamp(self.config.min_limit as f64, self.config.max_limit as f64);
+    }
+}
+
+#[async_trait]
+impl Middleware for LoadShedder {
+    async fn on_request(&self, _req: &mut Request<Body>, ctx: &mut Context) -> Option<Response<Body>> {
+        if ctx.background {
+            return None;
+        }
+        match self.admit() {
+            Some(admitted) => {
+                ctx.extensions.insert(admitted);
+                None
+            }
+            None => {
+                self.shed.fetch_add(1, Ordering::Relaxed);
+                let res = Response::builder()
+                    .status(StatusCode::SERVICE_UNAVAILABLE)
+                    .header(RETRY_AFTER, "1")
+                    .body(Body::from("Service Unavailable"))
+                    .unwrap();
+                Some(res)
+            }
+        }
+    }
+
+    async fn on_response(&self, _res: &mut Response<Body>, ctx: &mut Context) {
+        if let Some(admitted) = ctx.extensions.remove::<Admitted>() {
+            self.observe(&admitted);
+        }
+    }
+
+    async fn on_error(&self, _error: &(dyn std::error::Error + Send + Sync), ctx: &mut Context) {
+        if let Some(admitted) = ctx.extensions.remove::<Admitted>() {
+            self.observe(&admitted);
+        }
+    }
+}
@@ -0,0 +1,100 @@
+use async_trait::async_trait;
+use hyper::{Body, Request, Response};
+use std::net::IpAddr;
+use std::time::{Duration, Instant};
+use tracing::warn;
+
+use crate::access_log::{track_body, UpstreamUsed};
+use crate::config::SlowRequestsConfig;
+use crate::middleware::{Context, Middleware};
+
+/// Where the time before an upstream's response went, attached to the
+/// response extensions for the slow request log. The times are those of the
+/// last attempt.
+#[derive(Debug, Clone, Copy)]
+pub struct UpstreamTimings {
+    pub attempts: u32,
+    /// Waiting for the pool's concurrency limit to let the request through
+    pub queue: Duration,
+    /// Opening the connection, TLS included; `None` when one was reused
+    pub connect: Option<Duration>,
+    /// From sending the request to the response headers, connecting included
+    pub ttfb: Duration,
+}
+
+/// The request, as it arrived.
+struct Started {
+    at: Instant,
+    client_ip: IpAddr,
+    method: String,
+    path: String,
+    request_id: Option<String>,
+}
+
+/// Middleware logging the requests that took longer than a threshold, from
+/// their arrival until the last byte of the response was sent, with the
+/// upstream that answered and where the time went.
+pub struct SlowRequests {
+    threshold: Duration,
+}
+
+impl SlowRequests {
+    pub fn new(config: &SlowRequestsConfig) -> Self {
+        SlowRequests { threshold: Duration::from_millis(config.threshold_ms) }
+    }
+}
+
+#[async_trait]
+impl Middleware for SlowRequests {
+    async fn on_request(&self, req: &mut Request<Body>, ctx: &mut Context) -> Option<Response<Body>> {
+        if ctx.background {
+            return None;
+        }
+        ctx.extensions.insert(Started {
+            at: Instant::now(),
+            client_ip: ctx.client_addr.ip(),
+            method: req.method().to_string(),
+            path: req.uri().path_and_query().map(|p| p.to_string()).unwrap_or_else(|| "/".to_string()),
+            request_id: ctx.request_id.clone(),
+        });
+        None
+    }
+
+    async fn on_response(&self, res: &mut Response<Body>, ctx: &mut Context) {
+        let started = match ctx.extensions.remove::<Started>() {
+            Some(started) => started,
+            None => return,
+        };
+        let threshold = self.threshold;
+        let status = res.status().as_u16();
+        let upstream = res.extensions().get::<UpstreamUsed>().map(|u| u.0.clone());
+        let timings = res.extensions().get::<UpstreamTimings>().copied();
+        let body = std::mem::replace(res.body_mut(), Body::empty());
+        *res.body_mut() = track_body(body, move |bytes| {
+            let total = started.at.elapsed();
+            if total < threshold {
+                return;
+            }
+            warn!(
+                method = %started.method,
+                path = %started.path,
+                status,
+                client_ip = %started.client_ip,
+                request_id = started.request_id.as_deref(),
+                upstream = upstream.as_deref(),
+                attempts = timings.map(|t| t.attempts),
+                queue_ms = timings.map(|t| ms(t.queue)),
+                connect_ms = timings.and_then(|t| t.connect).map(ms),
+                reused_connection = timings.map(|t| t.connect.is_none()),
+                ttfb_ms = timings.map(|t| ms(t.ttfb)),
+                total_ms = ms(total),
+                bytes,
+                "Slow request"
+            );
+        });
+    }
+}
+
+fn ms(duration: Duration) -> f64 {
+    (duration.as_secs_f64() * 1000.0 * 10.0).round() / 10.0
+}
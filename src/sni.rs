@@ -0,0 +1,230 @@
+use std::io;
+use tokio::io::{AsyncRead, AsyncReadExt};
+
+/// TLS record type of handshake messages.
+const HANDSHAKE: u8 = 0x16;
+/// Handshake message type of the ClientHello.
+const CLIENT_HELLO: u8 = 0x01;
+/// Extension carrying the requested hostname.
+const SERVER_NAME: u16 = 0x0000;
+/// Upper bound on the ClientHello we are willing to buffer.
+const MAX_HELLO_LEN: usize = 64 * 1024;
+
+/// Reads the TLS ClientHello from the start of `stream` without terminating
+/// TLS. Returns the bytes read, which must be replayed to the upstream, and
+/// the SNI hostname if the client sent one.
+pub async fn read_client_hello<S: AsyncRead + Unpin>(stream: &mut S) -> io::Result<(Vec<u8>, Option<String>)> {
+    let mut raw = Vec::new();
+    let mut handshake = Vec::new();
+
+    // The ClientHello may be split across several records
+    loop {
+        let mut header = [0u8; 5];
+        stream.read_exact(&mut header).await?;
+        if header[0] != HANDSHAKE {
+            return Err(invalid("not a TLS handshake"));
+        }
+        let len = u16::from_be_bytes([header[3], header[4]]) as usize;
+        let mut fragment = vec![0u8; len];
+        stream.read_exact(&mut fragment).await?;
+        raw.extend_from_slice(&header);
+        raw.extend_from_slice(&fragment);
+        handshake.extend_from_slice(&fragment);
+
+        if handshake.len() >= 4 {
+            let message_len = u32::from_be_bytes([0, handshake[1], handshake[2], handshake[3]]) as usize;
+            if handshake.len() >= message_len + 4 {
+                break;
+            }
+        }
+        if raw.len() > MAX_HELLO_LEN {
+            return Err(invalid("TLS ClientHello too large"));
+        }
+    }
+
+    if handshake[0] != CLIENT_HELLO {
+        return Err(invalid("first TLS handshake message is not a ClientHello"));
+    }
+    let sni = server_name(&handshake[4..]).ok_or_else(|| invalid("malformed TLS ClientHello"))?;
+    Ok((raw, sni))
+}
+
+/// Extracts the host_name entry of the server_name extension from a
+/// ClientHello body. Returns `None` if the message is malformed.
+fn server_name(hello: &[u8]) -> Option<Option<String>> {
+    let mut reader = Reader(hello);
+    reader.skip(2 + 32)?; // client_version, random
+    reader.vec8()?; // session_id
+    reader.vec16()?; // cipher_suites
+    reader.vec8()?; // compression_methods
+    if reader.0.is_empty() {
+        // No extensions at all
+        return Some(None);
+    }
+
+    let mut extensions = Reader(reader.vec16()?);
+    while !extensions.0.is_empty() {
+        let kind = extensions.u16()?;
+        let mut data = Reader(extensions.vec16()?);
+        if kind != SERVER_NAME {
+            continue;
+        }
+        let mut names = Reader(data.vec16()?);
+        while !names.0.is_empty() {
+            let name_type = names.u8()?;
+            let name = names.vec16()?;
+            if name_type == 0 {
+                let name = std::str::from_utf8(name).ok()?;
+                return Some(Some(name.trim_end_matches('.').to_ascii_lowercase()));
+            }
+        }
+    }
+    Some(None)
+}
+
+/// Minimal big-endian cursor over a byte slice.
+struct Reader<'a>(&'a [u8]);
+
+impl<'a> Reader<'a> {
+    fn take(&mut self, n: usize) -> Option<&'a [u8]> {
+        if self.0.len() < n {
+            return None;
+        }
+        let (head, rest) = self.0.split_at(n);
+        self.0 = rest;
+        Some(head)
+    }
+
+    fn skip(&mut self, n: usize) -> Option<()> {
+        self.take(n).map(|_| ())
+    }
+
+    fn u8(&mut self) -> Option<u8> {
+        self.take(1).map(|b| b[0])
+    }
+
+    fn u16(&mut self) -> Option<u16> {
+        self.take(2).map(|b| u16::from_be_bytes([b[0], b[1]]))
+    }
+
+    /// A vector with a one-byte length prefix.
+    fn vec8(&mut self) -> Option<&'a [u8]> {
+        let len = self.u8()? as usize;
+        self.take(len)
+    }
+
+    /// A vector with a two-byte length prefix.
+    fn vec16(&mut self) -> Option<&'a [u8]> {
+        let len = self.u16()? as usize;
+        self.take(len)
+    }
+}
+
+fn invalid(message: &str) -> io::Error {
+    io::Error::new(io::ErrorKind::InvalidData, message)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    /// A ClientHello body with the given extensions.
+    fn hello(extensions: Option<&[u8]>) -> Vec<u8> {
+        let mut body = vec![0x03, 0x03];
+        body.extend_from_slice(&[0x42; 32]);
+        body.extend_from_slice(&[0x00]); // no session_id
+        body.extend_from_slice(&[0x00, 0x02, 0x13, 0x01]); // TLS_AES_128_GCM_SHA256
+        body.extend_from_slice(&[0x01, 0x00]); // null compression
+        if let Some(extensions) = extensions {
+            body.extend_from_slice(&(extensions.len() as u16).to_be_bytes());
+            body.extend_from_slice(extensions);
+        }
+        let mut message = vec![CLIENT_HELLO];
+        message.extend_from_slice(&(body.len() as u32).to_be_bytes()[1..]);
+        message.extend_from_slice(&body);
+        message
+    }
+
+    fn server_name_extension(name: &str) -> Vec<u8> {
+        let mut entry = vec![0x00];
+        entry.extend_from_slice(&(name.len() as u16).to_be_bytes());
+        entry.extend_from_slice(name.as_bytes());
+        let mut extension = SERVER_NAME.to_be_bytes().to_vec();
+        extension.extend_from_slice(&(entry.len() as u16 + 2).to_be_bytes());
+        extension.extend_from_slice(&(entry.len() as u16).to_be_bytes());
+        extension.extend_from_slice(&entry);
+        extension
+    }
+
+    /// `message` split into handshake records of at most `size` bytes.
+    fn records(message: &[u8], size: usize) -> Vec<u8> {
+        message.chunks(size).flat_map(|chunk| [&[HANDSHAKE, 0x03, 0x01][..], &(chunk.len() as u16).to_be_bytes(), chunk].concat()).collect()
+    }
+
+    async fn read(input: &[u8]) -> io::Result<(Vec<u8>, Option<String>)> {
+        let mut stream = input;
+        read_client_hello(&mut stream).await
+    }
+
+    #[tokio::test]
+    async fn reads_the_server_name() {
+        // Another extension first, then the name
+        let extensions = [&[0x00, 0x0b, 0x00, 0x02, 0x01, 0x00][..], &server_name_extension("Example.COM.")].concat();
+        let input = records(&hello(Some(&extensions)), 16 * 1024);
+        let mut stream = &[&input[..], b"rest"].concat()[..];
+        let (raw, sni) = read_client_hello(&mut stream).await.unwrap();
+        assert_eq!(sni.as_deref(), Some("example.com"));
+        assert_eq!(raw, input);
+        assert_eq!(stream, b"rest");
+    }
+
+    #[tokio::test]
+    async fn reads_a_hello_split_across_records() {
+        let input = records(&hello(Some(&server_name_extension("example.com"))), 7);
+        let (raw, sni) = read(&input).await.unwrap();
+        assert_eq!(sni.as_deref(), Some("example.com"));
+        assert_eq!(raw, input);
+    }
+
+    #[tokio::test]
+    async fn a_hello_without_a_name_has_none() {
+        assert_eq!(read(&records(&hello(None), 1024)).await.unwrap().1, None);
+        assert_eq!(read(&records(&hello(Some(&[])), 1024)).await.unwrap().1, None);
+        assert_eq!(read(&records(&hello(Some(&[0x00, 0x0b, 0x00, 0x02, 0x01, 0x00])), 1024)).await.unwrap().1, None);
+    }
+
+    #[tokio::test]
+    async fn rejects_malformed_hellos() {
+        let named = hello(Some(&server_name_extension("example.com")));
+        let mut not_a_hello = named.clone();
+        not_a_hello[0] = 0x02;
+        // The name runs past the end of its extension
+        let mut overrun = named.clone();
+        let last = overrun.len() - "example.com".len() - 1;
+        overrun[last] = 0xff;
+        let mut not_utf8 = named.clone();
+        *not_utf8.last_mut().unwrap() = 0xff;
+        // Complete as a message, but its body stops inside the cipher suites
+        let mut short = named[..40].to_vec();
+        short[1..4].copy_from_slice(&36u32.to_be_bytes()[1..]);
+        let mut not_handshake = records(&named, 1024);
+        not_handshake[0] = 0x17;
+        for input in [records(&not_a_hello, 1024), records(&overrun, 1024), records(&not_utf8, 1024), records(&short, 1024), not_handshake] {
+            assert_eq!(read(&input).await.unwrap_err().kind(), io::ErrorKind::InvalidData, "{:?}", input);
+        }
+        // Ends before the message is complete
+        let truncated = records(&named, 1024);
+        assert_eq!(read(&truncated[..truncated.len() - 1]).await.unwrap_err().kind(), io::ErrorKind::UnexpectedEof);
+        assert_eq!(read(&truncated[..3]).await.unwrap_err().kind(), io::ErrorKind::UnexpectedEof);
+    }
+
+    #[tokio::test]
+    async fn rejects_oversized_hellos() {
+        // Announces a message longer than any we buffer, then keeps sending records
+        let mut header = vec![CLIENT_HELLO, 0xff, 0xff, 0xff];
+        header.extend_from_slice(&[0; 1020]);
+        let mut input = records(&header, 1024);
+        input.extend_from_slice(&records(&vec![0; 128 * 1024], 16 * 1024));
+        assert_eq!(read(&input).await.unwrap_err().kind(), io::ErrorKind::InvalidData);
+    }
+}
@@ -0,0 +1,463 @@
+use hyper::header::{HeaderValue, ACCEPT_RANGES, ALLOW, CACHE_CONTROL, CONTENT_LENGTH, CONTENT_RANGE, CONTENT_TYPE, ETAG, IF_MODIFIED_SINCE, IF_NONE_MATCH, IF_RANGE, LAST_MODIFIED, LOCATION, RANGE};
+use hyper::{Body, Method, Request, Response, StatusCode};
+use std::fs::Metadata;
+use std::io::SeekFrom;
+use std::path::{Path, PathBuf};
+use std::time::UNIX_EPOCH;
+use tokio::io::{AsyncReadExt, AsyncSeekExt};
+use tokio_util::io::ReaderStream;
+use tracing::warn;
+
+use crate::config::StaticFilesConfig;
+
+/// The only hidden directory served, as it holds files meant to be public.
+const WELL_KNOWN: &str = ".well-known";
+
+/// Serves the files under a directory for a route, with ETags, range
+/// requests, directory index files and optionally listings, and a fallback
+/// file for single-page apps.
+#[derive(Debug)]
+pub struct StaticFiles {
+    /// Canonical, so files reached through symlinks can be kept inside it
+    root: PathBuf,
+    index: Vec<String>,
+    listing: bool,
+    fallback: Option<String>,
+    cache_control: Option<HeaderValue>,
+}
+
+/// What a request path names under the root.
+enum Found {
+    File(PathBuf, Metadata),
+    Directory(PathBuf),
+    Nothing,
+}
+
+impl StaticFiles {
+    pub fn new(config: &StaticFilesConfig) -> Result<Self, String> {
+        let root = std::fs::canonicalize(&config.root).map_err(|e| format!("static_files.root {}: {}", config.root, e))?;
+        if !root.is_dir() {
+            return Err(format!("static_files.root {} is not a directory", config.root));
+        }
+        Ok(StaticFiles {
+            root,
+            index: config.index.clone(),
+            listing: config.listing,
+            fallback: config.fallback.clone(),
+            cache_control: config.cache_control.as_deref().map(HeaderValue::from_str).transpose().map_err(|_| "invalid static_files.cache_control".to_string())?,
+        })
+    }
+
+    /// Answers a request for `path`, its path after the route's prefix
+    /// stripping and rewrites.
+    pub async fn serve(&self, req: &Request<Body>, path: &str) -> Response<Body> {
+        if req.method() != Method::GET && req.method() != Method::HEAD {
+            return Response::builder().status(StatusCode::METHOD_NOT_ALLOWED).header(ALLOW, "GET, HEAD").body(Body::from("Method Not Allowed")).unwrap();
+        }
+        let path = path.split('?').next().unwrap_or("/");
+        match self.find(path).await {
+            Found::File(file, metadata) => self.file_response(req, &file, &metadata).await,
+            Found::Directory(dir) => {
+                // Relative links in the index need the trailing slash
+                if !req.uri().path().ends_with('/') {
+                    let query = req.uri().query().map(|query| format!("?{}", query)).unwrap_or_default();
+                    let location = format!("{}/{}", req.uri().path(), query);
+                    return Response::builder().status(StatusCode::MOVED_PERMANENTLY).header(LOCATION, location).body(Body::empty()).unwrap();
+                }
+                for name in &self.index {
+                    if let Found::File(file, metadata) = self.inspect(dir.join(name)).await {
+                        return self.file_response(req, &file, &metadata).await;
+                    }
+                }
+                if self.listing {
+                    if let Some(res) = listing(&dir, req.uri().path(), dir != self.root).await {
+                        return res;
+                    }
+                }
+                self.fallback(req).await
+            }
+            Found::Nothing => self.fallback(req).await,
+        }
+    }
+
+    /// The fallback file, or a 404 without one.
+    async fn fallback(&self, req: &Request<Body>) -> Response<Body> {
+        if let Some(fallback) = &self.fallback {
+            if let Found::File(file, metadata) = self.find(fallback).await {
+                return self.file_response(req, &file, &metadata).await;
+            }
+        }
+        Response::builder().status(StatusCode::NOT_FOUND).body(Body::from("Not Found")).unwrap()
+    }
+
+    /// Looks up a URL path under the root. `..` is refused, as are hidden
+    /// files other than `.well-known`, e.g. `.git` or `.env`.
+    async fn find(&self, path: &str) -> Found {
+        let mut local = self.root.clone();
+        for segment in path.split('/') {
+            let segment = match percent_decode(segment) {
+                Some(segment) => segment,
+                None => return Found::Nothing,
+            };
+            match segment.as_str() {
+                "" | "." => {}
+                s if s.starts_with('.') && s != WELL_KNOWN => return Found::Nothing,
+                s if s.contains('/') || s.contains('\0') => return Found::Nothing,
+                s => local.push(s),
+            }
+        }
+        self.inspect(local).await
+    }
+
+    async fn inspect(&self, path: PathBuf) -> Found {
+        // Symlinks are followed, but not out of the root
+        let path = match tokio::fs::canonicalize(&path).await {
+            Ok(path) if path.starts_with(&self.root) => path,
+            _ => return Found::Nothing,
+        };
+        match tokio::fs::metadata(&path).await {
+            Ok(metadata) if metadata.is_file() => Found::File(path, metadata),
+            Ok(metadata) if metadata.is_dir() => Found::Directory(path),
+            _ => Found::Nothing,
+        }
+    }
+
+    async fn file_response(&self, req: &Request<Body>, path: &Path, metadata: &Metadata) -> Response<Body> {
+        let len = metadata.len();
+        let modified = metadata.modified().ok();
+        let mtime = modified.and_then(|time| time.duration_since(UNIX_EPOCH).ok()).map_or(0, |since| since.as_secs());
+        let etag = format!("\"{:x}-{:x}\"", mtime, len);
+        let last_modified = modified.map(httpdate::fmt_http_date);
+
+        let mut res = Response::builder().header(ACCEPT_RANGES, "bytes").header(ETAG, &etag).header(CONTENT_TYPE, content_type(path));
+        if let Some(last_modified) = &last_modified {
+            res = res.header(LAST_MODIFIED, last_modified);
+        }
+        if let Some(cache_control) = &self.cache_control {
+            res = res.header(CACHE_CONTROL, cache_control);
+        }
+
+        // A conditional request is answered in full, for the conditions to turn into a 304
+        let conditional = req.headers().contains_key(IF_NONE_MATCH) || req.headers().contains_key(IF_MODIFIED_SINCE);
+        let range = match req.headers().get(RANGE).and_then(|v| v.to_str().ok()) {
+            Some(range) if !conditional && if_range_holds(req, &etag, last_modified.as_deref()) => parse_range(range, len),
+            _ => Ok(None),
+        };
+        let (start, count) = match range {
+            Ok(Some((start, end))) => {
+                res = res.status(StatusCode::PARTIAL_CONTENT).header(CONTENT_RANGE, format!("bytes {}-{}/{}", start, end, len));
+                (start, end - start + 1)
+            }
+            Ok(None) => (0, len),
+            Err(()) => {
+                return res.status(StatusCode::RANGE_NOT_SATISFIABLE).header(CONTENT_RANGE, format!("bytes */{}", len)).body(Body::empty()).unwrap();
+            }
+        };
+        let res = res.header(CONTENT_LENGTH, count);
+        if req.method() == Method::HEAD {
+            return res.body(Body::empty()).unwrap();
+        }
+
+        let mut file = match tokio::fs::File::open(path).await {
+            Ok(file) => file,
+            Err(e) => {
+                warn!("Failed to open {}: {}", path.display(), e);
+                return Response::builder().status(StatusCode::NOT_FOUND).body(Body::from("Not Found")).unwrap();
+            }
+        };
+        if start > 0 {
+            if let Err(e) = file.seek(SeekFrom::Start(start)).await {
+                warn!("Failed to read {}: {}", path.display(), e);
+                return Response::builder().status(StatusCode::INTERNAL_SERVER_ERROR).body(Body::from("Internal Server Error")).unwrap();
+            }
+        }
+        res.body(Body::wrap_stream(ReaderStream::new(file.take(count)))).unwrap()
+    }
+}
+
+/// Whether `If-Range`, if sent, still names the file: its strong ETag or its
+/// exact modification date.
+fn if_range_holds(req: &Request<Body>, etag: &str, last_modified: Option<&str>) -> bool {
+    match req.headers().get(IF_RANGE).and_then(|v| v.to_str().ok()) {
+        None => true,
+        Some(tag) if tag.starts_with('"') => tag == etag,
+        Some(date) => last_modified.is_some_and(|modified| httpdate::parse_http_date(date).ok() == httpdate::parse_http_date(modified).ok()),
+    }
+}
+
+/// The first and last byte of a single `Range` over `len` bytes. `Ok(None)`
+/// means the whole file is served, for several ranges or a header that does
+/// not parse; `Err` that the range lies past the end.
+fn parse_range(value: &str, len: u64) -> Result<Option<(u64, u64)>, ()> {
+    let spec = match value.trim().strip_prefix("bytes=") {
+        Some(spec) if !spec.contains(',') => spec.trim(),
+        _ => return Ok(None),
+    };
+    let (first, last) = match spec.split_once('-') {
+        Some(bounds) => bounds,
+        None => return Ok(None),
+    };
+    let range = match (first.parse::<u64>(), last.parse::<u64>()) {
+        (Ok(first), Ok(last)) if first <= last => (first, last.min(len.saturating_sub(1))),
+        (Ok(first), Err(_)) if last.is_empty() => (first, len.saturating_sub(1)),
+        // The final bytes
+        (Err(_), Ok(suffix)) if first.is_empty() => {
+            if suffix == 0 {
+                return Err(());
+            }
+            (len.saturating_sub(suffix), len.saturating_sub(1))
+        }
+        _ => return Ok(None),
+    };
+    if range.0 >= len {
+        return Err(());
+    }
+    Ok(Some(range))
+}
+
+/// An HTML listing of a directory, hidden files left out.
+async fn listing(dir: &Path, url_path: &str, parent: bool) -> Option<Response<Body>> {
+    let mut entries = Vec::new();
+    let mut read = tokio::fs::read_dir(dir).await.ok()?;
+    while let Ok(Some(entry)) = read.next_entry().await {
+        let name = match entry.file_name().into_string() {
+            Ok(name) if !name.starts_with('.') => name,
+            _ => continue,
+        };
+        let is_dir = entry.metadata().await.map(|metadata| metadata.is_dir()).unwrap_or(false);
+        entries.push((!is_dir, name));
+    }
+    // Directories first
+    entries.sort();
+
+    let title = html_escape(url_path);
+    let mut html = format!("<!DOCTYPE html>\n<html>\n<head><meta charset=\"utf-8\"><title>Index of {0}</title></head>\n<body>\n<h1>Index of {0}</h1>\n<ul>\n", title);
+    if parent {
+        html.push_str("<li><a href=\"../\">../</a></li>\n");
+    }
+    for (is_file, name) in entries {
+        let slash = if is_file { "" } else { "/" };
+        html.push_str(&format!("<li><a href=\"{}{}\">{}{}</a></li>\n", percent_encode(&name), slash, html_escape(&name), slash));
+    }
+    html.push_str("</ul>\n</body>\n</html>\n");
+    Some(Response::builder().header(CONTENT_TYPE, "text/html; charset=utf-8").body(Body::from(html)).unwrap())
+}
+
+fn percent_decode(segment: &str) -> Option<String> {
+    let bytes = segment.as_bytes();
+    let mut decoded = Vec::with_capacity(bytes.len());
+    let mut i = 0;
+    while i < bytes.len() {
+        if bytes[i] == b'%' {
+            let hex = std::str::from_utf8(bytes.get(i + 1..i + 3)?).ok()?;
+            decoded.push(u8::from_str_radix(hex, 16).ok()?);
+            i += 3;
+        } else {
+            decoded.push(bytes[i]);
+            i += 1;
+        }
+    }
+    String::from_utf8(decoded).ok()
+}
+
+fn percent_encode(name: &str) -> String {
+    name.bytes()
+        .map(|b| match b {
+            b'A'..=b'Z' | b'a'..=b'z' | b'0'..=b'9' | b'-' | b'.' | b'_' | b'~' => (b as char).to_string(),
+            _ => format!("%{:02X}", b),
+        })
+        .collect()
+}
+
+fn html_escape(text: &str) -> String {
+    text.replace('&', "&amp;").replace('<', "&lt;").replace('>', "&gt;").replace('"', "&quot;")
+}
+
+/// The `Content-Type` of a file, by its extension.
+fn content_type(path: &Path) -> &'static str {
+    let extension = path.extension().and_then(|e| e.to_str()).map(str::to_ascii_lowercase).unwrap_or_default();
+    match extension.as_str() {
+        "html" | "htm" => "text/html; charset=utf-8",
+        "css" => "text/css; charset=utf-8",
+        "js" | "mjs" => "text/javascript; charset=utf-8",
+        "json" | "map" => "application/json",
+        "webmanifest" => "application/manifest+json",
+        "txt" | "md" => "text/plain; charset=utf-8",
+        "csv" => "text/csv; charset=utf-8",
+        "xml" => "application/xml",
+        "rss" => "application/rss+xml",
+        "atom" => "application/atom+xml",
+        "yaml" | "yml" => "application/yaml",
+        "wasm" => "application/wasm",
+        "pdf" => "application/pdf",
+        "zip" => "application/zip",
+        "gz" => "application/gzip",
+        "tar" => "application/x-tar",
+        "svg" => "image/svg+xml",
+        "png" => "image/png",
+        "apng" => "image/apng",
+        "jpg" | "jpeg" => "image/jpeg",
+        "gif" => "image/gif",
+        "webp" => "image/webp",
+        "avif" => "image/avif",
+        "ico" => "image/x-icon",
+        "bmp" => "image/bmp",
+        "woff" => "font/woff",
+        "woff2" => "font/woff2",
+        "ttf" => "font/ttf",
+        "otf" => "font/otf",
+        "mp4" | "m4v" => "video/mp4",
+        "webm" => "video/webm",
+        "mov" => "video/quicktime",
+        "mp3" => "audio/mpeg",
+        "m4a" => "audio/mp4",
+        "ogg" | "oga" => "audio/ogg",
+        "opus" => "audio/opus",
+        "wav" => "audio/wav",
+        "flac" => "audio/flac",
+        _ => "application/octet-stream",
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use hyper::body::to_bytes;
+
+    /// A root with a few files and, next to it, a file that must stay private.
+    struct Site(PathBuf);
+
+    impl Site {
+        fn new(name: &str) -> Self {
+            let dir = std::env::temp_dir().join(format!("riffy-static-{}-{}", name, std::process::id()));
+            let _ = std::fs::remove_dir_all(&dir);
+            std::fs::create_dir_all(dir.join("root/docs")).unwrap();
+            std::fs::create_dir_all(dir.join("root/.well-known")).unwrap();
+            std::fs::create_dir_all(dir.join("outside")).unwrap();
+            std::fs::write(dir.join("root/digits.txt"), "0123456789").unwrap();
+            std::fs::write(dir.join("root/docs/index.html"), "<h1>Docs</h1>").unwrap();
+            std::fs::write(dir.join("root/.env"), "SECRET=1").unwrap();
+            std::fs::write(dir.join("root/.well-known/security.txt"), "Contact: security@example.com").unwrap();
+            std::fs::write(dir.join("outside/secret.txt"), "secret").unwrap();
+            Site(dir)
+        }
+
+        fn files(&self) -> StaticFiles {
+            let root = self.0.join("root").display().to_string();
+            StaticFiles::new(&StaticFilesConfig { root, index: vec!["index.html".to_string()], listing: false, fallback: None, cache_control: None }).unwrap()
+        }
+    }
+
+    impl Drop for Site {
+        fn drop(&mut self) {
+            let _ = std::fs::remove_dir_all(&self.0);
+        }
+    }
+
+    async fn get(files: &StaticFiles, path: &str, headers: &[(&str, &str)]) -> (StatusCode, Option<String>, String) {
+        let mut req = Request::get(path);
+        for (name, value) in headers {
+            req = req.header(*name, *value);
+        }
+        let res = files.serve(&req.body(Body::empty()).unwrap(), path).await;
+        let content_range = res.headers().get(CONTENT_RANGE).map(|v| v.to_str().unwrap().to_string());
+        let status = res.status();
+        let body = to_bytes(res.into_body()).await.unwrap();
+        (status, content_range, String::from_utf8(body.to_vec()).unwrap())
+    }
+
+    #[test]
+    fn parses_ranges() {
+        assert_eq!(parse_range("bytes=0-3", 10), Ok(Some((0, 3))));
+        assert_eq!(parse_range("bytes=2-100", 10), Ok(Some((2, 9))));
+        // Open-ended and suffix ranges
+        assert_eq!(parse_range("bytes=7-", 10), Ok(Some((7, 9))));
+        assert_eq!(parse_range("bytes=-3", 10), Ok(Some((7, 9))));
+        assert_eq!(parse_range("bytes=-30", 10), Ok(Some((0, 9))));
+        assert_eq!(parse_range(" bytes= 4-4 ", 10), Ok(Some((4, 4))));
+        // Unsatisfiable
+        assert_eq!(parse_range("bytes=10-", 10), Err(()));
+        assert_eq!(parse_range("bytes=10-20", 10), Err(()));
+        assert_eq!(parse_range("bytes=-0", 10), Err(()));
+        assert_eq!(parse_range("bytes=0-", 0), Err(()));
+        // Served in full: several ranges, or ones that do not parse
+        for value in ["bytes=0-1,4-5", "bytes=5-2", "bytes=-", "bytes=a-b", "bytes=3", "items=0-3", "bytes=18446744073709551616-"] {
+            assert_eq!(parse_range(value, 10), Ok(None), "{}", value);
+        }
+    }
+
+    #[tokio::test]
+    async fn serves_ranges() {
+        let site = Site::new("ranges");
+        let files = site.files();
+        assert_eq!(get(&files, "/digits.txt", &[]).await, (StatusCode::OK, None, "0123456789".to_string()));
+        assert_eq!(get(&files, "/digits.txt", &[("range", "bytes=2-4")]).await, (StatusCode::PARTIAL_CONTENT, Some("bytes 2-4/10".to_string()), "234".to_string()));
+        assert_eq!(get(&files, "/digits.txt", &[("range", "bytes=-2")]).await, (StatusCode::PARTIAL_CONTENT, Some("bytes 8-9/10".to_string()), "89".to_string()));
+        assert_eq!(get(&files, "/digits.txt", &[("range", "bytes=6-")]).await, (StatusCode::PARTIAL_CONTENT, Some("bytes 6-9/10".to_string()), "6789".to_string()));
+        assert_eq!(get(&files, "/digits.txt", &[("range", "bytes=0-1,5-6")]).await, (StatusCode::OK, None, "0123456789".to_string()));
+        assert_eq!(get(&files, "/digits.txt", &[("range", "bytes=10-")]).await, (StatusCode::RANGE_NOT_SATISFIABLE, Some("bytes */10".to_string()), String::new()));
+        // Conditional requests get the whole file to match against
+        assert_eq!(get(&files, "/digits.txt", &[("range", "bytes=2-4"), ("if-none-match", "\"other\"")]).await.0, StatusCode::OK);
+    }
+
+    #[tokio::test]
+    async fn if_range_must_name_the_current_file() {
+        let site = Site::new("if-range");
+        let files = site.files();
+        let res = files.serve(&Request::get("/digits.txt").body(Body::empty()).unwrap(), "/digits.txt").await;
+        let etag = res.headers()[ETAG].to_str().unwrap().to_string();
+        let modified = res.headers()[LAST_MODIFIED].to_str().unwrap().to_string();
+
+        for (if_range, status) in [
+            (etag.as_str(), StatusCode::PARTIAL_CONTENT),
+            (modified.as_str(), StatusCode::PARTIAL_CONTENT),
+            ("\"0-0\"", StatusCode::OK),
+            // Weak tags never match
+            (&*format!("W/{}", etag), StatusCode::OK),
+            ("Thu, 01 Jan 1970 00:00:00 GMT", StatusCode::OK),
+            ("yesterday", StatusCode::OK),
+        ] {
+            assert_eq!(get(&files, "/digits.txt", &[("range", "bytes=0-0"), ("if-range", if_range)]).await.0, status, "{}", if_range);
+        }
+    }
+
+    #[tokio::test]
+    async fn refuses_paths_out_of_the_root() {
+        let site = Site::new("traversal");
+        let files = site.files();
+        assert_eq!(get(&files, "/docs/", &[]).await.2, "<h1>Docs</h1>");
+        assert_eq!(get(&files, "/%64igits.txt", &[]).await.0, StatusCode::OK);
+        assert_eq!(get(&files, "/.well-known/security.txt", &[]).await.0, StatusCode::OK);
+        for path in [
+            "/../outside/secret.txt",
+            "/docs/../../outside/secret.txt",
+            "/%2e%2e/outside/secret.txt",
+            "/%2E%2E/outside/secret.txt",
+            "/.%2e/outside/secret.txt",
+            "/docs/%2e%2e%2f%2e%2e%2foutside/secret.txt",
+            "/..%2foutside%2fsecret.txt",
+            "/..%5coutside%5csecret.txt",
+            "/digits.txt%00.html",
+            "/%zz",
+            "/.env",
+            "/%2eenv",
+        ] {
+            assert_eq!(get(&files, path, &[]).await.0, StatusCode::NOT_FOUND, "{}", path);
+        }
+    }
+
+    #[cfg(unix)]
+    #[tokio::test]
+    async fn follows_symlinks_only_within_the_root() {
+        let site = Site::new("symlinks");
+        std::os::unix::fs::symlink(site.0.join("root/digits.txt"), site.0.join("root/numbers.txt")).unwrap();
+        std::os::unix::fs::symlink(site.0.join("outside/secret.txt"), site.0.join("root/secret.txt")).unwrap();
+        std::os::unix::fs::symlink(site.0.join("outside"), site.0.join("root/outside")).unwrap();
+        std::os::unix::fs::symlink("../outside/secret.txt", site.0.join("root/docs/relative.txt")).unwrap();
+        let files = site.files();
+        assert_eq!(get(&files, "/numbers.txt", &[]).await.2, "0123456789");
+        for path in ["/secret.txt", "/outside/secret.txt", "/outside/", "/docs/relative.txt"] {
+            assert_eq!(get(&files, path, &[]).await.0, StatusCode::NOT_FOUND, "{}", path);
+        }
+    }
+}
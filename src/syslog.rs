@@ -0,0 +1,217 @@
+use std::io::{self, Write};
+use std::net::{TcpStream, UdpSocket};
+use std::path::PathBuf;
+use std::sync::mpsc::{self, Receiver, SyncSender, TrySendError};
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::sync::Arc;
+use std::time::{Duration, Instant, SystemTime};
+use tracing::warn;
+
+use crate::access_log::format_rfc3339;
+use crate::config::SyslogConfig;
+
+/// Messages waiting to be sent; more are dropped rather than holding up requests.
+const QUEUE: usize = 4096;
+/// How long after a failed connection sending is tried again.
+const RETRY_AFTER: Duration = Duration::from_secs(1);
+const CONNECT_TIMEOUT: Duration = Duration::from_secs(5);
+/// Identifies the messages as access log lines.
+const MSG_ID: &str = "access";
+
+/// Where syslog messages are sent.
+#[derive(Debug, Clone, PartialEq)]
+pub enum Transport {
+    /// One message per datagram
+    Udp(String),
+    /// Messages framed by octet counting (RFC 6587)
+    Tcp(String),
+    /// A local datagram socket such as `/dev/log`
+    Unix(PathBuf),
+}
+
+/// Parses `udp://host:port`, `tcp://host:port` or `unix:///path`.
+pub fn parse_address(address: &str) -> Result<Transport, String> {
+    let hostport = |rest: &str| match rest.rsplit_once(':') {
+        Some((host, port)) if !host.is_empty() && port.parse::<u16>().is_ok() => Ok(rest.to_string()),
+        _ => Err(format!("syslog address {} needs a host and port", address)),
+    };
+    if let Some(rest) = address.strip_prefix("udp://") {
+        hostport(rest).map(Transport::Udp)
+    } else if let Some(rest) = address.strip_prefix("tcp://") {
+        hostport(rest).map(Transport::Tcp)
+    } else if let Some(path) = address.strip_prefix("unix://").or_else(|| address.strip_prefix("unix:")).filter(|path| path.starts_with('/')) {
+        Ok(Transport::Unix(PathBuf::from(path)))
+    } else {
+        Err(format!("syslog address {} must be udp://host:port, tcp://host:port or unix:///path", address))
+    }
+}
+
+/// The code of a syslog facility, by its name as in syslog.conf.
+pub fn facility(name: &str) -> Option<u8> {
+    let code = match name.to_ascii_lowercase().as_str() {
+        "kern" => 0,
+        "user" => 1,
+        "mail" => 2,
+        "daemon" => 3,
+        "auth" => 4,
+        "syslog" => 5,
+        "lpr" => 6,
+        "news" => 7,
+        "uucp" => 8,
+        "cron" => 9,
+        "authpriv" => 10,
+        "ftp" => 11,
+        "local0" => 16,
+        "local1" => 17,
+        "local2" => 18,
+        "local3" => 19,
+        "local4" => 20,
+        "local5" => 21,
+        "local6" => 22,
+        "local7" => 23,
+        _ => return None,
+    };
+    Some(code)
+}
+
+/// Sends RFC 5424 messages to a syslog server or socket from a thread of its
+/// own, so a slow or unreachable server never holds up a request.
+pub struct Syslog {
+    facility: u8,
+    app_name: String,
+    hostname: String,
+    queue: SyncSender<Vec<u8>>,
+    /// Set while messages are dropped, so that is reported once
+    dropping: Arc<AtomicBool>,
+}
+
+impl Syslog {
+    pub fn new(config: &SyslogConfig) -> Result<Syslog, String> {
+        let transport = parse_address(&config.address)?;
+        let facility = facility(&config.facility).ok_or_else(|| format!("unknown syslog facility: {}", config.facility))?;
+        let (queue, messages) = mpsc::sync_channel(QUEUE);
+        let address = config.address.clone();
+        std::thread::Builder::new().name("riffy-syslog".to_string()).spawn(move || send_all(address, transport, messages)).map_err(|e| format!("failed to start syslog thread: {}", e))?;
+        Ok(Syslog { facility, app_name: config.app_name.clone(), hostname: hostname(), queue, dropping: Arc::new(AtomicBool::new(false)) })
+    }
+
+    /// Queues `message` with the given severity, 0 (emergency) to 7 (debug).
+    pub fn send(&self, severity: u8, message: &str) {
+        let line = format!(
+            "<{}>1 {} {} {} {} {} - {}",
+            self.facility * 8 + severity,
+            format_rfc3339(SystemTime::now()),
+            self.hostname,
+            self.app_name,
+            std::process::id(),
+            MSG_ID,
+            message
+        );
+        match self.queue.try_send(line.into_bytes()) {
+            Ok(()) => self.dropping.store(false, Ordering::Relaxed),
+            Err(TrySendError::Full(_)) => {
+                if !self.dropping.swap(true, Ordering::Relaxed) {
+                    warn!("Syslog server is not keeping up, dropping access log lines");
+                }
+            }
+            Err(TrySendError::Disconnected(_)) => {}
+        }
+    }
+}
+
+enum Connection {
+    Udp(UdpSocket),
+    Tcp(TcpStream),
+    #[cfg(unix)]
+    Unix(std::os::unix::net::UnixDatagram),
+}
+
+impl Connection {
+    fn open(transport: &Transport) -> io::Result<Connection> {
+        match transport {
+            Transport::Udp(address) => {
+                let socket = UdpSocket::bind(if address.starts_with('[') { "[::]:0" } else { "0.0.0.0:0" })?;
+                socket.connect(address.as_str())?;
+                Ok(Connection::Udp(socket))
+            }
+            Transport::Tcp(address) => {
+                let addr = std::net::ToSocketAddrs::to_socket_addrs(address.as_str())?.next().ok_or_else(|| io::Error::new(io::ErrorKind::NotFound, "no address found"))?;
+                let stream = TcpStream::connect_timeout(&addr, CONNECT_TIMEOUT)?;
+                stream.set_write_timeout(Some(CONNECT_TIMEOUT))?;
+                Ok(Connection::Tcp(stream))
+            }
+            #[cfg(unix)]
+            Transport::Unix(path) => {
+                let socket = std::os::unix::net::UnixDatagram::unbound()?;
+                socket.connect(path)?;
+                Ok(Connection::Unix(socket))
+            }
+            #[cfg(not(unix))]
+            Transport::Unix(_) => Err(io::Error::new(io::ErrorKind::Unsupported, "unix sockets are not supported on this platform")),
+        }
+    }
+
+    fn send(&mut self, message: &[u8]) -> io::Result<()> {
+        match self {
+            Connection::Udp(socket) => socket.send(message).map(drop),
+            Connection::Tcp(stream) => {
+                stream.write_all(format!("{} ", message.len()).as_bytes())?;
+                stream.write_all(message)
+            }
+            #[cfg(unix)]
+            Connection::Unix(socket) => socket.send(message).map(drop),
+        }
+    }
+}
+
+/// Sends queued messages until the `Syslog` is dropped, connecting again
+/// after a failure. Messages that arrive while the server is unreachable are
+/// dropped.
+fn send_all(address: String, transport: Transport, messages: Receiver<Vec<u8>>) {
+    let mut connection = None;
+    let mut failed_at: Option<Instant> = None;
+    while let Ok(message) = messages.recv() {
+        if connection.is_none() && failed_at.is_none_or(|at| at.elapsed() >= RETRY_AFTER) {
+            match Connection::open(&transport) {
+                Ok(opened) => connection = Some(opened),
+                Err(e) => {
+                    if failed_at.is_none() {
+                        warn!("Failed to connect to syslog at {}: {}", address, e);
+                    }
+                    failed_at = Some(Instant::now());
+                }
+            }
+        }
+        let sent = match &mut connection {
+            Some(connection) => connection.send(&message),
+            None => continue,
+        };
+        match sent {
+            Ok(()) => failed_at = None,
+            Err(e) => {
+                if failed_at.is_none() {
+                    warn!("Failed to send to syslog at {}: {}", address, e);
+                }
+                failed_at = Some(Instant::now());
+                connection = None;
+            }
+        }
+    }
+}
+
+/// This machine's hostname, or `-` when it is unknown, as RFC 5424 has it.
+fn hostname() -> String {
+    #[cfg(unix)]
+    {
+        let mut buf = [0u8; 256];
+        // SAFETY: the buffer is valid for its length, and gethostname leaves the name in it
+        if unsafe { libc::gethostname(buf.as_mut_ptr().cast(), buf.len()) } == 0 {
+            let len = buf.iter().position(|b| *b == 0).unwrap_or(buf.len());
+            let name = String::from_utf8_lossy(&buf[..len]).into_owned();
+            if !name.is_empty() && name.bytes().all(|b| b.is_ascii_graphic()) {
+                return name;
+            }
+        }
+    }
+    "-".to_string()
+}
@@ -0,0 +1,142 @@
+use socket2::{Socket, Type};
+use std::os::fd::{FromRawFd, OwnedFd, RawFd};
+use std::io::{self, Write};
+use std::os::unix::net::UnixDatagram;
+use std::path::Path;
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::time::Duration;
+use tracing::{info, warn};
+
+use crate::handoff;
+
+/// The first descriptor systemd passes with socket activation.
+const LISTEN_FDS_START: RawFd = 3;
+/// Where the journal takes entries in its native protocol.
+const JOURNAL_SOCKET: &str = "/run/systemd/journal/socket";
+
+/// Takes the sockets systemd passed with socket activation, if any, so that
+/// binding the address or path one of them is bound to uses it instead.
+pub fn adopt_sockets() {
+    let ours = std::env::var("LISTEN_PID").ok().and_then(|pid| pid.parse::<u32>().ok()) == Some(std::process::id());
+    let count = std::env::var("LISTEN_FDS").ok().and_then(|count| count.parse::<RawFd>().ok()).unwrap_or(0);
+    // Left set, they would tell any child process the sockets are its own
+    for name in ["LISTEN_PID", "LISTEN_FDS", "LISTEN_FDNAMES"] {
+        std::env::remove_var(name);
+    }
+    if !ours {
+        return;
+    }
+    for fd in LISTEN_FDS_START..LISTEN_FDS_START + count {
+        // SAFETY: systemd passes the descriptors from 3 on to this process, which owns them
+        let socket = unsafe { Socket::from(OwnedFd::from_raw_fd(fd)) };
+        let _ = socket.set_cloexec(true);
+        let key = match (socket.r#type(), socket.local_addr()) {
+            (Ok(Type::STREAM), Ok(addr)) => match (addr.as_socket(), addr.as_pathname()) {
+                (Some(addr), _) => format!("tcp:{}", addr),
+                (None, Some(path)) => format!("unix:{}", path.display()),
+                _ => {
+                    warn!("Ignoring socket {} from systemd: it is not bound to an address or path", fd);
+                    continue;
+                }
+            },
+            _ => {
+                warn!("Ignoring socket {} from systemd: only stream sockets can be listened on", fd);
+                continue;
+            }
+        };
+        info!("Using {} from systemd", key);
+        handoff::adopt(key, socket.into());
+    }
+}
+
+/// Sends `state` to systemd's notification socket, when running as a
+/// `Type=notify` service.
+pub fn notify(state: &str) {
+    let path = match std::env::var_os("NOTIFY_SOCKET") {
+        Some(path) => path,
+        None => return,
+    };
+    let sent = UnixDatagram::unbound().and_then(|socket| {
+        // A leading '@' names a socket in the abstract namespace
+        #[cfg(target_os = "linux")]
+        if let Some(name) = path.to_str().and_then(|path| path.strip_prefix('@')) {
+            use std::os::linux::net::SocketAddrExt;
+            let addr = std::os::unix::net::SocketAddr::from_abstract_name(name)?;
+            return socket.send_to_addr(state.as_bytes(), &addr);
+        }
+        socket.send_to(state.as_bytes(), &path)
+    });
+    if let Err(e) = sent {
+        warn!("Failed to notify systemd: {}", e);
+    }
+}
+
+/// Tells systemd the service is ready, naming this process its main one,
+/// which changes when a new process takes over through an upgrade.
+pub fn notify_ready() {
+    notify(&format!("READY=1\nMAINPID={}", std::process::id()));
+}
+
+/// Pings systemd's watchdog at half the interval it asks for, from a task of
+/// the runtime, so a wedged runtime gets the service restarted.
+pub fn spawn_watchdog() {
+    let interval = match std::env::var("WATCHDOG_USEC").ok().and_then(|usec| usec.parse::<u64>().ok()) {
+        Some(usec) if usec > 0 => Duration::from_micros(usec / 2),
+        _ => return,
+    };
+    if std::env::var("WATCHDOG_PID").ok().and_then(|pid| pid.parse::<u32>().ok()).is_some_and(|pid| pid != std::process::id()) {
+        return;
+    }
+    tokio::spawn(async move {
+        let mut ticks = tokio::time::interval(interval);
+        loop {
+            ticks.tick().await;
+            notify("WATCHDOG=1");
+        }
+    });
+}
+
+/// Writes entries to the systemd journal in its native protocol, so each of
+/// their fields can be searched on with journalctl.
+pub struct Journal {
+    socket: UnixDatagram,
+    /// Set while entries cannot be sent, so that is reported once
+    failing: AtomicBool,
+}
+
+impl Journal {
+    pub fn open() -> io::Result<Journal> {
+        if !Path::new(JOURNAL_SOCKET).exists() {
+            return Err(io::Error::new(io::ErrorKind::NotFound, format!("{} not found; is systemd-journald running?", JOURNAL_SOCKET)));
+        }
+        let socket = UnixDatagram::unbound()?;
+        // An entry is dropped rather than holding up a request when the journal is behind
+        socket.set_nonblocking(true)?;
+        Ok(Journal { socket, failing: AtomicBool::new(false) })
+    }
+
+    /// Sends an entry made of `fields`, whose names are upper case.
+    pub fn send(&self, fields: &[(&str, &str)]) {
+        let mut entry = Vec::new();
+        for (name, value) in fields {
+            if value.contains('\n') {
+                // Values spanning lines are sent with their length instead of after a '='
+                entry.extend_from_slice(name.as_bytes());
+                entry.push(b'\n');
+                entry.extend_from_slice(&(value.len() as u64).to_le_bytes());
+                entry.extend_from_slice(value.as_bytes());
+                entry.push(b'\n');
+            } else {
+                let _ = writeln!(entry, "{}={}", name, value);
+            }
+        }
+        match self.socket.send_to(&entry, JOURNAL_SOCKET) {
+            Ok(_) => self.failing.store(false, Ordering::Relaxed),
+            Err(e) => {
+                if !self.failing.swap(true, Ordering::Relaxed) {
+                    warn!("Failed to write to the systemd journal: {}", e);
+                }
+            }
+        }
+    }
+}
@@ -0,0 +1,403 @@
+use async_trait::async_trait;
+use hyper::client::Client;
+use hyper::header::{HeaderValue, CONTENT_TYPE, HOST, USER_AGENT};
+use hyper::{Body, Method, Request, Response, Uri};
+use rand::{Rng, RngCore};
+use serde_json::{json, Value};
+use std::sync::{Arc, Mutex, Weak};
+use std::time::{Duration, SystemTime, UNIX_EPOCH};
+use tracing::warn;
+
+use crate::access_log::UpstreamUsed;
+use crate::config::{TelemetryConfig, UpstreamsConfig};
+use crate::middleware::{Context, Middleware};
+use crate::tls::{self, ClientConnector};
+
+const TRACEPARENT: &str = "traceparent";
+/// Spans held for export; further spans are dropped until the next export.
+const MAX_QUEUED_SPANS: usize = 2048;
+/// OTLP span kind of a span covering the handling of an incoming request.
+const SPAN_KIND_SERVER: u8 = 2;
+const STATUS_ERROR: u8 = 2;
+
+/// W3C trace context of a request, as received or as started by Riffy.
+#[derive(Debug, Clone, Copy)]
+struct TraceContext {
+    trace_id: [u8; 16],
+    /// Span of the caller, when the request carried a `traceparent`
+    parent_id: Option<[u8; 8]>,
+    span_id: [u8; 8],
+    sampled: bool,
+}
+
+impl TraceContext {
+    /// The `traceparent` sent upstream, naming Riffy's span as the parent.
+    fn traceparent(&self) -> String {
+        format!("00-{}-{}-{}", hex(&self.trace_id), hex(&self.span_id), if self.sampled { "01" } else { "00" })
+    }
+}
+
+/// A request span in progress, carried from `on_request` to `on_response`.
+struct ActiveSpan {
+    context: TraceContext,
+    name: String,
+    start: SystemTime,
+    attributes: Vec<(&'static str, Value)>,
+}
+
+#[derive(Debug)]
+struct FinishedSpan {
+    context: TraceContext,
+    name: String,
+    start: SystemTime,
+    end: SystemTime,
+    attributes: Vec<(&'static str, Value)>,
+    error: Option<String>,
+}
+
+/// Middleware recording a server span per request, continuing the trace of an
+/// incoming `traceparent` and passing its own span on to the upstream. Spans
+/// are exported in batches over OTLP/HTTP.
+pub struct Tracer {
+    config: TelemetryConfig,
+    queue: Mutex<Vec<FinishedSpan>>,
+    dropped: Mutex<u64>,
+}
+
+impl Tracer {
+    /// Creates the tracer and spawns its exporter, which stops once the
+    /// tracer has been dropped, e.g. after a config reload.
+    pub fn spawn(config: &TelemetryConfig) -> Result<Arc<Tracer>, String> {
+        let endpoint: Uri = config.endpoint.parse().map_err(|e| format!("invalid telemetry.endpoint {}: {}", config.endpoint, e))?;
+        let client = tls::upstream_connector(&UpstreamsConfig::default(), Some(Duration::from_secs(5)))?.client(None);
+        let tracer = Arc::new(Tracer {
+            config: config.clone(),
+            queue: Mutex::new(Vec::new()),
+            dropped: Mutex::new(0),
+        });
+        let interval = Duration::from_secs(config.export_interval);
+        tokio::spawn(export_loop(Arc::downgrade(&tracer), client, endpoint, interval));
+        Ok(tracer)
+    }
+
+    /// Whether this tracer was created from `config`, so it can be kept across a reload.
+    pub fn uses(&self, config: &TelemetryConfig) -> bool {
+        self.config == *config
+    }
+
+    /// Continues the caller's trace, or starts one subject to sampling.
+    fn start_context(&self, req: &Request<Body>) -> TraceContext {
+        let mut rng = rand::thread_rng();
+        let mut span_id = [0u8; 8];
+        rng.fill_bytes(&mut span_id);
+        if let Some((trace_id, parent_id, sampled)) = req.headers().get(TRACEPARENT).and_then(|v| v.to_str().ok()).and_then(parse_traceparent) {
+            return TraceContext { trace_id, parent_id: Some(parent_id), span_id, sampled };
+        }
+        let mut trace_id = [0u8; 16];
+        rng.fill_bytes(&mut trace_id);
+        let ratio = self.config.sample_ratio;
+        let sampled = ratio >= 1.0 || rng.gen::<f64>() < ratio;
+        TraceContext { trace_id, parent_id: None, span_id, sampled }
+    }
+
+    fn finish(&self, span: ActiveSpan, error: Option<String>) {
+        let mut queue = self.queue.lock().unwrap();
+        if queue.len() >= MAX_QUEUED_SPANS {
+            *self.dropped.lock().unwrap() += 1;
+            return;
+        }
+        queue.push(FinishedSpan { context: span.context, name: span.name, start: span.start, end: SystemTime::now(), attributes: span.attributes, error });
+    }
+
+    /// Encodes queued spans as an OTLP/JSON export request.
+    fn drain(&self) -> Option<Value> {
+        let spans = std::mem::take(&mut *self.queue.lock().unwrap());
+        let dropped = std::mem::take(&mut *self.dropped.lock().unwrap());
+        if dropped > 0 {
+            warn!("Telemetry queue full, dropped {} spans", dropped);
+        }
+        if spans.is_empty() {
+            return None;
+        }
+        let spans: Vec<Value> = spans.iter().map(encode_span).collect();
+        Some(json!({
+            "resourceSpans": [{
+                "resource": { "attributes": [attribute("service.name", &json!(self.config.service_name))] },
+                "scopeSpans": [{
+                    "scope": { "name": "riffy", "version": env!("CARGO_PKG_VERSION") },
+                    "spans": spans,
+                }],
+            }],
+        }))
+    }
+}
+
+#[async_trait]
+impl Middleware for Tracer {
+    async fn on_request(&self, req: &mut Request<Body>, ctx: &mut Context) -> Option<Response<Body>> {
+        let context = self.start_context(req);
+        req.headers_mut().insert(TRACEPARENT, HeaderValue::from_str(&context.traceparent()).expect("valid traceparent"));
+        if !context.sampled {
+            return None;
+        }
+
+        let header = |name| req.headers().get(name).and_then(|v| v.to_str().ok()).map(str::to_string);
+        let mut attributes = vec![
+            ("http.request.method", json!(req.method().as_str())),
+            ("url.path", json!(req.uri().path())),
+            ("url.scheme", json!(if ctx.tls { "https" } else { "http" })),
+            ("client.address", json!(ctx.client_addr.ip().to_string())),
+        ];
+        if let Some(host) = header(HOST) {
+            attributes.push(("server.address", json!(host)));
+        }
+        if let Some(agent) = header(USER_AGENT) {
+            attributes.push(("user_agent.original", json!(agent)));
+        }
+        if let Some(id) = &ctx.request_id {
+            attributes.push(("riffy.request_id", json!(id)));
+        }
+        let name = req.method().to_string();
+        ctx.extensions.insert(ActiveSpan { context, name, start: SystemTime::now(), attributes });
+        None
+    }
+
+    async fn on_response(&self, res: &mut Response<Body>, ctx: &mut Context) {
+        let mut span = match ctx.extensions.remove::<ActiveSpan>() {
+            Some(span) => span,
+            None => return,
+        };
+        span.attributes.push(("http.response.status_code", json!(res.status().as_u16())));
+        if let Some(upstream) = res.extensions().get::<UpstreamUsed>() {
+            span.attributes.push(("riffy.upstream", json!(upstream.0)));
+        }
+        let error = res.status().is_server_error().then(|| res.status().to_string());
+        self.finish(span, error);
+    }
+
+    async fn on_error(&self, error: &(dyn std::error::Error + Send + Sync), ctx: &mut Context) {
+        if let Some(span) = ctx.extensions.remove::<ActiveSpan>() {
+            self.finish(span, Some(error.to_string()));
+        }
+    }
+}
+
+async fn export_loop(tracer: Weak<Tracer>, client: Client<ClientConnector>, endpoint: Uri, interval: Duration) {
+    let mut ticker = tokio::time::interval(interval);
+    loop {
+        ticker.tick().await;
+        let body = match tracer.upgrade() {
+            Some(tracer) => tracer.drain(),
+            None => return,
+        };
+        let body = match body {
+            Some(body) => body,
+            None => continue,
+        };
+
+        let req = Request::builder()
+            .method(Method::POST)
+            .uri(endpoint.clone())
+            .header(CONTENT_TYPE, "application/json")
+            .body(Body::from(body.to_string()))
+            .expect("valid export request");
+        match tokio::time::timeout(Duration::from_secs(10), client.request(req)).await {
+            Ok(Ok(res)) if res.status().is_success() => {}
+            Ok(Ok(res)) => warn!("Telemetry export to {} failed: {}", endpoint, res.status()),
+            Ok(Err(e)) => warn!("Telemetry export to {} failed: {}", endpoint, e),
+            Err(_) => warn!("Telemetry export to {} timed out", endpoint),
+        }
+    }
+}
+
+/// Parses a version 00 `traceparent` into trace ID, parent span ID and sampled flag.
+fn parse_traceparent(value: &str) -> Option<([u8; 16], [u8; 8], bool)> {
+    let parts: Vec<&str> = value.trim().split('-').collect();
+    let (version, trace, parent, flags) = match parts.as_slice() {
+        [version, trace, parent, flags] => (*version, *trace, *parent, *flags),
+        // Later versions may append fields
+        [version, trace, parent, flags, ..] if *version != "00" => (*version, *trace, *parent, *flags),
+        _ => return None,
+    };
+    if version.len() != 2 || version == "ff" || flags.len() != 2 {
+        return None;
+    }
+    let mut trace_id = [0u8; 16];
+    let mut parent_id = [0u8; 8];
+    unhex(trace, &mut trace_id)?;
+    unhex(parent, &mut parent_id)?;
+    unhex(version, &mut [0u8; 1])?;
+    let mut flags_byte = [0u8; 1];
+    unhex(flags, &mut flags_byte)?;
+    // All-zero IDs are invalid
+    if trace_id == [0; 16] || parent_id == [0; 8] {
+        return None;
+    }
+    Some((trace_id, parent_id, flags_byte[0] & 0x01 == 1))
+}
+
+fn encode_span(span: &FinishedSpan) -> Value {
+    let mut encoded = json!({
+        "traceId": hex(&span.context.trace_id),
+        "spanId": hex(&span.context.span_id),
+        "name": span.name,
+        "kind": SPAN_KIND_SERVER,
+        "startTimeUnixNano": unix_nanos(span.start).to_string(),
+        "endTimeUnixNano": unix_nanos(span.end).to_string(),
+        "attributes": span.attributes.iter().map(|(key, value)| attribute(key, value)).collect::<Vec<_>>(),
+    });
+    if let Some(parent) = &span.context.parent_id {
+        encoded["parentSpanId"] = json!(hex(parent));
+    }
+    if let Some(message) = &span.error {
+        encoded["status"] = json!({ "code": STATUS_ERROR, "message": message });
+    }
+    encoded
+}
+
+/// Wraps a value in the OTLP `AnyValue` form.
+fn attribute(key: &str, value: &Value) -> Value {
+    let value = match value {
+        Value::Number(n) => json!({ "intValue": n.to_string() }),
+        Value::Bool(b) => json!({ "boolValue": b }),
+        Value::String(s) => json!({ "stringValue": s }),
+        other => json!({ "stringValue": other.to_string() }),
+    };
+    json!({ "key": key, "value": value })
+}
+
+fn unix_nanos(time: SystemTime) -> u128 {
+    time.duration_since(UNIX_EPOCH).unwrap_or_default().as_nanos()
+}
+
+fn hex(bytes: &[u8]) -> String {
+    bytes.iter().map(|b| format!("{:02x}", b)).collect()
+}
+
+/// Decodes lowercase hex into `out`, which must match its length exactly.
+fn unhex(s: &str, out: &mut [u8]) -> Option<()> {
+    if s.len() != out.len() * 2 || !s.bytes().all(|b| b.is_ascii_digit() || (b'a'..=b'f').contains(&b)) {
+        return None;
+    }
+    for (i, byte) in out.iter_mut().enumerate() {
+        *byte = u8::from_str_radix(&s[i * 2..i * 2 + 2], 16).ok()?;
+    }
+    Some(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use hyper::StatusCode;
+
+    const TRACE: &str = "4bf92f3577b34da6a3ce929d0e0e4736";
+    const PARENT: &str = "00f067aa0ba902b7";
+
+    fn tracer(sample_ratio: f64) -> Tracer {
+        Tracer { config: TelemetryConfig { enabled: true, sample_ratio, ..TelemetryConfig::default() }, queue: Mutex::new(Vec::new()), dropped: Mutex::new(0) }
+    }
+
+    /// Traces a GET with the given `traceparent`, returning the one sent upstream.
+    async fn trace(tracer: &Tracer, traceparent: Option<&str>) -> String {
+        let mut req = Request::get("/orders").header(HOST, "shop.example.com");
+        if let Some(traceparent) = traceparent {
+            req = req.header(TRACEPARENT, traceparent);
+        }
+        let mut req = req.body(Body::empty()).unwrap();
+        let mut ctx = Context::new(([192, 0, 2, 1], 4000).into(), true);
+        assert!(tracer.on_request(&mut req, &mut ctx).await.is_none());
+        let mut res = Response::builder().status(StatusCode::BAD_GATEWAY).body(Body::empty()).unwrap();
+        tracer.on_response(&mut res, &mut ctx).await;
+        req.headers()[TRACEPARENT].to_str().unwrap().to_string()
+    }
+
+    #[test]
+    fn parses_valid_traceparents() {
+        let (trace_id, parent_id, sampled) = parse_traceparent(&format!("00-{}-{}-01", TRACE, PARENT)).unwrap();
+        assert_eq!((hex(&trace_id).as_str(), hex(&parent_id).as_str(), sampled), (TRACE, PARENT, true));
+        assert!(!parse_traceparent(&format!("00-{}-{}-00", TRACE, PARENT)).unwrap().2);
+        // Only the lowest flag bit means sampled
+        assert!(!parse_traceparent(&format!("00-{}-{}-02", TRACE, PARENT)).unwrap().2);
+        assert!(parse_traceparent(&format!(" 00-{}-{}-03 ", TRACE, PARENT)).unwrap().2);
+    }
+
+    #[test]
+    fn accepts_later_versions_with_more_fields() {
+        assert!(parse_traceparent(&format!("01-{}-{}-01", TRACE, PARENT)).is_some());
+        assert!(parse_traceparent(&format!("cc-{}-{}-01-what-comes-next", TRACE, PARENT)).is_some());
+    }
+
+    #[test]
+    fn rejects_invalid_traceparents() {
+        let zero_trace = "0".repeat(32);
+        let zero_parent = "0".repeat(16);
+        let upper_trace = TRACE.to_ascii_uppercase();
+        let invalid = [
+            format!("00-{}-{}-01", zero_trace, PARENT),
+            format!("00-{}-{}-01", TRACE, zero_parent),
+            format!("ff-{}-{}-01", TRACE, PARENT),
+            format!("00-{}-{}-01", upper_trace, PARENT),
+            format!("0A-{}-{}-01", TRACE, PARENT),
+            format!("00-{}-{}-0F", TRACE, PARENT),
+            format!("00-{}-{}-01", &TRACE[1..], PARENT),
+            format!("00-{}0-{}-01", TRACE, PARENT),
+            format!("00-{}-{}-01", TRACE, &PARENT[1..]),
+            format!("00-{}-{}-1", TRACE, PARENT),
+            format!("000-{}-{}-01", TRACE, PARENT),
+            format!("00-{}-{}-01-extra", TRACE, PARENT),
+            format!("00-{}-{}", TRACE, PARENT),
+            format!("00-{}-{}-0g", TRACE, PARENT),
+            String::new(),
+        ];
+        for value in invalid {
+            assert!(parse_traceparent(&value).is_none(), "{}", value);
+        }
+    }
+
+    #[test]
+    fn unhex_needs_the_exact_length_in_lowercase() {
+        let mut out = [0u8; 2];
+        assert_eq!(unhex("0aff", &mut out), Some(()));
+        assert_eq!(out, [0x0a, 0xff]);
+        for s in ["0AFF", "0af", "0aff00", "+aff", "0a f"] {
+            assert!(unhex(s, &mut [0u8; 2]).is_none(), "{}", s);
+        }
+    }
+
+    #[tokio::test]
+    async fn exports_spans_continuing_the_callers_trace() {
+        let tracer = tracer(0.0);
+        let sent = trace(&tracer, Some(&format!("00-{}-{}-01", TRACE, PARENT))).await;
+        let body = tracer.drain().expect("a span to export");
+        let span = &body["resourceSpans"][0]["scopeSpans"][0]["spans"][0];
+        assert_eq!(span["traceId"], TRACE);
+        assert_eq!(span["parentSpanId"], PARENT);
+        assert_eq!(span["kind"], SPAN_KIND_SERVER);
+        assert_eq!(span["status"]["code"], STATUS_ERROR);
+        // The upstream sees Riffy's span as its parent, still sampled
+        assert_eq!(sent, format!("00-{}-{}-01", TRACE, span["spanId"].as_str().unwrap()));
+        let status = span["attributes"].as_array().unwrap().iter().find(|a| a["key"] == "http.response.status_code").unwrap();
+        assert_eq!(status["value"]["intValue"], "502");
+        assert!(tracer.drain().is_none());
+    }
+
+    #[tokio::test]
+    async fn keeps_the_callers_sampling_decision() {
+        // Not sampled by the caller: nothing is exported, even at a ratio of 1
+        let tracer = tracer(1.0);
+        let sent = trace(&tracer, Some(&format!("00-{}-{}-00", TRACE, PARENT))).await;
+        assert!(sent.starts_with(&format!("00-{}-", TRACE)) && sent.ends_with("-00"), "{}", sent);
+        assert!(tracer.drain().is_none());
+
+        // New traces are sampled by the ratio and have no parent
+        let sent = trace(&tracer, None).await;
+        assert!(sent.ends_with("-01"), "{}", sent);
+        let body = tracer.drain().unwrap();
+        let span = &body["resourceSpans"][0]["scopeSpans"][0]["spans"][0];
+        assert!(span.get("parentSpanId").is_none());
+        assert_eq!(sent, format!("00-{}-{}-01", span["traceId"].as_str().unwrap(), span["spanId"].as_str().unwrap()));
+        let never = self::tracer(0.0);
+        assert!(trace(&never, None).await.ends_with("-00"));
+        assert!(never.drain().is_none());
+    }
+}
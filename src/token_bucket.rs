@@ -0,0 +1,121 @@
+use std::collections::HashMap;
+use std::net::IpAddr;
+use std::sync::Mutex;
+use std::time::{Duration, Instant};
+
+/// How often idle buckets are dropped from the table.
+const SWEEP_INTERVAL: Duration = Duration::from_secs(60);
+
+#[derive(Debug, Clone, Copy)]
+struct Bucket {
+    /// Negative while the client has taken ahead of the rate
+    tokens: f64,
+    updated: Instant,
+}
+
+#[derive(Debug)]
+struct Inner {
+    buckets: HashMap<IpAddr, Bucket>,
+    last_sweep: Instant,
+}
+
+/// A token bucket per client IP, holding up to `burst` tokens and refilled at
+/// `rate` tokens per second. Buckets that have refilled are dropped now and
+/// then, so the table only holds recently active clients.
+#[derive(Debug)]
+pub struct TokenBuckets {
+    rate: f64,
+    burst: f64,
+    inner: Mutex<Inner>,
+}
+
+impl TokenBuckets {
+    pub fn new(rate: f64, burst: f64) -> Self {
+        TokenBuckets { rate, burst, inner: Mutex::new(Inner { buckets: HashMap::new(), last_sweep: Instant::now() }) }
+    }
+
+    /// Takes `cost` tokens from `client`'s bucket if it holds that many, or
+    /// returns how long until it will.
+    pub fn take(&self, client: IpAddr, cost: f64) -> Result<(), Duration> {
+        self.with_bucket(client, |tokens| {
+            if *tokens >= cost {
+                *tokens -= cost;
+                Ok(())
+            } else {
+                Err(Duration::from_secs_f64((cost - *tokens) / self.rate))
+            }
+        })
+    }
+
+    /// Takes `cost` tokens from `client`'s bucket even when it holds fewer,
+    /// returning how long until it is out of debt.
+    pub fn take_ahead(&self, client: IpAddr, cost: f64) -> Duration {
+        self.with_bucket(client, |tokens| {
+            *tokens -= cost;
+            Duration::from_secs_f64((-*tokens).max(0.0) / self.rate)
+        })
+    }
+
+    /// Runs `f` on the refilled token count of `client`'s bucket.
+    fn with_bucket<T>(&self, client: IpAddr, f: impl FnOnce(&mut f64) -> T) -> T {
+        let now = Instant::now();
+        let mut inner = self.inner.lock().unwrap();
+
+        if now.duration_since(inner.last_sweep) >= SWEEP_INTERVAL {
+            // A bucket that has refilled completely behaves like a new one
+            let (rate, burst) = (self.rate, self.burst);
+            inner.buckets.retain(|_, b| b.tokens + now.duration_since(b.updated).as_secs_f64() * rate < burst);
+            inner.last_sweep = now;
+        }
+
+        let bucket = inner.buckets.entry(client).or_insert(Bucket { tokens: self.burst, updated: now });
+        bucket.tokens = (bucket.tokens + now.duration_since(bucket.updated).as_secs_f64() * self.rate).min(self.burst);
+        bucket.updated = now;
+        f(&mut bucket.tokens)
+    }
+
+    /// Moves `client`'s last refill `by` into the past.
+    #[cfg(test)]
+    pub(crate) fn rewind(&self, client: IpAddr, by: Duration) {
+        self.inner.lock().unwrap().buckets.get_mut(&client).unwrap().updated -= by;
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const CLIENT: IpAddr = IpAddr::V4(std::net::Ipv4Addr::new(192, 0, 2, 1));
+    const OTHER: IpAddr = IpAddr::V4(std::net::Ipv4Addr::new(192, 0, 2, 2));
+
+    #[test]
+    fn takes_ahead_and_waits_off_the_debt() {
+        let buckets = TokenBuckets::new(1000.0, 1000.0);
+        assert_eq!(buckets.take_ahead(CLIENT, 600.0), Duration::ZERO);
+        // 1000 tokens taken from 400, paid back at 1000 per second
+        let wait = buckets.take_ahead(CLIENT, 1000.0);
+        assert!(wait > Duration::from_millis(590) && wait <= Duration::from_millis(600), "{:?}", wait);
+        // In debt, nothing fits
+        assert!(buckets.take(CLIENT, 1.0).is_err());
+        buckets.rewind(CLIENT, Duration::from_millis(700));
+        assert!(buckets.take(CLIENT, 1.0).is_ok());
+    }
+
+    #[test]
+    fn sweeps_idle_buckets() {
+        let buckets = TokenBuckets::new(1.0, 5.0);
+        buckets.take(CLIENT, 1.0).unwrap();
+        assert!((0..5).all(|_| buckets.take(OTHER, 1.0).is_ok()));
+        // CLIENT has refilled completely by the sweep, OTHER has not
+        buckets.rewind(CLIENT, Duration::from_secs(2));
+        buckets.inner.lock().unwrap().last_sweep -= SWEEP_INTERVAL;
+        let third = IpAddr::V4(std::net::Ipv4Addr::new(192, 0, 2, 3));
+        buckets.take(third, 1.0).unwrap();
+        let inner = buckets.inner.lock().unwrap();
+        assert!(!inner.buckets.contains_key(&CLIENT));
+        assert!(inner.buckets.contains_key(&OTHER) && inner.buckets.contains_key(&third));
+        drop(inner);
+        // Dropping a full bucket loses nothing
+        assert!((0..5).all(|_| buckets.take(CLIENT, 1.0).is_ok()));
+    }
+}
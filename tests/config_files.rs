@@ -0,0 +1,68 @@
+use std::path::PathBuf;
+
+use riffy::Config;
+
+const TOML: &str = r#"
+[upstreams]
+servers = ["http://127.0.0.1:3000", "http://127.0.0.1:3001"]
+strategy = "least_connections"
+
+[upstreams.health_check]
+enabled = true
+path = "/healthz"
+interval = 5
+timeout = 2
+
+[[routes]]
+path_prefix = "/api"
+pool = "api"
+
+[pools.api]
+servers = ["http://127.0.0.1:4000"]
+"#;
+
+const YAML: &str = r#"
+upstreams:
+  servers: ["http://127.0.0.1:3000", "http://127.0.0.1:3001"]
+  strategy: least_connections
+  health_check:
+    enabled: true
+    path: /healthz
+    interval: 5
+    timeout: 2
+routes:
+  - path_prefix: /api
+    pool: api
+pools:
+  api:
+    servers: ["http://127.0.0.1:4000"]
+"#;
+
+fn write(name: &str, contents: &str) -> PathBuf {
+    let path = std::env::temp_dir().join(format!("riffy-{}-{}", std::process::id(), name));
+    std::fs::write(&path, contents).unwrap();
+    path
+}
+
+fn load(path: &PathBuf) -> Result<Config, String> {
+    let result = Config::load(path.to_str());
+    let _ = std::fs::remove_file(path);
+    result
+}
+
+#[test]
+fn yaml_files_read_like_toml_ones() {
+    let toml = load(&write("riffy.toml", TOML)).unwrap();
+    for name in ["riffy.yaml", "riffy.yml"] {
+        let yaml = load(&write(name, YAML)).unwrap();
+        assert_eq!(format!("{:?}", yaml), format!("{:?}", toml), "{}", name);
+    }
+}
+
+#[test]
+fn yaml_errors_name_the_file() {
+    let path = write("broken.yaml", "upstreams:\n  strategy: fastest\n");
+    let err = load(&path).unwrap_err();
+    assert!(err.starts_with(&format!("failed to parse {}", path.display())), "{}", err);
+    assert!(err.contains("fastest"), "{}", err);
+}
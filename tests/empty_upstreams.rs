@@ -0,0 +1,64 @@
+use std::net::{IpAddr, Ipv4Addr};
+
+use hyper::{Client, StatusCode};
+use riffy::balancer::{Balancer, PassiveHealthConfig, Strategy};
+use riffy::{Config, ProxyBuilder};
+use tokio::net::TcpListener;
+
+const CLIENT: IpAddr = IpAddr::V4(Ipv4Addr::LOCALHOST);
+
+fn parse(toml: &str) -> Config {
+    toml::from_str(toml).expect("config parses")
+}
+
+#[test]
+fn empty_servers_are_refused() {
+    let err = parse("[upstreams]\nservers = []\n").validate().unwrap_err();
+    assert!(err.contains("upstreams has no upstream servers"), "{}", err);
+}
+
+#[test]
+fn empty_pool_is_refused() {
+    let err = parse("[pools.api]\nservers = []\n").validate().unwrap_err();
+    assert!(err.contains("pools.api has no upstream servers"), "{}", err);
+}
+
+#[test]
+fn empty_servers_are_allowed_when_asked() {
+    parse("[upstreams]\nservers = []\nallow_empty = true\n").validate().unwrap();
+}
+
+#[test]
+fn blank_upstream_servers_variable_means_none() {
+    // The only test here touching the environment, so no other test sees it
+    std::env::set_var("UPSTREAM_SERVERS", " , ");
+    let result = Config::load(None);
+    std::env::remove_var("UPSTREAM_SERVERS");
+    let err = result.unwrap_err();
+    assert!(err.contains("upstreams has no upstream servers"), "{}", err);
+}
+
+#[test]
+fn empty_balancer_selects_nothing() {
+    for strategy in [Strategy::RoundRobin, Strategy::LeastConnections, Strategy::IpHash, Strategy::LeastLatency, Strategy::P2c] {
+        let balancer = Balancer::new(Vec::new(), strategy, PassiveHealthConfig::default(), None);
+        assert!(balancer.select(CLIENT, None, &[]).is_none(), "{:?}", strategy);
+        assert!(balancer.select(CLIENT, Some("gone"), &[]).is_none(), "{:?}", strategy);
+    }
+}
+
+#[tokio::test]
+async fn empty_pool_answers_503() {
+    let config = parse("[upstreams]\nservers = []\nallow_empty = true\n");
+    config.validate().unwrap();
+    let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+    let addr = listener.local_addr().unwrap();
+    let proxy = ProxyBuilder::new(config).build().unwrap();
+    tokio::spawn(proxy.serve_listener(listener));
+
+    let client = Client::new();
+    for _ in 0..3 {
+        let res = client.get(format!("http://{}/", addr).parse().unwrap()).await.unwrap();
+        assert_eq!(res.status(), StatusCode::SERVICE_UNAVAILABLE);
+    }
+}
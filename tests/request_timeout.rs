@@ -0,0 +1,45 @@
+use std::convert::Infallible;
+use std::net::SocketAddr;
+use std::time::Duration;
+
+use hyper::body::Bytes;
+use hyper::service::{make_service_fn, service_fn};
+use hyper::{Body, Client, Request, Response, Server, StatusCode};
+use riffy::{Config, ProxyBuilder};
+use tokio::net::TcpListener;
+
+/// Starts an upstream that sends its headers and a first chunk at once, and
+/// never finishes the body.
+async fn trickling_upstream() -> SocketAddr {
+    let make_service = make_service_fn(|_| async {
+        Ok::<_, Infallible>(service_fn(|_req: Request<Body>| async {
+            let (mut sender, body) = Body::channel();
+            tokio::spawn(async move {
+                sender.send_data(Bytes::from_static(b"first")).await.unwrap();
+                tokio::time::sleep(Duration::from_secs(60)).await;
+                drop(sender);
+            });
+            Ok::<_, Infallible>(Response::new(body))
+        }))
+    });
+    let server = Server::bind(&"127.0.0.1:0".parse().unwrap()).serve(make_service);
+    let addr = server.local_addr();
+    tokio::spawn(server);
+    addr
+}
+
+#[tokio::test]
+async fn request_timeout_covers_the_response_body() {
+    let upstream = trickling_upstream().await;
+    let config: Config = toml::from_str(&format!("[upstreams]\nservers = [\"http://{}\"]\n\n[timeouts]\nrequest = 1\n", upstream)).expect("config parses");
+    config.validate().unwrap();
+    let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+    let proxy = listener.local_addr().unwrap();
+    tokio::spawn(ProxyBuilder::new(config).build().unwrap().serve_listener(listener));
+
+    let res = Client::new().get(format!("http://{}/", proxy).parse().unwrap()).await.unwrap();
+    assert_eq!(res.status(), StatusCode::OK);
+    // The body is cut off once the second is up, rather than held open by the upstream
+    let body = tokio::time::timeout(Duration::from_secs(10), hyper::body::to_bytes(res.into_body())).await.expect("body ends");
+    assert!(body.is_err());
+}
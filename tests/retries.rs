@@ -0,0 +1,64 @@
+use std::convert::Infallible;
+use std::net::SocketAddr;
+use std::sync::atomic::{AtomicUsize, Ordering};
+use std::sync::Arc;
+
+use hyper::service::{make_service_fn, service_fn};
+use hyper::{Body, Client, Request, Response, Server, StatusCode};
+use riffy::{Config, ProxyBuilder};
+use tokio::net::TcpListener;
+
+/// Starts an upstream answering every request with `status` and `body`, and
+/// returns its address and a count of the requests it got.
+async fn failing_upstream(status: StatusCode, body: &'static str, requests: Arc<AtomicUsize>) -> SocketAddr {
+    let make_service = make_service_fn(move |_| {
+        let requests = Arc::clone(&requests);
+        async move {
+            Ok::<_, Infallible>(service_fn(move |_req: Request<Body>| {
+                requests.fetch_add(1, Ordering::SeqCst);
+                async move { Ok::<_, Infallible>(Response::builder().status(status).body(Body::from(body)).unwrap()) }
+            }))
+        }
+    });
+    let server = Server::bind(&"127.0.0.1:0".parse().unwrap()).serve(make_service);
+    let addr = server.local_addr();
+    tokio::spawn(server);
+    addr
+}
+
+async fn start(upstreams: &[SocketAddr]) -> SocketAddr {
+    let servers: Vec<String> = upstreams.iter().map(|addr| format!("\"http://{}\"", addr)).collect();
+    // One failure ejects an upstream, so retries run out of upstreams before attempts
+    let config: Config = toml::from_str(&format!("[upstreams]\nservers = [{}]\nmax_fails = 1\nfail_timeout = 60\n\n[upstreams.retry]\nretries = 3\nbackoff_ms = 1\n", servers.join(", "))).expect("config parses");
+    config.validate().unwrap();
+    let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+    let addr = listener.local_addr().unwrap();
+    tokio::spawn(ProxyBuilder::new(config).build().unwrap().serve_listener(listener));
+    addr
+}
+
+async fn get(proxy: SocketAddr) -> (StatusCode, String) {
+    let res = Client::new().get(format!("http://{}/", proxy).parse().unwrap()).await.unwrap();
+    let status = res.status();
+    let body = hyper::body::to_bytes(res.into_body()).await.unwrap();
+    (status, String::from_utf8(body.to_vec()).unwrap())
+}
+
+#[tokio::test]
+async fn last_response_stands_when_no_upstream_is_left() {
+    let requests = Arc::new(AtomicUsize::new(0));
+    let upstream = failing_upstream(StatusCode::SERVICE_UNAVAILABLE, "busy", Arc::clone(&requests)).await;
+    let proxy = start(&[upstream]).await;
+    assert_eq!(get(proxy).await, (StatusCode::SERVICE_UNAVAILABLE, "busy".to_string()));
+    assert_eq!(requests.load(Ordering::SeqCst), 1);
+}
+
+#[tokio::test]
+async fn retries_stop_once_every_upstream_failed() {
+    let requests = Arc::new(AtomicUsize::new(0));
+    let a = failing_upstream(StatusCode::BAD_GATEWAY, "down", Arc::clone(&requests)).await;
+    let b = failing_upstream(StatusCode::BAD_GATEWAY, "down", Arc::clone(&requests)).await;
+    let proxy = start(&[a, b]).await;
+    assert_eq!(get(proxy).await, (StatusCode::BAD_GATEWAY, "down".to_string()));
+    assert_eq!(requests.load(Ordering::SeqCst), 2);
+}
@@ -0,0 +1,294 @@
+use std::convert::Infallible;
+use std::net::SocketAddr;
+use std::sync::atomic::{AtomicUsize, Ordering};
+use std::sync::Arc;
+use std::time::Duration;
+
+use hyper::service::{make_service_fn, service_fn};
+use hyper::{Body, Client, Request, Response, Server, StatusCode};
+use riffy::{Config, ProxyBuilder};
+use tokio::net::TcpListener;
+
+/// Starts an upstream answering every request with a response any cache may
+/// store, and returns its address and the number of requests it got.
+async fn cacheable_upstream() -> (SocketAddr, Arc<AtomicUsize>) {
+    let requests = Arc::new(AtomicUsize::new(0));
+    let counted = Arc::clone(&requests);
+    let make_service = make_service_fn(move |_| {
+        let counted = Arc::clone(&counted);
+        async move {
+            Ok::<_, Infallible>(service_fn(move |_req: Request<Body>| {
+                counted.fetch_add(1, Ordering::SeqCst);
+                async { Ok::<_, Infallible>(Response::builder().header("cache-control", "public, max-age=60").body(Body::from("protected")).unwrap()) }
+            }))
+        }
+    });
+    let server = Server::bind(&"127.0.0.1:0".parse().unwrap()).serve(make_service);
+    let addr = server.local_addr();
+    tokio::spawn(server);
+    (addr, requests)
+}
+
+/// Starts a forward-auth service approving requests with the `session=ok`
+/// cookie, from clients it is told are on localhost.
+async fn auth_service() -> SocketAddr {
+    let make_service = make_service_fn(|_| async {
+        Ok::<_, Infallible>(service_fn(|req: Request<Body>| async move {
+            let header = |name: &str| req.headers().get(name).map(|v| v.to_str().unwrap().to_string()).unwrap_or_default();
+            let approved = header("cookie").contains("session=ok") && header("x-forwarded-for") == "127.0.0.1";
+            let (status, body) = if approved { (StatusCode::OK, "") } else { (StatusCode::UNAUTHORIZED, "log in first") };
+            Ok::<_, Infallible>(Response::builder().status(status).body(Body::from(body)).unwrap())
+        }))
+    });
+    let server = Server::bind(&"127.0.0.1:0".parse().unwrap()).serve(make_service);
+    let addr = server.local_addr();
+    tokio::spawn(server);
+    addr
+}
+
+/// Starts Riffy with `config`, in which `{upstream}` stands for the upstream's address.
+async fn start(config: &str, upstream: SocketAddr) -> SocketAddr {
+    let config: Config = toml::from_str(&config.replace("{upstream}", &upstream.to_string())).expect("config parses");
+    config.validate().unwrap();
+    let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+    let addr = listener.local_addr().unwrap();
+    let proxy = ProxyBuilder::new(config).build().unwrap();
+    tokio::spawn(proxy.serve_listener(listener));
+    addr
+}
+
+/// Sends a GET for `path` with the given headers and returns the status and `X-Cache`.
+async fn get(proxy: SocketAddr, path: &str, headers: &[(&str, &str)]) -> (StatusCode, Option<String>) {
+    let mut req = Request::get(format!("http://{}{}", proxy, path));
+    for (name, value) in headers {
+        req = req.header(*name, *value);
+    }
+    let res = Client::new().request(req.body(Body::empty()).unwrap()).await.unwrap();
+    let cache = res.headers().get("x-cache").map(|v| v.to_str().unwrap().to_string());
+    (res.status(), cache)
+}
+
+#[tokio::test]
+async fn cached_response_is_not_served_to_denied_client() {
+    let (upstream, requests) = cacheable_upstream().await;
+    let proxy = start(
+        r#"
+        [upstreams]
+        servers = ["http://{upstream}"]
+
+        [listener]
+        trusted_proxies = ["127.0.0.1/32"]
+
+        [cache]
+        enabled = true
+
+        [[routes]]
+        path_prefix = "/internal"
+
+        [routes.access]
+        allow = ["10.0.0.0/8"]
+        "#,
+        upstream,
+    )
+    .await;
+
+    let allowed = [("x-forwarded-for", "10.1.2.3")];
+    assert_eq!(get(proxy, "/internal/page", &allowed).await, (StatusCode::OK, Some("MISS".to_string())));
+    assert_eq!(get(proxy, "/internal/page", &allowed).await, (StatusCode::OK, Some("HIT".to_string())));
+    for path in ["/internal/page", "//internal/page", "/./internal/page", "/%69nternal/page", "/public/../internal/page"] {
+        assert_eq!(get(proxy, path, &[("x-forwarded-for", "192.0.2.7")]).await.0, StatusCode::FORBIDDEN, "{}", path);
+    }
+    assert_eq!(requests.load(Ordering::SeqCst), 1);
+}
+
+#[tokio::test]
+async fn denied_client_gets_nothing_from_middleware() {
+    let (upstream, requests) = cacheable_upstream().await;
+    let proxy = start(
+        r#"
+        [upstreams]
+        servers = ["http://{upstream}"]
+
+        [listener]
+        trusted_proxies = ["127.0.0.1/32"]
+
+        [listener.access]
+        deny = ["192.0.2.0/24"]
+
+        [cache]
+        enabled = true
+        "#,
+        upstream,
+    )
+    .await;
+
+    assert_eq!(get(proxy, "/", &[("x-forwarded-for", "10.1.2.3")]).await, (StatusCode::OK, Some("MISS".to_string())));
+    assert_eq!(get(proxy, "/", &[("x-forwarded-for", "192.0.2.7")]).await, (StatusCode::FORBIDDEN, None));
+    assert_eq!(requests.load(Ordering::SeqCst), 1);
+}
+
+#[tokio::test]
+async fn cached_response_is_not_served_in_maintenance() {
+    let (upstream, _) = cacheable_upstream().await;
+    let admin_port = std::net::TcpListener::bind("127.0.0.1:0").unwrap().local_addr().unwrap().port();
+    let proxy = start(
+        &format!(
+            r#"
+            [upstreams]
+            servers = ["http://{{upstream}}"]
+
+            [admin]
+            address = "127.0.0.1"
+            port = {}
+            token = "secret"
+
+            [cache]
+            enabled = true
+
+            [[routes]]
+            name = "shop"
+            path_prefix = "/shop"
+            "#,
+            admin_port
+        ),
+        upstream,
+    )
+    .await;
+
+    assert_eq!(get(proxy, "/shop/item", &[]).await, (StatusCode::OK, Some("MISS".to_string())));
+    // The admin server starts next to the listener, so it may take a moment
+    let mut switched = false;
+    for _ in 0..50 {
+        let req = Request::put(format!("http://127.0.0.1:{}/routes/shop/maintenance", admin_port)).header("authorization", "Bearer secret").body(Body::from(r#"{"enabled": true}"#)).unwrap();
+        if let Ok(res) = Client::new().request(req).await {
+            assert_eq!(res.status(), StatusCode::OK);
+            switched = true;
+            break;
+        }
+        tokio::time::sleep(Duration::from_millis(20)).await;
+    }
+    assert!(switched, "admin API did not answer");
+    assert_eq!(get(proxy, "/shop/item", &[]).await.0, StatusCode::SERVICE_UNAVAILABLE);
+}
+
+#[tokio::test]
+async fn cached_protected_route_asks_for_credentials() {
+    let (upstream, requests) = cacheable_upstream().await;
+    let dir = std::env::temp_dir().join(format!("riffy-basic-auth-{}", std::process::id()));
+    std::fs::create_dir_all(&dir).unwrap();
+    let htpasswd = dir.join("htpasswd");
+    // alice:secret, as written by htpasswd -s
+    std::fs::write(&htpasswd, "alice:{SHA}5en6G6MezRroT3XKqkdPOmY/BfQ=\n").unwrap();
+    let proxy = start(
+        &format!(
+            r#"
+            [upstreams]
+            servers = ["http://{{upstream}}"]
+
+            [cache]
+            enabled = true
+
+            [[routes]]
+            path_prefix = "/staging"
+
+            [routes.basic_auth]
+            htpasswd = "{}"
+            "#,
+            htpasswd.display()
+        ),
+        upstream,
+    )
+    .await;
+
+    let credentials = [("authorization", "Basic YWxpY2U6c2VjcmV0")];
+    assert_eq!(get(proxy, "/staging/", &credentials).await, (StatusCode::OK, Some("MISS".to_string())));
+    assert_eq!(get(proxy, "/staging/", &credentials).await, (StatusCode::OK, Some("HIT".to_string())));
+    assert_eq!(get(proxy, "/staging/", &[]).await.0, StatusCode::UNAUTHORIZED);
+    assert_eq!(get(proxy, "/staging/", &[("authorization", "Basic YWxpY2U6d3Jvbmc=")]).await.0, StatusCode::UNAUTHORIZED);
+    assert_eq!(requests.load(Ordering::SeqCst), 1);
+    std::fs::remove_dir_all(&dir).unwrap();
+}
+
+#[tokio::test]
+async fn forward_auth_is_asked_before_the_cache() {
+    let (upstream, requests) = cacheable_upstream().await;
+    let auth = auth_service().await;
+    let proxy = start(
+        &format!(
+            r#"
+            [upstreams]
+            servers = ["http://{{upstream}}"]
+
+            [cache]
+            enabled = true
+
+            [[routes]]
+            path_prefix = "/private"
+
+            [routes.forward_auth]
+            address = "http://{auth}/verify"
+
+            [[routes]]
+            path_prefix = "/shared"
+
+            [routes.forward_auth]
+            address = "http://{auth}/verify"
+            cache = true
+            "#,
+            auth = auth
+        ),
+        upstream,
+    )
+    .await;
+
+    let session = [("cookie", "session=ok")];
+    // Not stored without the route opting in
+    assert_eq!(get(proxy, "/private/", &session).await, (StatusCode::OK, None));
+    assert_eq!(get(proxy, "/private/", &session).await, (StatusCode::OK, None));
+    assert_eq!(get(proxy, "/private/", &[]).await.0, StatusCode::UNAUTHORIZED);
+    assert_eq!(requests.load(Ordering::SeqCst), 2);
+    // The service is told the real client, not one the client made up
+    assert_eq!(get(proxy, "/private/", &[("cookie", "session=ok"), ("x-forwarded-for", "10.9.9.9")]).await.0, StatusCode::OK);
+    assert_eq!(requests.load(Ordering::SeqCst), 3);
+
+    assert_eq!(get(proxy, "/shared/", &session).await, (StatusCode::OK, Some("MISS".to_string())));
+    assert_eq!(get(proxy, "/shared/", &session).await, (StatusCode::OK, Some("HIT".to_string())));
+    assert_eq!(get(proxy, "/shared/", &[]).await.0, StatusCode::UNAUTHORIZED);
+    assert_eq!(requests.load(Ordering::SeqCst), 4);
+}
+
+#[tokio::test]
+async fn forward_auth_answer_keeps_its_body() {
+    let (upstream, _) = cacheable_upstream().await;
+    let auth = auth_service().await;
+    let dir = std::env::temp_dir().join(format!("riffy-forward-auth-{}", std::process::id()));
+    std::fs::create_dir_all(&dir).unwrap();
+    let page = dir.join("401.html");
+    std::fs::write(&page, "<h1>Unauthorized</h1>").unwrap();
+    let proxy = start(
+        &format!(
+            r#"
+            [upstreams]
+            servers = ["http://{{upstream}}"]
+
+            [error_pages.401]
+            html = "{page}"
+
+            [[routes]]
+            path_prefix = "/private"
+
+            [routes.forward_auth]
+            address = "http://{auth}/verify"
+            "#,
+            page = page.display(),
+            auth = auth
+        ),
+        upstream,
+    )
+    .await;
+
+    let res = Client::new().get(format!("http://{}/private/", proxy).parse().unwrap()).await.unwrap();
+    assert_eq!(res.status(), StatusCode::UNAUTHORIZED);
+    assert_eq!(hyper::body::to_bytes(res.into_body()).await.unwrap(), "log in first");
+    std::fs::remove_dir_all(&dir).unwrap();
+}